@@ -1,15 +1,21 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
 use std::fs::{
-    create_dir, read_dir, read_to_string, remove_dir_all, symlink_metadata, File, OpenOptions,
+    create_dir, create_dir_all, read_dir, read_to_string, remove_dir_all, remove_file,
+    symlink_metadata, File,
 };
-use std::io::Write;
-use std::os::unix::{fs::symlink, io::AsRawFd};
+use std::io::{Read, Write};
+use std::os::unix::fs::symlink;
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Stdio};
 use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use crate::backend::Backend;
+use crate::backend::{
+    update_partition_cache, Backend, Placement, Progress, RemovalCheck, SafeToRemove,
+};
 
 use blkid::BlkId;
 use ceph::ceph::{connect_to_ceph, Rados};
@@ -18,17 +24,19 @@ use ceph::CephVersion;
 use ceph_safe_disk::diag::{DiagMap, Format, Status};
 use dirs::home_dir;
 use fstab::FsTab;
-use helpers::{error::*, host_information::Host};
+use hashicorp_vault::client::{TokenData, VaultClient};
+use helpers::{error::*, host_information::Host, lock_lvm};
 use init_daemon::{detect_daemon, Daemon};
 use log::{debug, error, info, trace};
 use lvm::*;
 use nix::{
-    convert_ioctl_res, ioc, ioctl_none, request_code_none,
+    fcntl::{flock, FlockArg},
     unistd::chown,
     unistd::{Gid, Uid},
 };
 use pwd::Passwd;
 use serde_derive::*;
+use serde_json::json;
 use tempdir::TempDir;
 
 /// Ceph cluster
@@ -71,6 +79,66 @@ impl fmt::Display for JournalDevice {
     }
 }
 
+/// The kinds of partition Bynar makes on shared devices
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SharedPartition {
+    /// Filestore journal.  Also a bluestore WAL when only journal_devices
+    /// are configured
+    Journal,
+    /// Bluestore block.db
+    Db,
+    /// Bluestore block.wal
+    Wal,
+}
+
+impl SharedPartition {
+    // GPT partition name
+    fn name(self) -> &'static str {
+        match self {
+            SharedPartition::Journal => "ceph_journal",
+            SharedPartition::Db => "ceph_block_db",
+            SharedPartition::Wal => "ceph_block_wal",
+        }
+    }
+
+    fn part_type(self) -> gpt::partition_types::Type {
+        match self {
+            SharedPartition::Journal => gpt::partition_types::CEPH_JOURNAL,
+            SharedPartition::Db => gpt::partition_types::CEPH_BLOCK_DB,
+            SharedPartition::Wal => gpt::partition_types::CEPH_BLOCK_WRITE_AHEAD_LOG,
+        }
+    }
+
+    // The ceph.conf option that sizes it and what to multiply that by to
+    // get bytes.  osd_journal_size is in MB
+    fn size_option(self) -> (&'static str, u64) {
+        match self {
+            SharedPartition::Journal => ("osd_journal_size", 1024 * 1024),
+            SharedPartition::Db => ("bluestore_block_db_size", 1),
+            SharedPartition::Wal => ("bluestore_block_wal_size", 1),
+        }
+    }
+}
+
+/// Partitions an osd has on shared devices
+#[derive(Debug, Default)]
+struct OsdPartitions {
+    /// Filestore journal or bluestore block.wal
+    journal: Option<JournalDevice>,
+    /// Bluestore block.db
+    db: Option<JournalDevice>,
+}
+
+impl OsdPartitions {
+    fn paths(&self) -> Vec<PathBuf> {
+        self.journal
+            .iter()
+            .chain(self.db.iter())
+            .map(|p| PathBuf::from(format!("{}", p)))
+            .collect()
+    }
+}
+
 #[test]
 fn test_journal_sorting() {
     let a = JournalDevice {
@@ -103,6 +171,117 @@ struct CephConfig {
     /// Bynar will create new partitions on these devices as needed
     /// if no journal_partition_id is given
     journal_devices: Option<Vec<JournalDevice>>,
+    /// The /dev/xxx devices to put bluestore block.db partitions on.
+    /// Sized with bluestore_block_db_size from ceph.conf
+    db_devices: Option<Vec<JournalDevice>>,
+    /// The /dev/xxx devices to put bluestore block.wal partitions on.
+    /// Sized with bluestore_block_wal_size from ceph.conf.  journal_devices
+    /// are used for the WAL if this isn't set
+    wal_devices: Option<Vec<JournalDevice>>,
+    /// Drain osds before removing them instead of marking them out
+    /// straight away.  Spreads the rebalance out over time
+    drain: Option<DrainConfig>,
+    /// Add osds at crush weight 0 and raise it in steps instead of
+    /// adding them at full weight
+    ramp: Option<RampConfig>,
+    /// Free journal, db and wal partitions that no osd uses any more
+    reclaim: Option<ReclaimConfig>,
+    /// Crush buckets new osds go under.  ie: rack=r12, chassis={serial_number}.
+    /// {hostname}, {region}, {serial_number} and {server_type} are filled in
+    /// from the host.  The host bucket defaults to the hostname
+    crush_location: Option<BTreeMap<String, String>>,
+    /// Crush device classes to use instead of the hdd, ssd or nvme that
+    /// goes with the media type.  Keyed by device name or by that class
+    device_classes: Option<HashMap<String, String>>,
+    /// Split NVMe devices into this many bluestore osds.  Defaults to 1
+    osds_per_device: Option<u64>,
+    /// LUKS encrypt the data, db and wal volumes of new bluestore osds.
+    /// The keys are kept in Vault
+    #[serde(default)]
+    dmcrypt: bool,
+    /// Vault to keep dm-crypt keys in.  Required with dmcrypt
+    vault_endpoint: Option<String>,
+    vault_token: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct DrainConfig {
+    /// How many steps to take the crush weight down to 0 in
+    #[serde(default = "default_drain_steps")]
+    steps: u32,
+    /// Seconds to wait between steps once the cluster is active+clean
+    #[serde(default)]
+    step_delay: u64,
+    /// Seconds between checks on the cluster
+    #[serde(default = "default_drain_poll_interval")]
+    poll_interval: u64,
+    /// Seconds to wait for the cluster to settle after a step before the
+    /// drain fails
+    #[serde(default = "default_drain_timeout")]
+    timeout: u64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RampConfig {
+    /// How many steps to take the crush weight up to its target in
+    #[serde(default = "default_ramp_steps")]
+    steps: u32,
+    /// Seconds to wait after each step
+    #[serde(default)]
+    step_delay: u64,
+    /// Seconds between checks on the cluster
+    #[serde(default = "default_ramp_poll_interval")]
+    poll_interval: u64,
+    /// Take the next step while backfill is below this many bytes a second
+    /// instead of waiting for every placement group to be active+clean
+    max_recovery_bytes_per_sec: Option<u64>,
+    /// Where ramps in progress are recorded.
+    /// Defaults to /var/lib/bynar/ceph_ramps
+    state_dir: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct ReclaimConfig {
+    /// Seconds between checks for unused partitions
+    #[serde(default = "default_reclaim_interval")]
+    interval: u64,
+    /// Seconds a partition has to stay unused before it's freed
+    #[serde(default = "default_reclaim_grace_period")]
+    grace_period: u64,
+    /// Only log the partitions that would be freed
+    #[serde(default)]
+    simulate: bool,
+    /// Where unused partitions and when they were first seen are recorded.
+    /// Defaults to /var/lib/bynar/ceph_unused_partitions.json
+    state_file: Option<String>,
+}
+
+fn default_reclaim_interval() -> u64 {
+    3600
+}
+
+fn default_reclaim_grace_period() -> u64 {
+    86400
+}
+
+fn default_drain_steps() -> u32 {
+    4
+}
+
+fn default_drain_poll_interval() -> u64 {
+    30
+}
+
+fn default_drain_timeout() -> u64 {
+    21600
+}
+
+fn default_ramp_steps() -> u32 {
+    4
+}
+
+fn default_ramp_poll_interval() -> u64 {
+    30
 }
 
 fn choose_ceph_config(config_dir: Option<&Path>) -> BynarResult<PathBuf> {
@@ -139,6 +318,13 @@ impl CephBackend {
         }
         let s = read_to_string(ceph_config)?;
         let deserialized: CephConfig = serde_json::from_str(&s)?;
+        if deserialized.dmcrypt
+            && (deserialized.vault_endpoint.is_none() || deserialized.vault_token.is_none())
+        {
+            return Err(BynarError::from(
+                "vault_endpoint and vault_token must be set in ceph.json for dmcrypt",
+            ));
+        }
 
         info!("Connecting to Ceph");
         let cluster_handle = connect_to_ceph(&deserialized.user_id, &deserialized.config_file)?;
@@ -156,21 +342,104 @@ impl CephBackend {
         &self,
         dev_path: &Path,
         id: Option<u64>,
+        placement: Option<&Placement>,
         simulate: bool,
+        progress: &dyn Progress,
     ) -> BynarResult<()> {
+        let count = self.osds_per_device(dev_path)?;
+        let mut ramps = vec![];
+        for slot in 0..count {
+            // A replaced disk only ever had the one osd
+            let (id, placement) = if slot == 0 {
+                (id, placement)
+            } else {
+                (None, None)
+            };
+            let (osd_id, osd_weight) = self
+                .create_bluestore_osd(dev_path, id, placement, slot, count, simulate, progress)?;
+            // Recorded as each osd is made so ones left at weight 0 by a
+            // later slot failing are ramped when the add is resumed
+            if let Some(record) = self.start_ramp(osd_id, dev_path, osd_weight, simulate)? {
+                ramps.push(record);
+            }
+        }
+        // Every osd on the device is in before any of them take on data
+        if let Some(ref ramp) = self.config.ramp {
+            for record in ramps {
+                self.ramp_osd(record, ramp, simulate, progress)?;
+            }
+        }
+        Ok(())
+    }
+
+    // How many osds to split a device into.  Only NVMe devices are split
+    fn osds_per_device(&self, dev_path: &Path) -> BynarResult<u64> {
+        let count = self.config.osds_per_device.unwrap_or(1).max(1);
+        if count == 1 {
+            return Ok(1);
+        }
+        match block_utils::get_device_info(dev_path)?.media_type {
+            block_utils::MediaType::NVME => Ok(count),
+            _ => Ok(1),
+        }
+    }
+
+    // Make one bluestore osd on slot of the count logical volumes the device
+    // is split into.  Returns the osd id and the crush weight to ramp it to
+    #[allow(clippy::too_many_arguments)]
+    fn create_bluestore_osd(
+        &self,
+        dev_path: &Path,
+        id: Option<u64>,
+        placement: Option<&Placement>,
+        slot: u64,
+        count: u64,
+        simulate: bool,
+        progress: &dyn Progress,
+    ) -> BynarResult<(u64, f64)> {
+        let osd_fsid = uuid::Uuid::new_v4();
+        let result = self.make_bluestore_osd(
+            osd_fsid, dev_path, id, placement, slot, count, simulate, progress,
+        );
+        if result.is_err() && self.config.dmcrypt {
+            self.discard_dmcrypt_key(&osd_fsid, simulate);
+        }
+        result
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn make_bluestore_osd(
+        &self,
+        osd_fsid: uuid::Uuid,
+        dev_path: &Path,
+        id: Option<u64>,
+        placement: Option<&Placement>,
+        slot: u64,
+        count: u64,
+        simulate: bool,
+        progress: &dyn Progress,
+    ) -> BynarResult<(u64, f64)> {
         /*
         //TODO  What is the deal with this tmpfs??
         mount, "-t", "tmpfs", "tmpfs", "/var/lib/ceph/osd/ceph-2"
             */
-        // Create the journal device if requested
-        let journal = self.select_journal()?;
+        // Create the wal and db partitions if requested
+        let partitions = self.partitions_for(placement, true)?;
 
         // Create a new osd id
-        let new_osd_id = osd_create(&self.cluster_handle, id, simulate)?;
+        progress.step("osd_create")?;
+        let new_osd_id = self.create_osd_id(id, &osd_fsid, simulate)?;
         debug!("New osd id created: {:?}", new_osd_id);
-        let osd_fsid = uuid::Uuid::new_v4();
-        let (lv_dev_name, vg_size) =
-            self.create_lvm(&osd_fsid, new_osd_id, &dev_path, journal.as_ref())?;
+        progress.step("lvm")?;
+        let (lv_dev_name, lv_size, block_uuid) =
+            self.create_lvm(&osd_fsid, new_osd_id, &dev_path, &partitions, slot, count)?;
+        // Encrypted osds are made on the unlocked volumes
+        let (block_dev, partitions) = if self.config.dmcrypt {
+            progress.step("dmcrypt")?;
+            self.encrypt_osd(&osd_fsid, &block_uuid, &lv_dev_name, &partitions, simulate)?
+        } else {
+            (lv_dev_name.clone(), partitions)
+        };
 
         // Mount the drive
         let mount_point = Path::new("/var/lib/ceph/osd").join(&format!("ceph-{}", new_osd_id));
@@ -190,24 +459,14 @@ impl CephBackend {
 
         // LVM's logical volume name is a symlink to the true device
         // This finds that device and then we chown it so ceph can use it
-        let backer_device = self.resolve_lvm_device(&lv_dev_name)?;
+        let backer_device = self.resolve_lvm_device(&block_dev)?;
         debug!("Resolved lvm device to {}", backer_device.display());
         debug!(
             "Symlinking {} to {}",
-            lv_dev_name.display(),
+            block_dev.display(),
             mount_point.join("block").display()
         );
-        symlink(&lv_dev_name, mount_point.join("block"))?;
-        // Optionally symlink the journal if using one
-        if let Some(journal) = &journal {
-            symlink(
-                &Path::new(&format!("{}", journal)),
-                mount_point.join("block.wal"),
-            )?;
-            let ceph_user = Passwd::from_name("ceph")?
-                .ok_or_else(|| BynarError::from("ceph user id not found"))?;
-            self.change_permissions(&[&Path::new(&format!("{}", journal))], &ceph_user)?;
-        }
+        symlink(&block_dev, mount_point.join("block"))?;
 
         // Write activate monmap out
         debug!("Getting latest monmap from ceph");
@@ -224,16 +483,22 @@ impl CephBackend {
             &[&backer_device, &activate_path, &mount_point, &fsid_path],
             &ceph_user,
         )?;
+        // ceph-osd --mkfs links these in as block.wal and block.db
+        for path in partitions.paths() {
+            self.change_permissions(&[&path], &ceph_user)?;
+        }
         debug!("Creating ceph authorization entry");
+        progress.step("auth_add")?;
         osd_auth_add(&self.cluster_handle, new_osd_id, simulate)?;
         let auth_key = auth_get_key(&self.cluster_handle, "osd", &new_osd_id.to_string())?;
         debug!("Saving ceph keyring");
         save_keyring(new_osd_id, &auth_key, Some(0), Some(0), simulate)?;
 
         // Format the osd with the osd filesystem
+        progress.step("mkfs")?;
         ceph_mkfs(
             new_osd_id,
-            journal.as_ref(),
+            &partitions,
             true,
             Some(&activate_path),
             Some(&mount_point),
@@ -242,25 +507,40 @@ impl CephBackend {
             Some("ceph"),
             simulate,
         )?;
-        ceph_bluestore_tool(&lv_dev_name, &mount_point, simulate)?;
+        ceph_bluestore_tool(&block_dev, &mount_point, simulate)?;
 
         let host_info = Host::new()?;
-        let gb_capacity = vg_size / 1_073_741_824;
+        let gb_capacity = lv_size / 1_073_741_824;
         let osd_weight = gb_capacity as f64 * 0.001_f64;
         debug!(
             "Adding OSD {} to crushmap under host {} with weight: {}",
             new_osd_id, host_info.hostname, osd_weight
         );
-        osd_crush_add(
-            &self.cluster_handle,
+        progress.step("crush_add")?;
+        self.crush_add(
             new_osd_id,
-            osd_weight,
-            &host_info.hostname,
+            self.initial_weight(osd_weight),
+            &host_info,
+            placement,
             simulate,
         )?;
-        systemctl_enable(new_osd_id, &osd_fsid, simulate)?;
+        // A replacement keeps the class of the disk it replaces
+        let class = match placement.and_then(|p| p.device_class.clone()) {
+            Some(class) => Some(class),
+            None => self.device_class(&block_utils::get_device_info(dev_path)?),
+        };
+        if let Some(class) = class {
+            progress.step("device_class")?;
+            self.set_device_class(new_osd_id, &class, simulate)?;
+        }
+        progress.step("systemd")?;
+        // ceph-volume can't get keys out of Vault.  disk-manager unlocks
+        // and starts encrypted osds itself when it starts
+        if !self.config.dmcrypt {
+            systemctl_enable(new_osd_id, &osd_fsid, simulate)?;
+        }
         setup_osd_init(new_osd_id, simulate)?;
-        Ok(())
+        Ok((new_osd_id, osd_weight))
     }
 
     /// Add a new /dev/ path as an osd.
@@ -270,7 +550,9 @@ impl CephBackend {
         &self,
         dev_path: &Path,
         id: Option<u64>,
+        placement: Option<&Placement>,
         simulate: bool,
+        progress: &dyn Progress,
     ) -> BynarResult<()> {
         //Format the drive
         let xfs_options = block_utils::Filesystem::Xfs {
@@ -285,6 +567,7 @@ impl CephBackend {
             "Formatting {:?} with XFS options: {:?}",
             dev_path, xfs_options
         );
+        progress.step("format")?;
         if !simulate {
             block_utils::format_block_device(dev_path, &xfs_options)?;
             let _ = settle_udev();
@@ -301,11 +584,15 @@ impl CephBackend {
             )));
         }
 
-        // Create a new osd id
-        let new_osd_id = osd_create(&self.cluster_handle, id, simulate)?;
+        // Create a new osd id.  A destroyed id being reused needs osd new
+        // and the fsid given to it has to match the one mkfs writes
+        progress.step("osd_create")?;
+        let osd_fsid = uuid::Uuid::new_v4();
+        let new_osd_id = self.create_osd_id(id, &osd_fsid, simulate)?;
         debug!("New osd id created: {:?}", new_osd_id);
 
         // Mount the drive
+        progress.step("mount")?;
         let mount_point = Path::new("/var/lib/ceph/osd/").join(format!("ceph-{}", new_osd_id));
         if !simulate {
             if !mount_point.exists() {
@@ -318,21 +605,23 @@ impl CephBackend {
             block_utils::mount_device(&info, &mount_point)?;
         }
 
-        let journal = self.select_journal()?;
+        let partitions = self.partitions_for(placement, false)?;
 
         // Format the osd with the osd filesystem
+        progress.step("mkfs")?;
         ceph_mkfs(
             new_osd_id,
-            journal.as_ref(),
+            &partitions,
             false,
             None,
             None,
-            None,
+            Some(&osd_fsid),
             None,
             None,
             simulate,
         )?;
         debug!("Creating ceph authorization entry");
+        progress.step("auth_add")?;
         osd_auth_add(&self.cluster_handle, new_osd_id, simulate)?;
         let auth_key = auth_get_key(&self.cluster_handle, "osd", &new_osd_id.to_string())?;
         debug!("Saving ceph keyring");
@@ -344,16 +633,19 @@ impl CephBackend {
             "Adding OSD {} to crushmap under host {} with weight: {}",
             new_osd_id, host_info.hostname, osd_weight
         );
-        osd_crush_add(
-            &self.cluster_handle,
+        progress.step("crush_add")?;
+        self.crush_add(
             new_osd_id,
-            osd_weight,
-            &host_info.hostname,
+            self.initial_weight(osd_weight),
+            &host_info,
+            placement,
             simulate,
         )?;
         add_osd_to_fstab(&info, new_osd_id, simulate)?;
         // This step depends on whether it's systemctl, upstart, etc
+        progress.step("systemd")?;
         setup_osd_init(new_osd_id, simulate)?;
+        self.ramp_new_osd(new_osd_id, dev_path, osd_weight, simulate, progress)?;
         Ok(())
     }
 
@@ -370,28 +662,43 @@ impl CephBackend {
         Ok(())
     }
 
-    // Create the LVM device and return the path and size of it
+    // Create the LVM device and return the path, size and uuid of it.  The
+    // volume group is made for the first slot and split between count
+    // logical volumes
     fn create_lvm(
         &self,
         osd_fsid: &uuid::Uuid,
         new_osd_id: u64,
         dev_path: &Path,
-        journal_device: Option<&JournalDevice>,
-    ) -> BynarResult<(PathBuf, u64)> {
+        partitions: &OsdPartitions,
+        slot: u64,
+        count: u64,
+    ) -> BynarResult<(PathBuf, u64, String)> {
         debug!("udev Probing device {:?}", dev_path);
         let info = block_utils::get_device_info(dev_path)?;
         debug!("udev info {:?}", info);
-        let vg_name = format!("ceph-{}", uuid::Uuid::new_v4());
         let lv_name = format!("osd-block-{}", osd_fsid);
-        let lv_dev_name = Path::new("/dev").join(&vg_name).join(&lv_name);
         debug!("initializing LVM");
+        let _lvm_lock = lock_lvm();
         let lvm = Lvm::new(None)?;
         lvm.scan()?;
-        debug!("Creating volume group: {}", vg_name);
-        let vg = lvm.vg_create(&vg_name)?;
-        debug!("Adding {} to volume group", dev_path.display());
-        vg.extend(dev_path)?;
-        vg.write()?;
+        let (vg_name, vg) = match lvm.vg_name_from_device(&dev_path.to_string_lossy())? {
+            Some(vg_name) if slot > 0 => {
+                debug!("Adding to volume group: {}", vg_name);
+                let vg = lvm.vg_open(&vg_name, &OpenMode::Write)?;
+                (vg_name, vg)
+            }
+            _ => {
+                let vg_name = format!("ceph-{}", uuid::Uuid::new_v4());
+                debug!("Creating volume group: {}", vg_name);
+                let vg = lvm.vg_create(&vg_name)?;
+                debug!("Adding {} to volume group", dev_path.display());
+                vg.extend(dev_path)?;
+                vg.write()?;
+                (vg_name, vg)
+            }
+        };
+        let lv_dev_name = Path::new("/dev").join(&vg_name).join(&lv_name);
         debug!(
             "Creating logical volume: {} of size: {} with {} extents free.  Extent size: {}",
             lv_name,
@@ -400,17 +707,20 @@ impl CephBackend {
             vg.get_extent_size(),
         );
         // TODO: Why does this magic number work but using the entire size doesn't?
-        let lv = vg.create_lv_linear(&lv_name, vg.get_size() - 10_485_760)?;
-
-        self.create_lvm_tags(
-            &lv,
-            &lv_dev_name,
-            &osd_fsid,
-            new_osd_id,
-            &info,
-            journal_device,
-        )?;
-        Ok((lv_dev_name.to_path_buf(), vg.get_size()))
+        let mut lv_size = vg.get_size() - 10_485_760;
+        if count > 1 {
+            // Whole extents so the last one still fits
+            lv_size /= count;
+            lv_size -= lv_size % vg.get_extent_size();
+        }
+        let lv = vg.create_lv_linear(&lv_name, lv_size)?;
+
+        self.create_lvm_tags(&lv, &lv_dev_name, &osd_fsid, new_osd_id, &info, partitions)?;
+        Ok((
+            lv_dev_name.to_path_buf(),
+            vg.get_size() / count,
+            lv.get_uuid().to_string(),
+        ))
     }
 
     // Add the lvm tags that ceph requires to identify the osd
@@ -421,7 +731,7 @@ impl CephBackend {
         osd_fsid: &uuid::Uuid,
         new_osd_id: u64,
         info: &block_utils::Device,
-        journal_device: Option<&JournalDevice>,
+        partitions: &OsdPartitions,
     ) -> BynarResult<()> {
         debug!("Creating lvm tags");
         let mut tags = vec![
@@ -432,39 +742,21 @@ impl CephBackend {
             // TODO: Find out where to find this.
             format!("ceph.cluster_name={}", "ceph"),
             format!("ceph.cluster_fsid={}", self.cluster_handle.rados_fsid()?),
-            format!("ceph.encrypted={}", "0"),
+            format!("ceph.encrypted={}", if self.config.dmcrypt { 1 } else { 0 }),
             "ceph.cephx_lockbox_secret=".to_string(),
             format!("ceph.block_uuid={}", lv.get_uuid()),
         ];
-        if let Some(journal_dev) = journal_device {
-            tags.push(format!("ceph.wal_device={}", journal_dev));
-            let uuid = match journal_dev.partition_uuid {
-                Some(uuid) => uuid,
-                None => {
-                    debug!("Discovering {} partition uuid", journal_dev);
-                    let blkid = BlkId::new(&Path::new(&format!("{}", journal_dev)))?;
-                    blkid.do_probe()?;
-                    uuid::Uuid::from_str(&blkid.lookup_value("PARTUUID")?)?
-                }
-            };
-            // Get the partition uuid from the device
-            tags.push(format!("ceph.wal_uuid={}", uuid));
+        if let Some(ref wal) = partitions.journal {
+            tags.extend(partition_tags("wal", wal)?);
+        }
+        if let Some(ref db) = partitions.db {
+            tags.extend(partition_tags("db", db)?);
         }
 
         // Tell ceph what type of underlying media this is
-        match info.media_type {
-            block_utils::MediaType::SolidState => {
-                tags.push("ceph.crush_device_class=ssd".into());
-            }
-            block_utils::MediaType::Rotational => {
-                tags.push("ceph.crush_device_class=hdd".into());
-            }
-            block_utils::MediaType::NVME => {
-                tags.push("ceph.crush_device_class=nvme".into());
-            }
-            _ => {
-                tags.push("ceph.crush_device_class=None".into());
-            }
+        match self.device_class(info) {
+            Some(class) => tags.push(format!("ceph.crush_device_class={}", class)),
+            None => tags.push("ceph.crush_device_class=None".into()),
         };
 
         // Add all the tags to the lvm
@@ -475,24 +767,27 @@ impl CephBackend {
         Ok(())
     }
 
-    fn remove_bluestore_osd(&self, dev_path: &Path, simulate: bool) -> BynarResult<()> {
-        debug!("initializing LVM");
-        let lvm = Lvm::new(None)?;
-        lvm.scan()?;
+    // keep_id destroys instead of purging so a replacement can take over
+    // the id and crush location
+    fn remove_bluestore_osd(
+        &self,
+        dev_path: &Path,
+        keep_id: bool,
+        simulate: bool,
+        progress: &dyn Progress,
+    ) -> BynarResult<()> {
         // Get the volume group that this device is associated with
-        let vol_group_name = match lvm
-            .vg_name_from_device(&dev_path.to_string_lossy())?
-            .ok_or_else(|| {
-                BynarError::new(format!(
-                    "No volume group associated with block device: {}",
-                    dev_path.display()
-                ))
-            }) {
+        let (vol_group_name, lv_tags) = match lvm_volume_tags(dev_path)?.ok_or_else(|| {
+            BynarError::new(format!(
+                "No volume group associated with block device: {}",
+                dev_path.display()
+            ))
+        }) {
             Ok(vg_group) => vg_group,
             Err(e) => {
                 // This might be a filestore osd.  Fall back possibly
                 if is_filestore(&dev_path)? {
-                    self.remove_filestore_osd(dev_path, simulate)?;
+                    self.remove_filestore_osd(dev_path, keep_id, simulate, progress)?;
                     return Ok(());
                 } else {
                     return Err(e);
@@ -500,15 +795,31 @@ impl CephBackend {
             }
         };
         debug!("Found volume group: {}", vol_group_name);
-        let vg = lvm.vg_open(&vol_group_name, &OpenMode::Write)?;
-        // Find the logical volume in that vol group
-        let lvs = vg.list_lvs()?;
-        // List the tags to get the osd id
-        let mut osd_id = None;
-        let mut osd_fsid = None;
-        for lv in &lvs {
-            let tags = lv.get_tags()?;
+        // List the tags to get the osd ids.  A split device has one logical
+        // volume per osd
+        let mut osds = vec![];
+        // The wal and db partitions this osd has on shared devices
+        let mut shared = vec![];
+        // dm-crypt mappings are named after the volume and partition uuids
+        let mut encrypted = false;
+        let mut mappings = vec![];
+        for tags in &lv_tags {
             debug!("Found tags for logical volume: {:?}", tags);
+            for tag in tags {
+                let mut parts = tag.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some("ceph.wal_device"), Some(dev)) | (Some("ceph.db_device"), Some(dev)) => {
+                        shared.push(PathBuf::from(dev))
+                    }
+                    (Some("ceph.encrypted"), Some("1")) => encrypted = true,
+                    (Some("ceph.block_uuid"), Some(uuid))
+                    | (Some("ceph.wal_uuid"), Some(uuid))
+                    | (Some("ceph.db_uuid"), Some(uuid)) => mappings.push(uuid.to_string()),
+                    _ => {}
+                }
+            }
+            let mut osd_id = None;
+            let mut osd_fsid = None;
             let id_tag = tags.iter().find(|t| t.starts_with("ceph.osd_id"));
             if let Some(tag) = id_tag {
                 let parts: Vec<String> = tag.split('=').map(|s| s.to_string()).collect();
@@ -523,36 +834,51 @@ impl CephBackend {
                     osd_fsid = Some(uuid::Uuid::parse_str(s)?);
                 }
             }
+            if let (Some(osd_id), Some(osd_fsid)) = (osd_id, osd_fsid) {
+                osds.push((osd_id, osd_fsid));
+            }
         }
-        if osd_id.is_none() || osd_fsid.is_none() {
+        if osds.is_empty() {
             return Err(BynarError::new(format!(
                 "No osd id's or fsid's were found on {}",
                 dev_path.display()
             )));
         }
-        let osd_id = osd_id.unwrap();
-        debug!("Setting osd {} out", osd_id);
-        osd_out(&self.cluster_handle, osd_id, simulate)?;
-        debug!("Removing osd {} from crush", osd_id);
-        osd_crush_remove(&self.cluster_handle, osd_id, simulate)?;
-        debug!("Deleting osd {} auth key", osd_id);
-        auth_del(&self.cluster_handle, osd_id, simulate)?;
-        systemctl_stop(osd_id, simulate)?;
-        debug!("Removing osd {}", osd_id);
-        osd_rm(&self.cluster_handle, osd_id, simulate)?;
+        // Everything on a split device goes down together
+        if let Some(ref drain) = self.config.drain {
+            for &(osd_id, _) in &osds {
+                self.drain_osd(osd_id, drain, simulate, progress)?;
+            }
+        }
+        for &(osd_id, _) in &osds {
+            debug!("Setting osd {} out", osd_id);
+            progress.step("osd_out")?;
+            osd_out(&self.cluster_handle, osd_id, simulate)?;
+            progress.step("osd_stop")?;
+            systemctl_stop(osd_id, simulate)?;
+            if keep_id && osds.len() == 1 {
+                // The id and crush location are kept for the replacement disk
+                progress.step("osd_destroy")?;
+                self.osd_destroy(osd_id, simulate)?;
+            } else {
+                // A replacement only takes over a single osd
+                progress.step("osd_purge")?;
+                self.osd_purge(osd_id, simulate)?;
+            }
+        }
+        if encrypted {
+            // The volumes can't be removed while they're unlocked
+            progress.step("dmcrypt_close")?;
+            for mapping in &mappings {
+                luks_close(mapping, simulate)?;
+            }
+        }
 
         // Wipe the disk
         debug!("Erasing disk {}", dev_path.display());
+        progress.step("erase")?;
         if !simulate {
-            // Remove all logical volumes associated with this volume group
-            for lv in &lvs {
-                lv.deactivate()?;
-                lv.remove()?;
-            }
-            // Remove the volume group
-            vg.remove()?;
-            // Remove the physical volume
-            lvm.pv_remove(&dev_path.to_string_lossy())?;
+            remove_volume_group(dev_path, &vol_group_name)?;
 
             // Erase the physical volume
             match block_utils::erase_block_device(&dev_path) {
@@ -565,16 +891,37 @@ impl CephBackend {
                     error!("{} failed to erase: {:?}", dev_path.display(), e);
                 }
             };
-            debug!("Cleaning up /var/lib/ceph/osd/ceph-{}", osd_id);
-            remove_dir_all(Path::new("/var/lib/ceph/osd/").join(&format!("ceph-{}", osd_id)))?;
+            for &(osd_id, _) in &osds {
+                debug!("Cleaning up /var/lib/ceph/osd/ceph-{}", osd_id);
+                remove_dir_all(Path::new("/var/lib/ceph/osd/").join(&format!("ceph-{}", osd_id)))?;
+            }
+        }
+        progress.step("free_partitions")?;
+        release_all(to_release(&shared, keep_id && osds.len() == 1), simulate);
+        if encrypted {
+            // Without the key nothing left on the disk can be read
+            for &(osd_id, ref osd_fsid) in &osds {
+                if let Err(e) = self.delete_dmcrypt_key(osd_fsid, simulate) {
+                    error!("Unable to delete dm-crypt key of osd {}: {:?}", osd_id, e);
+                }
+            }
         }
 
-        systemctl_disable(osd_id, &osd_fsid.unwrap(), simulate)?;
+        progress.step("systemd")?;
+        for &(osd_id, ref osd_fsid) in &osds {
+            systemctl_disable(osd_id, osd_fsid, simulate)?;
+        }
 
         Ok(())
     }
 
-    fn remove_filestore_osd(&self, dev_path: &Path, simulate: bool) -> BynarResult<()> {
+    fn remove_filestore_osd(
+        &self,
+        dev_path: &Path,
+        keep_id: bool,
+        simulate: bool,
+        progress: &dyn Progress,
+    ) -> BynarResult<()> {
         //If the OSD is still running we can query its version.  If not then we
         //should ask either another OSD or a monitor.
         let mount_point = match block_utils::get_mountpoint(&dev_path)? {
@@ -596,17 +943,39 @@ impl CephBackend {
                 get_osd_id_from_path(&mount_point)?
             }
         };
+        // Read before the osd is unmounted
+        let journal = mount_point.join("journal").read_link().ok();
+        if let Some(ref drain) = self.config.drain {
+            self.drain_osd(osd_id, drain, simulate, progress)?;
+        }
         debug!("Setting osd {} out", osd_id);
+        progress.step("osd_out")?;
         osd_out(&self.cluster_handle, osd_id, simulate)?;
-        debug!("Removing osd {} from crush", osd_id);
-        osd_crush_remove(&self.cluster_handle, osd_id, simulate)?;
-        debug!("Deleting osd {} auth key", osd_id);
-        auth_del(&self.cluster_handle, osd_id, simulate)?;
-        debug!("Removing osd {}", osd_id);
-        osd_rm(&self.cluster_handle, osd_id, simulate)?;
+        if self.version >= CephVersion::Luminous {
+            progress.step("osd_stop")?;
+            systemctl_stop(osd_id, simulate)?;
+            if keep_id {
+                progress.step("osd_destroy")?;
+                self.osd_destroy(osd_id, simulate)?;
+            } else {
+                progress.step("osd_purge")?;
+                self.osd_purge(osd_id, simulate)?;
+            }
+        } else {
+            debug!("Removing osd {} from crush", osd_id);
+            progress.step("crush_remove")?;
+            osd_crush_remove(&self.cluster_handle, osd_id, simulate)?;
+            debug!("Deleting osd {} auth key", osd_id);
+            progress.step("auth_del")?;
+            auth_del(&self.cluster_handle, osd_id, simulate)?;
+            debug!("Removing osd {}", osd_id);
+            progress.step("osd_rm")?;
+            osd_rm(&self.cluster_handle, osd_id, simulate)?;
+        }
 
         // Wipe the disk
         debug!("Erasing disk {}", dev_path.display());
+        progress.step("erase")?;
         if !simulate {
             match block_utils::erase_block_device(&dev_path) {
                 Ok(_) => {
@@ -619,10 +988,113 @@ impl CephBackend {
                 }
             };
         }
+        progress.step("free_partitions")?;
+        let kept = keep_id && self.version >= CephVersion::Luminous;
+        release_all(to_release(&journal, kept), simulate);
+
+        Ok(())
+    }
+
+    // LUKS format the osd's block volume and its wal and db partitions,
+    // save the key in Vault and unlock them.  Returns the unlocked block
+    // device and partitions.  Like ceph-volume the mappings are named after
+    // the logical volume uuid and partition uuids
+    fn encrypt_osd(
+        &self,
+        osd_fsid: &uuid::Uuid,
+        block_uuid: &str,
+        lv_dev_name: &Path,
+        partitions: &OsdPartitions,
+        simulate: bool,
+    ) -> BynarResult<(PathBuf, OsdPartitions)> {
+        let key = new_dmcrypt_key()?;
+        debug!("Saving dm-crypt key for osd {} in vault", osd_fsid);
+        if !simulate {
+            self.vault_client()?
+                .set_secret(dmcrypt_key_name(osd_fsid), &key)?;
+        }
+        luks_format(lv_dev_name, &key, simulate)?;
+        let block = luks_open(lv_dev_name, block_uuid, &key, simulate)?;
+
+        let encrypt = |partition: &Option<JournalDevice>| -> BynarResult<Option<JournalDevice>> {
+            let partition = match partition {
+                Some(partition) => partition,
+                None => return Ok(None),
+            };
+            let path = PathBuf::from(format!("{}", partition));
+            let uuid = match partition.partition_uuid {
+                Some(uuid) => uuid,
+                None => probe_partition_uuid(&path)?,
+            };
+            luks_format(&path, &key, simulate)?;
+            let mapped = luks_open(&path, &uuid.to_hyphenated().to_string(), &key, simulate)?;
+            Ok(Some(JournalDevice {
+                device: mapped,
+                partition_id: None,
+                partition_uuid: Some(uuid),
+                num_partitions: None,
+            }))
+        };
+        let unlocked = OsdPartitions {
+            journal: encrypt(&partitions.journal)?,
+            db: encrypt(&partitions.db)?,
+        };
+        Ok((block, unlocked))
+    }
+
+    // Unlock an encrypted osd's volumes with its key from Vault and start it
+    fn unlock_osd(&self, osd: &EncryptedOsd) -> BynarResult<()> {
+        debug!("Unlocking osd {}", osd.id);
+        let key = self
+            .vault_client()?
+            .get_secret(dmcrypt_key_name(&osd.fsid))?;
+        let ceph_user =
+            Passwd::from_name("ceph")?.ok_or_else(|| BynarError::from("ceph user id not found"))?;
+        for (device, mapping) in &osd.volumes {
+            let mapped = luks_open(device, mapping, &key, false)?;
+            // New mappings are owned by root
+            let backer_device = self.resolve_lvm_device(&mapped)?;
+            self.change_permissions(&[&backer_device], &ceph_user)?;
+        }
+        setup_osd_init(osd.id, false)
+    }
+
+    fn vault_client(&self) -> BynarResult<VaultClient<TokenData>> {
+        match (&self.config.vault_endpoint, &self.config.vault_token) {
+            (Some(endpoint), Some(token)) => Ok(VaultClient::new(endpoint.as_str(), token)?),
+            _ => Err(BynarError::from(
+                "vault_endpoint and vault_token must be set in ceph.json for dmcrypt",
+            )),
+        }
+    }
 
+    fn delete_dmcrypt_key(&self, osd_fsid: &uuid::Uuid, simulate: bool) -> BynarResult<()> {
+        debug!("Deleting dm-crypt key for osd {} from vault", osd_fsid);
+        if !simulate {
+            self.vault_client()?
+                .delete_secret(&dmcrypt_key_name(osd_fsid))?;
+        }
         Ok(())
     }
 
+    // encrypt_osd saves the key before it formats anything.  Nothing can be
+    // unlocked with it once the add has failed
+    fn discard_dmcrypt_key(&self, osd_fsid: &uuid::Uuid, simulate: bool) {
+        if simulate {
+            return;
+        }
+        let saved = self.vault_client().and_then(|client| {
+            client
+                .get_secret(dmcrypt_key_name(osd_fsid))
+                .map_err(BynarError::from)
+        });
+        if saved.is_ok() {
+            if let Err(e) = self.delete_dmcrypt_key(osd_fsid, simulate) {
+                error!("Unable to delete dm-crypt key of osd {}: {:?}", osd_fsid, e);
+            }
+        }
+    }
+
     // lvm devices are symlinks.  They need to be resolved back into an
     // absolute path to do anything useful with them.
     fn resolve_lvm_device(&self, lv_dev_name: &Path) -> BynarResult<PathBuf> {
@@ -647,22 +1119,58 @@ impl CephBackend {
 
     // Find the journal device that has enough free space
     fn select_journal(&self) -> BynarResult<Option<JournalDevice>> {
-        let journal_size = u64::from_str(&self.cluster_handle.config_get("osd_journal_size")?)?;
-        // The config file uses MB as the journal size
-        let journal_size_mb = journal_size * 1024 * 1024;
-        let mut journal_devices = self
-            .config
-            .journal_devices
-            .clone()
-            .unwrap_or_else(|| vec![]);
-        // Sort by number of partitions
-        journal_devices.sort_by_key(|j| j.num_partitions);
-        // Clear any space that we can
-        //remove_unused_journals(&journal_devices)?;
-        let journal: Option<&JournalDevice> = journal_devices
+        match self.config.journal_devices {
+            Some(ref devices) => self.select_partition(SharedPartition::Journal, devices),
+            None => Ok(None),
+        }
+    }
+
+    // Older configs only have journal_devices.  Those were always used for
+    // the WAL
+    fn select_wal(&self) -> BynarResult<Option<JournalDevice>> {
+        match self.config.wal_devices {
+            Some(ref devices) => self.select_partition(SharedPartition::Wal, devices),
+            None => self.select_journal(),
+        }
+    }
+
+    // An osd without the db it was configured with would keep its RocksDB on
+    // the slow disk so that fails the add
+    fn select_db(&self) -> BynarResult<Option<JournalDevice>> {
+        match self.config.db_devices {
+            Some(ref devices) => match self.select_partition(SharedPartition::Db, devices)? {
+                None if !devices.is_empty() => Err(BynarError::from(
+                    "None of the db_devices have room for a db partition",
+                )),
+                db => Ok(db),
+            },
+            None => Ok(None),
+        }
+    }
+
+    // Make a partition on the device with room for it that has the fewest
+    // partitions already.  Spreads osds evenly over the shared devices
+    fn select_partition(
+        &self,
+        kind: SharedPartition,
+        devices: &[JournalDevice],
+    ) -> BynarResult<Option<JournalDevice>> {
+        if devices.is_empty() {
+            return Ok(None);
+        }
+        let (option, multiplier) = kind.size_option();
+        let size = u64::from_str(&self.cluster_handle.config_get(option)?)? * multiplier;
+        if size == 0 {
+            return Err(BynarError::new(format!(
+                "{} has to be set in ceph.conf to make {} partitions",
+                option,
+                kind.name()
+            )));
+        }
+        let mut candidates: Vec<JournalDevice> = devices
             .iter()
             // Remove any devices without enough free space
-            .filter(|d| match enough_free_space(&d.device, journal_size_mb) {
+            .filter(|d| match enough_free_space(&d.device, size) {
                 Ok(enough) => enough,
                 Err(e) => {
                     error!(
@@ -673,73 +1181,1443 @@ impl CephBackend {
                     false
                 }
             })
-            // Take the first one
-            .take(1)
-            .next();
-        match journal {
-            Some(ref j) => Ok(Some(evaluate_journal(j, journal_size_mb)?)),
-            None => Ok(None),
+            .cloned()
+            .collect();
+        for candidate in &mut candidates {
+            candidate.update_num_partitions()?;
+        }
+        match least_used(&candidates) {
+            Some(j) => Ok(Some(evaluate_journal(j, size, kind)?)),
+            None => {
+                error!(
+                    "No device has room for a {} byte {} partition",
+                    size,
+                    kind.name()
+                );
+                Ok(None)
+            }
         }
     }
-}
 
-impl Backend for CephBackend {
-    fn add_disk(&self, device: &Path, id: Option<u64>, simulate: bool) -> BynarResult<()> {
-        debug!("ceph version: {:?}", self.version,);
+    // Reuse the journal and db of the disk being replaced if they're still
+    // there.  Anything else gets a new partition
+    fn partitions_for(
+        &self,
+        placement: Option<&Placement>,
+        bluestore: bool,
+    ) -> BynarResult<OsdPartitions> {
+        // The db goes first so an add that can't place one leaves no wal
+        // partition behind
+        let reused = placement
+            .and_then(|p| p.db.as_ref())
+            .and_then(|d| reusable(d, "db"));
+        let db = match reused {
+            Some(db) => Some(db),
+            None if bluestore => self.select_db()?,
+            None => None,
+        };
+        let reused = placement
+            .and_then(|p| p.journal.as_ref())
+            .and_then(|j| reusable(j, "journal"));
+        let journal = match reused {
+            Some(journal) => Some(journal),
+            None if bluestore => self.select_wal()?,
+            None => self.select_journal()?,
+        };
+        Ok(OsdPartitions { journal, db })
+    }
+
+    fn remove_osd(
+        &self,
+        device: &Path,
+        keep_id: bool,
+        simulate: bool,
+        progress: &dyn Progress,
+    ) -> BynarResult<()> {
         if self.version >= CephVersion::Luminous {
-            self.add_bluestore_osd(device, id, simulate)?;
+            // Check if the type file exists
+            self.remove_bluestore_osd(device, keep_id, simulate, progress)?;
         } else {
-            self.add_filestore_osd(device, id, simulate)?;
+            self.remove_filestore_osd(device, keep_id, simulate, progress)?;
         }
         Ok(())
     }
 
-    fn remove_disk(&self, device: &Path, simulate: bool) -> BynarResult<()> {
+    fn add_osd(
+        &self,
+        device: &Path,
+        id: Option<u64>,
+        placement: Option<&Placement>,
+        simulate: bool,
+        progress: &dyn Progress,
+    ) -> BynarResult<()> {
+        debug!("ceph version: {:?}", self.version,);
         if self.version >= CephVersion::Luminous {
-            // Check if the type file exists
-            self.remove_bluestore_osd(device, simulate)?;
+            self.add_bluestore_osd(device, id, placement, simulate, progress)?;
+        } else if self.config.dmcrypt {
+            return Err(BynarError::from(
+                "dmcrypt is only supported on bluestore osds",
+            ));
         } else {
-            self.remove_filestore_osd(device, simulate)?;
+            self.add_filestore_osd(device, id, placement, simulate, progress)?;
         }
         Ok(())
     }
 
-    fn safe_to_remove(&self, _device: &Path, _simulate: bool) -> BynarResult<bool> {
-        let diag_map = DiagMap::new().map_err(|e| BynarError::new(e.to_string()))?;
-        debug!("Checking if a disk is safe to remove from ceph");
-        match diag_map.exhaustive_diag(Format::Json) {
-            Status::Safe => Ok(true),
-            Status::NonSafe => Ok(false),
-            Status::Unknown => Ok(false),
+    // osd create can't hand out an id that was destroyed.  osd new can and
+    // is used whenever a specific id is asked for
+    fn create_osd_id(
+        &self,
+        id: Option<u64>,
+        osd_fsid: &uuid::Uuid,
+        simulate: bool,
+    ) -> BynarResult<u64> {
+        match id {
+            Some(id) if self.version >= CephVersion::Luminous => {
+                debug!("Creating osd.{} with fsid {}", id, osd_fsid);
+                if !simulate {
+                    let uuid = osd_fsid.to_hyphenated().to_string();
+                    let cmd = json!({"prefix": "osd new", "uuid": uuid, "id": id});
+                    self.cluster_handle.ceph_mon_command_without_data(&cmd)?;
+                }
+                Ok(id)
+            }
+            _ => osd_create(&self.cluster_handle, id, simulate),
         }
     }
-}
 
-// A fallback function to get the osd id from the mount path.  This isn't
-// 100% accurate but it should be good enough for most cases unless the disk
-// is mounted in the wrong location or is missing an osd id in the path name
-fn get_osd_id_from_path(path: &Path) -> BynarResult<u64> {
-    match path.file_name() {
-        Some(name) => {
-            let name_string = name.to_string_lossy().into_owned();
-            let parts: Vec<&str> = name_string.split('-').collect();
-            let id = u64::from_str(parts[1])?;
-            Ok(id)
+    // Add the osd under this host or back where the disk it replaces was
+    fn crush_add(
+        &self,
+        osd_id: u64,
+        weight: f64,
+        host: &Host,
+        placement: Option<&Placement>,
+        simulate: bool,
+    ) -> BynarResult<()> {
+        let location = match placement {
+            Some(p) if !p.location.is_empty() => p.location.clone(),
+            _ => match self.crush_location(host) {
+                Some(location) => location,
+                None => {
+                    return osd_crush_add(
+                        &self.cluster_handle,
+                        osd_id,
+                        weight,
+                        &host.hostname,
+                        simulate,
+                    )
+                }
+            },
+        };
+        let args: Vec<String> = location
+            .iter()
+            .map(|(bucket, name)| format!("{}={}", bucket, name))
+            .collect();
+        debug!(
+            "Adding osd.{} to crush at {} with weight {}",
+            osd_id,
+            args.join(" "),
+            weight
+        );
+        if !simulate {
+            let osd = format!("osd.{}", osd_id);
+            let cmd = json!({"prefix": "osd crush add", "id": osd, "weight": weight, "args": args});
+            self.cluster_handle.ceph_mon_command_without_data(&cmd)?;
         }
-        None => Err(BynarError::new(format!(
-            "Unable to get filename from {}",
-            path.display()
-        ))),
+        Ok(())
     }
-}
 
-// Get an osd ID from the whoami file in the osd mount directory
-fn get_osd_id(path: &Path, simulate: bool) -> BynarResult<u64> {
-    if simulate {
-        return Ok(0);
+    // Where new osds go in crush.  crush_location from ceph.json with the
+    // host's details filled in.  The host bucket defaults to the hostname
+    fn crush_location(&self, host: &Host) -> Option<BTreeMap<String, String>> {
+        let template = self.config.crush_location.as_ref()?;
+        let values = [
+            ("hostname", host.hostname.as_str()),
+            ("region", host.region.as_str()),
+            ("serial_number", host.serial_number.as_str()),
+            ("server_type", host.server_type.as_str()),
+        ];
+        let mut location: BTreeMap<String, String> = template
+            .iter()
+            .map(|(bucket, name)| (bucket.clone(), fill_in(name, &values)))
+            .collect();
+        location
+            .entry("host".to_string())
+            .or_insert_with(|| host.hostname.clone());
+        Some(location)
     }
-    let whoami_path = path.join("whoami");
-    debug!("Discovering osd id number from: {}", whoami_path.display());
+
+    fn device_class(&self, info: &block_utils::Device) -> Option<String> {
+        device_class(
+            &info.name,
+            &info.media_type,
+            self.config.device_classes.as_ref(),
+        )
+    }
+
+    fn set_device_class(&self, osd_id: u64, class: &str, simulate: bool) -> BynarResult<()> {
+        debug!("Setting osd.{} device class to {}", osd_id, class);
+        if simulate {
+            return Ok(());
+        }
+        // set-device-class refuses to change a class that's already set
+        let ids = vec![osd_id.to_string()];
+        let cmd = json!({"prefix": "osd crush rm-device-class", "ids": ids});
+        self.cluster_handle.ceph_mon_command_without_data(&cmd)?;
+        let cmd = json!({"prefix": "osd crush set-device-class", "class": class, "ids": ids});
+        self.cluster_handle.ceph_mon_command_without_data(&cmd)?;
+        Ok(())
+    }
+
+    // Unlike osd rm this keeps the id and crush location so a replacement
+    // disk can take them over.  The auth key is removed
+    fn osd_destroy(&self, osd_id: u64, simulate: bool) -> BynarResult<()> {
+        debug!("Destroying osd.{}", osd_id);
+        if simulate {
+            return Ok(());
+        }
+        // Luminous and Mimic want sure.  Nautilus renamed it
+        let cmd = json!({
+            "prefix": "osd destroy",
+            "id": osd_id,
+            "sure": "--yes-i-really-mean-it",
+            "yes_i_really_mean_it": true
+        });
+        self.cluster_handle.ceph_mon_command_without_data(&cmd)?;
+        Ok(())
+    }
+
+    // Remove an osd from crush, auth and the osd map in one go
+    fn osd_purge(&self, osd_id: u64, simulate: bool) -> BynarResult<()> {
+        debug!("Purging osd.{}", osd_id);
+        if simulate {
+            return Ok(());
+        }
+        let cmd = json!({
+            "prefix": "osd purge",
+            "id": osd_id,
+            "sure": "--yes-i-really-mean-it",
+            "yes_i_really_mean_it": true
+        });
+        self.cluster_handle.ceph_mon_command_without_data(&cmd)?;
+        Ok(())
+    }
+}
+
+// The part of osd find that says where an osd is in crush
+#[derive(Debug, Deserialize)]
+struct OsdFind {
+    #[serde(default)]
+    crush_location: BTreeMap<String, String>,
+}
+
+impl Backend for CephBackend {
+    fn add_disk(
+        &self,
+        device: &Path,
+        id: Option<u64>,
+        simulate: bool,
+        progress: &dyn Progress,
+    ) -> BynarResult<()> {
+        self.add_osd(device, id, None, simulate, progress)
+    }
+
+    fn replace_disk(
+        &self,
+        device: &Path,
+        placement: &Placement,
+        simulate: bool,
+        progress: &dyn Progress,
+    ) -> BynarResult<()> {
+        self.add_osd(
+            device,
+            Some(placement.id),
+            Some(placement),
+            simulate,
+            progress,
+        )
+    }
+
+    fn remove_disk(
+        &self,
+        device: &Path,
+        simulate: bool,
+        progress: &dyn Progress,
+    ) -> BynarResult<()> {
+        self.remove_osd(device, true, simulate, progress)
+    }
+
+    fn purge_disk(
+        &self,
+        device: &Path,
+        simulate: bool,
+        progress: &dyn Progress,
+    ) -> BynarResult<()> {
+        self.remove_osd(device, false, simulate, progress)
+    }
+
+    fn safe_to_remove(&self, device: &Path, simulate: bool) -> BynarResult<bool> {
+        Ok(self.check_safe_to_remove(device, simulate)?.safe)
+    }
+
+    // Safe if ceph says the osd can be destroyed without losing anything.
+    // Otherwise it has to be ok to stop with every placement group on it
+    // clean and left with at least min_size copies
+    fn check_safe_to_remove(&self, device: &Path, simulate: bool) -> BynarResult<SafeToRemove> {
+        if simulate {
+            return Ok(SafeToRemove {
+                safe: true,
+                checks: vec![],
+                affected: vec![],
+            });
+        }
+        if self.version < CephVersion::Luminous {
+            // safe-to-destroy and ok-to-stop are mgr commands
+            return Ok(cluster_diag());
+        }
+        let osd_ids = osd_ids_for_device(device)?;
+        let affected: Vec<String> = osd_ids.iter().map(|id| format!("osd.{}", id)).collect();
+        debug!(
+            "Checking if {} on {} is safe to remove",
+            affected.join(", "),
+            device.display()
+        );
+        let ids: Vec<String> = osd_ids.iter().map(|id| id.to_string()).collect();
+        let safe_to_destroy = self.mgr_check(
+            "safe_to_destroy",
+            &json!({"prefix": "osd safe-to-destroy", "ids": ids, "format": "json"}),
+        );
+        let ok_to_stop = self.mgr_check(
+            "ok_to_stop",
+            &json!({"prefix": "osd ok-to-stop", "ids": ids, "format": "json"}),
+        );
+        let mut pgs = vec![];
+        for osd_id in &osd_ids {
+            pgs.extend(self.pgs_on_osd(*osd_id)?);
+        }
+        // Placement groups on more than one of the osds are listed for each
+        pgs.sort_by(|a, b| a.pgid.cmp(&b.pgid));
+        pgs.dedup_by(|a, b| a.pgid == b.pgid);
+        let pools = self.pools()?;
+        let pg_checks = check_pgs(&osd_ids, &pgs, &pools);
+
+        let safe = safe_to_destroy.passed
+            || (ok_to_stop.passed && pg_checks.iter().all(|check| check.passed));
+        let mut checks = vec![safe_to_destroy, ok_to_stop];
+        checks.extend(pg_checks);
+        Ok(SafeToRemove {
+            safe,
+            checks,
+            affected,
+        })
+    }
+
+    fn placement(&self, device: &Path) -> BynarResult<Option<Placement>> {
+        if self.version < CephVersion::Luminous {
+            // Without osd destroy the id is gone once the osd is removed
+            return Ok(None);
+        }
+        let osd_id = match osd_ids_for_device(device)?.as_slice() {
+            [osd_id] => *osd_id,
+            // The osds of a split device are purged, not destroyed
+            _ => return Ok(None),
+        };
+        let cmd = json!({"prefix": "osd find", "id": osd_id, "format": "json"});
+        let (out, _) = self.cluster_handle.ceph_mon_command_without_data(&cmd)?;
+        let found: OsdFind = serde_json::from_slice(&out)?;
+        Ok(Some(Placement {
+            id: osd_id,
+            location: found.crush_location,
+            device_class: self.osd_df(osd_id)?.device_class,
+            journal: journal_for_device(device)?,
+            db: osd_lvm_tag(device, "ceph.db_device")?.map(PathBuf::from),
+        }))
+    }
+
+    fn interrupted_adds(&self) -> BynarResult<Vec<PathBuf>> {
+        match self.config.ramp {
+            Some(ref ramp) => {
+                // A split device has a ramp for each osd
+                let mut devices: Vec<PathBuf> = CephBackend::load_ramp_records(ramp)?
+                    .into_iter()
+                    .map(|r| r.device)
+                    .collect();
+                devices.sort();
+                devices.dedup();
+                Ok(devices)
+            }
+            None => Ok(vec![]),
+        }
+    }
+
+    fn resume_add(&self, device: &Path, progress: &dyn Progress) -> BynarResult<()> {
+        let ramp = self
+            .config
+            .ramp
+            .as_ref()
+            .ok_or_else(|| BynarError::from("No ramp is configured in ceph.json"))?;
+        let records: Vec<RampRecord> = CephBackend::load_ramp_records(ramp)?
+            .into_iter()
+            .filter(|r| r.device == device)
+            .collect();
+        if records.is_empty() {
+            return Err(BynarError::new(format!(
+                "No crush weight ramp for {}",
+                device.display()
+            )));
+        }
+        for record in records {
+            self.ramp_osd(record, ramp, false, progress)?;
+        }
+        Ok(())
+    }
+
+    fn activate(&self) -> BynarResult<()> {
+        if !self.config.dmcrypt {
+            return Ok(());
+        }
+        for osd in encrypted_osds()? {
+            if let Err(e) = self.unlock_osd(&osd) {
+                error!("Unable to unlock osd {}: {:?}", osd.id, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn reclaim_interval(&self) -> Option<Duration> {
+        self.config
+            .reclaim
+            .as_ref()
+            .map(|r| Duration::from_secs(r.interval))
+    }
+
+    fn reclaim(&self) -> BynarResult<()> {
+        match self.config.reclaim {
+            Some(ref reclaim) => self.reclaim_partitions(reclaim),
+            None => Ok(()),
+        }
+    }
+}
+
+impl CephBackend {
+    // Every device Bynar makes journal, db or wal partitions on
+    fn shared_devices(&self) -> Vec<PathBuf> {
+        let mut devices: Vec<PathBuf> = self
+            .config
+            .journal_devices
+            .iter()
+            .chain(self.config.db_devices.iter())
+            .chain(self.config.wal_devices.iter())
+            .flatten()
+            .map(|d| d.device.clone())
+            .collect();
+        devices.sort();
+        devices.dedup();
+        devices
+    }
+
+    // Free the partitions on shared devices that no osd has used for the
+    // whole grace period
+    fn reclaim_partitions(&self, reclaim: &ReclaimConfig) -> BynarResult<()> {
+        let state_file = reclaim.state_file.as_ref().map_or_else(
+            || PathBuf::from("/var/lib/bynar/ceph_unused_partitions.json"),
+            PathBuf::from,
+        );
+        let mut first_seen: HashMap<String, u64> = if state_file.exists() {
+            serde_json::from_str(&read_to_string(&state_file)?)?
+        } else {
+            HashMap::new()
+        };
+        let referenced = referenced_partitions()?;
+        let mut unused = vec![];
+        for device in self.shared_devices() {
+            match unused_partitions(&device, &referenced) {
+                Ok(partitions) => unused.extend(partitions),
+                Err(e) => error!(
+                    "Unable to check {} for unused partitions: {:?}",
+                    device.display(),
+                    e
+                ),
+            }
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let uuids: Vec<String> = unused
+            .iter()
+            .map(|p| p.uuid.to_hyphenated().to_string())
+            .collect();
+        let due = due_for_reclaim(&mut first_seen, &uuids, now, reclaim.grace_period);
+        for (partition, uuid) in unused.iter().zip(uuids.iter()) {
+            if !due.contains(uuid) {
+                continue;
+            }
+            if reclaim.simulate {
+                info!(
+                    "Would free unused partition {} on {}",
+                    partition.number,
+                    partition.device.display()
+                );
+                continue;
+            }
+            info!(
+                "Freeing unused partition {} on {}",
+                partition.number,
+                partition.device.display()
+            );
+            match free_partitions(&partition.device, &[partition.number]) {
+                Ok(_) => {
+                    first_seen.remove(uuid);
+                }
+                Err(e) => error!(
+                    "Unable to free partition {} on {}: {:?}",
+                    partition.number,
+                    partition.device.display(),
+                    e
+                ),
+            }
+        }
+        if let Some(parent) = state_file.parent() {
+            create_dir_all(parent)?;
+        }
+        let mut f = File::create(&state_file)?;
+        f.write_all(serde_json::to_string(&first_seen)?.as_bytes())?;
+
+        Ok(())
+    }
+
+    // Lower the osd's crush weight a step at a time, letting the cluster
+    // settle in between, until no placement groups map to it
+    fn drain_osd(
+        &self,
+        osd_id: u64,
+        drain: &DrainConfig,
+        simulate: bool,
+        progress: &dyn Progress,
+    ) -> BynarResult<()> {
+        let start_weight = self.osd_df(osd_id)?.crush_weight;
+        info!(
+            "Draining osd.{} from crush weight {} in {} steps",
+            osd_id, start_weight, drain.steps
+        );
+        for weight in drain_weights(start_weight, drain.steps) {
+            progress.step(&format!("drain: crush weight {:.4}", weight))?;
+            if simulate {
+                continue;
+            }
+            self.crush_reweight(osd_id, weight)?;
+            self.wait_for_active_clean(osd_id, weight, drain, progress)?;
+            if drain.step_delay > 0 {
+                thread::sleep(Duration::from_secs(drain.step_delay));
+            }
+        }
+        if simulate {
+            return Ok(());
+        }
+        // Weight 0 should move everything off but wait to be sure
+        let started = Instant::now();
+        loop {
+            let pgs = self.osd_df(osd_id)?.pgs;
+            if pgs == 0 {
+                info!("osd.{} is drained", osd_id);
+                return Ok(());
+            }
+            drain_timed_out(osd_id, started, drain)?;
+            progress.step(&format!("drain: waiting for {} pgs to move off", pgs))?;
+            thread::sleep(Duration::from_secs(drain.poll_interval));
+        }
+    }
+
+    fn wait_for_active_clean(
+        &self,
+        osd_id: u64,
+        weight: f64,
+        drain: &DrainConfig,
+        progress: &dyn Progress,
+    ) -> BynarResult<()> {
+        let started = Instant::now();
+        loop {
+            let settling = not_active_clean(&self.status()?.pgmap);
+            if settling == 0 {
+                return Ok(());
+            }
+            drain_timed_out(osd_id, started, drain)?;
+            debug!("Waiting on {} pgs to be active+clean", settling);
+            // Checks for cancellation while the job shows it's waiting
+            progress.step(&format!(
+                "drain: crush weight {:.4}, {} pgs on osd.{}, waiting for {} pgs to be active+clean",
+                weight,
+                self.osd_df(osd_id)?.pgs,
+                osd_id,
+                settling
+            ))?;
+            thread::sleep(Duration::from_secs(drain.poll_interval));
+        }
+    }
+
+    fn crush_reweight(&self, osd_id: u64, weight: f64) -> BynarResult<()> {
+        debug!("Setting osd.{} crush weight to {}", osd_id, weight);
+        let cmd = json!({
+            "prefix": "osd crush reweight",
+            "name": format!("osd.{}", osd_id),
+            "weight": weight
+        });
+        self.cluster_handle.ceph_mon_command_without_data(&cmd)?;
+        Ok(())
+    }
+
+    fn status(&self) -> BynarResult<CephStatus> {
+        let cmd = json!({"prefix": "status", "format": "json"});
+        let (out, _) = self.cluster_handle.ceph_mon_command_without_data(&cmd)?;
+        Ok(serde_json::from_slice(&out)?)
+    }
+
+    fn osd_df(&self, osd_id: u64) -> BynarResult<OsdDfNode> {
+        let cmd = json!({"prefix": "osd df", "format": "json"});
+        let (out, _) = self.cluster_handle.ceph_mon_command_without_data(&cmd)?;
+        let df: OsdDf = serde_json::from_slice(&out)?;
+        df.nodes
+            .into_iter()
+            .find(|node| node.id == osd_id as i64)
+            .ok_or_else(|| BynarError::new(format!("osd.{} not found in osd df", osd_id)))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OsdDfNode {
+    id: i64,
+    crush_weight: f64,
+    pgs: u64,
+    /// Missing before luminous
+    #[serde(default)]
+    device_class: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OsdDf {
+    nodes: Vec<OsdDfNode>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PgStateCount {
+    state_name: String,
+    count: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct PgMap {
+    num_pgs: u64,
+    #[serde(default)]
+    pgs_by_state: Vec<PgStateCount>,
+    /// Only reported while recovery is running
+    #[serde(default)]
+    recovering_bytes_per_sec: u64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct CephHealth {
+    /// HEALTH_OK, HEALTH_WARN or HEALTH_ERR.  Missing before luminous
+    #[serde(default)]
+    status: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CephStatus {
+    #[serde(default)]
+    health: CephHealth,
+    pgmap: PgMap,
+}
+
+// An error once a drain has waited longer than its timeout for a step
+fn drain_timed_out(osd_id: u64, started: Instant, drain: &DrainConfig) -> BynarResult<()> {
+    if started.elapsed() >= Duration::from_secs(drain.timeout) {
+        return Err(BynarError::new(format!(
+            "Gave up draining osd.{} after waiting {} seconds for the cluster to settle",
+            osd_id, drain.timeout
+        )));
+    }
+    Ok(())
+}
+
+// Placement groups in the cluster that aren't active+clean yet.  Scrubbing
+// doesn't count against them
+fn not_active_clean(pgmap: &PgMap) -> u64 {
+    let clean: u64 = pgmap
+        .pgs_by_state
+        .iter()
+        .filter(|s| {
+            let states: Vec<&str> = s.state_name.split('+').collect();
+            states.contains(&"active") && states.contains(&"clean")
+        })
+        .map(|s| s.count)
+        .sum();
+    pgmap.num_pgs.saturating_sub(clean)
+}
+
+// Placement groups in the cluster that aren't active.  ie: peering or down
+fn not_active(pgmap: &PgMap) -> u64 {
+    let active: u64 = pgmap
+        .pgs_by_state
+        .iter()
+        .filter(|s| s.state_name.split('+').any(|state| state == "active"))
+        .map(|s| s.count)
+        .sum();
+    pgmap.num_pgs.saturating_sub(active)
+}
+
+// The crush weights to step through, ending at 0
+fn drain_weights(start: f64, steps: u32) -> Vec<f64> {
+    let steps = steps.max(1);
+    (1..=steps)
+        .map(|step| start * f64::from(steps - step) / f64::from(steps))
+        .collect()
+}
+
+#[test]
+fn test_drain_weights() {
+    assert_eq!(drain_weights(4.0, 4), vec![3.0, 2.0, 1.0, 0.0]);
+    assert_eq!(drain_weights(3.64, 1), vec![0.0]);
+    // 0 steps is treated as 1
+    assert_eq!(drain_weights(1.0, 0), vec![0.0]);
+}
+
+#[test]
+fn test_not_active_clean() {
+    let pgmap: PgMap = serde_json::from_str(
+        r#"{"num_pgs": 128, "pgs_by_state": [
+            {"state_name": "active+clean", "count": 110},
+            {"state_name": "active+clean+scrubbing", "count": 6},
+            {"state_name": "active+clean+scrubbing+deep", "count": 4},
+            {"state_name": "active+remapped+backfilling", "count": 8}
+        ]}"#,
+    )
+    .unwrap();
+    assert_eq!(not_active_clean(&pgmap), 8);
+    let empty: PgMap = serde_json::from_str(r#"{"num_pgs": 0}"#).unwrap();
+    assert_eq!(not_active_clean(&empty), 0);
+}
+
+impl CephBackend {
+    // New osds join the crush map at 0 when they're going to be ramped up
+    fn initial_weight(&self, osd_weight: f64) -> f64 {
+        match self.config.ramp {
+            Some(_) => 0.0,
+            None => osd_weight,
+        }
+    }
+
+    fn ramp_new_osd(
+        &self,
+        osd_id: u64,
+        dev_path: &Path,
+        osd_weight: f64,
+        simulate: bool,
+        progress: &dyn Progress,
+    ) -> BynarResult<()> {
+        match (
+            &self.config.ramp,
+            self.start_ramp(osd_id, dev_path, osd_weight, simulate)?,
+        ) {
+            (Some(ramp), Some(record)) => self.ramp_osd(record, ramp, simulate, progress),
+            _ => Ok(()),
+        }
+    }
+
+    // Record a ramp for a new osd at weight 0 so an add that stops before
+    // the ramp starts can still be resumed.  None if ramps aren't configured
+    fn start_ramp(
+        &self,
+        osd_id: u64,
+        dev_path: &Path,
+        osd_weight: f64,
+        simulate: bool,
+    ) -> BynarResult<Option<RampRecord>> {
+        let ramp = match self.config.ramp {
+            Some(ref ramp) => ramp,
+            None => return Ok(None),
+        };
+        let record = RampRecord {
+            osd_id,
+            device: dev_path.to_path_buf(),
+            target_weight: osd_weight,
+            weight: 0.0,
+        };
+        if !simulate {
+            CephBackend::save_ramp_record(ramp, &record)?;
+        }
+        Ok(Some(record))
+    }
+
+    fn ramp_dir(ramp: &RampConfig) -> PathBuf {
+        match ramp.state_dir {
+            Some(ref d) => PathBuf::from(d),
+            None => PathBuf::from("/var/lib/bynar/ceph_ramps"),
+        }
+    }
+
+    fn load_ramp_records(ramp: &RampConfig) -> BynarResult<Vec<RampRecord>> {
+        let dir = CephBackend::ramp_dir(ramp);
+        if !dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut records = Vec::new();
+        for entry in read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            match read_to_string(&path)
+                .map_err(BynarError::from)
+                .and_then(|s| serde_json::from_str(&s).map_err(BynarError::from))
+            {
+                Ok(record) => records.push(record),
+                Err(e) => error!("Skipping ramp record {}: {}", path.display(), e),
+            }
+        }
+        Ok(records)
+    }
+
+    // One file per osd so ramps on different disks don't trip over each other
+    fn save_ramp_record(ramp: &RampConfig, record: &RampRecord) -> BynarResult<()> {
+        let dir = CephBackend::ramp_dir(ramp);
+        create_dir_all(&dir)?;
+        let mut f = File::create(dir.join(format!("osd.{}.json", record.osd_id)))?;
+        f.write_all(serde_json::to_string_pretty(record)?.as_bytes())?;
+        Ok(())
+    }
+
+    fn remove_ramp_record(ramp: &RampConfig, osd_id: u64) -> BynarResult<()> {
+        let p = CephBackend::ramp_dir(ramp).join(format!("osd.{}.json", osd_id));
+        if p.exists() {
+            remove_file(p)?;
+        }
+        Ok(())
+    }
+
+    // Raise a new osd's crush weight a step at a time up to its target.
+    // Each step waits until the cluster can take more backfill
+    fn ramp_osd(
+        &self,
+        mut record: RampRecord,
+        ramp: &RampConfig,
+        simulate: bool,
+        progress: &dyn Progress,
+    ) -> BynarResult<()> {
+        info!(
+            "Ramping osd.{} from crush weight {} to {} in {} steps",
+            record.osd_id, record.weight, record.target_weight, ramp.steps
+        );
+        if !simulate {
+            CephBackend::save_ramp_record(ramp, &record)?;
+        }
+        // A cancelled ramp is dropped so it isn't picked back up after a
+        // restart.  The osd stays at the weight it reached
+        let osd_id = record.osd_id;
+        let step = |name: String| {
+            progress.step(&name).map_err(|e| {
+                if !simulate {
+                    if let Err(e) = CephBackend::remove_ramp_record(ramp, osd_id) {
+                        error!("Unable to remove osd.{} ramp record: {}", osd_id, e);
+                    }
+                }
+                e
+            })
+        };
+        for weight in ramp_weights(record.target_weight, ramp.steps) {
+            if weight <= record.weight {
+                // Done before a restart
+                continue;
+            }
+            step(format!(
+                "ramp: crush weight {:.4} of {:.4}",
+                weight, record.target_weight
+            ))?;
+            if simulate {
+                continue;
+            }
+            loop {
+                let blocker = ramp_blocker(&self.status()?, ramp.max_recovery_bytes_per_sec);
+                match blocker {
+                    Some(reason) => {
+                        debug!("Holding osd.{} ramp: {}", record.osd_id, reason);
+                        // Checks for cancellation while the job shows it's waiting
+                        step(format!(
+                            "ramp: crush weight {:.4} of {:.4}, waiting for {}",
+                            record.weight, record.target_weight, reason
+                        ))?;
+                        thread::sleep(Duration::from_secs(ramp.poll_interval));
+                    }
+                    None => break,
+                }
+            }
+            self.crush_reweight(record.osd_id, weight)?;
+            record.weight = weight;
+            CephBackend::save_ramp_record(ramp, &record)?;
+            if ramp.step_delay > 0 {
+                thread::sleep(Duration::from_secs(ramp.step_delay));
+            }
+        }
+        if !simulate {
+            CephBackend::remove_ramp_record(ramp, record.osd_id)?;
+        }
+        info!("osd.{} is at its full crush weight", record.osd_id);
+        Ok(())
+    }
+}
+
+/// A crush weight ramp in progress.  Saved after every step so it can
+/// carry on after a restart
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+struct RampRecord {
+    osd_id: u64,
+    device: PathBuf,
+    target_weight: f64,
+    /// The weight the osd is at now
+    weight: f64,
+}
+
+// The crush weights to step through, ending at target
+fn ramp_weights(target: f64, steps: u32) -> Vec<f64> {
+    let steps = steps.max(1);
+    (1..=steps)
+        .map(|step| target * f64::from(step) / f64::from(steps))
+        .collect()
+}
+
+// Why the ramp can't take its next step yet.  None if it can.  Without
+// max_recovery every placement group has to be active+clean.  With it the
+// ramp goes ahead while backfill runs slower than that
+fn ramp_blocker(status: &CephStatus, max_recovery: Option<u64>) -> Option<String> {
+    if status.health.status == "HEALTH_ERR" {
+        return Some("the cluster to leave HEALTH_ERR".to_string());
+    }
+    match max_recovery {
+        None => {
+            let settling = not_active_clean(&status.pgmap);
+            if settling > 0 {
+                return Some(format!("{} pgs to be active+clean", settling));
+            }
+        }
+        Some(max) => {
+            let inactive = not_active(&status.pgmap);
+            if inactive > 0 {
+                return Some(format!("{} pgs to be active", inactive));
+            }
+            if status.pgmap.recovering_bytes_per_sec > max {
+                return Some(format!(
+                    "recovery to drop below {} bytes/s from {}",
+                    max, status.pgmap.recovering_bytes_per_sec
+                ));
+            }
+        }
+    }
+    None
+}
+
+#[test]
+fn test_ramp_weights() {
+    assert_eq!(ramp_weights(4.0, 4), vec![1.0, 2.0, 3.0, 4.0]);
+    assert_eq!(ramp_weights(3.64, 0), vec![3.64]);
+}
+
+#[test]
+fn test_ramp_blocker() {
+    let status: CephStatus = serde_json::from_str(
+        r#"{"health": {"status": "HEALTH_WARN"},
+            "pgmap": {"num_pgs": 100, "recovering_bytes_per_sec": 50000000,
+                      "pgs_by_state": [
+                        {"state_name": "active+clean", "count": 90},
+                        {"state_name": "active+remapped+backfilling", "count": 10}
+                      ]}}"#,
+    )
+    .unwrap();
+    assert!(ramp_blocker(&status, None).is_some());
+    assert!(ramp_blocker(&status, Some(10_000_000)).is_some());
+    assert!(ramp_blocker(&status, Some(100_000_000)).is_none());
+
+    // Scrubbing doesn't hold up a ramp
+    let status: CephStatus = serde_json::from_str(
+        r#"{"health": {"status": "HEALTH_OK"},
+            "pgmap": {"num_pgs": 100, "pgs_by_state": [
+                        {"state_name": "active+clean", "count": 97},
+                        {"state_name": "active+clean+scrubbing+deep", "count": 3}
+                      ]}}"#,
+    )
+    .unwrap();
+    assert!(ramp_blocker(&status, None).is_none());
+
+    let status: CephStatus = serde_json::from_str(
+        r#"{"health": {"status": "HEALTH_ERR"},
+            "pgmap": {"num_pgs": 1, "pgs_by_state": [{"state_name": "active+clean", "count": 1}]}}"#,
+    )
+    .unwrap();
+    assert!(ramp_blocker(&status, None).is_some());
+}
+
+// The check used before luminous.  A cluster wide diagnosis from
+// ceph-safe-disk
+fn cluster_diag() -> SafeToRemove {
+    let (passed, detail) = match DiagMap::new() {
+        Ok(diag_map) => match diag_map.exhaustive_diag(Format::Json) {
+            Status::Safe => (true, "Cluster can lose a disk".to_string()),
+            Status::NonSafe => (false, "Cluster can't lose a disk".to_string()),
+            Status::Unknown => (false, "Cluster state is unknown".to_string()),
+        },
+        Err(e) => (false, e.to_string()),
+    };
+    SafeToRemove {
+        safe: passed,
+        checks: vec![RemovalCheck {
+            name: "cluster_diag".to_string(),
+            passed,
+            detail,
+        }],
+        affected: vec![],
+    }
+}
+
+impl CephBackend {
+    // Run a mgr command that fails when the answer is no
+    fn mgr_check(&self, name: &str, cmd: &serde_json::Value) -> RemovalCheck {
+        let (passed, detail) = match self.cluster_handle.ceph_mgr_command_without_data(cmd) {
+            Ok((_, status)) => (true, status.unwrap_or_default()),
+            Err(e) => (false, e.to_string()),
+        };
+        debug!("{}: {} {}", name, passed, detail);
+        RemovalCheck {
+            name: name.to_string(),
+            passed,
+            detail,
+        }
+    }
+
+    fn pgs_on_osd(&self, osd_id: u64) -> BynarResult<Vec<PgStat>> {
+        let osd = format!("osd.{}", osd_id);
+        let cmd = json!({"prefix": "pg ls-by-osd", "osd": osd, "format": "json"});
+        let (out, _) = self.cluster_handle.ceph_mgr_command_without_data(&cmd)?;
+        let pgs: PgList = serde_json::from_slice(&out)?;
+        Ok(match pgs {
+            PgList::Stats(pgs) => pgs,
+            PgList::Wrapped { pg_stats } => pg_stats,
+        })
+    }
+
+    fn pools(&self) -> BynarResult<HashMap<u64, PoolDetail>> {
+        let cmd = json!({"prefix": "osd dump", "format": "json"});
+        let (out, _) = self.cluster_handle.ceph_mon_command_without_data(&cmd)?;
+        let dump: OsdDump = serde_json::from_slice(&out)?;
+        Ok(dump.pools.into_iter().map(|p| (p.pool, p)).collect())
+    }
+}
+
+// Placement group summary from pg ls-by-osd
+#[derive(Debug, Deserialize)]
+struct PgStat {
+    pgid: String,
+    state: String,
+    acting: Vec<i64>,
+}
+
+// Luminous returns a list.  Nautilus wraps it in an object
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum PgList {
+    Stats(Vec<PgStat>),
+    Wrapped { pg_stats: Vec<PgStat> },
+}
+
+#[derive(Debug, Deserialize)]
+struct PoolDetail {
+    pool: u64,
+    pool_name: String,
+    size: u64,
+    min_size: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct OsdDump {
+    pools: Vec<PoolDetail>,
+}
+
+// Marks a missing erasure coded shard in an acting set
+const CRUSH_ITEM_NONE: i64 = 0x7fff_ffff;
+// How many problem placement groups to name in a check's detail
+const MAX_LISTED_PGS: usize = 5;
+
+fn pg_list_detail(osd_ids: &[u64], problem: &[String], what: &str) -> String {
+    let osds: Vec<String> = osd_ids.iter().map(|id| format!("osd.{}", id)).collect();
+    let mut detail = format!("{} pgs on {} {}", problem.len(), osds.join(", "), what);
+    if !problem.is_empty() {
+        let listed: Vec<&str> = problem
+            .iter()
+            .take(MAX_LISTED_PGS)
+            .map(|p| p.as_str())
+            .collect();
+        detail.push_str(&format!(": {}", listed.join(", ")));
+    }
+    detail
+}
+
+// Would taking osd_ids away leave their placement groups unhealthy, under
+// min_size or without any other copy
+fn check_pgs(
+    osd_ids: &[u64],
+    pgs: &[PgStat],
+    pools: &HashMap<u64, PoolDetail>,
+) -> Vec<RemovalCheck> {
+    let mut unclean = Vec::new();
+    let mut under_min_size = Vec::new();
+    let mut single_copy = Vec::new();
+    for pg in pgs {
+        let states: Vec<&str> = pg.state.split('+').collect();
+        if !states.contains(&"active") || !states.contains(&"clean") {
+            unclean.push(format!("{} ({})", pg.pgid, pg.state));
+        }
+        let pool = pg
+            .pgid
+            .split('.')
+            .next()
+            .and_then(|id| u64::from_str(id).ok())
+            .and_then(|id| pools.get(&id));
+        let pool = match pool {
+            Some(pool) => pool,
+            None => {
+                // Can't tell how many copies it needs so assume the worst
+                under_min_size.push(format!("{} (unknown pool)", pg.pgid));
+                continue;
+            }
+        };
+        if pool.size <= 1 {
+            single_copy.push(format!("{} ({})", pg.pgid, pool.pool_name));
+        }
+        let remaining = pg
+            .acting
+            .iter()
+            .filter(|id| **id != CRUSH_ITEM_NONE && !osd_ids.iter().any(|o| *o as i64 == **id))
+            .count() as u64;
+        if remaining < pool.min_size {
+            under_min_size.push(format!(
+                "{} ({} of min_size {})",
+                pg.pgid, remaining, pool.min_size
+            ));
+        }
+    }
+    vec![
+        RemovalCheck {
+            name: "pg_states".to_string(),
+            passed: unclean.is_empty(),
+            detail: pg_list_detail(osd_ids, &unclean, "aren't active+clean"),
+        },
+        RemovalCheck {
+            name: "min_size".to_string(),
+            passed: under_min_size.is_empty(),
+            detail: pg_list_detail(osd_ids, &under_min_size, "would drop below min_size"),
+        },
+        RemovalCheck {
+            name: "replication".to_string(),
+            passed: single_copy.is_empty(),
+            detail: pg_list_detail(osd_ids, &single_copy, "are in pools with a single copy"),
+        },
+    ]
+}
+
+#[test]
+fn test_check_pgs() {
+    let mut pools = HashMap::new();
+    pools.insert(
+        1,
+        PoolDetail {
+            pool: 1,
+            pool_name: "rbd".to_string(),
+            size: 3,
+            min_size: 2,
+        },
+    );
+    pools.insert(
+        2,
+        PoolDetail {
+            pool: 2,
+            pool_name: "scratch".to_string(),
+            size: 1,
+            min_size: 1,
+        },
+    );
+    let pg = |pgid: &str, state: &str, acting: Vec<i64>| PgStat {
+        pgid: pgid.to_string(),
+        state: state.to_string(),
+        acting,
+    };
+    let healthy = vec![pg("1.0", "active+clean", vec![3, 4, 5])];
+    assert!(check_pgs(&[3], &healthy, &pools).iter().all(|c| c.passed));
+
+    let degraded = vec![
+        pg("1.0", "active+clean", vec![3, 4, 5]),
+        pg("1.1", "active+undersized+degraded", vec![3, 4]),
+        pg("2.0", "active+clean", vec![3]),
+    ];
+    let checks = check_pgs(&[3], &degraded, &pools);
+    let failed: Vec<&str> = checks
+        .iter()
+        .filter(|c| !c.passed)
+        .map(|c| c.name.as_str())
+        .collect();
+    assert_eq!(failed, vec!["pg_states", "min_size", "replication"]);
+    assert!(checks[0].detail.contains("1.1"));
+
+    // Missing erasure coded shards don't count as copies
+    let ec = vec![pg("1.2", "active+clean", vec![3, CRUSH_ITEM_NONE, 6])];
+    assert!(!check_pgs(&[3], &ec, &pools)[1].passed);
+
+    // Every osd on a split device goes at once
+    let split = check_pgs(&[3, 4], &healthy, &pools);
+    assert!(!split[1].passed);
+    assert!(split[1].detail.contains("osd.3, osd.4"));
+}
+
+// The crush device class for a device.  An override for the device's name
+// wins over one for the class its media type goes with
+fn device_class(
+    name: &str,
+    media_type: &block_utils::MediaType,
+    overrides: Option<&HashMap<String, String>>,
+) -> Option<String> {
+    let detected = match *media_type {
+        block_utils::MediaType::Rotational => Some("hdd"),
+        block_utils::MediaType::SolidState => Some("ssd"),
+        block_utils::MediaType::NVME => Some("nvme"),
+        _ => None,
+    };
+    if let Some(overrides) = overrides {
+        let found = overrides
+            .get(name)
+            .or_else(|| detected.and_then(|class| overrides.get(class)));
+        if let Some(class) = found {
+            return Some(class.clone());
+        }
+    }
+    detected.map(|class| class.to_string())
+}
+
+#[test]
+fn test_device_class() {
+    use block_utils::MediaType;
+    assert_eq!(
+        device_class("sda", &MediaType::Rotational, None),
+        Some("hdd".to_string())
+    );
+    assert_eq!(device_class("loop0", &MediaType::Loopback, None), None);
+
+    let mut overrides = HashMap::new();
+    overrides.insert("ssd".to_string(), "fast".to_string());
+    overrides.insert("sdc".to_string(), "hdd".to_string());
+    assert_eq!(
+        device_class("sdb", &MediaType::SolidState, Some(&overrides)),
+        Some("fast".to_string())
+    );
+    assert_eq!(
+        device_class("sdc", &MediaType::SolidState, Some(&overrides)),
+        Some("hdd".to_string())
+    );
+    assert_eq!(
+        device_class("nvme0n1", &MediaType::NVME, Some(&overrides)),
+        Some("nvme".to_string())
+    );
+}
+
+// Replace each {name} in a template with its value
+fn fill_in(template: &str, values: &[(&str, &str)]) -> String {
+    values
+        .iter()
+        .fold(template.to_string(), |filled, (name, value)| {
+            filled.replace(&format!("{{{}}}", name), value)
+        })
+}
+
+#[test]
+fn test_fill_in() {
+    let values = [("hostname", "ceph01"), ("region", "us-east")];
+    assert_eq!(fill_in("{region}-row1", &values), "us-east-row1");
+    assert_eq!(fill_in("{hostname}", &values), "ceph01");
+    assert_eq!(fill_in("r12", &values), "r12");
+    assert_eq!(fill_in("{rack}", &values), "{rack}");
+}
+
+// Find the osds using a device.  Bluestore osds are tagged in LVM and
+// filestore ones have a whoami file where they're mounted.  A split device
+// has more than one
+fn osd_ids_for_device(dev_path: &Path) -> BynarResult<Vec<u64>> {
+    let ids = osd_lvm_tags(dev_path, "ceph.osd_id")?;
+    if !ids.is_empty() {
+        return ids.iter().map(|id| Ok(u64::from_str(id)?)).collect();
+    }
+    match block_utils::get_mountpoint(&dev_path)? {
+        Some(mount_point) => Ok(vec![
+            get_osd_id(&mount_point, false).or_else(|_| get_osd_id_from_path(&mount_point))?
+        ]),
+        None => Err(BynarError::new(format!(
+            "No osd found on {}",
+            dev_path.display()
+        ))),
+    }
+}
+
+// The journal or WAL partition an osd on a device uses.  None if it's
+// collocated
+fn journal_for_device(dev_path: &Path) -> BynarResult<Option<PathBuf>> {
+    if let Some(wal) = osd_lvm_tag(dev_path, "ceph.wal_device")? {
+        return Ok(Some(PathBuf::from(wal)));
+    }
+    match block_utils::get_mountpoint(&dev_path)? {
+        // Filestore journals are symlinked unless they're a file on the osd
+        Some(mount_point) => Ok(mount_point.join("journal").read_link().ok()),
+        None => Ok(None),
+    }
+}
+
+// A partition the replaced disk used if it's still there and free.  kind is
+// only for logging
+fn reusable(partition: &Path, kind: &str) -> Option<JournalDevice> {
+    // The partition number could have been handed to another osd after the
+    // old one was freed
+    let in_use = probe_partition_uuid(partition)
+        .and_then(|uuid| partition_in_use(&uuid))
+        .unwrap_or(true);
+    if partition.exists() && !in_use {
+        debug!("Reusing {} {}", kind, partition.display());
+        Some(JournalDevice {
+            device: partition.to_path_buf(),
+            partition_id: None,
+            partition_uuid: None,
+            num_partitions: None,
+        })
+    } else {
+        info!(
+            "The {} {} of the replaced disk is gone or taken.  Selecting a new one",
+            kind,
+            partition.display()
+        );
+        None
+    }
+}
+
+// The device with the fewest partitions.  The first one listed wins a tie
+fn least_used(devices: &[JournalDevice]) -> Option<&JournalDevice> {
+    devices.iter().min_by_key(|d| d.num_partitions.unwrap_or(0))
+}
+
+#[test]
+fn test_least_used() {
+    let device = |name: &str, num_partitions: Option<usize>| JournalDevice {
+        device: PathBuf::from(name),
+        partition_id: None,
+        partition_uuid: None,
+        num_partitions,
+    };
+    assert_eq!(least_used(&[]), None);
+    let devices = vec![
+        device("/dev/nvme0n1", Some(3)),
+        device("/dev/nvme1n1", Some(2)),
+        device("/dev/nvme2n1", Some(2)),
+    ];
+    assert_eq!(least_used(&devices), Some(&devices[1]));
+}
+
+// ceph-volume's tags for a db or wal partition
+fn partition_tags(kind: &str, partition: &JournalDevice) -> BynarResult<Vec<String>> {
+    let uuid = match partition.partition_uuid {
+        Some(uuid) => uuid,
+        None => {
+            // Get the partition uuid from the device
+            debug!("Discovering {} partition uuid", partition);
+            probe_partition_uuid(&Path::new(&format!("{}", partition)))?
+        }
+    };
+    Ok(vec![
+        format!("ceph.{}_device={}", kind, partition),
+        format!("ceph.{}_uuid={}", kind, uuid),
+    ])
+}
+
+// Value of a ceph.* tag on the logical volumes of a device
+fn osd_lvm_tag(dev_path: &Path, name: &str) -> BynarResult<Option<String>> {
+    Ok(osd_lvm_tags(dev_path, name)?.into_iter().next())
+}
+
+// Values of a ceph.* tag on every logical volume of a device
+fn osd_lvm_tags(dev_path: &Path, name: &str) -> BynarResult<Vec<String>> {
+    let mut values = vec![];
+    if let Some((_, lv_tags)) = lvm_volume_tags(dev_path)? {
+        for tag in lv_tags.iter().flatten() {
+            let mut parts = tag.splitn(2, '=');
+            if parts.next() == Some(name) {
+                values.extend(parts.next().map(|value| value.to_string()));
+            }
+        }
+    }
+    Ok(values)
+}
+
+// The volume group on a device and the tags of each of its logical volumes.
+// The LVM lock is only held while reading them
+fn lvm_volume_tags(dev_path: &Path) -> BynarResult<Option<(String, Vec<Vec<String>>)>> {
+    let _lvm_lock = lock_lvm();
+    let lvm = Lvm::new(None)?;
+    lvm.scan()?;
+    let vg_name = match lvm.vg_name_from_device(&dev_path.to_string_lossy())? {
+        Some(vg_name) => vg_name,
+        None => return Ok(None),
+    };
+    let vg = lvm.vg_open(&vg_name, &OpenMode::Read)?;
+    let mut lv_tags = vec![];
+    for lv in &vg.list_lvs()? {
+        lv_tags.push(lv.get_tags()?);
+    }
+    Ok(Some((vg_name, lv_tags)))
+}
+
+// Remove every logical volume in a device's volume group, the group and
+// then the physical volume
+fn remove_volume_group(dev_path: &Path, vg_name: &str) -> BynarResult<()> {
+    let _lvm_lock = lock_lvm();
+    let lvm = Lvm::new(None)?;
+    lvm.scan()?;
+    let vg = lvm.vg_open(vg_name, &OpenMode::Write)?;
+    for lv in &vg.list_lvs()? {
+        lv.deactivate()?;
+        lv.remove()?;
+    }
+    vg.remove()?;
+    lvm.pv_remove(&dev_path.to_string_lossy())?;
+    Ok(())
+}
+
+// A fallback function to get the osd id from the mount path.  This isn't
+// 100% accurate but it should be good enough for most cases unless the disk
+// is mounted in the wrong location or is missing an osd id in the path name
+fn get_osd_id_from_path(path: &Path) -> BynarResult<u64> {
+    match path.file_name() {
+        Some(name) => {
+            let name_string = name.to_string_lossy().into_owned();
+            let parts: Vec<&str> = name_string.split('-').collect();
+            let id = u64::from_str(parts[1])?;
+            Ok(id)
+        }
+        None => Err(BynarError::new(format!(
+            "Unable to get filename from {}",
+            path.display()
+        ))),
+    }
+}
+
+// Get an osd ID from the whoami file in the osd mount directory
+fn get_osd_id(path: &Path, simulate: bool) -> BynarResult<u64> {
+    if simulate {
+        return Ok(0);
+    }
+    let whoami_path = path.join("whoami");
+    debug!("Discovering osd id number from: {}", whoami_path.display());
     let buff = read_to_string(&whoami_path)?;
     Ok(u64::from_str(buff.trim())?)
 }
@@ -804,64 +2682,295 @@ fn add_osd_to_fstab(
     Ok(())
 }
 
+/// An osd whose volumes are LUKS encrypted
+#[derive(Debug)]
+struct EncryptedOsd {
+    id: u64,
+    fsid: uuid::Uuid,
+    /// Encrypted devices and the names to map them to once unlocked
+    volumes: Vec<(PathBuf, String)>,
+}
+
+// Finds the encrypted osds on this system from their LVM tags
+fn encrypted_osds() -> BynarResult<Vec<EncryptedOsd>> {
+    let mut osds = vec![];
+    let _lvm_lock = lock_lvm();
+    let lvm = Lvm::new(None)?;
+    lvm.scan()?;
+    for vg_name in lvm.list_vg_names()? {
+        let vg = lvm.vg_open(&vg_name, &OpenMode::Read)?;
+        for lv in &vg.list_lvs()? {
+            let name = format!("{}/{}", vg_name, lv.get_name());
+            osds.extend(encrypted_osd(&name, &lv.get_tags()?)?);
+        }
+    }
+    Ok(osds)
+}
+
+// The encrypted osd a logical volume's tags describe.  None unless it's the
+// block volume of an encrypted osd
+fn encrypted_osd(name: &str, tags: &[String]) -> BynarResult<Option<EncryptedOsd>> {
+    let tags: HashMap<&str, &str> = tags
+        .iter()
+        .filter_map(|tag| {
+            let mut parts = tag.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => Some((key, value)),
+                _ => None,
+            }
+        })
+        .collect();
+    let is_block = tags.get("ceph.type") == Some(&"block");
+    let encrypted = tags.get("ceph.encrypted") == Some(&"1");
+    if !is_block || !encrypted {
+        return Ok(None);
+    }
+    let tag = |key: &str| -> BynarResult<String> {
+        tags.get(key)
+            .map(|value| value.to_string())
+            .ok_or_else(|| BynarError::new(format!("{} has no {} tag", name, key)))
+    };
+    let mut volumes = vec![(
+        PathBuf::from(tag("ceph.block_device")?),
+        tag("ceph.block_uuid")?,
+    )];
+    for kind in &["wal", "db"] {
+        let device = tags.get(format!("ceph.{}_device", kind).as_str());
+        let uuid = tags.get(format!("ceph.{}_uuid", kind).as_str());
+        if let (Some(device), Some(uuid)) = (device, uuid) {
+            volumes.push((PathBuf::from(device), uuid.to_string()));
+        }
+    }
+    Ok(Some(EncryptedOsd {
+        id: u64::from_str(&tag("ceph.osd_id")?)?,
+        fsid: uuid::Uuid::parse_str(&tag("ceph.osd_fsid")?)?,
+        volumes,
+    }))
+}
+
+#[test]
+fn test_encrypted_osd() {
+    let tags = |tags: &[&str]| -> Vec<String> { tags.iter().map(|t| t.to_string()).collect() };
+    let block = tags(&[
+        "ceph.type=block",
+        "ceph.encrypted=1",
+        "ceph.osd_id=4",
+        "ceph.osd_fsid=5c3b3e1c-0a4e-4f5e-9a6f-3d2a7f0c8b11",
+        "ceph.block_device=/dev/ceph-vg/osd-block-0",
+        "ceph.block_uuid=Kq3Xzp-1abc",
+        "ceph.db_device=/dev/nvme0n1p2",
+        "ceph.db_uuid=0e7c4a8d-6f1b-4a2c-9d3e-5b6a7c8d9e0f",
+        // A wal without its uuid isn't unlocked
+        "ceph.wal_device=/dev/nvme1n1p1",
+    ]);
+    let osd = encrypted_osd("ceph-vg/osd-block-0", &block)
+        .unwrap()
+        .unwrap();
+    assert_eq!(osd.id, 4);
+    assert_eq!(
+        osd.fsid.to_hyphenated().to_string(),
+        "5c3b3e1c-0a4e-4f5e-9a6f-3d2a7f0c8b11"
+    );
+    assert_eq!(
+        osd.volumes,
+        vec![
+            (
+                PathBuf::from("/dev/ceph-vg/osd-block-0"),
+                "Kq3Xzp-1abc".to_string()
+            ),
+            (
+                PathBuf::from("/dev/nvme0n1p2"),
+                "0e7c4a8d-6f1b-4a2c-9d3e-5b6a7c8d9e0f".to_string()
+            ),
+        ]
+    );
+
+    // Unencrypted osds and other volumes are skipped
+    let plain: Vec<String> = block
+        .iter()
+        .filter(|t| !t.starts_with("ceph.encrypted"))
+        .cloned()
+        .collect();
+    assert!(encrypted_osd("vg/lv", &plain).unwrap().is_none());
+    let db = tags(&["ceph.type=db", "ceph.encrypted=1"]);
+    assert!(encrypted_osd("vg/lv", &db).unwrap().is_none());
+    assert!(encrypted_osd("vg/lv", &[]).unwrap().is_none());
+
+    // An encrypted block volume missing a tag it needs is an error
+    let missing: Vec<String> = block
+        .iter()
+        .filter(|t| !t.starts_with("ceph.block_uuid"))
+        .cloned()
+        .collect();
+    assert!(encrypted_osd("vg/lv", &missing).is_err());
+}
+
+// Where an osd's dm-crypt key is kept in Vault.  Named like the
+// config-key ceph-volume keeps its keys under
+fn dmcrypt_key_name(osd_fsid: &uuid::Uuid) -> String {
+    format!("dm-crypt/osd/{}/luks", osd_fsid)
+}
+
+// 128 random bytes, hex encoded.  As much randomness as ceph-volume uses
+fn new_dmcrypt_key() -> BynarResult<String> {
+    let mut bytes = [0; 128];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+#[test]
+fn test_dmcrypt_key() {
+    let fsid = uuid::Uuid::parse_str("5c3b3e1c-0a4e-4f5e-9a6f-3d2a7f0c8b11").unwrap();
+    assert_eq!(
+        dmcrypt_key_name(&fsid),
+        "dm-crypt/osd/5c3b3e1c-0a4e-4f5e-9a6f-3d2a7f0c8b11/luks"
+    );
+
+    let key = new_dmcrypt_key().unwrap();
+    assert_eq!(key.len(), 256);
+    assert!(key
+        .chars()
+        .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c)));
+    assert_ne!(key, new_dmcrypt_key().unwrap());
+}
+
+// Run cryptsetup with the key on stdin
+fn cryptsetup(args: &[&str], key: Option<&str>, simulate: bool) -> BynarResult<()> {
+    debug!("cmd: cryptsetup {:?}", args);
+    if simulate {
+        return Ok(());
+    }
+    let mut child = Command::new("cryptsetup")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // Dropping stdin closes it so cryptsetup sees the end of the key
+    if let Some(mut stdin) = child.stdin.take() {
+        if let Some(key) = key {
+            stdin.write_all(key.as_bytes())?;
+        }
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        error!("cryptsetup {:?} failed: {}", args, stderr);
+        return Err(BynarError::new(stderr));
+    }
+    Ok(())
+}
+
+fn luks_format(device: &Path, key: &str, simulate: bool) -> BynarResult<()> {
+    let dev_str = device.to_string_lossy().into_owned();
+    cryptsetup(
+        &["--batch-mode", "--key-file", "-", "luksFormat", &dev_str],
+        Some(key),
+        simulate,
+    )
+}
+
+// Unlock a device as /dev/mapper/<mapping>.  Already unlocked devices are
+// left alone.  Simulating hands back the device itself since nothing gets
+// mapped
+fn luks_open(device: &Path, mapping: &str, key: &str, simulate: bool) -> BynarResult<PathBuf> {
+    if simulate {
+        debug!("Would unlock {} as {}", device.display(), mapping);
+        return Ok(device.to_path_buf());
+    }
+    let mapped = Path::new("/dev/mapper").join(mapping);
+    if mapped.exists() {
+        debug!("{} is already unlocked", device.display());
+        return Ok(mapped);
+    }
+    let dev_str = device.to_string_lossy().into_owned();
+    cryptsetup(
+        &[
+            "--key-file",
+            "-",
+            "--allow-discards",
+            "luksOpen",
+            &dev_str,
+            mapping,
+        ],
+        Some(key),
+        false,
+    )?;
+    Ok(mapped)
+}
+
+fn luks_close(mapping: &str, simulate: bool) -> BynarResult<()> {
+    if !Path::new("/dev/mapper").join(mapping).exists() {
+        return Ok(());
+    }
+    cryptsetup(&["luksClose", mapping], None, simulate)
+}
+
 // Look through all the /var/lib/ceph/osd/ directories and try to find
 // a partition id that matches this one.
 fn partition_in_use(partition_uuid: &uuid::Uuid) -> BynarResult<bool> {
+    Ok(referenced_partitions()?.contains(partition_uuid))
+}
+
+// Partition uuids of every journal, db and wal an osd on this system points
+// to.  Both the osd directories and the LVM tags are checked so an osd
+// that's down and hasn't been activated still holds on to its partitions
+fn referenced_partitions() -> BynarResult<HashSet<uuid::Uuid>> {
+    let mut referenced = HashSet::new();
     // Check every osd on the system
     for osd_dir in read_dir("/var/lib/ceph/osd/")? {
         let osd_dir = osd_dir?;
-        trace!("Locating journal symlink in {}", osd_dir.path().display());
-        // Ceph Jewel and older uses journal as the journal symlink name
-        let old_journal_path = osd_dir.path().join("journal");
-        // Ceph Luminous and newer users block.wal as the journal device symlink name
-        let new_journal_path = osd_dir.path().join("block.wal");
-
-        let journal_path = match (old_journal_path.exists(), new_journal_path.exists()) {
-            (true, true) => {
-                // Ok this isn't possible
-                return Err(BynarError::new(format!(
-                    "Unable to determine which journal path to use.  Both {} and {} exist.",
-                    old_journal_path.display(),
-                    new_journal_path.display(),
-                )));
-            }
-            (true, false) => {
-                // Old Ceph
-                old_journal_path
-            }
-            (false, true) => {
-                // New Ceph
-                new_journal_path
+        // Ceph Jewel and older uses journal as the journal symlink name.
+        // Ceph Luminous and newer use block.wal and block.db
+        for link in &["journal", "block.wal", "block.db"] {
+            let link_path = osd_dir.path().join(link);
+            trace!("Checking {}", link_path.display());
+            match symlink_metadata(&link_path) {
+                Ok(ref meta) if meta.file_type().is_symlink() => {}
+                // Not there or a journal file on the osd disk itself
+                _ => continue,
             }
-            (false, false) => {
-                // No journal
-                return Ok(false);
+            // Resolve the device the symlink points to
+            let dev = link_path.read_link()?;
+            match probe_partition_uuid(&dev) {
+                Ok(uuid) => {
+                    debug!("{} partition uuid: {}", link_path.display(), uuid);
+                    referenced.insert(uuid);
+                }
+                // ie: a logical volume
+                Err(e) => debug!("{} has no partition uuid: {:?}", dev.display(), e),
             }
-        };
-        debug!("Journal path: {}", journal_path.display());
-        let meta = symlink_metadata(&journal_path)?;
-        if !meta.file_type().is_symlink() {
-            // Whoops.  Symlink pointer missing.  Can't proceed
-            // TODO: Is this always true?
-            return Err(BynarError::new(format!(
-                "Journal {} is not a symlink. Unable to find the device this journal points to",
-                journal_path.display(),
-            )));
         }
-
-        // Resolve the device the symlink points to
-        let dev = journal_path.read_link()?;
-        let blkid = BlkId::new(&dev)?;
-        blkid.do_probe()?;
-        // Get the partition uuid from the device
-        let dev_partition_uuid = uuid::Uuid::from_str(&blkid.lookup_value("PARTUUID")?)?;
-        debug!("Journal partition uuid: {}", dev_partition_uuid);
-        if partition_uuid == &dev_partition_uuid {
-            return Ok(true);
+    }
+    let _lvm_lock = lock_lvm();
+    let lvm = Lvm::new(None)?;
+    lvm.scan()?;
+    for vg_name in lvm.list_vg_names()? {
+        let vg = lvm.vg_open(&vg_name, &OpenMode::Read)?;
+        for lv in &vg.list_lvs()? {
+            for tag in lv.get_tags()? {
+                let mut parts = tag.splitn(2, '=');
+                match parts.next() {
+                    Some("ceph.wal_uuid") | Some("ceph.db_uuid") => {}
+                    _ => continue,
+                }
+                // ceph-volume puts logical volume uuids in here too.
+                // Those won't match any partition
+                if let Some(Ok(uuid)) = parts.next().map(uuid::Uuid::parse_str) {
+                    referenced.insert(uuid);
+                }
+            }
         }
     }
 
-    Ok(false)
+    Ok(referenced)
+}
+
+// Get the partition uuid of a device
+fn probe_partition_uuid(dev_path: &Path) -> BynarResult<uuid::Uuid> {
+    let blkid = BlkId::new(dev_path)?;
+    blkid.do_probe()?;
+    Ok(uuid::Uuid::from_str(&blkid.lookup_value("PARTUUID")?)?)
 }
 
 fn systemctl_disable(osd_id: u64, osd_uuid: &uuid::Uuid, simulate: bool) -> BynarResult<()> {
@@ -967,7 +3076,7 @@ fn settle_udev() -> BynarResult<()> {
 // Run ceph-osd --mkfs and return the osd UUID
 fn ceph_mkfs(
     osd_id: u64,
-    journal: Option<&JournalDevice>,
+    partitions: &OsdPartitions,
     bluestore: bool,
     monmap: Option<&Path>,
     osd_data: Option<&Path>,
@@ -984,12 +3093,19 @@ fn ceph_mkfs(
         osd_id.to_string(),
         "--mkfs".to_string(),
     ];
-    if let Some(journal) = journal {
-        args.push("--osd-journal".to_string());
-        args.push(format!("{}", journal));
-    }
     if bluestore {
         args.extend_from_slice(&["--osd-objectstore".to_string(), "bluestore".to_string()]);
+        if let Some(ref wal) = partitions.journal {
+            args.push("--bluestore-block-wal-path".to_string());
+            args.push(format!("{}", wal));
+        }
+        if let Some(ref db) = partitions.db {
+            args.push("--bluestore-block-db-path".to_string());
+            args.push(format!("{}", db));
+        }
+    } else if let Some(ref journal) = partitions.journal {
+        args.push("--osd-journal".to_string());
+        args.push(format!("{}", journal));
     }
     if let Some(monmap) = monmap {
         args.push("--monmap".to_string());
@@ -1057,12 +3173,19 @@ fn ceph_bluestore_tool(device: &Path, mount_path: &Path, simulate: bool) -> Byna
     Ok(())
 }
 
-/// Create a new ceph journal on a given deivce with name + size in bytes
-fn create_journal(name: &str, size: u64, path: &Path) -> BynarResult<(u32, uuid::Uuid)> {
-    debug!("Creating journal on {} of size: {}", path.display(), size);
+/// Create a new ceph journal, db or wal partition on a given deivce with
+/// size in bytes
+fn create_journal(kind: SharedPartition, size: u64, path: &Path) -> BynarResult<(u32, uuid::Uuid)> {
+    debug!(
+        "Creating {} on {} of size: {}",
+        kind.name(),
+        path.display(),
+        size
+    );
+    let _lock = lock_device(path)?;
     let cfg = gpt::GptConfig::new().writable(true).initialized(true);
     let mut disk = cfg.open(path)?;
-    let part_id = disk.add_partition(name, size, gpt::partition_types::CEPH_JOURNAL, 0)?;
+    let part_id = disk.add_partition(kind.name(), size, kind.part_type(), 0)?;
     // Write it out
     disk.write()?;
     update_partition_cache(&path)?;
@@ -1110,7 +3233,11 @@ fn enough_free_space(device: &Path, size: u64) -> BynarResult<bool> {
 // 1. Attempt to discover if a device exists at that journal path
 // 2. Create a journal partition if needed.
 // 3. Returns a path to use for the journal
-fn evaluate_journal(journal: &JournalDevice, journal_size: u64) -> BynarResult<JournalDevice> {
+fn evaluate_journal(
+    journal: &JournalDevice,
+    journal_size: u64,
+    kind: SharedPartition,
+) -> BynarResult<JournalDevice> {
     match (&journal.device, journal.partition_id) {
         (journal, Some(part_id)) => {
             // Got both a journal device and a partition id
@@ -1132,8 +3259,7 @@ fn evaluate_journal(journal: &JournalDevice, journal_size: u64) -> BynarResult<J
                         });
                     } else {
                         // Create a new partition because the old one is in use
-                        let partition_info =
-                            create_journal("ceph_journal", journal_size, &journal)?;
+                        let partition_info = create_journal(kind, journal_size, &journal)?;
                         let mut j = JournalDevice {
                             device: journal.to_path_buf(),
                             partition_id: Some(partition_info.0),
@@ -1155,7 +3281,7 @@ fn evaluate_journal(journal: &JournalDevice, journal_size: u64) -> BynarResult<J
         (journal, None) => {
             // Got just a journal device
             // Create a new journal partition on there
-            let partition_info = create_journal("ceph_journal", journal_size, &journal)?;
+            let partition_info = create_journal(kind, journal_size, &journal)?;
             let mut j = JournalDevice {
                 device: journal.to_path_buf(),
                 partition_id: Some(partition_info.0),
@@ -1168,40 +3294,232 @@ fn evaluate_journal(journal: &JournalDevice, journal_size: u64) -> BynarResult<J
     }
 }
 
-// NOTE: This function is currently unused because I don't have complete trust
-// in it yet.
-// Checks all osd drives on the system against the journals and deletes all
-// unused partitions.
-fn remove_unused_journals(journals: &[JournalDevice]) -> BynarResult<()> {
-    for journal in journals {
-        let cfg = gpt::GptConfig::new().writable(true).initialized(true);
-        debug!("Checking for unused journal partitions on {}", journal);
-        let mut disk = cfg.open(&journal.device)?;
-        let mut changed = false;
-        let mut partitions: BTreeMap<u32, gpt::partition::Partition> = disk.partitions().clone();
-        for part in partitions.iter_mut() {
-            trace!("Checking if {:?} is in use", part);
-            let partition_used = match partition_in_use(&part.1.part_guid) {
-                Ok(used) => used,
-                Err(e) => {
-                    error!("partition_in_use error: {:?}. Not modifying partition", e);
-                    true
-                }
-            };
-            if !partition_used {
-                // mark as unused
-                changed = true;
-                part.1.part_type_guid = gpt::partition_types::UNUSED;
+/// A journal, db or wal partition that no osd points to
+#[derive(Clone, Debug)]
+struct UnusedPartition {
+    device: PathBuf,
+    number: u32,
+    uuid: uuid::Uuid,
+}
+
+// Finds the journal, db and wal partitions on a device that no osd on the
+// system uses.  Other partitions are left alone
+fn unused_partitions(
+    device: &Path,
+    referenced: &HashSet<uuid::Uuid>,
+) -> BynarResult<Vec<UnusedPartition>> {
+    debug!("Checking for unused partitions on {}", device.display());
+    let disk = gpt::GptConfig::new()
+        .writable(false)
+        .initialized(true)
+        .open(device)?;
+    let kinds = [
+        SharedPartition::Journal,
+        SharedPartition::Db,
+        SharedPartition::Wal,
+    ];
+    let mut unused = vec![];
+    for (number, part) in disk.partitions() {
+        if !kinds.iter().any(|k| k.part_type() == part.part_type_guid) {
+            continue;
+        }
+        trace!("Checking if {:?} is in use", part);
+        if !referenced.contains(&part.part_guid) {
+            unused.push(UnusedPartition {
+                device: device.to_path_buf(),
+                number: *number,
+                uuid: part.part_guid,
+            });
+        }
+    }
+    Ok(unused)
+}
+
+// Mark partitions as unused and have the kernel drop them
+fn free_partitions(device: &Path, numbers: &[u32]) -> BynarResult<()> {
+    let _lock = lock_device(device)?;
+    let mut disk = gpt::GptConfig::new()
+        .writable(true)
+        .initialized(true)
+        .open(device)?;
+    let mut partitions: BTreeMap<u32, gpt::partition::Partition> = disk.partitions().clone();
+    for number in numbers {
+        if let Some(part) = partitions.get_mut(number) {
+            debug!("Freeing partition {} on {}", number, device.display());
+            part.part_type_guid = gpt::partition_types::UNUSED;
+        }
+    }
+    trace!("Saving partitions: {:?}", partitions);
+    disk.update_partitions(partitions)?;
+    disk.write()?;
+    update_partition_cache(device)
+}
+
+// Adds and the reclaimer can both change the partition table of a shared
+// device.  Holding this lock keeps them from writing over each other
+fn lock_device(device: &Path) -> BynarResult<File> {
+    let file = File::open(device)?;
+    flock(file.as_raw_fd(), FlockArg::LockExclusive)?;
+    Ok(file)
+}
+
+// What a removed osd had on shared devices that gets freed.  When the id is
+// kept its partitions are left for the replacement to reuse.  The reclaimer
+// frees them if none shows up.  Logical volumes can't be reused so they
+// always go
+fn to_release<'a, I: IntoIterator<Item = &'a PathBuf>>(devices: I, kept: bool) -> Vec<&'a PathBuf> {
+    devices
+        .into_iter()
+        .filter(|device| !kept || lv_names(device).is_some())
+        .collect()
+}
+
+#[test]
+fn test_to_release() {
+    let shared = vec![
+        PathBuf::from("/dev/nvme0n1p3"),
+        PathBuf::from("/dev/ceph-journals/osd-3-wal"),
+    ];
+    assert_eq!(to_release(&shared, false), vec![&shared[0], &shared[1]]);
+    assert_eq!(to_release(&shared, true), vec![&shared[1]]);
+    let journal = Some(PathBuf::from("/dev/sdb2"));
+    assert!(to_release(&journal, true).is_empty());
+}
+
+// Free everything a removed osd had on shared devices.  Failures are only
+// logged.  The reclaimer picks up anything left behind
+fn release_all<'a, I: IntoIterator<Item = &'a PathBuf>>(devices: I, simulate: bool) {
+    for device in devices {
+        info!("Freeing {}", device.display());
+        if let Err(e) = release_shared(device, simulate) {
+            error!("Unable to free {}: {:?}", device.display(), e);
+        }
+    }
+}
+
+// Free a journal, db or wal that a removed osd used.  Logical volumes are
+// removed and partitions are marked unused
+fn release_shared(device: &Path, simulate: bool) -> BynarResult<()> {
+    if let Some((vg_name, lv_name)) = lv_names(device) {
+        debug!("Removing logical volume {}/{}", vg_name, lv_name);
+        if simulate {
+            return Ok(());
+        }
+        let _lvm_lock = lock_lvm();
+        let lvm = Lvm::new(None)?;
+        lvm.scan()?;
+        let vg = lvm.vg_open(&vg_name, &OpenMode::Write)?;
+        for lv in vg.list_lvs()? {
+            if lv.get_name() == lv_name {
+                lv.deactivate()?;
+                lv.remove()?;
             }
         }
-        if changed {
-            trace!("Saving partitions: {:?}", partitions);
-            disk.update_partitions(partitions)?;
-            disk.write()?;
+        return Ok(());
+    }
+    let uuid = probe_partition_uuid(device)?;
+    if partition_in_use(&uuid)? {
+        return Err(BynarError::new(format!(
+            "{} is still used by another osd",
+            device.display()
+        )));
+    }
+    let (disk, number) = partition_parent(device)?;
+    debug!("Freeing partition {} on {}", number, disk.display());
+    if simulate {
+        return Ok(());
+    }
+    free_partitions(&disk, &[number])
+}
+
+// The volume group and logical volume names of an LVM device path.
+// ie: /dev/ceph-journals/osd-3-wal.  None for anything else
+fn lv_names(device: &Path) -> Option<(String, String)> {
+    let parts: Vec<String> = device
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    match parts.as_slice() {
+        [root, dev, vg, lv] if root == "/" && dev == "dev" && vg != "disk" && vg != "mapper" => {
+            Some((vg.clone(), lv.clone()))
         }
+        _ => None,
     }
+}
 
-    Ok(())
+#[test]
+fn test_lv_names() {
+    assert_eq!(
+        lv_names(Path::new("/dev/ceph-journals/osd-3-wal")),
+        Some(("ceph-journals".to_string(), "osd-3-wal".to_string()))
+    );
+    assert_eq!(lv_names(Path::new("/dev/sdb3")), None);
+    assert_eq!(lv_names(Path::new("/dev/mapper/vg-lv")), None);
+    assert_eq!(lv_names(Path::new("/dev/disk/by-partuuid/abc")), None);
+}
+
+// The disk a partition is on and its partition number
+fn partition_parent(partition: &Path) -> BynarResult<(PathBuf, u32)> {
+    let partition = partition.canonicalize()?;
+    let name = partition
+        .file_name()
+        .ok_or_else(|| BynarError::new(format!("{} has no device name", partition.display())))?;
+    let sys_path = Path::new("/sys/class/block").join(name);
+    let number = u32::from_str(read_to_string(sys_path.join("partition"))?.trim())?;
+    // /sys/class/block/sdb3 links to .../block/sdb/sdb3
+    let parent = sys_path
+        .canonicalize()?
+        .parent()
+        .and_then(|p| p.file_name())
+        .map(|p| Path::new("/dev").join(p))
+        .ok_or_else(|| {
+            BynarError::new(format!(
+                "Unable to find the disk {} is on",
+                partition.display()
+            ))
+        })?;
+    Ok((parent, number))
+}
+
+// Records when each unused partition was first seen and returns the ones
+// that have been unused for the whole grace period.  Partitions that are
+// in use again are forgotten
+fn due_for_reclaim(
+    first_seen: &mut HashMap<String, u64>,
+    unused: &[String],
+    now: u64,
+    grace_period: u64,
+) -> Vec<String> {
+    first_seen.retain(|uuid, _| unused.contains(uuid));
+    unused
+        .iter()
+        .filter(|uuid| {
+            let seen = *first_seen.entry(uuid.to_string()).or_insert(now);
+            now.saturating_sub(seen) >= grace_period
+        })
+        .cloned()
+        .collect()
+}
+
+#[test]
+fn test_due_for_reclaim() {
+    let mut first_seen = HashMap::new();
+    let unused = vec!["a".to_string(), "b".to_string()];
+    // Nothing is reclaimed the first time it's seen
+    assert!(due_for_reclaim(&mut first_seen, &unused, 100, 50).is_empty());
+    assert_eq!(first_seen.get("a"), Some(&100));
+
+    // b is in use again so it starts over if it shows up later
+    let unused = vec!["a".to_string()];
+    assert!(due_for_reclaim(&mut first_seen, &unused, 120, 50).is_empty());
+    assert_eq!(first_seen.get("b"), None);
+
+    let unused = vec!["a".to_string(), "b".to_string()];
+    assert_eq!(
+        due_for_reclaim(&mut first_seen, &unused, 150, 50),
+        vec!["a".to_string()]
+    );
+    assert_eq!(first_seen.get("b"), Some(&150));
 }
 
 fn is_filestore(dev_path: &Path) -> BynarResult<bool> {
@@ -1226,30 +3544,3 @@ fn is_filestore(dev_path: &Path) -> BynarResult<bool> {
 
     Ok(false)
 }
-
-// Linux specific ioctl to update the partition table cache.
-fn update_partition_cache(device: &Path) -> BynarResult<()> {
-    debug!(
-        "Requesting kernel to refresh partition cache for {} ",
-        device.display()
-    );
-    let device = OpenOptions::new().read(true).write(true).open(device)?;
-    let ret = unsafe { blkrrpart(device.as_raw_fd()) }?;
-    if ret != 0 {
-        Err(BynarError::new(format!(
-            "BLKRRPART ioctl failed with return code: {}",
-            ret,
-        )))
-    } else {
-        Ok(())
-    }
-}
-
-// This macro from the nix crate crates an ioctl to call the linux kernel
-// and ask it to update its internal partition cache. Without this the
-// partitions don't show up after being created on the disks which then
-// breaks parts of bynar later.
-ioctl_none! {
-    /// Linux BLKRRPART ioctl to update partition tables.  Defined in linux/fs.h
-    blkrrpart, 0x12, 95
-}
@@ -1,36 +1,466 @@
-use crate::backend::Backend;
+use crate::backend::{Backend, Progress};
 
+use std::fs::{create_dir_all, read_to_string, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use fstab::FsTab;
 use helpers::error::*;
-use std::path::Path;
+use hostname::get_hostname;
+use log::{debug, error, info, warn};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use serde_derive::*;
+use tempdir::TempDir;
 
-pub struct GlusterBackend;
 /*
     Add a disk
     0. Make sure the brick pid isn't running
     1. Need to know which brick we're replacing.
-      a. Pull from sqlite3
+      a. Pull from the brick record saved during removal
+      b. The replacement can show up under another device name.  The
+         replica set the client gives picks the record then
     2. Create a dir on the gluster mount that doesn't exist
     Using Temp
          mkdir /mnt/r2/<name-of-nonexistent-dir>
          rmdir /mnt/r2/<name-of-nonexistent-dir>
          setfattr -n trusted.non-existent-key -v abc /mnt/r2
          setfattr -x trusted.non-existent-key  /mnt/r2
+    3. Tell gluster the brick is back with reset-brick commit force
+       and start a full heal onto it
 
     Remove a disk
     1. Kill the brick pid
     2. wipe it
-    3. Record in sqlite where it was mounted so we can replace it
+    3. Record where it was mounted so we can replace it
     later
 */
 
+/// Gluster cluster
+pub struct GlusterBackend {
+    config: GlusterConfig,
+    hostname: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+struct GlusterConfig {
+    /// Where the bricks removed by Bynar are recorded so they can be
+    /// put back in place once the disk is replaced.
+    /// Defaults to /var/lib/bynar/gluster_bricks.json
+    brick_record_file: Option<String>,
+}
+
+/// A brick that was removed from a volume and is waiting for a
+/// replacement disk
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+struct BrickRecord {
+    volume: String,
+    /// The host:/path of the brick as gluster knows it
+    brick: String,
+    device: PathBuf,
+    mount_point: PathBuf,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct VolumeInfo {
+    name: String,
+    replica_count: usize,
+    /// host:/path bricks in the order gluster lists them.  Replica sets
+    /// are consecutive runs of replica_count bricks
+    bricks: Vec<String>,
+}
+
+impl VolumeInfo {
+    fn replica_set(&self, brick: &str) -> Option<&[String]> {
+        self.bricks
+            .chunks(self.replica_count)
+            .find(|set| set.iter().any(|b| b == brick))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct BrickStatus {
+    brick: String,
+    online: bool,
+    pid: Option<i32>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct HealInfo {
+    brick: String,
+    connected: bool,
+    // None when gluster can't count the entries.  Usually because the
+    // brick is down
+    entries: Option<u64>,
+}
+
+#[test]
+fn test_parse_volume_info() {
+    let output = r#"
+Volume Name: test
+Type: Distributed-Replicate
+Volume ID: 0ab6f3d4-5d7e-4f8a-8a3d-2f4a0c8d1e11
+Status: Started
+Snapshot Count: 0
+Number of Bricks: 2 x 3 = 6
+Transport-type: tcp
+Bricks:
+Brick1: server1:/mnt/r1/brick
+Brick2: server2:/mnt/r1/brick
+Brick3: server3:/mnt/r1/brick
+Brick4: server1:/mnt/r2/brick
+Brick5: server2:/mnt/r2/brick
+Brick6: server3:/mnt/r2/brick
+Options Reconfigured:
+transport.address-family: inet
+"#;
+    let info = parse_volume_info(output).unwrap();
+    assert_eq!(info.name, "test");
+    assert_eq!(info.replica_count, 3);
+    assert_eq!(info.bricks.len(), 6);
+    assert_eq!(
+        info.replica_set("server2:/mnt/r2/brick").unwrap(),
+        &[
+            "server1:/mnt/r2/brick".to_string(),
+            "server2:/mnt/r2/brick".to_string(),
+            "server3:/mnt/r2/brick".to_string(),
+        ]
+    );
+
+    let arbiter = "Volume Name: arb\nNumber of Bricks: 1 x (2 + 1) = 3\nBrick1: a:/b1\nBrick2: b:/b1\nBrick3: c:/b1 (arbiter)\n";
+    let info = parse_volume_info(arbiter).unwrap();
+    assert_eq!(info.replica_count, 3);
+    assert_eq!(info.bricks[2], "c:/b1");
+}
+
+#[test]
+fn test_parse_volume_status() {
+    let output = r#"Status of volume: test
+Gluster process                             TCP Port  RDMA Port  Online  Pid
+------------------------------------------------------------------------------
+Brick server1:/mnt/r1/brick                 49152     0          Y       2201
+Brick server2.some.very.long.domain.name:/mnt/r1/brick
+                                            N/A       N/A        N       N/A
+Self-heal Daemon on localhost               N/A       N/A        Y       2222
+
+Task Status of Volume test
+"#;
+    let status = parse_volume_status(output);
+    assert_eq!(
+        status,
+        vec![
+            BrickStatus {
+                brick: "server1:/mnt/r1/brick".into(),
+                online: true,
+                pid: Some(2201),
+            },
+            BrickStatus {
+                brick: "server2.some.very.long.domain.name:/mnt/r1/brick".into(),
+                online: false,
+                pid: None,
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_parse_heal_info() {
+    let output = r#"Brick server1:/mnt/r1/brick
+Status: Connected
+Number of entries: 0
+
+Brick server2:/mnt/r1/brick
+/some/file
+Status: Connected
+Number of entries: 1
+
+Brick server3:/mnt/r1/brick
+Status: Transport endpoint is not connected
+Number of entries: -
+"#;
+    let heal = parse_heal_info(output);
+    assert_eq!(heal.len(), 3);
+    assert_eq!(heal[0].entries, Some(0));
+    assert_eq!(heal[1].entries, Some(1));
+    assert!(!heal[2].connected);
+    assert_eq!(heal[2].entries, None);
+}
+
+impl GlusterBackend {
+    pub fn new(config_dir: Option<&Path>) -> BynarResult<GlusterBackend> {
+        // The gluster.json file is optional.  Everything in it has a default
+        let config = match config_dir {
+            Some(dir) if dir.join("gluster.json").exists() => {
+                debug!("Loading gluster config from: {}", dir.display());
+                helpers::load_config(dir, "gluster.json")?
+            }
+            _ => GlusterConfig::default(),
+        };
+        let hostname = get_hostname().ok_or_else(|| BynarError::from("hostname not found"))?;
+        Ok(GlusterBackend { config, hostname })
+    }
+
+    fn brick_record_file(&self) -> PathBuf {
+        match self.config.brick_record_file {
+            Some(ref f) => PathBuf::from(f),
+            None => PathBuf::from("/var/lib/bynar/gluster_bricks.json"),
+        }
+    }
+
+    fn load_brick_records(&self) -> BynarResult<Vec<BrickRecord>> {
+        let p = self.brick_record_file();
+        if !p.exists() {
+            return Ok(vec![]);
+        }
+        let s = read_to_string(&p)?;
+        let records: Vec<BrickRecord> = serde_json::from_str(&s)?;
+        Ok(records)
+    }
+
+    fn save_brick_records(&self, records: &[BrickRecord]) -> BynarResult<()> {
+        let p = self.brick_record_file();
+        if let Some(parent) = p.parent() {
+            create_dir_all(parent)?;
+        }
+        debug!("Saving {} brick records to {}", records.len(), p.display());
+        let mut f = File::create(&p)?;
+        f.write_all(serde_json::to_string_pretty(records)?.as_bytes())?;
+        Ok(())
+    }
+
+    // Returns true if the host part of a host:/path brick refers to this server
+    fn is_local_host(&self, host: &str) -> bool {
+        host == self.hostname
+            || host.starts_with(&format!("{}.", self.hostname))
+            || self.hostname.starts_with(&format!("{}.", host))
+    }
+
+    // Locate the volume and brick that live on this device
+    fn find_brick(&self, device: &Path) -> BynarResult<(VolumeInfo, String, PathBuf)> {
+        let mount_point = block_utils::get_mountpoint(device)?.ok_or_else(|| {
+            BynarError::new(format!(
+                "{} is not mounted.  Unable to find its gluster brick",
+                device.display()
+            ))
+        })?;
+        debug!("{} mounted at {}", device.display(), mount_point.display());
+        for volume in volume_list()? {
+            let info = volume_info(&volume)?;
+            for brick in &info.bricks {
+                let (host, path) = split_brick(brick)?;
+                if self.is_local_host(host) && Path::new(path).starts_with(&mount_point) {
+                    debug!("Found brick {} in volume {}", brick, volume);
+                    let brick = brick.clone();
+                    return Ok((info, brick, mount_point));
+                }
+            }
+        }
+        Err(BynarError::new(format!(
+            "No gluster brick found on {} mounted at {}",
+            device.display(),
+            mount_point.display()
+        )))
+    }
+
+    // Stop the brick process so the disk underneath can be pulled
+    fn stop_brick(&self, volume: &str, brick: &str, simulate: bool) -> BynarResult<()> {
+        debug!("Stopping brick {} with reset-brick start", brick);
+        if simulate {
+            return Ok(());
+        }
+        if let Err(e) = gluster(&["volume", "reset-brick", volume, brick, "start"]) {
+            // Older glusters don't know about reset-brick.  Kill the brick pid
+            warn!("reset-brick start failed: {}.  Killing brick pid", e);
+            let status = parse_volume_status(&gluster(&["volume", "status", volume, brick])?);
+            if let Some(pid) = status.iter().find(|s| s.brick == brick).and_then(|s| s.pid) {
+                debug!("Sending SIGTERM to brick pid {}", pid);
+                kill(Pid::from_raw(pid), Signal::SIGTERM)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Mount the volume and touch it so the surviving bricks mark the
+    // replaced brick as needing a heal.  Without this the self heal daemon
+    // doesn't know the new brick is empty.
+    fn mark_pending_heal(&self, volume: &str, simulate: bool) -> BynarResult<()> {
+        let mnt_dir = TempDir::new("bynar")?;
+        let mnt = mnt_dir.path();
+        let nonexistent = mnt.join(uuid::Uuid::new_v4().to_hyphenated().to_string());
+        debug!("Mounting volume {} at {}", volume, mnt.display());
+        if simulate {
+            return Ok(());
+        }
+        run_cmd(
+            "mount",
+            &[
+                "-t",
+                "glusterfs",
+                &format!("localhost:/{}", volume),
+                &mnt.to_string_lossy(),
+            ],
+        )?;
+        let result = (|| -> BynarResult<()> {
+            debug!("mkdir/rmdir {}", nonexistent.display());
+            std::fs::create_dir(&nonexistent)?;
+            std::fs::remove_dir(&nonexistent)?;
+            let mnt_str = mnt.to_string_lossy();
+            run_cmd(
+                "setfattr",
+                &["-n", "trusted.non-existent-key", "-v", "abc", &mnt_str],
+            )?;
+            run_cmd("setfattr", &["-x", "trusted.non-existent-key", &mnt_str])?;
+            Ok(())
+        })();
+        if let Err(e) = run_cmd("umount", &[&mnt.to_string_lossy()]) {
+            error!("umount {} failed: {}", mnt.display(), e);
+        }
+        result
+    }
+}
+
 impl Backend for GlusterBackend {
-    fn add_disk(&self, _device: &Path, _id: Option<u64>, _simulate: bool) -> BynarResult<()> {
+    fn add_disk(
+        &self,
+        device: &Path,
+        id: Option<u64>,
+        simulate: bool,
+        progress: &dyn Progress,
+    ) -> BynarResult<()> {
+        self.add_disk_in_replica_set(device, id, &[], simulate, progress)
+    }
+
+    fn add_disk_in_replica_set(
+        &self,
+        device: &Path,
+        id: Option<u64>,
+        replica_set: &[String],
+        simulate: bool,
+        progress: &dyn Progress,
+    ) -> BynarResult<()> {
+        if id.is_some() {
+            debug!("Gluster bricks don't have ids.  Ignoring {:?}", id);
+        }
+        let mut records = self.load_brick_records()?;
+        let record = choose_brick_record(&records, device, replica_set)?;
+        info!(
+            "Replacing brick {} of volume {} on {}",
+            record.brick,
+            record.volume,
+            device.display()
+        );
+
+        // Make sure the old brick process isn't still hanging around
+        let status = parse_volume_status(&gluster(&["volume", "status", &record.volume])?);
+        if let Some(pid) = status
+            .iter()
+            .find(|s| s.brick == record.brick)
+            .and_then(|s| s.pid)
+        {
+            debug!("Brick pid {} is still running.  Stopping it", pid);
+            if !simulate {
+                kill(Pid::from_raw(pid), Signal::SIGTERM)?;
+            }
+        }
+
+        // Format and mount the new disk where the old one lived
+        let xfs_options = block_utils::Filesystem::Xfs {
+            stripe_size: None,
+            stripe_width: None,
+            block_size: None,
+            agcount: None,
+            inode_size: Some(512),
+            force: true,
+        };
+        debug!(
+            "Formatting {} with XFS options: {:?}",
+            device.display(),
+            xfs_options
+        );
+        progress.step("format")?;
+        if !simulate {
+            block_utils::format_block_device(device, &xfs_options)?;
+            let info = block_utils::get_device_info(device)?;
+            create_dir_all(&record.mount_point)?;
+            block_utils::mount_device(&info, &record.mount_point)?;
+            add_brick_to_fstab(&info, &record.mount_point)?;
+            let (_, brick_path) = split_brick(&record.brick)?;
+            create_dir_all(brick_path)?;
+        }
+
+        progress.step("mark_pending_heal")?;
+        self.mark_pending_heal(&record.volume, simulate)?;
+
+        // The brick keeps the same host:/path so this is a reset-brick
+        // rather than a replace-brick
+        debug!(
+            "Committing brick {} back into {}",
+            record.brick, record.volume
+        );
+        progress.step("reset_brick")?;
+        if !simulate {
+            gluster(&[
+                "volume",
+                "reset-brick",
+                &record.volume,
+                &record.brick,
+                &record.brick,
+                "commit",
+                "force",
+            ])?;
+            gluster(&["volume", "heal", &record.volume, "full"])?;
+            records.retain(|r| r != &record);
+            self.save_brick_records(&records)?;
+        }
         Ok(())
     }
 
     /// Remove a disk from a cluster
     /// If simulate is passed no action should be taken
-    fn remove_disk(&self, _device: &Path, _simulate: bool) -> BynarResult<()> {
+    fn remove_disk(
+        &self,
+        device: &Path,
+        simulate: bool,
+        progress: &dyn Progress,
+    ) -> BynarResult<()> {
+        let (info, brick, mount_point) = self.find_brick(device)?;
+        let record = BrickRecord {
+            volume: info.name.clone(),
+            brick: brick.clone(),
+            device: device.to_path_buf(),
+            mount_point: mount_point.clone(),
+        };
+        // Record the brick before anything destructive happens so it
+        // can always be put back
+        debug!("Recording removed brick {:?}", record);
+        if !simulate {
+            let mut records = self.load_brick_records()?;
+            records.retain(|r| r.device != device);
+            records.push(record);
+            self.save_brick_records(&records)?;
+        }
+        progress.step("stop_brick")?;
+        self.stop_brick(&info.name, &brick, simulate)?;
+
+        // Wipe the disk
+        debug!("Erasing disk {}", device.display());
+        progress.step("erase")?;
+        if !simulate {
+            if let Err(e) = block_utils::unmount_device(&mount_point) {
+                error!("unmount {} failed: {}", mount_point.display(), e);
+            }
+            match block_utils::erase_block_device(device) {
+                Ok(_) => {
+                    debug!("{} erased", device.display());
+                }
+                Err(e) => {
+                    // At this point the disk is about to be replaced anyways
+                    // so this doesn't really matter
+                    error!("{} failed to erase: {:?}", device.display(), e);
+                }
+            };
+        }
         Ok(())
     }
 
@@ -38,7 +468,282 @@ impl Backend for GlusterBackend {
     /// If simulate is passed then this always returns true
     /// Take any actions needed with this call to figure out if a disk is safe
     /// to remove from the cluster.
-    fn safe_to_remove(&self, _device: &Path, _simulate: bool) -> BynarResult<bool> {
+    fn safe_to_remove(&self, device: &Path, simulate: bool) -> BynarResult<bool> {
+        if simulate {
+            return Ok(true);
+        }
+        let (info, brick, _) = self.find_brick(device)?;
+        if info.replica_count < 2 {
+            debug!("Volume {} has no replicas.  Not safe", info.name);
+            return Ok(false);
+        }
+        let replica_set = info.replica_set(&brick).ok_or_else(|| {
+            BynarError::new(format!("{} not found in volume {}", brick, info.name))
+        })?;
+        debug!("Replica set for {}: {:?}", brick, replica_set);
+
+        // Every other brick holding a copy of this data must be up
+        let status = parse_volume_status(&gluster(&["volume", "status", &info.name])?);
+        for other in replica_set.iter().filter(|b| *b != &brick) {
+            match status.iter().find(|s| &s.brick == other) {
+                Some(s) if s.online => {}
+                _ => {
+                    debug!("Replica {} is offline.  Not safe", other);
+                    return Ok(false);
+                }
+            }
+        }
+
+        // And no files in the replica set can be waiting on a heal
+        let heal = parse_heal_info(&gluster(&["volume", "heal", &info.name, "info"])?);
+        for h in heal.iter().filter(|h| replica_set.contains(&h.brick)) {
+            match (h.brick == brick, h.connected, h.entries) {
+                // The failing brick may well be unreachable
+                (true, false, _) => {}
+                (_, true, Some(0)) => {}
+                _ => {
+                    debug!("{:?} has pending heals.  Not safe", h);
+                    return Ok(false);
+                }
+            }
+        }
         Ok(true)
     }
 }
+
+// The removed brick a new disk replaces.  The record for the device wins
+// unless the client named a replica set it isn't in.  A replacement disk can
+// come up under another name so the replica set is the fallback
+fn choose_brick_record(
+    records: &[BrickRecord],
+    device: &Path,
+    replica_set: &[String],
+) -> BynarResult<BrickRecord> {
+    let by_device = records.iter().find(|r| r.device == device);
+    let in_set: Vec<&BrickRecord> = records
+        .iter()
+        .filter(|r| replica_set.contains(&r.brick))
+        .collect();
+    match (by_device, in_set.as_slice()) {
+        (Some(record), _) if replica_set.is_empty() || replica_set.contains(&record.brick) => {
+            Ok(record.clone())
+        }
+        (_, [record]) => {
+            debug!(
+                "Replacing brick {} of {} from replica set {:?}",
+                record.brick,
+                record.device.display(),
+                replica_set
+            );
+            Ok((*record).clone())
+        }
+        (_, []) => Err(BynarError::new(format!(
+            "No removed brick recorded for {} or replica set {:?}.  Unable to tell which \
+             brick to replace",
+            device.display(),
+            replica_set
+        ))),
+        (_, _) => Err(BynarError::new(format!(
+            "More than one removed brick is in replica set {:?}.  Unable to tell which brick \
+             {} replaces",
+            replica_set,
+            device.display()
+        ))),
+    }
+}
+
+#[test]
+fn test_choose_brick_record() {
+    let record = |brick: &str, device: &str| BrickRecord {
+        volume: "test".into(),
+        brick: brick.into(),
+        device: PathBuf::from(device),
+        mount_point: PathBuf::new(),
+    };
+    let records = vec![
+        record("server1:/mnt/r1/brick", "/dev/sdb"),
+        record("server1:/mnt/r2/brick", "/dev/sdc"),
+    ];
+    let r2_set = vec![
+        "server1:/mnt/r2/brick".to_string(),
+        "server2:/mnt/r2/brick".to_string(),
+        "server3:/mnt/r2/brick".to_string(),
+    ];
+    let chosen = |device: &str, set: &[String]| {
+        choose_brick_record(&records, Path::new(device), set).map(|r| r.brick)
+    };
+    assert_eq!(chosen("/dev/sdc", &[]).unwrap(), "server1:/mnt/r2/brick");
+    assert_eq!(
+        chosen("/dev/sdc", &r2_set).unwrap(),
+        "server1:/mnt/r2/brick"
+    );
+    // The replacement came up as sdd
+    assert_eq!(
+        chosen("/dev/sdd", &r2_set).unwrap(),
+        "server1:/mnt/r2/brick"
+    );
+    // The set overrides a record for the device that isn't in it
+    assert_eq!(
+        chosen("/dev/sdb", &r2_set).unwrap(),
+        "server1:/mnt/r2/brick"
+    );
+    assert!(chosen("/dev/sdd", &[]).is_err());
+    assert!(chosen("/dev/sdd", &["server9:/mnt/r9/brick".to_string()]).is_err());
+}
+
+fn add_brick_to_fstab(device_info: &block_utils::Device, mount_point: &Path) -> BynarResult<()> {
+    let fstab = FsTab::default();
+    let fstab_entry = fstab::FsEntry {
+        fs_spec: format!(
+            "UUID={}",
+            device_info
+                .id
+                .ok_or_else(|| BynarError::from("Formatted brick is missing a filesystem UUID"))?
+                .to_hyphenated()
+                .to_string()
+        ),
+        mountpoint: mount_point.to_path_buf(),
+        vfs_type: device_info.fs_type.to_string(),
+        mount_options: vec!["noatime".into(), "inode64".into()],
+        dump: false,
+        fsck_order: 2,
+    };
+    debug!("Saving Fstab entry {:?}", fstab_entry);
+    if fstab.add_entry(fstab_entry)? {
+        debug!("Fstab entry saved");
+    } else {
+        debug!("Fstab entry was updated");
+    }
+    Ok(())
+}
+
+// Split a host:/path brick into its parts
+fn split_brick(brick: &str) -> BynarResult<(&str, &str)> {
+    let mut parts = brick.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(host), Some(path)) => Ok((host, path)),
+        _ => Err(BynarError::new(format!("Invalid brick name: {}", brick))),
+    }
+}
+
+fn run_cmd(cmd: &str, args: &[&str]) -> BynarResult<String> {
+    debug!("cmd: {} {:?}", cmd, args);
+    let output = Command::new(cmd).args(args).output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        error!(
+            "{} cmd failed: {}. stderr: {}",
+            cmd,
+            String::from_utf8_lossy(&output.stdout),
+            stderr
+        );
+        return Err(BynarError::new(stderr));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn gluster(args: &[&str]) -> BynarResult<String> {
+    run_cmd("gluster", args)
+}
+
+fn volume_list() -> BynarResult<Vec<String>> {
+    let output = gluster(&["volume", "list"])?;
+    Ok(output
+        .lines()
+        .map(|l| l.trim().to_string())
+        .filter(|l| !l.is_empty() && l != "No volumes present in cluster")
+        .collect())
+}
+
+fn volume_info(volume: &str) -> BynarResult<VolumeInfo> {
+    parse_volume_info(&gluster(&["volume", "info", volume])?)
+}
+
+fn parse_volume_info(output: &str) -> BynarResult<VolumeInfo> {
+    let mut name = None;
+    let mut replica_count = 1;
+    let mut bricks = Vec::new();
+    for line in output.lines().map(|l| l.trim()) {
+        if line.starts_with("Volume Name:") {
+            name = Some(line["Volume Name:".len()..].trim().to_string());
+        } else if line.starts_with("Number of Bricks:") {
+            // Formats: "3", "2 x 3 = 6" or "1 x (2 + 1) = 3" for arbiters
+            let count = &line["Number of Bricks:".len()..];
+            if let Some(x) = count.find(" x ") {
+                let replica = count[x + 3..].split('=').next().unwrap_or("");
+                replica_count = replica
+                    .trim_matches(|c: char| c == '(' || c == ')' || c.is_whitespace())
+                    .split('+')
+                    .map(|n| n.trim().parse::<usize>())
+                    .sum::<Result<usize, _>>()?;
+            }
+        } else if line.starts_with("Brick") {
+            // Brick1: host:/path (arbiter)
+            if let Some(idx) = line.find(':') {
+                if line[5..idx].chars().all(|c| c.is_ascii_digit()) {
+                    if let Some(brick) = line[idx + 1..].split_whitespace().next() {
+                        bricks.push(brick.to_string());
+                    }
+                }
+            }
+        }
+    }
+    match name {
+        Some(name) => Ok(VolumeInfo {
+            name,
+            replica_count: replica_count.max(1),
+            bricks,
+        }),
+        None => Err(BynarError::from(
+            "gluster volume info output missing Volume Name",
+        )),
+    }
+}
+
+fn parse_volume_status(output: &str) -> Vec<BrickStatus> {
+    let mut status = Vec::new();
+    let mut pending_brick: Option<String> = None;
+    for line in output.lines() {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        // Long brick names get wrapped and the columns end up on the next line
+        let (brick, columns) = match (pending_brick.take(), parts.first()) {
+            (Some(brick), _) => (brick, &parts[..]),
+            (None, Some(&"Brick")) if parts.len() == 2 => {
+                pending_brick = Some(parts[1].to_string());
+                continue;
+            }
+            (None, Some(&"Brick")) if parts.len() >= 6 => (parts[1].to_string(), &parts[2..]),
+            _ => continue,
+        };
+        // TCP Port, RDMA Port, Online, Pid
+        if columns.len() < 4 {
+            continue;
+        }
+        status.push(BrickStatus {
+            brick,
+            online: columns[2] == "Y",
+            pid: columns[3].parse::<i32>().ok(),
+        });
+    }
+    status
+}
+
+fn parse_heal_info(output: &str) -> Vec<HealInfo> {
+    let mut heal: Vec<HealInfo> = Vec::new();
+    for line in output.lines().map(|l| l.trim()) {
+        if line.starts_with("Brick ") {
+            heal.push(HealInfo {
+                brick: line["Brick ".len()..].trim().to_string(),
+                connected: false,
+                entries: None,
+            });
+        } else if let Some(current) = heal.last_mut() {
+            if line.starts_with("Status:") {
+                current.connected = line["Status:".len()..].trim() == "Connected";
+            } else if line.starts_with("Number of entries:") {
+                current.entries = line["Number of entries:".len()..].trim().parse().ok();
+            }
+        }
+    }
+    heal
+}
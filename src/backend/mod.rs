@@ -2,14 +2,72 @@ pub mod ceph;
 //#[cfg(feature = "gluster")]
 pub mod gluster;
 
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 
 use self::ceph::CephBackend;
 use self::gluster::GlusterBackend;
 use helpers::error::*;
+use log::debug;
+use nix::{convert_ioctl_res, ioc, ioctl_none, request_code_none};
 use serde_derive::*;
 
+/// Reports how far along a long running add or remove is
+pub trait Progress {
+    /// Called as a backend starts each step.  ie: osd_create, lvm, mkfs.
+    /// Returns an error if the operation was cancelled.  The backend should
+    /// stop there and return that error
+    fn step(&self, name: &str) -> BynarResult<()>;
+}
+
+/// For callers that don't track progress
+pub struct NoProgress;
+
+impl Progress for NoProgress {
+    fn step(&self, _name: &str) -> BynarResult<()> {
+        Ok(())
+    }
+}
+
+/// One of the checks behind a safe_to_remove answer
+#[derive(Clone, Debug)]
+pub struct RemovalCheck {
+    /// Short name of the check.  ie: ok_to_stop, pg_states
+    pub name: String,
+    pub passed: bool,
+    /// What was found.  Explains why a check failed
+    pub detail: String,
+}
+
+/// A safe_to_remove answer and the checks that led to it
+#[derive(Clone, Debug)]
+pub struct SafeToRemove {
+    pub safe: bool,
+    pub checks: Vec<RemovalCheck>,
+    /// What removing the disk takes down.  ie: every osd on a split device
+    pub affected: Vec<String>,
+}
+
+/// Where a disk sat in the cluster.  Saved when it's removed so the disk
+/// replacing it can take its place
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Placement {
+    /// ie: the Ceph osd id
+    pub id: u64,
+    /// Crush location.  ie: root=default, host=ceph01
+    pub location: BTreeMap<String, String>,
+    /// ie: hdd, ssd or nvme
+    pub device_class: Option<String>,
+    /// Journal or WAL partition the disk used
+    pub journal: Option<PathBuf>,
+    /// Bluestore block.db partition the disk used
+    pub db: Option<PathBuf>,
+}
+
 /// Different distributed storage clusters have different ways of adding and removing
 /// disks.  This will be consolidated here in trait impl's.
 pub trait Backend {
@@ -18,17 +76,115 @@ pub trait Backend {
     /// For gluster or other services it might be much easier
     /// If simulate is passed no action should be taken
     /// An optional osd_id can be provided to ensure the osd is set to that
-    fn add_disk(&self, device: &Path, id: Option<u64>, simulate: bool) -> BynarResult<()>;
+    fn add_disk(
+        &self,
+        device: &Path,
+        id: Option<u64>,
+        simulate: bool,
+        progress: &dyn Progress,
+    ) -> BynarResult<()>;
+
+    /// Add a disk back into the replica set a client named.  The set's
+    /// entries are host:/path bricks for Gluster.  Backends without replica
+    /// sets ignore it
+    fn add_disk_in_replica_set(
+        &self,
+        device: &Path,
+        id: Option<u64>,
+        _replica_set: &[String],
+        simulate: bool,
+        progress: &dyn Progress,
+    ) -> BynarResult<()> {
+        self.add_disk(device, id, simulate, progress)
+    }
 
     /// Remove a disk from a cluster
     /// If simulate is passed no action should be taken
-    fn remove_disk(&self, device: &Path, simulate: bool) -> BynarResult<()>;
+    fn remove_disk(
+        &self,
+        device: &Path,
+        simulate: bool,
+        progress: &dyn Progress,
+    ) -> BynarResult<()>;
+
+    /// Remove a disk without keeping anything for a replacement to take
+    /// over.  Used when nothing records where the disk sat.  Defaults to
+    /// remove_disk
+    fn purge_disk(
+        &self,
+        device: &Path,
+        simulate: bool,
+        progress: &dyn Progress,
+    ) -> BynarResult<()> {
+        self.remove_disk(device, simulate, progress)
+    }
 
     /// Check if it's safe to remove a disk from a cluster
     /// If simulate is passed then this always returns true
     /// Take any actions needed with this call to figure out if a disk is safe
     /// to remove from the cluster.
     fn safe_to_remove(&self, device: &Path, simulate: bool) -> BynarResult<bool>;
+
+    /// Same as safe_to_remove but also says why.  Backends that can't
+    /// explain their answer get this default which has no checks
+    fn check_safe_to_remove(&self, device: &Path, simulate: bool) -> BynarResult<SafeToRemove> {
+        Ok(SafeToRemove {
+            safe: self.safe_to_remove(device, simulate)?,
+            checks: vec![],
+            affected: vec![],
+        })
+    }
+
+    /// Where a disk sits in the cluster.  Called before a disk is removed.
+    /// Backends that can't put a replacement back in the same place
+    /// return None
+    fn placement(&self, _device: &Path) -> BynarResult<Option<Placement>> {
+        Ok(None)
+    }
+
+    /// Add a disk in place of one that was removed.  Defaults to an add
+    /// that reuses the old id
+    fn replace_disk(
+        &self,
+        device: &Path,
+        placement: &Placement,
+        simulate: bool,
+        progress: &dyn Progress,
+    ) -> BynarResult<()> {
+        self.add_disk(device, Some(placement.id), simulate, progress)
+    }
+
+    /// Adds that stopped part way through and can be picked up again.
+    /// ie: a Ceph crush weight ramp cut short by a restart
+    fn interrupted_adds(&self) -> BynarResult<Vec<PathBuf>> {
+        Ok(vec![])
+    }
+
+    /// Finish an add returned by interrupted_adds
+    fn resume_add(&self, device: &Path, _progress: &dyn Progress) -> BynarResult<()> {
+        Err(BynarError::new(format!(
+            "No interrupted add to resume on {}",
+            device.display()
+        )))
+    }
+
+    /// Bring up disks that need Bynar's help to start.  Called when
+    /// disk-manager starts.  ie: Ceph osds encrypted with keys kept in Vault
+    fn activate(&self) -> BynarResult<()> {
+        Ok(())
+    }
+
+    /// How often reclaim should run.  None if the backend has nothing to
+    /// reclaim or reclaiming is turned off
+    fn reclaim_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Free space that removed disks left behind.  ie: Ceph journal
+    /// partitions that no osd uses any more
+    fn reclaim(&self) -> BynarResult<()> {
+        Ok(())
+    }
 }
 
 /// The supported backend types
@@ -59,8 +215,35 @@ pub fn load_backend(
     let backend: Box<dyn Backend> = match *backend_type {
         BackendType::Ceph => Box::new(CephBackend::new(config_dir)?),
         //#[cfg(feature = "gluster")]
-        BackendType::Gluster => Box::new(GlusterBackend {}),
+        BackendType::Gluster => Box::new(GlusterBackend::new(config_dir)?),
     };
 
     Ok(backend)
 }
+
+// Linux specific ioctl to update the partition table cache.
+pub(crate) fn update_partition_cache(device: &Path) -> BynarResult<()> {
+    debug!(
+        "Requesting kernel to refresh partition cache for {} ",
+        device.display()
+    );
+    let device = OpenOptions::new().read(true).write(true).open(device)?;
+    let ret = unsafe { blkrrpart(device.as_raw_fd()) }?;
+    if ret != 0 {
+        Err(BynarError::new(format!(
+            "BLKRRPART ioctl failed with return code: {}",
+            ret,
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+// This macro from the nix crate crates an ioctl to call the linux kernel
+// and ask it to update its internal partition cache. Without this the
+// partitions don't show up after being created on the disks which then
+// breaks parts of bynar later.
+ioctl_none! {
+    /// Linux BLKRRPART ioctl to update partition tables.  Defined in linux/fs.h
+    blkrrpart, 0x12, 95
+}
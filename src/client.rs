@@ -1,24 +1,35 @@
 /// This is built into a separate binary called bynar-client
 //mod disk_manager;
+mod in_progress;
+mod test_disk;
+
 use std::fs::{read_to_string, File};
+use std::io::Write;
 use std::path::Path;
+use std::process::{Command, Stdio};
 use std::str::FromStr;
+use std::time::Duration;
 
 //use disk_manager::disk_manager;
-use api::service::Disk;
+use api::service::{Disk, Job};
 use clap::{crate_authors, crate_version, App, Arg, ArgMatches, SubCommand};
-use helpers::error::BynarResult;
+use helpers::error::{BynarError, BynarResult};
+use helpers::{migrations, ConfigSettings};
 use hostname::get_hostname;
 use log::{error, info,trace};
+use postgres::params::{ConnectParams, Host};
+use postgres::{Connection, TlsMode};
 use simplelog::{CombinedLogger, Config, TermLogger, WriteLogger};
 use zmq::Socket;
 /*
     CLI client to call functions over RPC
 */
 
-fn add_disk(s: &mut Socket, path: &Path, id: Option<u64>, simulate: bool) -> BynarResult<()> {
-    helpers::add_disk_request(s, path, id, simulate)?;
-    Ok(())
+// How often --wait checks on a job
+const JOB_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+fn add_disk(s: &mut Socket, path: &Path, id: Option<u64>, simulate: bool) -> BynarResult<String> {
+    helpers::add_disk_request(s, path, id, simulate)
 }
 
 fn list_disks(s: &mut Socket) -> BynarResult<Vec<Disk>> {
@@ -28,8 +39,58 @@ fn list_disks(s: &mut Socket) -> BynarResult<Vec<Disk>> {
     Ok(disks)
 }
 
-fn remove_disk(s: &mut Socket, path: &Path, id: Option<u64>, simulate: bool) -> BynarResult<()> {
-    helpers::remove_disk_request(s, path, id, simulate)?;
+fn remove_disk(
+    s: &mut Socket,
+    path: &Path,
+    id: Option<u64>,
+    simulate: bool,
+) -> BynarResult<String> {
+    helpers::remove_disk_request(s, path, id, simulate)
+}
+
+fn print_job(job: &Job) {
+    println!(
+        "{} {:?} {} {:?} step: {} started: {} updated: {}{}",
+        job.get_job_id(),
+        job.get_op(),
+        job.get_disk(),
+        job.get_state(),
+        job.get_step(),
+        job.get_start_time(),
+        job.get_update_time(),
+        if job.has_error_msg() {
+            format!(" error: {}", job.get_error_msg())
+        } else {
+            String::new()
+        }
+    );
+}
+
+// Print the job id or wait for the job to finish if --wait was given
+fn handle_job_started(s: &mut Socket, job_id: &str, matches: &ArgMatches<'_>) -> BynarResult<()> {
+    println!("Started job {}", job_id);
+    if matches.is_present("wait") {
+        let job = helpers::wait_for_job(s, job_id, JOB_POLL_INTERVAL)?;
+        print_job(&job);
+    }
+    Ok(())
+}
+
+fn handle_job(s: &mut Socket, matches: &ArgMatches<'_>) -> BynarResult<()> {
+    if let Some(ref status_matches) = matches.subcommand_matches("status") {
+        let job = helpers::get_job_status_request(s, status_matches.value_of("job_id").unwrap())?;
+        print_job(&job);
+    }
+    if matches.subcommand_matches("list").is_some() {
+        for job in helpers::list_jobs_request(s)? {
+            print_job(&job);
+        }
+    }
+    if let Some(ref cancel_matches) = matches.subcommand_matches("cancel") {
+        let job_id = cancel_matches.value_of("job_id").unwrap();
+        helpers::cancel_job_request(s, job_id)?;
+        println!("Cancelled job {}", job_id);
+    }
     Ok(())
 }
 
@@ -44,7 +105,7 @@ fn handle_add_disk(s: &mut Socket, matches: &ArgMatches<'_>) {
         Some(s) => bool::from_str(&s).unwrap(),
         None => false,
     };
-    match add_disk(s, &p, id, simulate) {
+    match add_disk(s, &p, id, simulate).and_then(|job_id| handle_job_started(s, &job_id, matches)) {
         Ok(_) => {
             println!("Adding disk successful");
         }
@@ -54,6 +115,26 @@ fn handle_add_disk(s: &mut Socket, matches: &ArgMatches<'_>) {
     };
 }
 
+fn handle_add_partition(s: &mut Socket, matches: &ArgMatches<'_>) {
+    let p = Path::new(matches.value_of("path").unwrap());
+    // Validated by clap
+    let start = u64::from_str(matches.value_of("start").unwrap()).unwrap();
+    let end = u64::from_str(matches.value_of("end").unwrap()).unwrap();
+    let simulate = match matches.value_of("simulate") {
+        Some(s) => bool::from_str(&s).unwrap(),
+        None => false,
+    };
+    info!("Adding partition {}-{} to {}", start, end, p.display());
+    match helpers::add_partition_request(s, &p, start, end, matches.value_of("name"), simulate) {
+        Ok(partition) => {
+            println!("Adding partition successful: {:?}", partition);
+        }
+        Err(e) => {
+            println!("Adding partition failed: {}", e);
+        }
+    };
+}
+
 fn handle_list_disks(s: &mut Socket) {
     info!("Listing disks");
     match list_disks(s) {
@@ -87,7 +168,9 @@ fn handle_remove_disk(s: &mut Socket, matches: &ArgMatches<'_>) {
         Some(s) => bool::from_str(&s).unwrap(),
         None => false,
     };
-    match remove_disk(s, &p, id, simulate) {
+    match remove_disk(s, &p, id, simulate)
+        .and_then(|job_id| handle_job_started(s, &job_id, matches))
+    {
         Ok(_) => {
             println!("Removing disk successful");
         }
@@ -97,6 +180,85 @@ fn handle_remove_disk(s: &mut Socket, matches: &ArgMatches<'_>) {
     }
 }
 
+// Connects straight to the database in bynar.json.  No disk-manager needed
+fn handle_db_migrate(config_dir: &Path, matches: &ArgMatches<'_>) -> BynarResult<()> {
+    let config: ConfigSettings = helpers::load_config(config_dir, "bynar.json")?;
+    let db = &config.database;
+    let connection_params = ConnectParams::builder()
+        .user(&db.username, db.password.as_deref())
+        .port(db.port)
+        .database(&db.dbname)
+        .build(Host::Tcp(db.endpoint.to_string()));
+    let conn = Connection::connect(connection_params, TlsMode::None)?;
+    println!(
+        "Database revision: {}. Latest known revision: {}",
+        migrations::current_revision(&conn)?,
+        migrations::latest_revision()
+    );
+    if matches.is_present("dry_run") {
+        let pending = migrations::pending_migrations(&conn)?;
+        if pending.is_empty() {
+            println!("No pending migrations");
+        }
+        for m in pending {
+            println!("Pending: {} {}", m.revision, m.name);
+        }
+    } else {
+        let applied = migrations::run_migrations(&conn)?;
+        if applied.is_empty() {
+            println!("No pending migrations");
+        }
+        for revision in applied {
+            println!("Applied: {}", revision);
+        }
+    }
+    Ok(())
+}
+
+// Prints the disk state machine.  A disk's saved state and last run are
+// looked up in the database in bynar.json to highlight them
+fn handle_state_graph(config_dir: &Path, matches: &ArgMatches<'_>) -> BynarResult<()> {
+    let (state, trace) = match matches.value_of("device") {
+        Some(device) => {
+            let config: ConfigSettings = helpers::load_config(config_dir, "bynar.json")?;
+            let pool = in_progress::create_db_connection_pool(&config.database)?;
+            let hostname = match matches.value_of("hostname") {
+                Some(hostname) => hostname.to_string(),
+                None => get_hostname().unwrap_or_default(),
+            };
+            match in_progress::get_state_and_trace(&pool, &hostname, Path::new(device))? {
+                Some((state, trace)) => (Some(state), trace),
+                None => {
+                    return Err(BynarError::new(format!(
+                        "{} on {} isn't in the database",
+                        device, hostname
+                    )));
+                }
+            }
+        }
+        None => (None, vec![]),
+    };
+    let dot = test_disk::state_graph(state, &trace);
+    if matches.value_of("format") == Some("svg") {
+        // Needs graphviz installed
+        let mut child = Command::new("dot")
+            .arg("-Tsvg")
+            .stdin(Stdio::piped())
+            .spawn()?;
+        // stdin is dropped after the write so dot knows the graph is done
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(dot.as_bytes())?;
+        }
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(BynarError::new(format!("dot -Tsvg failed: {}", status)));
+        }
+    } else {
+        print!("{}", dot);
+    }
+    Ok(())
+}
+
 fn get_cli_args(default_server_key: &str) -> ArgMatches<'_> {
     App::new("Ceph Disk Manager Client")
         .version(crate_version!())
@@ -118,6 +280,15 @@ fn get_cli_args(default_server_key: &str) -> ArgMatches<'_> {
                 .short("p")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("endpoint")
+                .help(
+                    "zmq endpoint to call instead of --host and -p.  ie: ipc:///run/bynar/dm.sock",
+                )
+                .long("endpoint")
+                .required(false)
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("server_key")
                 .default_value(default_server_key)
@@ -126,6 +297,28 @@ fn get_cli_args(default_server_key: &str) -> ArgMatches<'_> {
                 .long("serverkey")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("configdir")
+                .default_value("/etc/bynar")
+                .help("The directory where bynar.json can be found")
+                .long("configdir")
+                .takes_value(true)
+                .required(false),
+        )
+        .subcommand(
+            SubCommand::with_name("db")
+                .about("Manage the bynar database")
+                .subcommand(
+                    SubCommand::with_name("migrate")
+                        .about("Apply pending database schema migrations")
+                        .arg(
+                            Arg::with_name("dry_run")
+                                .help("List the pending migrations without applying them")
+                                .long("dry-run")
+                                .required(false),
+                        ),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("add")
                 .about("Add a disk into the cluster")
@@ -154,9 +347,119 @@ fn get_cli_args(default_server_key: &str) -> ArgMatches<'_> {
                         .possible_values(&["false", "true"])
                         .required(false)
                         .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("wait")
+                        .help("Wait for the job to finish")
+                        .long("wait")
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("add-partition")
+                .about("Create a GPT partition on a disk")
+                .arg(
+                    Arg::with_name("path")
+                        .help("The disk to partition: Ex: /dev/sda")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("start")
+                        .help("First LBA of the partition.  Must be 1MiB aligned")
+                        .long("start")
+                        .required(true)
+                        .takes_value(true)
+                        .validator(|v| match u64::from_str(&v) {
+                            Ok(_) => Ok(()),
+                            Err(_) => Err("start must be a valid u64".to_string()),
+                        }),
+                )
+                .arg(
+                    Arg::with_name("end")
+                        .help("Last LBA of the partition")
+                        .long("end")
+                        .required(true)
+                        .takes_value(true)
+                        .validator(|v| match u64::from_str(&v) {
+                            Ok(_) => Ok(()),
+                            Err(_) => Err("end must be a valid u64".to_string()),
+                        }),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .help("An optional partition name")
+                        .long("name")
+                        .required(false)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("simulate")
+                        .default_value("false")
+                        .help("Only check the partition could be created")
+                        .long("simulate")
+                        .possible_values(&["false", "true"])
+                        .required(false)
+                        .takes_value(true),
                 ),
         )
         .subcommand(SubCommand::with_name("list").about("List all disks on a server"))
+        .subcommand(
+            SubCommand::with_name("state-graph")
+                .about("Print the disk state machine as a graph")
+                .arg(
+                    Arg::with_name("device")
+                        .help(
+                            "Highlight this disk's state and the path its last check took. \
+                             ie: /dev/sdb",
+                        )
+                        .long("device")
+                        .required(false)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("hostname")
+                        .help("Host the disk is on.  Defaults to this host")
+                        .long("hostname")
+                        .requires("device")
+                        .required(false)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .default_value("dot")
+                        .help("svg needs graphviz's dot installed")
+                        .long("format")
+                        .possible_values(&["dot", "svg"])
+                        .required(false)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("job")
+                .about("Check on or cancel add and remove jobs")
+                .subcommand(
+                    SubCommand::with_name("status")
+                        .about("Show the progress of a job")
+                        .arg(
+                            Arg::with_name("job_id")
+                                .help("The job id printed by add or remove")
+                                .required(true)
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(SubCommand::with_name("list").about("List the jobs on a server"))
+                .subcommand(
+                    SubCommand::with_name("cancel")
+                        .about("Cancel a job.  Running jobs stop at their next step")
+                        .arg(
+                            Arg::with_name("job_id")
+                                .help("The job id printed by add or remove")
+                                .required(true)
+                                .takes_value(true),
+                        ),
+                ),
+        )
         .subcommand(SubCommand::with_name("get_jira_tickets").about("get all tickets created"))
         .subcommand(
             SubCommand::with_name("remove")
@@ -186,6 +489,12 @@ fn get_cli_args(default_server_key: &str) -> ArgMatches<'_> {
                         .possible_values(&["false", "true"])
                         .required(false)
                         .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("wait")
+                        .help("Wait for the job to finish")
+                        .long("wait")
+                        .required(false),
                 ),
         )
         .arg(
@@ -219,9 +528,29 @@ fn main() {
         ),
     ]);
     info!("Starting up");
+    if let Some(ref db_matches) = matches.subcommand_matches("db") {
+        let config_dir = Path::new(matches.value_of("configdir").unwrap());
+        if let Some(ref migrate_matches) = db_matches.subcommand_matches("migrate") {
+            if let Err(e) = handle_db_migrate(config_dir, migrate_matches) {
+                println!("Database migration failed: {}", e);
+            }
+        }
+        return;
+    }
+    if let Some(ref graph_matches) = matches.subcommand_matches("state-graph") {
+        let config_dir = Path::new(matches.value_of("configdir").unwrap());
+        if let Err(e) = handle_state_graph(config_dir, graph_matches) {
+            println!("State graph failed: {}", e);
+        }
+        return;
+    }
     let server_pubkey = read_to_string(matches.value_of("server_key").unwrap()).unwrap();
 
-    let mut s = match helpers::connect(host, port, &server_pubkey) {
+    let endpoint = match matches.value_of("endpoint") {
+        Some(endpoint) => endpoint.to_string(),
+        None => format!("tcp://{}:{}", host, port),
+    };
+    let mut s = match helpers::connect_endpoint(&endpoint, &server_pubkey) {
         Ok(s) => s,
         Err(e) => {
             error!("Error connecting to socket: {:?}", e);
@@ -231,12 +560,20 @@ fn main() {
     if let Some(ref matches) = matches.subcommand_matches("add") {
         handle_add_disk(&mut s, matches);
     }
+    if let Some(ref matches) = matches.subcommand_matches("add-partition") {
+        handle_add_partition(&mut s, matches);
+    }
     if matches.subcommand_matches("list").is_some() {
         handle_list_disks(&mut s);
     }
     if let Some(ref matches) = matches.subcommand_matches("remove") {
         handle_remove_disk(&mut s, matches);
     }
+    if let Some(ref matches) = matches.subcommand_matches("job") {
+        if let Err(e) = handle_job(&mut s, matches) {
+            println!("Job request failed: {}", e);
+        }
+    }
     if let Some(ref matches) = matches.subcommand_matches("get_jira_tickets") {
         handle_jira_tickets(&mut s);
     }
@@ -0,0 +1,2 @@
+--disk serial number if we can find it
+ALTER TABLE devices ADD COLUMN IF NOT EXISTS serial_number VARCHAR;
@@ -0,0 +1 @@
+UPDATE operation_types SET op_name='waiting_for_replacement' WHERE op_name='waitingforreplacement';
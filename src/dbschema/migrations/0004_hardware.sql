@@ -0,0 +1,29 @@
+-- Added during redfish support which monitors different hardwares other
+-- than just disks.
+CREATE TABLE IF NOT EXISTS hardware_types (
+    hardware_id SERIAL NOT NULL UNIQUE,
+    hardware_type VARCHAR (256) PRIMARY KEY NOT NULL
+    );
+INSERT INTO hardware_types (hardware_type) VALUES ('array_controller') ON CONFLICT DO NOTHING;
+INSERT INTO hardware_types (hardware_type) VALUES ('disk') ON CONFLICT DO NOTHING;
+INSERT INTO hardware_types (hardware_type) VALUES ('fan') ON CONFLICT DO NOTHING;
+INSERT INTO hardware_types (hardware_type) VALUES ('ilo_manager') ON CONFLICT DO NOTHING;
+INSERT INTO hardware_types (hardware_type) VALUES ('power_supply') ON CONFLICT DO NOTHING;
+INSERT INTO hardware_types (hardware_type) VALUES ('storage_controller') ON CONFLICT DO NOTHING;
+
+-- Rename devices table to hardware. Add a type column to it
+-- to indicate hardware_types. Make device_name and detail_id unique instead
+-- of device_path. Add fields needed for other hardware.
+-- Skipped if bynar_stats.sql already renamed it by hand.
+DO $$
+BEGIN
+    IF to_regclass('public.devices') IS NOT NULL THEN
+        ALTER TABLE devices ADD COLUMN IF NOT EXISTS hardware_type INTEGER REFERENCES hardware_types(hardware_id);
+        ALTER TABLE devices DROP CONSTRAINT IF EXISTS devices_device_path_detail_id_key;
+        ALTER TABLE devices ALTER COLUMN device_path DROP NOT NULL;
+        ALTER TABLE devices DROP CONSTRAINT IF EXISTS device_name_detail_id;
+        ALTER TABLE devices ADD CONSTRAINT device_name_detail_id UNIQUE(device_name, detail_id);
+        ALTER TABLE devices RENAME TO hardware;
+    END IF;
+END
+$$;
@@ -0,0 +1,11 @@
+-- Disk-manager add and remove jobs are tracked as operation details.
+-- Jobs can run against the same device more than once per operation so
+-- the primary key moves to operation_detail_id.  Other details keep one
+-- row per (operation_id, type_id)
+ALTER TABLE operation_details DROP CONSTRAINT IF EXISTS operation_details_pkey;
+ALTER TABLE operation_details ADD PRIMARY KEY (operation_detail_id);
+ALTER TABLE operation_details ADD COLUMN IF NOT EXISTS job_id VARCHAR UNIQUE;
+ALTER TABLE operation_details ADD COLUMN IF NOT EXISTS step VARCHAR; -- step the job is on
+ALTER TABLE operation_details ADD COLUMN IF NOT EXISTS error_msg VARCHAR; -- why a job failed
+CREATE UNIQUE INDEX IF NOT EXISTS operation_details_operation_type
+    ON operation_details (operation_id, type_id) WHERE job_id IS NULL;
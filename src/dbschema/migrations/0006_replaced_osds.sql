@@ -0,0 +1,11 @@
+-- Where a removed osd sat in the cluster.  The disk replacing it reuses
+-- the osd id, crush location, device class and journal recorded here.
+-- One row per disk slot.  It's removed once the replacement is added
+CREATE TABLE IF NOT EXISTS replaced_osds (
+    device_id INTEGER PRIMARY KEY REFERENCES hardware(device_id) ON DELETE CASCADE,
+    osd_id INTEGER NOT NULL,
+    crush_location VARCHAR, -- bucket=name pairs.  ie: root=default host=ceph01
+    device_class VARCHAR, -- ie: hdd, ssd
+    journal VARCHAR, -- journal or WAL partition.  NULL if collocated
+    removed_time TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+    );
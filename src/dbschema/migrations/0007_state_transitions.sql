@@ -0,0 +1,12 @@
+-- The transitions the disk state machine tried during its last run on a
+-- device.  Replaced every run.  Shows how a disk ended up in its state
+CREATE TABLE IF NOT EXISTS state_transitions (
+    device_id INTEGER NOT NULL REFERENCES hardware(device_id) ON DELETE CASCADE,
+    step INTEGER NOT NULL, -- order the transitions were tried in
+    from_state VARCHAR NOT NULL,
+    to_state VARCHAR NOT NULL,
+    label VARCHAR NOT NULL, -- the transition.  ie: Scan, Eval
+    result VARCHAR NOT NULL, -- state the transition returned.  fail if it didn't apply
+    duration_ms INTEGER NOT NULL,
+    PRIMARY KEY (device_id, step)
+    );
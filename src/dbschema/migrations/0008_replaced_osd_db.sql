@@ -0,0 +1,3 @@
+-- The block.db partition of a removed bluestore osd.  NULL if it was
+-- collocated or the osd was filestore
+ALTER TABLE replaced_osds ADD COLUMN IF NOT EXISTS db VARCHAR;
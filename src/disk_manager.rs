@@ -1,22 +1,29 @@
 use serde_derive::*;
 
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::{create_dir, File};
 use std::io::{Error, ErrorKind, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
 use api::service::{
-    Disk, DiskType, Disks, JiraInfo, Op, OpBoolResult, OpJiraTicketsResult, OpResult, Partition,
-    PartitionInfo, ResultType,
+    Disk, DiskType, Disks, ErrorCode, JiraInfo, Op, OpBoolResult, OpJiraTicketsResult, OpJobResult,
+    OpJobsResult, OpPartitionResult, OpResult, Operation, Partition, PartitionInfo, RemovalCheck,
+    ResultType,
 };
 mod backend;
 mod in_progress;
+mod jobs;
+mod support_tickets;
 mod test_disk;
 
-use crate::backend::BackendType;
+use crate::backend::{update_partition_cache, Backend, BackendType};
 use crate::in_progress::create_db_connection_pool;
+use crate::jobs::{JobDb, Jobs};
+use crate::support_tickets::{load_ticket_backend, TicketBackend};
 use block_utils::{Device, MediaType};
 use clap::{crate_authors, crate_version, App, Arg};
 use gpt::{disk, header::read_header, partition::read_partitions};
@@ -27,6 +34,8 @@ use log::{debug, error, info, trace, warn};
 use protobuf::parse_from_bytes;
 use protobuf::Message as ProtobufMsg;
 use protobuf::RepeatedField;
+use r2d2::Pool;
+use r2d2_postgres::PostgresConnectionManager as ConnectionManager;
 use simplelog::{CombinedLogger, Config, SharedLogger, TermLogger, WriteLogger};
 use zmq::{Message, Socket};
 
@@ -35,6 +44,13 @@ struct DiskManagerConfig {
     backend: BackendType,
     vault_token: Option<String>,
     vault_endpoint: Option<String>,
+    /// How many requests can be handled at the same time
+    #[serde(default = "default_worker_threads")]
+    worker_threads: usize,
+    /// zmq endpoints to listen on.  ie: tcp://10.0.0.1:5555 or
+    /// ipc:///run/bynar/disk-manager.sock.  Overrides --listenaddress and --port
+    #[serde(default)]
+    listen_endpoints: Vec<String>,
 }
 
 fn convert_media_to_disk_type(m: &MediaType) -> DiskType {
@@ -92,199 +108,597 @@ fn setup_curve(s: &mut Socket, config_dir: &Path, vault: bool) -> BynarResult<()
     Ok(())
 }
 
+// The broker hands requests to the workers through this
+const WORKER_ENDPOINT: &str = "inproc://workers";
+
+// Requests that can wait for a worker before new ones are turned away
+const MAX_WAITING_REQUESTS: usize = 100;
+
+// How long reclaim waits to try again when the backend can't be loaded
+const RECLAIM_RETRY_SECS: u64 = 600;
+
+fn default_worker_threads() -> usize {
+    4
+}
+
+/// Devices with an add or remove in flight.  Shared by every worker so two
+/// operations can't touch the same disk at once
+type DeviceLocks = Arc<Mutex<HashSet<PathBuf>>>;
+
+/// Marks a device busy until dropped
+struct DeviceGuard {
+    locks: DeviceLocks,
+    device: PathBuf,
+}
+
+impl DeviceGuard {
+    fn lock(locks: &DeviceLocks, device: &Path) -> BynarResult<DeviceGuard> {
+        // /dev/disk/by-id/... and /dev/sdb should be the same lock
+        let device = device
+            .canonicalize()
+            .unwrap_or_else(|_| device.to_path_buf());
+        // Nothing panics while holding this so a poisoned set is still sane
+        let mut busy = locks.lock().unwrap_or_else(|e| e.into_inner());
+        if !busy.insert(device.clone()) {
+            return Err(BynarError::new(format!(
+                "{} is busy with another operation",
+                device.display()
+            )));
+        }
+        Ok(DeviceGuard {
+            locks: locks.clone(),
+            device,
+        })
+    }
+}
+
+impl Drop for DeviceGuard {
+    fn drop(&mut self) {
+        let mut busy = self.locks.lock().unwrap_or_else(|e| e.into_inner());
+        busy.remove(&self.device);
+    }
+}
+
+/// The backend is loaded on first use and kept for the life of the thread.
+/// Loading can fail if the cluster is down so it's retried on the next request
+struct CachedBackend<'a> {
+    backend_type: &'a BackendType,
+    config_dir: &'a Path,
+    backend: Option<Box<dyn Backend>>,
+}
+
+impl<'a> CachedBackend<'a> {
+    fn new(backend_type: &'a BackendType, config_dir: &'a Path) -> Self {
+        CachedBackend {
+            backend_type,
+            config_dir,
+            backend: None,
+        }
+    }
+
+    fn get(&mut self) -> BynarResult<&dyn Backend> {
+        if self.backend.is_none() {
+            debug!("Loading {:?} backend", self.backend_type);
+            self.backend = Some(backend::load_backend(
+                self.backend_type,
+                Some(self.config_dir),
+            )?);
+        }
+        Ok(self.backend.as_ref().unwrap().as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_partition, error_reply, DeviceGuard, DeviceLocks};
+    use api::service::{Disks, ErrorCode, Op, OpBoolResult, OpResult, ResultType};
+    use protobuf::parse_from_bytes;
+    use std::path::Path;
+
+    #[test]
+    fn test_check_partition() {
+        // A 1GiB disk with 512 byte sectors and one partition at 1MiB-2MiB
+        let usable = (34, 2_097_118);
+        let existing = [(2048, 4095)];
+        assert!(check_partition(4096, 8191, 512, usable, &existing).is_ok());
+        // Overlaps the end of the existing partition
+        assert!(check_partition(4094, 8191, 512, usable, &existing).is_err());
+        // Overlaps the start
+        assert!(check_partition(0, 2048, 512, usable, &existing).is_err());
+        // Not 1MiB aligned
+        assert!(check_partition(5000, 8191, 512, usable, &existing).is_err());
+        // 1MiB is 256 sectors on 4k disks
+        assert!(check_partition(4352, 8191, 4096, usable, &existing).is_ok());
+        // Past the backup header
+        assert!(check_partition(2_095_104, 2_097_151, 512, usable, &existing).is_err());
+        // Backwards
+        assert!(check_partition(8192, 4096, 512, usable, &existing).is_err());
+    }
+
+    #[test]
+    fn test_error_reply() {
+        // Each reply has to parse as the type the client expects
+        let reply = error_reply(Some(Op::List), ErrorCode::INTERNAL, "no udev");
+        let disks = parse_from_bytes::<Disks>(&reply).unwrap();
+        assert_eq!(disks.get_result(), ResultType::ERR);
+        assert_eq!(disks.get_error_msg(), "no udev");
+
+        let reply = error_reply(Some(Op::SafeToRemove), ErrorCode::MISSING_FIELD, "disk");
+        let result = parse_from_bytes::<OpBoolResult>(&reply).unwrap();
+        assert_eq!(result.get_result(), ResultType::ERR);
+        assert_eq!(result.get_error_code(), ErrorCode::MISSING_FIELD);
+
+        let reply = error_reply(None, ErrorCode::INVALID_REQUEST, "garbage");
+        let result = parse_from_bytes::<OpResult>(&reply).unwrap();
+        assert_eq!(result.get_error_code(), ErrorCode::INVALID_REQUEST);
+    }
+
+    #[test]
+    fn test_device_guard() {
+        let locks = DeviceLocks::default();
+        let sdb = DeviceGuard::lock(&locks, Path::new("/dev/fake_sdb")).unwrap();
+        assert!(DeviceGuard::lock(&locks, Path::new("/dev/fake_sdb")).is_err());
+        // Other devices aren't affected
+        let sdc = DeviceGuard::lock(&locks, Path::new("/dev/fake_sdc")).unwrap();
+        drop(sdb);
+        assert!(DeviceGuard::lock(&locks, Path::new("/dev/fake_sdb")).is_ok());
+        drop(sdc);
+        assert!(locks.lock().unwrap().is_empty());
+    }
+}
+
 /*
-Server that manages disks
+Server that manages disks.  A ROUTER socket faces the clients and hands
+requests to a pool of worker threads so a long add or remove doesn't block
+everyone else
 */
 fn listen(
     backend_type: &backend::BackendType,
     config_dir: &Path,
-    listen_address: &str,
+    endpoints: &[String],
     vault: bool,
+    worker_threads: usize,
 ) -> BynarResult<()> {
     debug!("Starting zmq listener with version({:?})", zmq::version());
     let context = zmq::Context::new();
-    let mut responder = context.socket(zmq::REP)?;
+    let mut frontend = context.socket(zmq::ROUTER)?;
+    let workers = context.socket(zmq::ROUTER)?;
 
-    debug!("Listening on tcp://{}:5555", listen_address);
     // Fail to start if this fails
-    setup_curve(&mut responder, config_dir, vault)?;
-    assert!(responder
-        .bind(&format!("tcp://{}:5555", listen_address))
-        .is_ok());
-
-    loop {
-        let msg = responder.recv_bytes(0)?;
-        debug!("Got msg len: {}", msg.len());
-        trace!("Parsing msg {:?} as hex", msg);
-        let operation = match parse_from_bytes::<api::service::Operation>(&msg) {
-            Ok(bytes) => bytes,
-            Err(e) => {
-                error!("Failed to parse_from_bytes {:?}.  Ignoring request", e);
-                continue;
-            }
-        };
+    setup_curve(&mut frontend, config_dir, vault)?;
+    for endpoint in endpoints {
+        info!("Listening on {}", endpoint);
+        frontend
+            .bind(endpoint)
+            .map_err(|e| BynarError::new(format!("Unable to listen on {}: {}", endpoint, e)))?;
+    }
+    // inproc needs to be bound before the workers connect
+    workers.bind(WORKER_ENDPOINT)?;
 
-        debug!("Operation requested: {:?}", operation.get_Op_type());
-        match operation.get_Op_type() {
-            Op::Add => {
-                let id = if operation.has_osd_id() {
-                    Some(operation.get_osd_id())
-                } else {
-                    None
-                };
-                if !operation.has_disk() {
-                    error!("Add operation must include disk field.  Ignoring request");
-                    // We still have to respond with an error message
-                    let mut result = OpResult::new();
-                    result.set_result(ResultType::ERR);
-                    result.set_error_msg(
-                        "missing operation field in protocol. Ignoring request".to_string(),
-                    );
-
-                    let _ = respond_to_client(&result, &mut responder);
-                    continue;
-                }
-                match add_disk(
-                    &mut responder,
-                    operation.get_disk(),
-                    &backend_type,
+    let locks = DeviceLocks::default();
+    let (jobs, queue) = Jobs::new(open_job_db(config_dir));
+    let queue = Arc::new(Mutex::new(queue));
+    let tickets = Arc::new(open_ticket_source(config_dir));
+    for id in 0..worker_threads.max(1) {
+        let worker_context = context.clone();
+        let worker_backend_type = backend_type.clone();
+        let worker_config_dir = config_dir.to_path_buf();
+        let worker_locks = locks.clone();
+        let worker_jobs = jobs.clone();
+        let worker_tickets = tickets.clone();
+        thread::Builder::new()
+            .name(format!("worker-{}", id))
+            .spawn(move || {
+                if let Err(e) = worker(
                     id,
-                    config_dir,
+                    &worker_context,
+                    &worker_backend_type,
+                    &worker_config_dir,
+                    &worker_locks,
+                    &worker_jobs,
+                    (*worker_tickets).as_ref(),
                 ) {
-                    Ok(_) => {
-                        info!("Add disk finished");
-                    }
-                    Err(e) => {
-                        error!("Add disk error: {:?}", e);
-                    }
-                };
-            }
-            Op::AddPartition => {
-                //
-            }
-            Op::List => {
-                match list_disks(&mut responder) {
-                    Ok(_) => {
-                        info!("List disks finished");
-                    }
-                    Err(e) => {
-                        error!("List disks error: {:?}", e);
-                    }
-                };
-            }
-            Op::Remove => {
-                if !operation.has_disk() {
-                    error!("Remove operation must include disk field.  Ignoring request");
-                    continue;
+                    error!("Worker {} exited: {:?}", id, e);
                 }
-                let mut result = OpResult::new();
-                match safe_to_remove(&Path::new(operation.get_disk()), &backend_type, config_dir) {
-                    Ok(true) => {
-                        match remove_disk(
-                            &mut responder,
-                            operation.get_disk(),
-                            &backend_type,
-                            config_dir,
-                        ) {
-                            Ok(_) => {
-                                info!("Remove disk finished");
-                            }
-                            Err(e) => {
-                                error!("Remove disk error: {:?}", e);
-                            }
-                        };
-                    }
-                    Ok(false) => {
-                        debug!("Disk is not safe to remove");
-                        //Response to client
-                        result.set_result(ResultType::ERR);
-                        result.set_error_msg("Not safe to remove disk".to_string());
-                        let _ = respond_to_client(&result, &mut responder);
-                    }
-                    Err(e) => {
-                        error!("safe to remove failed: {:?}", e);
-                        // Response to client
-                        result.set_result(ResultType::ERR);
-                        result.set_error_msg(e.to_string());
-                        let _ = respond_to_client(&result, &mut responder);
-                    }
-                };
+            })?;
+
+        // Add and remove run here so the workers stay free for requests
+        let runner_backend_type = backend_type.clone();
+        let runner_config_dir = config_dir.to_path_buf();
+        let runner_jobs = jobs.clone();
+        let runner_queue = queue.clone();
+        thread::Builder::new()
+            .name(format!("job-runner-{}", id))
+            .spawn(move || {
+                let mut backend = CachedBackend::new(&runner_backend_type, &runner_config_dir);
+                jobs::run_jobs(&runner_jobs, &runner_queue, &mut backend);
+            })?;
+    }
+    debug!("Started {} workers", worker_threads.max(1));
+
+    // Start any disks the backend has to unlock and pick up any adds a
+    // restart cut short.  The disk locks keep new requests off those disks
+    // until they finish
+    let resume_backend_type = backend_type.clone();
+    let resume_config_dir = config_dir.to_path_buf();
+    let resume_locks = locks.clone();
+    let resume_jobs = jobs.clone();
+    thread::Builder::new()
+        .name("resume".to_string())
+        .spawn(move || {
+            let mut backend = CachedBackend::new(&resume_backend_type, &resume_config_dir);
+            if let Err(e) = backend.get().and_then(|b| b.activate()) {
+                error!("Unable to activate disks: {:?}", e);
             }
-            Op::SafeToRemove => {
-                if !operation.has_disk() {
-                    error!("SafeToRemove operation must include disk field.  Ignoring request");
-                    continue;
-                }
-                match safe_to_remove_disk(
-                    &mut responder,
-                    operation.get_disk(),
-                    &backend_type,
-                    config_dir,
-                ) {
-                    Ok(_) => {
-                        info!("Safe to remove disk finished");
-                    }
-                    Err(e) => {
-                        error!("Safe to remove error: {:?}", e);
-                    }
-                };
+            if let Err(e) = resume_adds(&mut backend, &resume_locks, &resume_jobs) {
+                error!("Unable to resume interrupted adds: {:?}", e);
             }
-            Op::GetCreatedTickets => {
-               match get_jira_tickets(&mut responder,config_dir) {
-                    Ok(_) => {
-                        info!("Fetching jira tickets finished");
-                    }
-                    Err(e) => {
-                        error!("Fetching jira error: {:?}", e);
-                    }
-                };
+        })?;
+
+    // Free space removed disks left on shared devices
+    let reclaim_backend_type = backend_type.clone();
+    let reclaim_config_dir = config_dir.to_path_buf();
+    thread::Builder::new()
+        .name("reclaim".to_string())
+        .spawn(move || {
+            let mut backend = CachedBackend::new(&reclaim_backend_type, &reclaim_config_dir);
+            reclaim(&mut backend);
+        })?;
+
+    broker(&frontend, &workers)
+}
+
+fn resume_adds(backend: &mut CachedBackend, locks: &DeviceLocks, jobs: &Jobs) -> BynarResult<()> {
+    for device in backend.get()?.interrupted_adds()? {
+        let guard = match DeviceGuard::lock(locks, &device) {
+            Ok(guard) => guard,
+            Err(e) => {
+                error!("Unable to resume add on {}: {:?}", device.display(), e);
+                continue;
             }
         };
-        thread::sleep(Duration::from_millis(10));
+        match jobs.resume_add(&device, guard) {
+            Ok(job_id) => info!("Resuming add on {} as job {}", device.display(), job_id),
+            Err(e) => error!("Unable to resume add on {}: {:?}", device.display(), e),
+        }
     }
+    Ok(())
 }
 
-fn respond_to_client<T: protobuf::Message>(result: &T, s: &mut Socket) -> BynarResult<()> {
-    let encoded = result.write_to_bytes()?;
-    let msg = Message::from_slice(&encoded)?;
-    debug!("Responding to client with msg len: {}", msg.len());
-    s.send_msg(msg, 0)?;
-    Ok(())
+// Runs the backend's reclaim every reclaim_interval.  Returns straight
+// away if the backend has nothing to reclaim
+fn reclaim(backend: &mut CachedBackend) {
+    loop {
+        let interval = match backend.get() {
+            Ok(b) => match b.reclaim_interval() {
+                Some(interval) => interval,
+                None => return,
+            },
+            Err(e) => {
+                error!("Unable to load backend for reclaim: {:?}", e);
+                Duration::from_secs(RECLAIM_RETRY_SECS)
+            }
+        };
+        thread::sleep(interval);
+        if let Err(e) = backend.get().and_then(|b| b.reclaim()) {
+            error!("Reclaim failed: {:?}", e);
+        }
+    }
 }
 
-fn add_disk(
-    s: &mut Socket,
-    d: &str,
-    backend: &BackendType,
-    id: Option<u64>,
+/// What answering GetCreatedTickets needs.  Built once and shared by every
+/// worker
+struct TicketSource {
+    backend: Box<dyn TicketBackend>,
+    pool: Pool<ConnectionManager>,
+}
+
+// Without the ticketing config or the database GetCreatedTickets fails but
+// everything else still works
+fn open_ticket_source(config_dir: &Path) -> Option<TicketSource> {
+    let open = || -> BynarResult<TicketSource> {
+        let config: ConfigSettings = helpers::load_config(config_dir, "bynar.json")?;
+        Ok(TicketSource {
+            backend: load_ticket_backend(&config)?,
+            pool: create_db_connection_pool(&config.database)?,
+        })
+    };
+    match open() {
+        Ok(tickets) => Some(tickets),
+        Err(e) => {
+            warn!("Unable to load ticketing: {}.  Tickets can't be listed", e);
+            None
+        }
+    }
+}
+
+// Jobs are recorded in the bynar database if disk-manager can reach it.
+// They still run without it but their history is lost on restart
+fn open_job_db(config_dir: &Path) -> Option<JobDb> {
+    let open = || -> BynarResult<JobDb> {
+        let config: ConfigSettings = helpers::load_config(config_dir, "bynar.json")?;
+        let pool = create_db_connection_pool(&config.database)?;
+        in_progress::migrate_database(&pool)?;
+        let host = Host::new()?;
+        let mapping = in_progress::update_storage_info(&host, &pool)?;
+        let interrupted = in_progress::fail_interrupted_jobs(&pool, mapping.storage_detail_id)?;
+        if interrupted > 0 {
+            warn!("Marked {} interrupted jobs as failed", interrupted);
+        }
+        Ok(JobDb { pool, mapping })
+    };
+    match open() {
+        Ok(db) => Some(db),
+        Err(e) => {
+            warn!(
+                "Unable to open the job database: {}.  Job history won't be saved",
+                e
+            );
+            None
+        }
+    }
+}
+
+/*
+Hand each client request to an idle worker.  Requests that arrive while
+every worker is busy wait in line so a quick List never gets stuck behind a
+slow one.  Once MAX_WAITING_REQUESTS are waiting new ones are turned away
+with SERVER_BUSY.  Workers say they're idle by sending READY or the reply to
+their last request.  Only returns on error
+*/
+fn broker(frontend: &Socket, workers: &Socket) -> BynarResult<()> {
+    let mut idle: VecDeque<Vec<u8>> = VecDeque::new();
+    // Client envelope of the request each busy worker is handling
+    let mut busy: HashMap<Vec<u8>, Vec<Vec<u8>>> = HashMap::new();
+    // [client id, "", request] of requests no worker has taken yet
+    let mut waiting: VecDeque<Vec<Vec<u8>>> = VecDeque::new();
+    loop {
+        let mut items = [
+            workers.as_poll_item(zmq::POLLIN),
+            frontend.as_poll_item(zmq::POLLIN),
+        ];
+        zmq::poll(&mut items, -1)?;
+
+        if items[0].is_readable() {
+            // [worker id, "", READY or reply]
+            let mut frames = workers.recv_multipart(0)?;
+            let worker_id = frames.remove(0);
+            let body = frames.pop().unwrap_or_default();
+            if let Some(envelope) = busy.remove(&worker_id) {
+                let mut reply: Vec<&[u8]> = envelope.iter().map(|f| f.as_slice()).collect();
+                reply.push(&body);
+                frontend.send_multipart(&reply, 0)?;
+            }
+            idle.push_back(worker_id);
+        }
+        if items[1].is_readable() {
+            let mut envelope = frontend.recv_multipart(0)?;
+            if waiting.len() < MAX_WAITING_REQUESTS {
+                waiting.push_back(envelope);
+            } else {
+                let request = envelope.pop().unwrap_or_default();
+                let op = parse_from_bytes::<Operation>(&request)
+                    .ok()
+                    .map(|o| o.get_Op_type());
+                warn!("Every worker is busy.  Turning away {:?}", op);
+                envelope.push(error_reply(
+                    op,
+                    ErrorCode::SERVER_BUSY,
+                    "Every disk-manager worker is busy.  Try again later",
+                ));
+                frontend.send_multipart(&envelope, 0)?;
+            }
+        }
+        // Hand out as many waiting requests as there are idle workers
+        let ready = idle.len().min(waiting.len());
+        for (worker_id, mut envelope) in idle.drain(..ready).zip(waiting.drain(..ready)) {
+            let request = envelope.pop().unwrap_or_default();
+            let parts: [&[u8]; 3] = [&worker_id, b"", &request];
+            workers.send_multipart(&parts, 0)?;
+            busy.insert(worker_id, envelope);
+        }
+    }
+}
+
+fn worker(
+    id: usize,
+    context: &zmq::Context,
+    backend_type: &BackendType,
     config_dir: &Path,
+    locks: &DeviceLocks,
+    jobs: &Jobs,
+    tickets: Option<&TicketSource>,
 ) -> BynarResult<()> {
-    let mut result = OpResult::new();
-    let backend = match backend::load_backend(backend, Some(config_dir)) {
-        Ok(backend) => backend,
-        Err(e) => {
-            result.set_result(ResultType::ERR);
-            result.set_error_msg(e.to_string());
+    let responder = context.socket(zmq::REQ)?;
+    responder.set_identity(format!("worker-{}", id).as_bytes())?;
+    responder.connect(WORKER_ENDPOINT)?;
+    let mut backend = CachedBackend::new(backend_type, config_dir);
+    // Every reply after this one also tells the broker we're free again
+    responder.send_msg(Message::from_slice(b"READY")?, 0)?;
+
+    loop {
+        let msg = responder.recv_bytes(0)?;
+        debug!("Got msg len: {}", msg.len());
+        trace!("Parsing msg {:?} as hex", msg);
+        let reply = match parse_from_bytes::<Operation>(&msg) {
+            Ok(operation) => handle_operation(&operation, tickets, &mut backend, locks, jobs),
+            Err(e) => {
+                error!("Failed to parse_from_bytes {:?}", e);
+                error_reply(
+                    None,
+                    ErrorCode::INVALID_REQUEST,
+                    &format!("Unable to parse request: {}", e),
+                )
+            }
+        };
+        // The client is stuck until it hears back so every request gets a
+        // reply, even if it's only an error
+        debug!("Responding to client with msg len: {}", reply.len());
+        responder.send_msg(Message::from_slice(&reply)?, 0)?;
+    }
+}
 
-            // Bail early.  We can't load the backend
-            let _ = respond_to_client(&result, s);
-            return Ok(());
+// Run one request and return the encoded reply
+fn handle_operation(
+    operation: &Operation,
+    tickets: Option<&TicketSource>,
+    backend: &mut CachedBackend,
+    locks: &DeviceLocks,
+    jobs: &Jobs,
+) -> Vec<u8> {
+    let op = operation.get_Op_type();
+    debug!("Operation requested: {:?}", op);
+    let missing = match op {
+        Op::Add | Op::AddPartition | Op::Remove | Op::SafeToRemove if !operation.has_disk() => {
+            Some("disk")
         }
+        Op::AddPartition if !operation.has_partition_start() => Some("partition_start"),
+        Op::AddPartition if !operation.has_partition_end() => Some("partition_end"),
+        Op::GetJobStatus | Op::CancelJob if !operation.has_job_id() => Some("job_id"),
+        _ => None,
     };
+    if let Some(field) = missing {
+        error!("{:?} operation must include {} field", op, field);
+        return error_reply(
+            Some(op),
+            ErrorCode::MISSING_FIELD,
+            &format!("{:?} operation must include the {} field", op, field),
+        );
+    }
 
-    //Send back OpResult
-    match backend.add_disk(&Path::new(d), id, false) {
-        Ok(_) => {
-            result.set_result(ResultType::OK);
+    let reply = match op {
+        Op::Add => {
+            let id = if operation.has_osd_id() {
+                Some(operation.get_osd_id())
+            } else {
+                None
+            };
+            let replica_set = operation.get_replica_set().to_vec();
+            submit_job(jobs, locks, operation, id, replica_set)
+        }
+        Op::AddPartition => add_partition(operation, locks),
+        Op::List => list_disks(),
+        Op::Remove => submit_job(jobs, locks, operation, None, vec![]),
+        Op::SafeToRemove => safe_to_remove_disk(operation.get_disk(), backend),
+        Op::GetCreatedTickets => get_jira_tickets(tickets),
+        Op::GetJobStatus => get_job_status(jobs, operation.get_job_id()),
+        Op::ListJobs => list_jobs(jobs),
+        Op::CancelJob => cancel_job(jobs, operation.get_job_id()),
+    };
+    match reply {
+        Ok(reply) => {
+            info!("{:?} finished", op);
+            reply
         }
         Err(e) => {
-            result.set_result(ResultType::ERR);
-            result.set_error_msg(e.to_string());
+            error!("{:?} error: {:?}", op, e);
+            error_reply(Some(op), ErrorCode::INTERNAL, &e.to_string())
         }
+    }
+}
+
+macro_rules! error_result {
+    ($t:ty, $code:expr, $msg:expr) => {{
+        let mut result = <$t>::new();
+        result.set_result(ResultType::ERR);
+        result.set_error_code($code);
+        result.set_error_msg($msg.to_string());
+        result.write_to_bytes()
+    }};
+}
+
+// An error in the reply type the client is waiting on.  Requests that
+// couldn't be parsed get an OpResult
+fn error_reply(op: Option<Op>, code: ErrorCode, msg: &str) -> Vec<u8> {
+    let encoded = match op {
+        Some(Op::AddPartition) => error_result!(OpPartitionResult, code, msg),
+        Some(Op::List) => error_result!(Disks, code, msg),
+        Some(Op::SafeToRemove) => error_result!(OpBoolResult, code, msg),
+        Some(Op::GetCreatedTickets) => error_result!(OpJiraTicketsResult, code, msg),
+        Some(Op::GetJobStatus) => error_result!(OpJobResult, code, msg),
+        Some(Op::ListJobs) => error_result!(OpJobsResult, code, msg),
+        _ => error_result!(OpResult, code, msg),
     };
-    let _ = respond_to_client(&result, s);
+    // Encoding only fails when a required field is missing and every one
+    // is set above
+    encoded.unwrap_or_default()
+}
 
-    Ok(())
+// Queue an Add or Remove and reply with the job id
+fn submit_job(
+    jobs: &Jobs,
+    locks: &DeviceLocks,
+    operation: &Operation,
+    id: Option<u64>,
+    replica_set: Vec<String>,
+) -> BynarResult<Vec<u8>> {
+    let op = operation.get_Op_type();
+    let d = operation.get_disk();
+    let guard = match DeviceGuard::lock(locks, Path::new(d)) {
+        Ok(guard) => guard,
+        Err(e) => {
+            return Ok(error_reply(
+                Some(op),
+                ErrorCode::DEVICE_BUSY,
+                &e.to_string(),
+            ))
+        }
+    };
+    let mut result = OpResult::new();
+    result.set_result(ResultType::OK);
+    result.set_job_id(jobs.submit(
+        op,
+        Path::new(d),
+        id,
+        replica_set,
+        operation.get_simulate(),
+        guard,
+    )?);
+    Ok(result.write_to_bytes()?)
+}
+
+fn get_job_status(jobs: &Jobs, job_id: &str) -> BynarResult<Vec<u8>> {
+    match jobs.get(job_id)? {
+        Some(job) => {
+            let mut result = OpJobResult::new();
+            result.set_result(ResultType::OK);
+            result.set_job(job);
+            Ok(result.write_to_bytes()?)
+        }
+        None => Ok(error_reply(
+            Some(Op::GetJobStatus),
+            ErrorCode::NOT_FOUND,
+            &format!("Unknown job {}", job_id),
+        )),
+    }
+}
+
+fn list_jobs(jobs: &Jobs) -> BynarResult<Vec<u8>> {
+    let mut result = OpJobsResult::new();
+    result.set_result(ResultType::OK);
+    result.set_jobs(RepeatedField::from_vec(jobs.list()?));
+    Ok(result.write_to_bytes()?)
+}
+
+fn cancel_job(jobs: &Jobs, job_id: &str) -> BynarResult<Vec<u8>> {
+    if jobs.get(job_id)?.is_none() {
+        return Ok(error_reply(
+            Some(Op::CancelJob),
+            ErrorCode::NOT_FOUND,
+            &format!("Unknown job {}", job_id),
+        ));
+    }
+    if let Err(e) = jobs.cancel(job_id) {
+        return Ok(error_reply(
+            Some(Op::CancelJob),
+            ErrorCode::JOB_FINISHED,
+            &e.to_string(),
+        ));
+    }
+    let mut result = OpResult::new();
+    result.set_result(ResultType::OK);
+    result.set_job_id(job_id.to_string());
+    Ok(result.write_to_bytes()?)
 }
 
 fn get_disks() -> BynarResult<Vec<Disk>> {
@@ -319,6 +733,16 @@ fn get_disks() -> BynarResult<Vec<Disk>> {
     Ok(disks)
 }
 
+fn to_proto_partition(part: &gpt::partition::Partition) -> Partition {
+    let mut p = Partition::new();
+    p.set_uuid(part.part_guid.to_hyphenated().to_string());
+    p.set_first_lba(part.first_lba);
+    p.set_last_lba(part.last_lba);
+    p.set_flags(part.flags);
+    p.set_name(part.name.clone());
+    p
+}
+
 fn get_partition_info(dev_path: &Path) -> BynarResult<PartitionInfo> {
     let mut partition_info = PartitionInfo::new();
     let h = read_header(dev_path, disk::DEFAULT_SECTOR_SIZE)?;
@@ -327,150 +751,245 @@ fn get_partition_info(dev_path: &Path) -> BynarResult<PartitionInfo> {
     // Transform partitions to protobuf
     let proto_parts: Vec<Partition> = partitions
         .iter()
-        .map(|part| {
-            let mut p = Partition::new();
-            p.set_uuid(part.1.part_guid.to_hyphenated().to_string());
-            p.set_first_lba(part.1.first_lba);
-            p.set_last_lba(part.1.last_lba);
-            p.set_flags(part.1.flags);
-            p.set_name(part.1.name.clone());
-            p
-        })
+        .map(|part| to_proto_partition(part.1))
         .collect();
     partition_info.set_partition(RepeatedField::from_vec(proto_parts));
     Ok(partition_info)
 }
 
-fn list_disks(s: &mut Socket) -> BynarResult<()> {
-    let disk_list: Vec<Disk> = get_disks()?;
+// Partitions start on 1MiB boundaries like parted and sgdisk use
+const PARTITION_ALIGNMENT: u64 = 1024 * 1024;
+// GPT partition names are 36 UTF-16 code units at most
+const MAX_PARTITION_NAME: usize = 36;
 
-    let mut disks = Disks::new();
-    disks.set_disk(RepeatedField::from_vec(disk_list));
-    debug!("Encoding disk list");
-    let encoded = disks.write_to_bytes()?;
-
-    let msg = Message::from_slice(&encoded)?;
-    debug!("Responding to client with msg len: {}", msg.len());
-    s.send_msg(msg, 0)?;
+// Check a new partition is aligned, inside the usable sectors and doesn't
+// overlap an existing one.  All LBAs are inclusive
+fn check_partition(
+    start: u64,
+    end: u64,
+    lba_size: u64,
+    usable: (u64, u64),
+    existing: &[(u64, u64)],
+) -> BynarResult<()> {
+    if end < start {
+        return Err(BynarError::new(format!(
+            "partition_end {} is before partition_start {}",
+            end, start
+        )));
+    }
+    let alignment = PARTITION_ALIGNMENT / lba_size;
+    if start % alignment != 0 {
+        return Err(BynarError::new(format!(
+            "partition_start {} isn't aligned to {} sectors",
+            start, alignment
+        )));
+    }
+    if start < usable.0 || end > usable.1 {
+        return Err(BynarError::new(format!(
+            "Partition {}-{} is outside the usable sectors {}-{}",
+            start, end, usable.0, usable.1
+        )));
+    }
+    if let Some((first, last)) = existing
+        .iter()
+        .find(|(first, last)| start <= *last && *first <= end)
+    {
+        return Err(BynarError::new(format!(
+            "Partition {}-{} overlaps partition {}-{}",
+            start, end, first, last
+        )));
+    }
     Ok(())
 }
 
-fn remove_disk(
-    s: &mut Socket,
-    d: &str,
-    backend: &BackendType,
-    config_dir: &Path,
-) -> BynarResult<()> {
-    //Returns OpResult
-    let mut result = OpResult::new();
-    let backend = match backend::load_backend(backend, Some(config_dir)) {
-        Ok(b) => b,
+// Create a GPT partition and reply with it.  Simulate only validates
+fn add_partition(operation: &Operation, locks: &DeviceLocks) -> BynarResult<Vec<u8>> {
+    let dev_path = Path::new(operation.get_disk());
+    let simulate = operation.get_simulate();
+    let invalid = |msg: &str| -> BynarResult<Vec<u8>> {
+        Ok(error_reply(
+            Some(Op::AddPartition),
+            ErrorCode::INVALID_ARGUMENT,
+            msg,
+        ))
+    };
+    let _guard = match DeviceGuard::lock(locks, dev_path) {
+        Ok(guard) => guard,
         Err(e) => {
-            result.set_result(ResultType::ERR);
-            result.set_error_msg(e.to_string());
-
-            // Bail early.  We can't load the backend
-            let _ = respond_to_client(&result, s);
-            return Ok(());
+            return Ok(error_reply(
+                Some(Op::AddPartition),
+                ErrorCode::DEVICE_BUSY,
+                &e.to_string(),
+            ))
         }
     };
-    match backend.remove_disk(&Path::new(d), false) {
-        Ok(_) => {
-            result.set_result(ResultType::OK);
-        }
-        Err(e) => {
-            result.set_result(ResultType::ERR);
-            result.set_error_msg(e.to_string());
+    let name = if operation.has_partition_name() {
+        operation.get_partition_name()
+    } else {
+        "bynar"
+    };
+    if name.encode_utf16().count() > MAX_PARTITION_NAME {
+        return invalid(&format!(
+            "partition_name {} is longer than {} characters",
+            name, MAX_PARTITION_NAME
+        ));
+    }
+
+    let mut gpt_disk = gpt::GptConfig::new()
+        .writable(!simulate)
+        .initialized(true)
+        .open(dev_path)?;
+    let lba_size: u64 = match gpt_disk.logical_block_size() {
+        disk::LogicalBlockSize::Lb512 => 512,
+        disk::LogicalBlockSize::Lb4096 => 4096,
+    };
+    let (usable, num_parts) = match gpt_disk.primary_header() {
+        Some(h) => ((h.first_usable, h.last_usable), h.num_parts),
+        None => return invalid(&format!("{} has no GPT header", dev_path.display())),
+    };
+    let mut partitions = gpt_disk.partitions().clone();
+    let existing: Vec<(u64, u64)> = partitions
+        .values()
+        .filter(|p| p.part_type_guid != gpt::partition_types::UNUSED)
+        .map(|p| (p.first_lba, p.last_lba))
+        .collect();
+    let start = operation.get_partition_start();
+    let end = operation.get_partition_end();
+    if let Err(e) = check_partition(start, end, lba_size, usable, &existing) {
+        return invalid(&e.to_string());
+    }
+    let id = match (1..=num_parts).find(|id| {
+        partitions
+            .get(id)
+            .map_or(true, |p| p.part_type_guid == gpt::partition_types::UNUSED)
+    }) {
+        Some(id) => id,
+        None => {
+            return invalid(&format!(
+                "{} has no free partition entries",
+                dev_path.display()
+            ))
         }
     };
-    let _ = respond_to_client(&result, s);
-    Ok(())
+    let partition = gpt::partition::Partition {
+        part_type_guid: gpt::partition_types::LINUX_FS,
+        part_guid: uuid::Uuid::new_v4(),
+        first_lba: start,
+        last_lba: end,
+        flags: 0,
+        name: name.to_string(),
+    };
+    if simulate {
+        info!(
+            "Simulate: would add partition {} {}-{} to {}",
+            id,
+            start,
+            end,
+            dev_path.display()
+        );
+    } else {
+        info!(
+            "Adding partition {} {}-{} to {}",
+            id,
+            start,
+            end,
+            dev_path.display()
+        );
+        partitions.insert(id, partition.clone());
+        gpt_disk.update_partitions(partitions)?;
+        gpt_disk.write()?;
+        update_partition_cache(dev_path)?;
+    }
+
+    let mut result = OpPartitionResult::new();
+    result.set_result(ResultType::OK);
+    result.set_partition(to_proto_partition(&partition));
+    Ok(result.write_to_bytes()?)
 }
 
-fn safe_to_remove(d: &Path, backend: &BackendType, config_dir: &Path) -> BynarResult<bool> {
-    let backend = backend::load_backend(backend, Some(config_dir))?;
-    let safe = backend.safe_to_remove(d, false)?;
+fn list_disks() -> BynarResult<Vec<u8>> {
+    let disk_list: Vec<Disk> = get_disks()?;
 
-    Ok(safe)
+    let mut disks = Disks::new();
+    disks.set_disk(RepeatedField::from_vec(disk_list));
+    debug!("Encoding disk list");
+    Ok(disks.write_to_bytes()?)
 }
 
-fn safe_to_remove_disk(
-    s: &mut Socket,
-    d: &str,
-    backend: &BackendType,
-    config_dir: &Path,
-) -> BynarResult<()> {
+fn safe_to_remove_disk(d: &str, backend: &mut CachedBackend) -> BynarResult<Vec<u8>> {
     debug!("Checking if {} is safe to remove", d);
-    let mut result = OpBoolResult::new();
-    match safe_to_remove(&Path::new(d), &backend, &config_dir) {
-        Ok(val) => {
-            debug!("Safe to remove: {}", val);
+    match backend
+        .get()
+        .and_then(|backend| backend.check_safe_to_remove(&Path::new(d), false))
+    {
+        Ok(answer) => {
+            debug!("Safe to remove: {:?}", answer);
+            let checks: Vec<RemovalCheck> = answer
+                .checks
+                .iter()
+                .map(|c| {
+                    let mut check = RemovalCheck::new();
+                    check.set_name(c.name.clone());
+                    check.set_passed(c.passed);
+                    check.set_detail(c.detail.clone());
+                    check
+                })
+                .collect();
+            let mut result = OpBoolResult::new();
             result.set_result(ResultType::OK);
-            result.set_value(val);
+            result.set_value(answer.safe);
+            result.set_checks(RepeatedField::from_vec(checks));
+            result.set_affected(RepeatedField::from_vec(answer.affected));
+            Ok(result.write_to_bytes()?)
         }
         Err(e) => {
             debug!("Safe to remove err: {}", e);
-            result.set_result(ResultType::ERR);
-            result.set_error_msg(e.to_string());
-            let encoded = result.write_to_bytes()?;
-            let msg = Message::from_slice(&encoded)?;
-            debug!("Responding to client with msg len: {}", msg.len());
-            s.send_msg(msg, 0)?;
-            return Err(BynarError::new(format!("safe to remove error: {}", e)));
+            Ok(error_reply(
+                Some(Op::SafeToRemove),
+                ErrorCode::BACKEND,
+                &e.to_string(),
+            ))
         }
-    };
-    let encoded = result.write_to_bytes()?;
-    let msg = Message::from_slice(&encoded)?;
-    debug!("Responding to client with msg len: {}", msg.len());
-    s.send_msg(msg, 0)?;
-    Ok(())
+    }
 }
 
- pub fn get_jira_tickets(s: &mut Socket, config_dir: &Path) -> BynarResult<()> {
-    let mut result = OpJiraTicketsResult::new();
-    let config: ConfigSettings = match helpers::load_config(&config_dir, "bynar.json") {
-        Ok(p) => p,
-        Err(e) => {
-            error!("Failed to load config file {}", e);
-            result.set_result(ResultType::ERR);
-            result.set_error_msg(e.to_string());
-
-            // unable to load config file
-            let _ = respond_to_client(&result, s);
-            return Ok(());
-        }
-    };
-    let db_config = config.database;
-    let db_pool = match in_progress::create_db_connection_pool(&db_config) {
-        Ok(p) => p,
-        Err(e) => {
-            error!("Failed to create database pool {}", e);
-            result.set_result(ResultType::ERR);
-            result.set_error_msg(e.to_string());
-
-            // unable to create DB connection
-            let _ = respond_to_client(&result, s);
-            return Ok(());
+fn get_jira_tickets(tickets: Option<&TicketSource>) -> BynarResult<Vec<u8>> {
+    let (ticket_backend, db_pool) = match tickets {
+        Some(tickets) => (&tickets.backend, &tickets.pool),
+        None => {
+            return Err(BynarError::from(
+                "Ticketing couldn't be loaded when disk-manager started",
+            ))
         }
     };
 
     info!("Getting all pending repair tickets");
-    let tickets = in_progress::get_all_pending_tickets(&db_pool)?;
+    let tickets = in_progress::get_all_pending_tickets(db_pool)?;
     debug!("outstanding tickets: {:?}", tickets);
+    let mut proto_jira: Vec<JiraInfo> = Vec::new();
+    for j in tickets {
+        // The database can lag behind the ticketing system.  Skip anything
+        // that was already resolved there
+        match ticket_backend.ticket_resolved(&j.ticket_id) {
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(e) => {
+                warn!(
+                    "Unable to check if ticket {} is resolved: {}",
+                    j.ticket_id, e
+                );
+            }
+        }
+        let mut jira_result = JiraInfo::new();
+        jira_result.set_ticket_id(j.ticket_id.clone());
+        let host_name = in_progress::get_host_name(db_pool, j.device_id)?;
+        jira_result.set_server_name(host_name.unwrap_or_default());
+        proto_jira.push(jira_result);
+    }
+    let mut result = OpJiraTicketsResult::new();
     result.set_result(ResultType::OK);
-    let proto_jira: Vec<JiraInfo> = tickets
-        .iter()
-        .map(|j| {
-            let mut jira_result = JiraInfo::new();
-            jira_result.set_ticket_id(j.ticket_id.clone());
-            let host_name = in_progress::get_host_name(&db_pool,j.device_id);
-            jira_result.set_server_name(host_name.unwrap().unwrap());
-            jira_result
-        })
-        .collect();
     result.set_tickets(RepeatedField::from_vec(proto_jira));
-    let _ = respond_to_client(&result, s);
-    Ok(())
+    Ok(result.write_to_bytes()?)
 }
 
 fn main() {
@@ -483,7 +1002,7 @@ fn main() {
                 .default_value("ceph")
                 .help("Backend cluster type to manage disks for")
                 .long("backend")
-                .possible_values(&["ceph"])
+                .possible_values(&["ceph", "gluster"])
                 .takes_value(true)
                 .required(false),
         )
@@ -495,6 +1014,26 @@ fn main() {
                 .takes_value(true)
                 .required(false),
         )
+        .arg(
+            Arg::with_name("port")
+                .default_value("5555")
+                .help("Port to listen on.  Should match manager_port in bynar.json")
+                .long("port")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("endpoint")
+                .help(
+                    "zmq endpoint to listen on.  ie: tcp://*:5555 or ipc:///run/bynar/dm.sock. \
+                     Can be given more than once.  Overrides --listenaddress and --port",
+                )
+                .long("endpoint")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+                .required(false),
+        )
         .arg(
             Arg::with_name("configdir")
                 .default_value("/etc/bynar")
@@ -568,11 +1107,34 @@ fn main() {
         File::create(log).expect("log file creation failed"),
     ));
     let _ = CombinedLogger::init(loggers);
+    let (worker_threads, config_endpoints) =
+        match helpers::load_config::<DiskManagerConfig>(config_dir, "disk-manager.json") {
+            Ok(config) => (config.worker_threads, config.listen_endpoints),
+            Err(e) => {
+                warn!(
+                    "Unable to load disk-manager.json: {}.  Using {} workers",
+                    e,
+                    default_worker_threads()
+                );
+                (default_worker_threads(), vec![])
+            }
+        };
+    // --endpoint wins over disk-manager.json which wins over --listenaddress
+    let endpoints: Vec<String> = match matches.values_of("endpoint") {
+        Some(values) => values.map(|v| v.to_string()).collect(),
+        None if !config_endpoints.is_empty() => config_endpoints,
+        None => vec![format!(
+            "tcp://{}:{}",
+            matches.value_of("listen").unwrap(),
+            matches.value_of("port").unwrap()
+        )],
+    };
     match listen(
         &backend,
         config_dir,
-        matches.value_of("listen").unwrap(),
+        &endpoints,
         vault_support,
+        worker_threads,
     ) {
         Ok(_) => {
             println!("Finished");
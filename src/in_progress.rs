@@ -1,15 +1,15 @@
 //use super::DBConfig;
-use crate::test_disk::{BlockDevice, State};
+use crate::test_disk::{BlockDevice, State, TransitionRecord};
 /// Monitor in progress disk repairs
 use chrono::offset::Utc;
 use chrono::DateTime;
-use helpers::{error::*, host_information::Host as MyHost, DBConfig};
+use helpers::{error::*, host_information::Host as MyHost, migrations::run_migrations, DBConfig};
 use log::{debug, error, info};
 use postgres::{params::ConnectParams, params::Host, rows::Row, transaction::Transaction};
 use r2d2::{Pool, PooledConnection};
 use r2d2_postgres::{PostgresConnectionManager as ConnectionManager, TlsMode};
 use std::fmt::{Display, Formatter, Result as fResult};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::id;
 use std::str::FromStr;
 use std::time::Duration;
@@ -17,10 +17,17 @@ use std::time::Duration;
 #[cfg(test)]
 mod tests {
     use super::super::ConfigSettings;
-    use block_utils::{Device, FilesystemType, MediaType, ScsiInfo};
+    use super::{ConnectionManager, Pool};
+    use crate::test_disk::tests::test_device;
+    use block_utils::MediaType;
+    use helpers::{host_information::StorageTypeEnum, migrations, DBConfig};
     use simplelog::{Config, TermLogger};
-    use std::collections::BTreeMap;
+    use std::env;
+    use std::fs;
+    use std::net::TcpListener;
     use std::path::{Path, PathBuf};
+    use std::process::Command;
+    use tempdir::TempDir;
     use uuid::Uuid;
 
     #[test]
@@ -51,24 +58,11 @@ mod tests {
         let drive_uuid = Uuid::new_v4();
         let dev_name = format!("some_path-{}", drive_uuid);
         let path = format!("/some/{}", dev_name);
-        let mut d = crate::test_disk::BlockDevice {
-            device: Device {
-                id: Some(drive_uuid),
-                name: dev_name,
-                media_type: MediaType::Rotational,
-                capacity: 26214400,
-                fs_type: FilesystemType::Xfs,
-                serial_number: Some("123456".into()),
-            },
-            dev_path: PathBuf::from(path),
-            device_database_id: None,
-            mount_point: None,
-            partitions: BTreeMap::new(),
-            scsi_info: ScsiInfo::default(),
-            state: crate::test_disk::State::Unscanned,
-            storage_detail_id: result.storage_detail_id,
-            operation_id: None,
-        };
+        let mut d = test_device(&dev_name, MediaType::Rotational);
+        d.device.id = Some(drive_uuid);
+        d.device.serial_number = Some("123456".into());
+        d.dev_path = PathBuf::from(path);
+        d.storage_detail_id = result.storage_detail_id;
 
         println!("Adding disk {:#?}", d);
         let _disk_result = super::add_disk_detail(&pool, &mut d).unwrap();
@@ -183,6 +177,308 @@ mod tests {
         //TODO: add failure tests
         // 1. set entry_id = 0
     }
+
+    /// A throwaway Postgres cluster in a temp dir, stopped on drop
+    struct TestPostgres {
+        bin_dir: PathBuf,
+        dir: TempDir,
+        port: u16,
+        // initdb refuses to run as root so the integration tests run
+        // the cluster as the postgres user
+        run_as: Option<&'static str>,
+    }
+
+    impl TestPostgres {
+        // Returns None if the Postgres server binaries aren't usable here
+        fn start() -> Option<TestPostgres> {
+            let bin_dir = find_postgres_bin_dir()?;
+            let dir = TempDir::new("bynar-postgres").unwrap();
+            let run_as = if nix::unistd::geteuid().is_root() {
+                let chown = Command::new("chown")
+                    .arg("postgres")
+                    .arg(dir.path())
+                    .status()
+                    .ok()?;
+                if !chown.success() {
+                    return None;
+                }
+                Some("postgres")
+            } else {
+                None
+            };
+            let data = dir.path().join("data");
+            run(
+                pg_command(&bin_dir, run_as, "initdb"),
+                &[
+                    "-D",
+                    &data.to_string_lossy(),
+                    "-U",
+                    "postgres",
+                    "--auth=trust",
+                    "-E",
+                    "UTF8",
+                ],
+            );
+            // Grab a free port from the kernel
+            let port = TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port();
+            run(
+                pg_command(&bin_dir, run_as, "pg_ctl"),
+                &[
+                    "-D",
+                    &data.to_string_lossy(),
+                    "-l",
+                    &dir.path().join("postgres.log").to_string_lossy(),
+                    "-o",
+                    &format!(
+                        "-p {} -k {} -c listen_addresses=127.0.0.1",
+                        port,
+                        dir.path().display()
+                    ),
+                    "-w",
+                    "start",
+                ],
+            );
+            let pg = TestPostgres {
+                bin_dir,
+                dir,
+                port,
+                run_as,
+            };
+            let conn = postgres::Connection::connect(
+                format!("postgres://postgres@127.0.0.1:{}/postgres", port),
+                postgres::TlsMode::None,
+            )
+            .unwrap();
+            conn.batch_execute("CREATE DATABASE bynar").unwrap();
+            Some(pg)
+        }
+
+        fn pool(&self) -> Pool<ConnectionManager> {
+            let db_config = DBConfig {
+                username: "postgres".into(),
+                password: None,
+                port: self.port,
+                endpoint: "127.0.0.1".into(),
+                dbname: "bynar".into(),
+            };
+            let pool = super::create_db_connection_pool(&db_config).unwrap();
+            super::migrate_database(&pool).unwrap();
+            pool
+        }
+    }
+
+    impl Drop for TestPostgres {
+        fn drop(&mut self) {
+            let _ = pg_command(&self.bin_dir, self.run_as, "pg_ctl")
+                .args(&["-D", &self.dir.path().join("data").to_string_lossy()])
+                .args(&["-m", "immediate", "stop"])
+                .output();
+        }
+    }
+
+    fn find_postgres_bin_dir() -> Option<PathBuf> {
+        let mut candidates: Vec<PathBuf> = env::var_os("PATH")
+            .map(|p| env::split_paths(&p).collect())
+            .unwrap_or_default();
+        // Debian and Ubuntu keep the server binaries out of the PATH
+        if let Ok(versions) = fs::read_dir("/usr/lib/postgresql") {
+            candidates.extend(
+                versions
+                    .filter_map(|v| v.ok())
+                    .map(|v| v.path().join("bin")),
+            );
+        }
+        candidates
+            .into_iter()
+            .find(|dir| dir.join("initdb").exists() && dir.join("pg_ctl").exists())
+    }
+
+    fn pg_command(bin_dir: &Path, run_as: Option<&str>, name: &str) -> Command {
+        match run_as {
+            Some(user) => {
+                let mut cmd = Command::new("runuser");
+                cmd.args(&["-u", user, "--"]).arg(bin_dir.join(name));
+                cmd
+            }
+            None => Command::new(bin_dir.join(name)),
+        }
+    }
+
+    fn run(mut cmd: Command, args: &[&str]) {
+        let output = cmd.args(args).output().unwrap();
+        assert!(
+            output.status.success(),
+            "{:?} failed: {}",
+            cmd,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    // Needs initdb and pg_ctl.  Run with cargo test -- --ignored
+    #[test]
+    #[ignore]
+    fn test_migrations() {
+        let pg = TestPostgres::start().expect("Unable to start a disposable Postgres");
+        let pool = pg.pool();
+        let conn = pool.get().unwrap();
+        let latest = migrations::latest_revision();
+        assert_eq!(migrations::current_revision(&*conn).unwrap(), latest);
+        // Running again is a no-op
+        assert!(migrations::run_migrations(&*conn).unwrap().is_empty());
+        assert!(migrations::pending_migrations(&*conn).unwrap().is_empty());
+
+        // A database from a newer bynar is refused
+        conn.execute("UPDATE schema_mgmt SET revision = $1", &[&(latest + 1)])
+            .unwrap();
+        assert!(migrations::pending_migrations(&*conn).is_err());
+        assert!(super::migrate_database(&pool).is_err());
+        assert_eq!(migrations::current_revision(&*conn).unwrap(), latest + 1);
+    }
+
+    const HOSTILE: &[&str] = &[
+        "it's",
+        "'; DROP TABLE regions; --",
+        "\\'",
+        "\"quoted\"",
+        "$1",
+        "ünïcødé ☃",
+        "%s %d {}",
+    ];
+
+    fn hostile_host(name: &str) -> super::MyHost {
+        super::MyHost {
+            hostname: format!("host-{}", name),
+            ip: "10.0.0.1".parse().unwrap(),
+            region: format!("region-{}", name),
+            kernel: "4.15.0".into(),
+            server_type: "test".into(),
+            serial_number: name.into(),
+            machine_architecture: "x86_64".into(),
+            scsi_info: vec![],
+            storage_type: StorageTypeEnum::Ceph,
+            array_name: Some(format!("array-{}", name)),
+            pool_name: Some(format!("pool-{}", name)),
+        }
+    }
+
+    fn hostile_device(name: &str, storage_detail_id: u32) -> crate::test_disk::BlockDevice {
+        let mut d = test_device(&format!("sd-{}", name), MediaType::Rotational);
+        d.device.id = Some(Uuid::new_v4());
+        d.device.serial_number = Some(format!("serial-{}", name));
+        d.mount_point = Some(PathBuf::from(format!("/mnt/{}", name)));
+        d.storage_detail_id = storage_detail_id;
+        d
+    }
+
+    // Needs initdb and pg_ctl.  Run with cargo test -- --ignored
+    #[test]
+    #[ignore]
+    fn test_hostile_inputs() {
+        let pg = TestPostgres::start().expect("Unable to start a disposable Postgres");
+        let pool = pg.pool();
+
+        for name in HOSTILE {
+            let host = hostile_host(name);
+            let mapping = super::update_storage_info(&host, &pool).unwrap();
+            // Running it again must find the same rows instead of failing
+            let again = super::update_storage_info(&host, &pool).unwrap();
+            assert_eq!(mapping.region_id, again.region_id);
+            assert_eq!(mapping.storage_detail_id, again.storage_detail_id);
+            assert_eq!(
+                super::get_region_id(&pool, &host.region).unwrap(),
+                Some(mapping.region_id)
+            );
+            let storage_id = super::get_storage_id(&pool, "ceph").unwrap().unwrap();
+            assert_eq!(
+                super::get_storage_detail_id(&pool, storage_id, mapping.region_id, &host.hostname)
+                    .unwrap(),
+                Some(mapping.storage_detail_id)
+            );
+
+            let mut d = hostile_device(name, mapping.storage_detail_id);
+            super::add_disk_detail(&pool, &mut d).unwrap();
+            let dev_id = d.device_database_id.unwrap();
+            let devices = super::get_devices_from_db(&pool, mapping.storage_detail_id).unwrap();
+            assert!(devices
+                .iter()
+                .any(|(id, dev_name, _)| *id == dev_id && dev_name == &d.device.name));
+
+            super::save_smart_result(&pool, &d, true).unwrap();
+            assert!(super::get_smart_result(&pool, &d).unwrap());
+            let state = crate::test_disk::State::WaitingForReplacement;
+            super::save_state(&pool, &d, state).unwrap();
+            assert_eq!(super::get_state(&pool, &d).unwrap(), state);
+
+            let mut op_info = super::OperationInfo::new(mapping.entry_id, dev_id);
+            op_info.behalf_of = Some(name.to_string());
+            op_info.reason = Some(name.to_string());
+            assert_eq!(
+                super::get_operation_id(&pool, mapping.entry_id, dev_id).unwrap(),
+                None
+            );
+            super::add_or_update_operation(&pool, &mut op_info).unwrap();
+            let op_id = op_info.operation_id.unwrap();
+            assert_eq!(
+                super::get_operation_id(&pool, mapping.entry_id, dev_id).unwrap(),
+                Some(op_id)
+            );
+            // A second insert for the same device and entry is rejected
+            let mut dup = super::OperationInfo::new(mapping.entry_id, dev_id);
+            assert!(super::add_or_update_operation(&pool, &mut dup).is_err());
+
+            let mut detail =
+                super::OperationDetail::new(op_id, super::OperationType::WaitingForReplacement);
+            detail.set_tracking_id(format!("ticket-{}", name));
+            super::add_or_update_operation_detail(&pool, &mut detail).unwrap();
+            let tickets =
+                super::get_outstanding_repair_tickets(&pool, mapping.storage_detail_id).unwrap();
+            assert!(tickets
+                .iter()
+                .any(|t| t.ticket_id == format!("ticket-{}", name)));
+            assert!(super::is_hardware_waiting_repair(
+                &pool,
+                mapping.storage_detail_id,
+                &d.device.name,
+                None
+            )
+            .unwrap());
+
+            super::resolve_ticket_in_db(&pool, &format!("ticket-{}", name)).unwrap();
+            let tickets =
+                super::get_outstanding_repair_tickets(&pool, mapping.storage_detail_id).unwrap();
+            assert!(tickets.is_empty());
+
+            let replaced = super::ReplacedOsd {
+                osd_id: 7,
+                crush_location: vec![format!("host={}", name.replace(' ', "_"))],
+                device_class: Some(name.to_string()),
+                journal: None,
+                db: Some("/dev/nvme0n1p3".to_string()),
+            };
+            super::save_replaced_osd(&pool, mapping.storage_detail_id, &d.dev_path, &replaced)
+                .unwrap();
+            assert_eq!(
+                super::get_replaced_osd(&pool, mapping.storage_detail_id, &d.dev_path).unwrap(),
+                Some(replaced)
+            );
+            super::remove_replaced_osd(&pool, mapping.storage_detail_id, &d.dev_path).unwrap();
+            assert!(
+                super::get_replaced_osd(&pool, mapping.storage_detail_id, &d.dev_path)
+                    .unwrap()
+                    .is_none()
+            );
+            super::deregister_from_process_manager(&pool, mapping.entry_id).unwrap();
+        }
+        // Nothing got dropped along the way
+        let conn = pool.get().unwrap();
+        let regions = conn.query("SELECT count(*) FROM regions", &[]).unwrap();
+        let count: i64 = regions.get(0).get(0);
+        assert_eq!(count, HOSTILE.len() as i64);
+    }
 }
 
 #[derive(Debug)]
@@ -253,7 +549,7 @@ impl OperationInfo {
             done_time: None,
         }
     }
-    fn set_operation_id(&mut self, op_id: u32) {
+    pub fn set_operation_id(&mut self, op_id: u32) {
         self.operation_id = Some(op_id);
     }
     pub fn set_done_time(&mut self, done_time: DateTime<Utc>) {
@@ -286,11 +582,13 @@ impl Display for OperationType {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum OperationStatus {
     Pending,
     InProgress,
     Complete,
+    Failed,
+    Cancelled,
 }
 
 impl Display for OperationStatus {
@@ -299,11 +597,28 @@ impl Display for OperationStatus {
             OperationStatus::Pending => "pending",
             OperationStatus::InProgress => "in_progress",
             OperationStatus::Complete => "complete",
+            OperationStatus::Failed => "failed",
+            OperationStatus::Cancelled => "cancelled",
         };
         write!(f, "{}", message)
     }
 }
 
+impl FromStr for OperationStatus {
+    type Err = BynarError;
+
+    fn from_str(s: &str) -> BynarResult<Self> {
+        match s {
+            "pending" => Ok(OperationStatus::Pending),
+            "in_progress" => Ok(OperationStatus::InProgress),
+            "complete" => Ok(OperationStatus::Complete),
+            "failed" => Ok(OperationStatus::Failed),
+            "cancelled" => Ok(OperationStatus::Cancelled),
+            _ => Err(BynarError::new(format!("Unknown operation status: {}", s))),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct OperationDetail {
     pub op_detail_id: Option<u32>,
@@ -314,6 +629,12 @@ pub struct OperationDetail {
     pub start_time: DateTime<Utc>,
     pub snapshot_time: DateTime<Utc>,
     pub done_time: Option<DateTime<Utc>>,
+    /// Set for disk-manager add and remove jobs
+    pub job_id: Option<String>,
+    /// The step a job is on
+    pub step: Option<String>,
+    /// Why a job failed
+    pub error_msg: Option<String>,
 }
 
 impl OperationDetail {
@@ -327,6 +648,9 @@ impl OperationDetail {
             start_time: Utc::now(),
             snapshot_time: Utc::now(),
             done_time: None,
+            job_id: None,
+            step: None,
+            error_msg: None,
         }
     }
     fn set_operation_detail_id(&mut self, op_detail_id: u32) {
@@ -344,6 +668,49 @@ impl OperationDetail {
     pub fn set_operation_status(&mut self, status: OperationStatus) {
         self.status = status;
     }
+
+    pub fn set_job_id(&mut self, job_id: String) {
+        self.job_id = Some(job_id);
+    }
+
+    pub fn set_step(&mut self, step: String) {
+        self.step = Some(step);
+    }
+
+    pub fn set_error_msg(&mut self, error_msg: String) {
+        self.error_msg = Some(error_msg);
+    }
+
+    pub fn set_snapshot_time(&mut self, snapshot_time: DateTime<Utc>) {
+        self.snapshot_time = snapshot_time;
+    }
+}
+
+/// A disk-manager job as recorded in operation_details
+#[derive(Clone, Debug)]
+pub struct JobInfo {
+    pub job_id: String,
+    pub op_type: String,
+    pub device_path: String,
+    pub status: OperationStatus,
+    pub step: Option<String>,
+    pub error_msg: Option<String>,
+    pub start_time: DateTime<Utc>,
+    pub snapshot_time: DateTime<Utc>,
+    pub done_time: Option<DateTime<Utc>>,
+}
+
+/// Where a removed osd sat so the disk replacing it can take its place
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplacedOsd {
+    pub osd_id: u64,
+    /// bucket=name pairs.  ie: root=default, host=ceph01
+    pub crush_location: Vec<String>,
+    pub device_class: Option<String>,
+    /// Journal or WAL partition.  None if it was collocated
+    pub journal: Option<String>,
+    /// Bluestore block.db partition.  None if it was collocated
+    pub db: Option<String>,
 }
 
 /// Reads the config file to establish a pool of database connections
@@ -370,6 +737,19 @@ pub fn create_db_connection_pool(db_config: &DBConfig) -> BynarResult<Pool<Conne
     Ok(db_pool)
 }
 
+/// Bring the database schema up to date by applying any pending migrations.
+/// Refuses to touch a database that is newer than this binary
+pub fn migrate_database(pool: &Pool<ConnectionManager>) -> BynarResult<()> {
+    let conn = get_connection_from_pool(pool)?;
+    let applied = run_migrations(&*conn)?;
+    if applied.is_empty() {
+        debug!("Database schema is up to date");
+    } else {
+        info!("Applied database migrations {:?}", applied);
+    }
+    Ok(())
+}
+
 /// return one connection from the pool
 pub fn get_connection_from_pool(
     pool: &Pool<ConnectionManager>,
@@ -414,33 +794,28 @@ pub fn update_storage_info(
 /// responsible to store the pid, ip of the system on which bynar is running
 fn register_to_process_manager(conn: &Transaction<'_>, ip: &str) -> BynarResult<u32> {
     // get process id
-    let pid = id();
+    let pid = id() as i32;
     debug!("Adding daemon details with pid {} to process manager", pid);
     let mut entry_id: u32 = 0;
-    let stmt = format!(
-        "SELECT entry_id FROM process_manager WHERE
-    pid={} AND ip='{}'",
-        pid, &ip
-    );
-    let stmt_query = conn.query(&stmt, &[])?;
+    let stmt_query = conn.query(
+        "SELECT entry_id FROM process_manager WHERE pid=$1 AND ip=$2",
+        &[&pid, &ip],
+    )?;
     if let Some(row) = stmt_query.into_iter().next() {
         // entry exists for this ip with this pid. Update status
         let r: i32 = row.get("entry_id");
-        let update_stmt = format!(
-            "UPDATE process_manager SET status='idle'
-           WHERE pid={} AND ip='{}'",
-            pid, &ip
-        );
-        conn.execute(&update_stmt, &[])?;
+        conn.execute(
+            "UPDATE process_manager SET status='idle' WHERE pid=$1 AND ip=$2",
+            &[&pid, &ip],
+        )?;
         entry_id = r as u32;
     } else {
         // does not exist, insert
-        let insert_stmt = format!(
+        let insert_stmt_query = conn.query(
             "INSERT INTO process_manager (pid, ip, status)
-                            VALUES ({}, '{}', 'idle') RETURNING entry_id",
-            pid, &ip
-        );
-        let insert_stmt_query = conn.query(&insert_stmt, &[])?;
+                            VALUES ($1, $2, 'idle') RETURNING entry_id",
+            &[&pid, &ip],
+        )?;
         if let Some(r) = insert_stmt_query.into_iter().next() {
             let e: i32 = r.get("entry_id");
             entry_id = e as u32;
@@ -450,19 +825,27 @@ fn register_to_process_manager(conn: &Transaction<'_>, ip: &str) -> BynarResult<
 }
 
 /// Responsible to de-register itself when daemon exists
-pub fn deregister_from_process_manager() -> BynarResult<()> {
-    // DELETE FROM process_manager WHERE IP=<>
+/// The row is kept and marked stopped because operations still reference it
+pub fn deregister_from_process_manager(
+    pool: &Pool<ConnectionManager>,
+    entry_id: u32,
+) -> BynarResult<()> {
+    debug!("Marking process manager entry {} as stopped", entry_id);
+    let conn = get_connection_from_pool(pool)?;
+    conn.execute(
+        "UPDATE process_manager SET status='stopped' WHERE entry_id=$1",
+        &[&(entry_id as i32)],
+    )?;
     Ok(())
 }
 
 // Checks for the region in the database, inserts if it does not exist
 // and returns the region_id
 fn update_region(conn: &Transaction<'_>, region: &str) -> BynarResult<u32> {
-    let stmt = format!(
-        "SELECT region_id FROM regions WHERE region_name = '{}'",
-        region
-    );
-    let stmt_query = conn.query(&stmt, &[])?;
+    let stmt_query = conn.query(
+        "SELECT region_id FROM regions WHERE region_name = $1",
+        &[&region],
+    )?;
     let mut region_id: u32 = 0;
 
     if let Some(res) = stmt_query.into_iter().next() {
@@ -472,12 +855,10 @@ fn update_region(conn: &Transaction<'_>, region: &str) -> BynarResult<u32> {
     } else {
         // does not exist, insert
         debug!("Adding region {} to database", region);
-        let stmt = format!(
-            "INSERT INTO regions (region_name)
-                            VALUES ('{}') RETURNING region_id",
-            region
-        );
-        let stmt_query = conn.query(&stmt, &[])?;
+        let stmt_query = conn.query(
+            "INSERT INTO regions (region_name) VALUES ($1) RETURNING region_id",
+            &[&region],
+        )?;
         if let Some(res) = stmt_query.into_iter().next() {
             // Exists
             let id: i32 = res.get(0);
@@ -492,24 +873,22 @@ fn update_storage_details(
     s_info: &MyHost,
     region_id: u32,
 ) -> BynarResult<u32> {
-    let stmt = format!(
-        "SELECT storage_id FROM storage_types WHERE storage_type='{}'",
-        s_info.storage_type
-    );
-    let stmt_query = conn.query(&stmt, &[])?;
+    let stmt_query = conn.query(
+        "SELECT storage_id FROM storage_types WHERE storage_type=$1",
+        &[&s_info.storage_type.to_string()],
+    )?;
     let mut storage_detail_id: u32 = 0;
+    let region_id = region_id as i32;
 
     if let Some(r) = stmt_query.into_iter().next() {
-        let sid: i32 = r.get("storage_id");
-        let storage_id: u32 = sid as u32;
+        let storage_id: i32 = r.get("storage_id");
 
         // query if these storage details are already in DB
-        let details_query = format!(
-            "SELECT detail_id FROM storage_details WHERE storage_id = {}
-            AND region_id = {} AND hostname = '{}'",
-            storage_id, region_id, s_info.hostname
-        );
-        let details_query_exec = conn.query(&details_query, &[])?;
+        let details_query_exec = conn.query(
+            "SELECT detail_id FROM storage_details WHERE storage_id = $1
+            AND region_id = $2 AND hostname = $3",
+            &[&storage_id, &region_id, &s_info.hostname],
+        )?;
         if let Some(res) = details_query_exec.into_iter().next() {
             //Exists
             let sdi: i32 = res.get("detail_id");
@@ -517,28 +896,18 @@ fn update_storage_details(
         } else {
             // TODO: modify when exact storage details are added
 
-            let mut details_query = "INSERT INTO storage_details
-            (storage_id, region_id, hostname"
-                .to_string();
-            if s_info.array_name.is_some() {
-                details_query.push_str(", name_key1");
-            }
-            if s_info.pool_name.is_some() {
-                details_query.push_str(", name_key2");
-            }
-            details_query.push_str(&format!(
-                ") VALUES ({}, {}, '{}'",
-                storage_id, region_id, s_info.hostname
-            ));
-            if let Some(ref array_name) = s_info.array_name {
-                details_query.push_str(&format!(", '{}'", array_name));
-            }
-            if let Some(ref pool_name) = s_info.pool_name {
-                details_query.push_str(&format!(", '{}'", pool_name));
-            }
-            details_query.push_str(") RETURNING detail_id");
-
-            let dqr = conn.query(&details_query, &[])?;
+            let dqr = conn.query(
+                "INSERT INTO storage_details
+                (storage_id, region_id, hostname, name_key1, name_key2)
+                VALUES ($1, $2, $3, $4, $5) RETURNING detail_id",
+                &[
+                    &storage_id,
+                    &region_id,
+                    &s_info.hostname,
+                    &s_info.array_name,
+                    &s_info.pool_name,
+                ],
+            )?;
             if let Some(res) = dqr.into_iter().next() {
                 let sdi: i32 = res.get("detail_id");
                 storage_detail_id = sdi as u32;
@@ -572,8 +941,6 @@ pub fn add_disk_detail(
     )?;
     if stmt_query.is_empty() {
         // A record doesn't exist, insert
-        let mut stmt = String::new();
-
         let mut hardware_type: i32 = 2; // this is the usual value added to DB for disk type
 
         // Get hardware_type id from DB
@@ -585,41 +952,26 @@ pub fn add_disk_detail(
             hardware_type = res.get("hardware_id");
         }
 
-        stmt.push_str(
-            "INSERT INTO hardware(detail_id, device_path, device_name, state, hardware_type",
-        );
-        if disk_info.mount_point.is_some() {
-            stmt.push_str(", mount_path");
-        }
-        if disk_info.device.id.is_some() {
-            stmt.push_str(", device_uuid");
-        }
-
-        if disk_info.device.serial_number.is_some() {
-            stmt.push_str(", serial_number");
-        }
-
-        stmt.push_str(&format!(
-            ") VALUES ({}, '{}', '{}', '{}', {}",
-            disk_info.storage_detail_id,
-            disk_info.dev_path.display(),
-            disk_info.device.name,
-            disk_info.state,
-            hardware_type
-        ));
-
-        if let Some(ref mount) = disk_info.mount_point {
-            stmt.push_str(&format!(", '{}'", mount.display()));
-        }
-        if let Some(ref uuid) = disk_info.device.id {
-            stmt.push_str(&format!(", '{}'", uuid));
-        }
-        if let Some(ref serial) = disk_info.device.serial_number {
-            stmt.push_str(&format!(", '{}'", serial));
-        }
-
-        stmt.push_str(") RETURNING device_id");
-        let stmt_q = conn.query(&stmt, &[])?;
+        let mount_path = disk_info
+            .mount_point
+            .as_ref()
+            .map(|m| format!("{}", m.display()));
+        let device_uuid = disk_info.device.id.map(|uuid| uuid.to_string());
+        let stmt_q = conn.query(
+            "INSERT INTO hardware(detail_id, device_path, device_name, state, hardware_type,
+            mount_path, device_uuid, serial_number)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING device_id",
+            &[
+                &detail_id,
+                &format!("{}", disk_info.dev_path.display()),
+                &disk_info.device.name,
+                &disk_info.state.to_string(),
+                &hardware_type,
+                &mount_path,
+                &device_uuid,
+                &disk_info.device.serial_number,
+            ],
+        )?;
         if let Some(row) = stmt_q.into_iter().next() {
             let id: i32 = row.get("device_id");
             disk_info.set_device_database_id(id as u32);
@@ -661,14 +1013,31 @@ pub fn add_disk_detail(
     }
 }
 
+// Returns the operation a process entry already has open on a device.
+// A long running process checks the same device many times under one entry_id
+// and (device_id, entry_id) is the primary key of operations.
+pub fn get_operation_id(
+    pool: &Pool<ConnectionManager>,
+    entry_id: u32,
+    device_id: u32,
+) -> BynarResult<Option<u32>> {
+    let conn = get_connection_from_pool(pool)?;
+    let stmt_query = conn.query(
+        "SELECT operation_id FROM operations WHERE device_id=$1 AND entry_id=$2",
+        &[&(device_id as i32), &(entry_id as i32)],
+    )?;
+    Ok(stmt_query.into_iter().next().map(|row| {
+        let oid: i32 = row.get("operation_id");
+        oid as u32
+    }))
+}
+
 // inserts the operation record. If successful insert, the provided input op_info
 // is modified. Returns error if insert or update fails.
 pub fn add_or_update_operation(
     pool: &Pool<ConnectionManager>,
     op_info: &mut OperationInfo,
 ) -> BynarResult<()> {
-    let mut stmt = String::new();
-
     let conn = get_connection_from_pool(pool)?;
     match op_info.operation_id {
         None => {
@@ -678,51 +1047,19 @@ pub fn add_or_update_operation(
                     "A process tracking ID is required and is missing".to_string(),
                 ));
             }
-            stmt.push_str(
+            let stmt_query = conn.query(
                 "INSERT INTO operations (
-                                    entry_id, start_time, snapshot_time, device_id",
-            );
-
-            if op_info.behalf_of.is_some() {
-                stmt.push_str(", behalf_of");
-            }
-            if op_info.reason.is_some() {
-                stmt.push_str(", reason");
-            }
-
-            stmt.push_str(")");
-
-            stmt.push_str(&format!(
-                " VALUES ({},'{}', '{}', {}",
-                op_info.entry_id, op_info.start_time, op_info.snapshot_time, op_info.device_id
-            ));
-
-            if let Some(ref behalf_of) = op_info.behalf_of {
-                stmt.push_str(&format!(", '{}'", behalf_of));
-            }
-            if let Some(ref reason) = op_info.reason {
-                stmt.push_str(&format!(", '{}'", reason));
-            }
-            stmt.push_str(") RETURNING operation_id");
-        }
-        Some(id) => {
-            // update existing record. Only snapshot_time and done_time
-            // can be updated.
-            stmt.push_str(&format!(
-                "UPDATE operations SET snapshot_time = '{}'",
-                op_info.snapshot_time
-            ));
-
-            if let Some(d_time) = op_info.done_time {
-                stmt.push_str(&format!(", done_time = '{}'", d_time));
-            }
-            stmt.push_str(&format!(" WHERE operation_id = {}", id));
-        }
-    }
-    let stmt_query = conn.query(&stmt, &[])?;
-    match op_info.operation_id {
-        None => {
-            // insert
+                    entry_id, start_time, snapshot_time, device_id, behalf_of, reason)
+                    VALUES ($1, $2, $3, $4, $5, $6) RETURNING operation_id",
+                &[
+                    &(op_info.entry_id as i32),
+                    &op_info.start_time,
+                    &op_info.snapshot_time,
+                    &(op_info.device_id as i32),
+                    &op_info.behalf_of,
+                    &op_info.reason,
+                ],
+            )?;
             if let Some(row) = stmt_query.into_iter().next() {
                 let oid: i32 = row.get("operation_id");
                 op_info.set_operation_id(oid as u32);
@@ -733,8 +1070,14 @@ pub fn add_or_update_operation(
                 ))
             }
         }
-        Some(_) => {
-            // update. even if query to update failed that's fine.
+        Some(id) => {
+            // update existing record. Only snapshot_time and done_time
+            // can be updated.
+            conn.execute(
+                "UPDATE operations SET snapshot_time = $1,
+                    done_time = COALESCE($2, done_time) WHERE operation_id = $3",
+                &[&op_info.snapshot_time, &op_info.done_time, &(id as i32)],
+            )?;
             Ok(())
         }
     }
@@ -745,16 +1088,13 @@ pub fn add_or_update_operation_detail(
     operation_detail: &mut OperationDetail,
 ) -> BynarResult<()> {
     let conn = get_connection_from_pool(pool)?;
-    let mut stmt = String::new();
     match operation_detail.op_detail_id {
         None => {
             // insert new detail record
-            let stmt2 = format!(
-                "SELECT type_id FROM operation_types WHERE
-                                op_name='{}'",
-                operation_detail.op_type
-            );
-            let stmt_query = conn.query(&stmt2, &[])?;
+            let stmt_query = conn.query(
+                "SELECT type_id FROM operation_types WHERE op_name=$1",
+                &[&operation_detail.op_type.to_string()],
+            )?;
             if stmt_query.len() != 1 {
                 return Err(BynarError::new(format!(
                     "More than one record found in database for operation {}",
@@ -770,65 +1110,57 @@ pub fn add_or_update_operation_detail(
             let row = stmt_query.get(0);
             let type_id: i32 = row.get("type_id");
 
-            stmt.push_str(
+            let stmt_query = conn.query(
                 "INSERT INTO operation_details (operation_id, type_id,
-                            status, start_time, snapshot_time",
-            );
-            if operation_detail.tracking_id.is_some() {
-                stmt.push_str(", tracking_id");
-            }
-            if operation_detail.done_time.is_some() {
-                stmt.push_str(", done_time");
-            }
-
-            stmt.push_str(&format!(
-                " ) VALUES ({}, {}, '{}', '{}', '{}'",
-                operation_detail.operation_id,
-                type_id as u32,
-                operation_detail.status,
-                operation_detail.start_time,
-                operation_detail.snapshot_time
-            ));
-
-            if let Some(ref t_id) = operation_detail.tracking_id {
-                stmt.push_str(&format!(", '{}'", t_id));
-            }
-            if let Some(done_time) = operation_detail.done_time {
-                stmt.push_str(&format!(", '{}'", done_time));
+                    status, start_time, snapshot_time, tracking_id, done_time,
+                    job_id, step, error_msg)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+                    RETURNING operation_detail_id",
+                &[
+                    &(operation_detail.operation_id as i32),
+                    &type_id,
+                    &operation_detail.status.to_string(),
+                    &operation_detail.start_time,
+                    &operation_detail.snapshot_time,
+                    &operation_detail.tracking_id,
+                    &operation_detail.done_time,
+                    &operation_detail.job_id,
+                    &operation_detail.step,
+                    &operation_detail.error_msg,
+                ],
+            )?;
+            if let Some(row) = stmt_query.into_iter().next() {
+                let oid: i32 = row.get("operation_detail_id");
+                operation_detail.set_operation_detail_id(oid as u32);
+            } else {
+                return Err(BynarError::new(
+                    "Query to insert operation detail into database failed".to_string(),
+                ));
             }
-            stmt.push_str(") RETURNING operation_detail_id");
         }
         Some(id) => {
             // update existing detail record.
-            // Only tracking_id, snapshot_time, done_time and status are update-able
-            stmt.push_str(&format!(
-                "UPDATE operation_details SET snapshot_time = '{}', 
-                            status = '{}'",
-                operation_detail.snapshot_time, operation_detail.status
-            ));
-            if let Some(ref t_id) = operation_detail.tracking_id {
-                stmt.push_str(&format!(", tracking_id = '{}'", t_id));
-            }
-            if let Some(done_time) = operation_detail.done_time {
-                stmt.push_str(&format!(", done_time = '{}'", done_time));
-            }
-            stmt.push_str(&format!(" WHERE operation_detail_id = {}", id));
-        }
-    }
-
-    let stmt_query = conn.query(&stmt, &[])?;
-    if operation_detail.op_detail_id.is_none() {
-        // insert.
-        if let Some(row) = stmt_query.into_iter().next() {
-            let oid: i32 = row.get("operation_detail_id");
-            operation_detail.set_operation_detail_id(oid as u32);
-        } else {
-            return Err(BynarError::new(
-                "Query to insert operation detail into database failed".to_string(),
-            ));
+            // Only tracking_id, snapshot_time, done_time, status, step and
+            // error_msg are update-able
+            conn.execute(
+                "UPDATE operation_details SET snapshot_time = $1, status = $2,
+                    tracking_id = COALESCE($3, tracking_id),
+                    done_time = COALESCE($4, done_time),
+                    step = COALESCE($5, step),
+                    error_msg = COALESCE($6, error_msg)
+                    WHERE operation_detail_id = $7",
+                &[
+                    &operation_detail.snapshot_time,
+                    &operation_detail.status.to_string(),
+                    &operation_detail.tracking_id,
+                    &operation_detail.done_time,
+                    &operation_detail.step,
+                    &operation_detail.error_msg,
+                    &(id as i32),
+                ],
+            )?;
         }
     }
-    // update. even if query to update failed that's fine.
     Ok(())
 }
 
@@ -847,11 +1179,10 @@ pub fn save_state(
         // Device is in database, update the state. Start a transaction to roll back if needed.
         // transaction rolls back by default.
         let transaction = conn.transaction()?;
-        let stmt = format!(
-            "UPDATE hardware SET state = '{}' WHERE device_id={}",
-            state, dev_id
-        );
-        let stmt_query = transaction.execute(&stmt, &[])?;
+        let stmt_query = transaction.execute(
+            "UPDATE hardware SET state = $1 WHERE device_id=$2",
+            &[&state.to_string(), &(dev_id as i32)],
+        )?;
         info!(
             "Updated {} rows in database with state information",
             stmt_query
@@ -877,6 +1208,94 @@ pub fn save_state(
     }
 }
 
+/// Replace the saved trace of a device's last state machine run
+pub fn save_trace(pool: &Pool<ConnectionManager>, device_detail: &BlockDevice) -> BynarResult<()> {
+    let dev_id = match device_detail.device_database_id {
+        Some(dev_id) => dev_id as i32,
+        None => {
+            return Err(BynarError::new(format!(
+                "Device {} for storage detail with id {} is not in database",
+                device_detail.device.name, device_detail.storage_detail_id
+            )));
+        }
+    };
+    debug!(
+        "Saving {} transitions for device {}",
+        device_detail.trace.len(),
+        device_detail.device.name
+    );
+    let conn = get_connection_from_pool(pool)?;
+    let transaction = conn.transaction()?;
+    transaction.execute(
+        "DELETE FROM state_transitions WHERE device_id = $1",
+        &[&dev_id],
+    )?;
+    for (step, record) in device_detail.trace.iter().enumerate() {
+        transaction.execute(
+            "INSERT INTO state_transitions (device_id, step, from_state, to_state,
+                label, result, duration_ms) VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            &[
+                &dev_id,
+                &(step as i32),
+                &record.from.to_string(),
+                &record.to.to_string(),
+                &record.label,
+                &record.result.to_string(),
+                &(record.duration.as_millis() as i32),
+            ],
+        )?;
+    }
+    transaction.commit()?;
+    Ok(())
+}
+
+/// The saved state of a disk and the transitions its last state machine
+/// run tried.  None if bynar hasn't seen the disk on that host
+pub fn get_state_and_trace(
+    pool: &Pool<ConnectionManager>,
+    hostname: &str,
+    dev_path: &Path,
+) -> BynarResult<Option<(State, Vec<TransitionRecord>)>> {
+    let conn = get_connection_from_pool(pool)?;
+    let (path, name) = path_and_name(dev_path);
+    let devices = conn.query(
+        "SELECT device_id, state FROM hardware JOIN storage_details USING (detail_id)
+            WHERE hostname = $1 AND (device_path = $2 OR device_name = $3)",
+        &[&hostname, &path, &name],
+    )?;
+    let (device_id, state) = match devices.into_iter().next() {
+        Some(row) => {
+            let device_id: i32 = row.get("device_id");
+            let state: Option<String> = row.get("state");
+            (device_id, state)
+        }
+        None => return Ok(None),
+    };
+    let state = state
+        .and_then(|s| State::from_str(&s).ok())
+        .unwrap_or(State::Unscanned);
+    let rows = conn.query(
+        "SELECT from_state, to_state, label, result, duration_ms FROM state_transitions
+            WHERE device_id = $1 ORDER BY step",
+        &[&device_id],
+    )?;
+    let mut trace = Vec::new();
+    for row in &rows {
+        let from: String = row.get("from_state");
+        let to: String = row.get("to_state");
+        let result: String = row.get("result");
+        let duration_ms: i32 = row.get("duration_ms");
+        trace.push(TransitionRecord {
+            from: State::from_str(&from)?,
+            to: State::from_str(&to)?,
+            label: row.get("label"),
+            result: State::from_str(&result)?,
+            duration: Duration::from_millis(duration_ms as u64),
+        });
+    }
+    Ok(Some((state, trace)))
+}
+
 pub fn save_smart_result(
     pool: &Pool<ConnectionManager>,
     device_detail: &BlockDevice,
@@ -892,11 +1311,10 @@ pub fn save_smart_result(
         // Device is in database, update smart_passed. Start a transaction to roll back if needed.
         // transaction rolls back by default.
         let transaction = conn.transaction()?;
-        let stmt = format!(
-            "UPDATE hardware SET smart_passed = {} WHERE device_id={}",
-            smart_passed, dev_id
-        );
-        let stmt_query = transaction.execute(&stmt, &[])?;
+        let stmt_query = transaction.execute(
+            "UPDATE hardware SET smart_passed = $1 WHERE device_id=$2",
+            &[&smart_passed, &(dev_id as i32)],
+        )?;
         info!(
             "Updated {} rows in database with smart check result",
             stmt_query
@@ -999,19 +1417,19 @@ pub fn get_smart_result(
     let conn = get_connection_from_pool(pool)?;
 
     if let Some(dev_id) = device_detail.device_database_id {
-        let stmt = format!(
-            "SELECT smart_passed FROM hardware WHERE device_id = {}",
-            dev_id
-        );
-        let stmt_query = conn.query(&stmt, &[])?;
+        let stmt_query = conn.query(
+            "SELECT smart_passed FROM hardware WHERE device_id = $1",
+            &[&(dev_id as i32)],
+        )?;
         if stmt_query.len() != 1 || stmt_query.is_empty() {
             // Query didn't return anything. Assume smart checks have not been done/passed
             Ok(false)
         } else {
             // got something from the database
             let row = stmt_query.get(0);
-            let smart_passed = row.get("smart_passed");
-            Ok(smart_passed)
+            // NULL until the first smart check is saved
+            let smart_passed: Option<bool> = row.get("smart_passed");
+            Ok(smart_passed.unwrap_or(false))
         }
     } else {
         // No entry of this device in database table. Cannot get smart_cheks info
@@ -1087,12 +1505,10 @@ pub fn resolve_ticket_in_db(pool: &Pool<ConnectionManager>, ticket_id: &str) ->
     debug!("Attempting to resolve ticket {}", ticket_id);
 
     // TODO[SD]: make sure there is one ticket with this ID
-    let stmt = format!(
-        "UPDATE operation_details SET status='{}' WHERE ticket_id='{}'",
-        OperationStatus::Complete,
-        ticket_id
-    );
-    let stmt_query = conn.execute(&stmt, &[])?;
+    let stmt_query = conn.execute(
+        "UPDATE operation_details SET status=$1 WHERE tracking_id=$2",
+        &[&OperationStatus::Complete.to_string(), &ticket_id],
+    )?;
     info!(
         "Updated {} rows in database. Ticket {} marked as complete.",
         stmt_query, ticket_id
@@ -1187,7 +1603,10 @@ pub fn get_storage_detail_id(
     // Get storage detail Id
     let stmt = "SELECT detail_id FROM storage_details WHERE storage_id = $1
             AND region_id = $2 AND hostname = $3 ";
-    let stmt_query = conn.query(&stmt, &[&storage_id,&region_id, &host_name])?;
+    let stmt_query = conn.query(
+        &stmt,
+        &[&(storage_id as i32), &(region_id as i32), &host_name],
+    )?;
 
     if let Some(res) = stmt_query.into_iter().next() {
         // Exists, return storage_id
@@ -1280,3 +1699,203 @@ pub fn get_host_name(
         Ok(None)
     }
 }
+
+// Disks are found by either their path or their name.  ie: /dev/sdb or sdb
+fn path_and_name(dev_path: &Path) -> (String, String) {
+    let path = format!("{}", dev_path.display());
+    let name = dev_path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.clone());
+    (path, name)
+}
+
+/// Returns the device_id of a disk on this host, adding it to the hardware
+/// table if bynar hasn't seen it yet.  ie: a new disk being added
+pub fn get_or_add_device_id(
+    pool: &Pool<ConnectionManager>,
+    storage_detail_id: u32,
+    dev_path: &Path,
+) -> BynarResult<u32> {
+    let conn = get_connection_from_pool(pool)?;
+    let detail_id = storage_detail_id as i32;
+    let (path, name) = path_and_name(dev_path);
+    let stmt_query = conn.query(
+        "SELECT device_id FROM hardware WHERE detail_id=$1
+            AND (device_path=$2 OR device_name=$3)",
+        &[&detail_id, &path, &name],
+    )?;
+    if let Some(row) = stmt_query.into_iter().next() {
+        let id: i32 = row.get("device_id");
+        return Ok(id as u32);
+    }
+    debug!("Adding {} to the hardware table", path);
+    let stmt_query = conn.query(
+        "INSERT INTO hardware(detail_id, device_path, device_name, state, hardware_type)
+            VALUES ($1, $2, $3, $4,
+            (SELECT hardware_id FROM hardware_types WHERE hardware_type='disk'))
+            RETURNING device_id",
+        &[&detail_id, &path, &name, &State::Unscanned.to_string()],
+    )?;
+    match stmt_query.into_iter().next() {
+        Some(row) => {
+            let id: i32 = row.get("device_id");
+            Ok(id as u32)
+        }
+        None => Err(BynarError::new(format!(
+            "Failed to add {} to database",
+            path
+        ))),
+    }
+}
+
+const JOB_QUERY: &str = "SELECT d.job_id, t.op_name,
+    COALESCE(h.device_path, h.device_name) AS device_path, d.status, d.step,
+    d.error_msg, d.start_time, d.snapshot_time, d.done_time
+    FROM operation_details d JOIN operation_types t USING (type_id)
+    JOIN operations o USING (operation_id)
+    JOIN hardware h ON o.device_id = h.device_id";
+
+fn job_from_row(row: &Row<'_>) -> BynarResult<JobInfo> {
+    let status: String = row.get("status");
+    Ok(JobInfo {
+        job_id: row.get("job_id"),
+        op_type: row.get("op_name"),
+        device_path: row.get("device_path"),
+        status: OperationStatus::from_str(&status)?,
+        step: row.get("step"),
+        error_msg: row.get("error_msg"),
+        start_time: row.get("start_time"),
+        snapshot_time: row.get("snapshot_time"),
+        done_time: row.get("done_time"),
+    })
+}
+
+/// Look up a disk-manager job by id
+pub fn get_job(pool: &Pool<ConnectionManager>, job_id: &str) -> BynarResult<Option<JobInfo>> {
+    let conn = get_connection_from_pool(pool)?;
+    let stmt_query = conn.query(&format!("{} WHERE d.job_id = $1", JOB_QUERY), &[&job_id])?;
+    match stmt_query.iter().next() {
+        Some(row) => Ok(Some(job_from_row(&row)?)),
+        None => Ok(None),
+    }
+}
+
+/// All disk-manager jobs run on a host, oldest first
+pub fn get_jobs(
+    pool: &Pool<ConnectionManager>,
+    storage_detail_id: u32,
+) -> BynarResult<Vec<JobInfo>> {
+    let conn = get_connection_from_pool(pool)?;
+    let stmt_query = conn.query(
+        &format!(
+            "{} WHERE d.job_id IS NOT NULL AND h.detail_id = $1 ORDER BY d.start_time",
+            JOB_QUERY
+        ),
+        &[&(storage_detail_id as i32)],
+    )?;
+    stmt_query.iter().map(|row| job_from_row(&row)).collect()
+}
+
+/// Jobs that were pending or running when disk-manager stopped will never
+/// finish.  Mark them failed.  Returns how many were marked
+pub fn fail_interrupted_jobs(
+    pool: &Pool<ConnectionManager>,
+    storage_detail_id: u32,
+) -> BynarResult<u64> {
+    let conn = get_connection_from_pool(pool)?;
+    let updated = conn.execute(
+        "UPDATE operation_details SET status = $1, error_msg = $2,
+            snapshot_time = now(), done_time = now()
+            WHERE job_id IS NOT NULL AND status IN ($3, $4) AND operation_id IN
+            (SELECT operation_id FROM operations JOIN hardware USING (device_id)
+            WHERE detail_id = $5)",
+        &[
+            &OperationStatus::Failed.to_string(),
+            &"Interrupted by a disk-manager restart",
+            &OperationStatus::Pending.to_string(),
+            &OperationStatus::InProgress.to_string(),
+            &(storage_detail_id as i32),
+        ],
+    )?;
+    Ok(updated)
+}
+
+/// Record where the osd on a disk sat before the disk is pulled.  Replaces
+/// anything recorded for that disk before
+pub fn save_replaced_osd(
+    pool: &Pool<ConnectionManager>,
+    storage_detail_id: u32,
+    dev_path: &Path,
+    osd: &ReplacedOsd,
+) -> BynarResult<()> {
+    let device_id = get_or_add_device_id(pool, storage_detail_id, dev_path)? as i32;
+    let conn = get_connection_from_pool(pool)?;
+    debug!("Recording osd.{} on {}", osd.osd_id, dev_path.display());
+    conn.execute(
+        "INSERT INTO replaced_osds (device_id, osd_id, crush_location, device_class, journal, db)
+            VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT (device_id) DO UPDATE SET
+            osd_id = $2, crush_location = $3, device_class = $4, journal = $5, db = $6,
+            removed_time = CURRENT_TIMESTAMP",
+        &[
+            &device_id,
+            &(osd.osd_id as i32),
+            &osd.crush_location.join(" "),
+            &osd.device_class,
+            &osd.journal,
+            &osd.db,
+        ],
+    )?;
+    Ok(())
+}
+
+/// The osd that used to be on a disk if it was replaced
+pub fn get_replaced_osd(
+    pool: &Pool<ConnectionManager>,
+    storage_detail_id: u32,
+    dev_path: &Path,
+) -> BynarResult<Option<ReplacedOsd>> {
+    let conn = get_connection_from_pool(pool)?;
+    let (path, name) = path_and_name(dev_path);
+    let stmt_query = conn.query(
+        "SELECT osd_id, crush_location, device_class, journal, db FROM replaced_osds
+            JOIN hardware USING (device_id) WHERE detail_id = $1
+            AND (device_path = $2 OR device_name = $3)",
+        &[&(storage_detail_id as i32), &path, &name],
+    )?;
+    match stmt_query.into_iter().next() {
+        Some(row) => {
+            let osd_id: i32 = row.get("osd_id");
+            let crush_location: Option<String> = row.get("crush_location");
+            Ok(Some(ReplacedOsd {
+                osd_id: osd_id as u64,
+                crush_location: crush_location
+                    .unwrap_or_default()
+                    .split_whitespace()
+                    .map(|l| l.to_string())
+                    .collect(),
+                device_class: row.get("device_class"),
+                journal: row.get("journal"),
+                db: row.get("db"),
+            }))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Forget the osd that used to be on a disk once its replacement is in
+pub fn remove_replaced_osd(
+    pool: &Pool<ConnectionManager>,
+    storage_detail_id: u32,
+    dev_path: &Path,
+) -> BynarResult<()> {
+    let conn = get_connection_from_pool(pool)?;
+    let (path, name) = path_and_name(dev_path);
+    conn.execute(
+        "DELETE FROM replaced_osds WHERE device_id IN
+            (SELECT device_id FROM hardware WHERE detail_id = $1
+            AND (device_path = $2 OR device_name = $3))",
+        &[&(storage_detail_id as i32), &path, &name],
+    )?;
+    Ok(())
+}
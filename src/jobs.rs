@@ -0,0 +1,762 @@
+//! Add and remove run in the background as jobs so a client doesn't have to
+//! hold its socket open for the 20 minutes a Ceph OSD add can take.  Clients
+//! get a job id back straight away and poll GetJobStatus for progress.
+//! Jobs are recorded in operation_details when a database is configured.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use crate::backend::{Backend, Placement, Progress};
+use crate::in_progress::{
+    self, HostDetailsMapping, JobInfo, OperationDetail, OperationInfo, OperationStatus,
+    OperationType, ReplacedOsd,
+};
+use crate::{CachedBackend, DeviceGuard};
+use api::service::{Job, JobState, Op};
+use chrono::offset::Utc;
+use helpers::error::*;
+use log::{debug, error, info, warn};
+use r2d2::Pool;
+use r2d2_postgres::PostgresConnectionManager as ConnectionManager;
+
+// Finished jobs kept in memory.  Older ones are still in the database
+const MAX_FINISHED_JOBS: usize = 100;
+
+/// A job waiting for a runner
+pub struct JobRequest {
+    job_id: String,
+    op: Op,
+    disk: PathBuf,
+    osd_id: Option<u64>,
+    // host:/path bricks the disk belongs with.  Empty unless the client
+    // gave them
+    replica_set: Vec<String>,
+    // Log what would be done without doing it
+    simulate: bool,
+    // Picks up an add that was cut short by a restart
+    resume: bool,
+    // Held until the job finishes so nothing else touches the disk
+    _guard: DeviceGuard,
+}
+
+struct JobEntry {
+    info: JobInfo,
+    cancelled: bool,
+    // The operation_details row for this job
+    detail: Option<OperationDetail>,
+}
+
+/// Where jobs get recorded
+pub struct JobDb {
+    pub pool: Pool<ConnectionManager>,
+    pub mapping: HostDetailsMapping,
+}
+
+pub struct Jobs {
+    jobs: Mutex<HashMap<String, JobEntry>>,
+    queue: Mutex<Sender<JobRequest>>,
+    db: Option<JobDb>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run_job, JobProgress, Jobs};
+    use crate::backend::{Backend, NoProgress, Placement, Progress};
+    use crate::in_progress::OperationStatus;
+    use crate::{DeviceGuard, DeviceLocks};
+    use api::service::{JobState, Op};
+    use helpers::error::*;
+    use std::cell::RefCell;
+    use std::path::Path;
+
+    // Records what was asked of it
+    struct FakeBackend {
+        placement: fn() -> BynarResult<Option<Placement>>,
+        calls: RefCell<Vec<&'static str>>,
+        // The simulate flag of every call
+        simulated: RefCell<Vec<bool>>,
+    }
+
+    impl FakeBackend {
+        fn new(placement: fn() -> BynarResult<Option<Placement>>) -> FakeBackend {
+            FakeBackend {
+                placement,
+                calls: RefCell::new(vec![]),
+                simulated: RefCell::new(vec![]),
+            }
+        }
+    }
+
+    impl Backend for FakeBackend {
+        fn add_disk(
+            &self,
+            _device: &Path,
+            _id: Option<u64>,
+            simulate: bool,
+            _progress: &dyn Progress,
+        ) -> BynarResult<()> {
+            self.simulated.borrow_mut().push(simulate);
+            self.calls.borrow_mut().push("add");
+            Ok(())
+        }
+
+        fn remove_disk(
+            &self,
+            _device: &Path,
+            simulate: bool,
+            _progress: &dyn Progress,
+        ) -> BynarResult<()> {
+            self.simulated.borrow_mut().push(simulate);
+            self.calls.borrow_mut().push("remove");
+            Ok(())
+        }
+
+        fn purge_disk(
+            &self,
+            _device: &Path,
+            simulate: bool,
+            _progress: &dyn Progress,
+        ) -> BynarResult<()> {
+            self.simulated.borrow_mut().push(simulate);
+            self.calls.borrow_mut().push("purge");
+            Ok(())
+        }
+
+        fn safe_to_remove(&self, _device: &Path, simulate: bool) -> BynarResult<bool> {
+            self.simulated.borrow_mut().push(simulate);
+            Ok(true)
+        }
+
+        fn placement(&self, _device: &Path) -> BynarResult<Option<Placement>> {
+            (self.placement)()
+        }
+    }
+
+    #[test]
+    fn test_remove_job() {
+        let (jobs, queue) = Jobs::new(None);
+        let locks = DeviceLocks::default();
+        let disk = Path::new("/dev/fake_sdc");
+
+        // Not knowing where the disk sits fails the job before anything is
+        // removed
+        let backend = FakeBackend::new(|| Err(BynarError::from("osd find failed")));
+        let guard = DeviceGuard::lock(&locks, disk).unwrap();
+        jobs.submit(Op::Remove, disk, None, vec![], false, guard)
+            .unwrap();
+        let request = queue.recv().unwrap();
+        assert!(run_job(&backend, None, &request, &NoProgress).is_err());
+        assert!(backend.calls.borrow().is_empty());
+        drop(request);
+
+        // Without a database to record the placement the osd is purged
+        let backend = FakeBackend::new(|| {
+            Ok(Some(Placement {
+                id: 3,
+                ..Default::default()
+            }))
+        });
+        let guard = DeviceGuard::lock(&locks, disk).unwrap();
+        jobs.submit(Op::Remove, disk, None, vec![], false, guard)
+            .unwrap();
+        let request = queue.recv().unwrap();
+        run_job(&backend, None, &request, &NoProgress).unwrap();
+        assert_eq!(*backend.calls.borrow(), vec!["purge"]);
+        assert_eq!(*backend.simulated.borrow(), vec![false, false]);
+    }
+
+    #[test]
+    fn test_simulated_jobs() {
+        let (jobs, queue) = Jobs::new(None);
+        let locks = DeviceLocks::default();
+        let disk = Path::new("/dev/fake_sdd");
+        let backend = FakeBackend::new(|| {
+            Ok(Some(Placement {
+                id: 4,
+                ..Default::default()
+            }))
+        });
+
+        let guard = DeviceGuard::lock(&locks, disk).unwrap();
+        jobs.submit(Op::Add, disk, None, vec![], true, guard)
+            .unwrap();
+        let request = queue.recv().unwrap();
+        run_job(&backend, None, &request, &NoProgress).unwrap();
+        drop(request);
+
+        let guard = DeviceGuard::lock(&locks, disk).unwrap();
+        jobs.submit(Op::Remove, disk, None, vec![], true, guard)
+            .unwrap();
+        let request = queue.recv().unwrap();
+        run_job(&backend, None, &request, &NoProgress).unwrap();
+
+        assert_eq!(*backend.calls.borrow(), vec!["add", "purge"]);
+        // Every backend call was told to only simulate
+        assert_eq!(*backend.simulated.borrow(), vec![true, true, true]);
+    }
+
+    #[test]
+    fn test_job_lifecycle() {
+        let (jobs, queue) = Jobs::new(None);
+        let locks = DeviceLocks::default();
+        let disk = Path::new("/dev/fake_sdb");
+
+        let guard = DeviceGuard::lock(&locks, disk).unwrap();
+        let id = jobs
+            .submit(Op::Add, disk, None, vec![], false, guard)
+            .unwrap();
+        assert_eq!(
+            jobs.get(&id).unwrap().unwrap().get_state(),
+            JobState::PENDING
+        );
+        let request = queue.recv().unwrap();
+        assert!(jobs.start(&request.job_id));
+        let progress = JobProgress {
+            jobs: &jobs,
+            job_id: &id,
+        };
+        progress.step("osd_create").unwrap();
+        let job = jobs.get(&id).unwrap().unwrap();
+        assert_eq!(job.get_state(), JobState::RUNNING);
+        assert_eq!(job.get_step(), "osd_create");
+        jobs.finish(&id, &Ok(()));
+        drop(request);
+        assert_eq!(
+            jobs.get(&id).unwrap().unwrap().get_state(),
+            JobState::COMPLETE
+        );
+        assert!(jobs.cancel(&id).is_err());
+        assert!(locks.lock().unwrap().is_empty());
+
+        // Cancelling a running job stops it at the next step
+        let guard = DeviceGuard::lock(&locks, disk).unwrap();
+        let id = jobs
+            .submit(Op::Remove, disk, None, vec![], false, guard)
+            .unwrap();
+        let request = queue.recv().unwrap();
+        assert!(jobs.start(&request.job_id));
+        jobs.cancel(&id).unwrap();
+        let progress = JobProgress {
+            jobs: &jobs,
+            job_id: &id,
+        };
+        let result = progress.step("osd_out");
+        assert!(result.is_err());
+        jobs.finish(&id, &result);
+        drop(request);
+        let job = jobs.get(&id).unwrap().unwrap();
+        assert_eq!(job.get_state(), JobState::CANCELLED);
+        assert_eq!(job.get_op(), Op::Remove);
+
+        // A pending job never starts once cancelled
+        let guard = DeviceGuard::lock(&locks, disk).unwrap();
+        let id = jobs
+            .submit(Op::Add, disk, None, vec![], false, guard)
+            .unwrap();
+        jobs.cancel(&id).unwrap();
+        let request = queue.recv().unwrap();
+        assert!(!jobs.start(&request.job_id));
+        assert_eq!(
+            jobs.jobs.lock().unwrap()[&id].info.status,
+            OperationStatus::Cancelled
+        );
+        assert_eq!(jobs.list().unwrap().len(), 3);
+    }
+}
+
+fn job_state(status: OperationStatus) -> JobState {
+    match status {
+        OperationStatus::Pending => JobState::PENDING,
+        OperationStatus::InProgress => JobState::RUNNING,
+        OperationStatus::Complete => JobState::COMPLETE,
+        OperationStatus::Failed => JobState::FAILED,
+        OperationStatus::Cancelled => JobState::CANCELLED,
+    }
+}
+
+fn is_finished(status: OperationStatus) -> bool {
+    match status {
+        OperationStatus::Pending | OperationStatus::InProgress => false,
+        _ => true,
+    }
+}
+
+fn to_proto(info: &JobInfo) -> Job {
+    let mut job = Job::new();
+    job.set_job_id(info.job_id.clone());
+    if info.op_type == OperationType::DiskRemove.to_string() {
+        job.set_op(Op::Remove);
+    } else {
+        job.set_op(Op::Add);
+    }
+    job.set_disk(info.device_path.clone());
+    job.set_state(job_state(info.status));
+    if let Some(ref step) = info.step {
+        job.set_step(step.clone());
+    }
+    if let Some(ref msg) = info.error_msg {
+        job.set_error_msg(msg.clone());
+    }
+    job.set_start_time(info.start_time.to_rfc3339());
+    job.set_update_time(info.snapshot_time.to_rfc3339());
+    if let Some(done) = info.done_time {
+        job.set_done_time(done.to_rfc3339());
+    }
+    job
+}
+
+impl JobDb {
+    // Add the operation and operation_details rows for a new job
+    fn record(&self, info: &JobInfo, op_type: OperationType) -> BynarResult<OperationDetail> {
+        let device_id = in_progress::get_or_add_device_id(
+            &self.pool,
+            self.mapping.storage_detail_id,
+            Path::new(&info.device_path),
+        )?;
+        let mut op_info = OperationInfo::new(self.mapping.entry_id, device_id);
+        op_info.behalf_of = Some("disk-manager".to_string());
+        op_info.reason = Some(format!("{} requested over the API", op_type));
+        // A device can see several jobs over the life of one disk-manager
+        if let Some(op_id) =
+            in_progress::get_operation_id(&self.pool, self.mapping.entry_id, device_id)?
+        {
+            op_info.set_operation_id(op_id);
+        }
+        in_progress::add_or_update_operation(&self.pool, &mut op_info)?;
+        let operation_id = op_info
+            .operation_id
+            .ok_or_else(|| BynarError::from("Operation id missing after insert"))?;
+        let mut detail = OperationDetail::new(operation_id, op_type);
+        detail.set_job_id(info.job_id.clone());
+        in_progress::add_or_update_operation_detail(&self.pool, &mut detail)?;
+        Ok(detail)
+    }
+
+    // Remember where a removed disk sat for the disk that replaces it
+    fn save_placement(&self, disk: &Path, placement: &Placement) -> BynarResult<()> {
+        let replaced = ReplacedOsd {
+            osd_id: placement.id,
+            crush_location: placement
+                .location
+                .iter()
+                .map(|(bucket, name)| format!("{}={}", bucket, name))
+                .collect(),
+            device_class: placement.device_class.clone(),
+            journal: placement
+                .journal
+                .as_ref()
+                .map(|j| format!("{}", j.display())),
+            db: placement.db.as_ref().map(|d| format!("{}", d.display())),
+        };
+        in_progress::save_replaced_osd(&self.pool, self.mapping.storage_detail_id, disk, &replaced)
+    }
+
+    fn placement(&self, disk: &Path) -> BynarResult<Option<Placement>> {
+        let replaced =
+            in_progress::get_replaced_osd(&self.pool, self.mapping.storage_detail_id, disk)?;
+        Ok(replaced.map(|r| Placement {
+            id: r.osd_id,
+            location: r
+                .crush_location
+                .iter()
+                .filter_map(|l| {
+                    let mut parts = l.splitn(2, '=');
+                    match (parts.next(), parts.next()) {
+                        (Some(bucket), Some(name)) => Some((bucket.to_string(), name.to_string())),
+                        _ => None,
+                    }
+                })
+                .collect(),
+            device_class: r.device_class,
+            journal: r.journal.map(PathBuf::from),
+            db: r.db.map(PathBuf::from),
+        }))
+    }
+}
+
+impl Jobs {
+    /// Returns the job registry and the queue the job runners take from
+    pub fn new(db: Option<JobDb>) -> (Arc<Jobs>, Receiver<JobRequest>) {
+        let (sender, receiver) = channel();
+        let jobs = Jobs {
+            jobs: Mutex::new(HashMap::new()),
+            queue: Mutex::new(sender),
+            db,
+        };
+        (Arc::new(jobs), receiver)
+    }
+
+    /// Queue an Add or Remove and return its job id.  A simulated job
+    /// only logs what the backend would do
+    pub fn submit(
+        &self,
+        op: Op,
+        disk: &Path,
+        osd_id: Option<u64>,
+        replica_set: Vec<String>,
+        simulate: bool,
+        guard: DeviceGuard,
+    ) -> BynarResult<String> {
+        self.queue_job(JobRequest {
+            job_id: String::new(),
+            op,
+            disk: disk.to_path_buf(),
+            osd_id,
+            replica_set,
+            simulate,
+            resume: false,
+            _guard: guard,
+        })
+    }
+
+    /// Queue a job to finish an add the backend says was interrupted
+    pub fn resume_add(&self, disk: &Path, guard: DeviceGuard) -> BynarResult<String> {
+        self.queue_job(JobRequest {
+            job_id: String::new(),
+            op: Op::Add,
+            disk: disk.to_path_buf(),
+            osd_id: None,
+            replica_set: vec![],
+            simulate: false,
+            resume: true,
+            _guard: guard,
+        })
+    }
+
+    // Gives the request a job id and queues it
+    fn queue_job(&self, mut request: JobRequest) -> BynarResult<String> {
+        let op = request.op;
+        let op_type = match op {
+            Op::Add => OperationType::DiskAdd,
+            Op::Remove => OperationType::DiskRemove,
+            _ => return Err(BynarError::new(format!("{:?} can't run as a job", op))),
+        };
+        let job_id = uuid::Uuid::new_v4().to_hyphenated().to_string();
+        request.job_id = job_id.clone();
+        let info = JobInfo {
+            job_id: job_id.clone(),
+            op_type: op_type.to_string(),
+            device_path: format!("{}", request.disk.display()),
+            status: OperationStatus::Pending,
+            step: None,
+            error_msg: None,
+            start_time: Utc::now(),
+            snapshot_time: Utc::now(),
+            done_time: None,
+        };
+        let detail = match self.db {
+            Some(ref db) => match db.record(&info, op_type) {
+                Ok(detail) => Some(detail),
+                Err(e) => {
+                    warn!("Unable to record job {} in the database: {}", job_id, e);
+                    None
+                }
+            },
+            None => None,
+        };
+        info!(
+            "Queueing {:?} of {} as job {}",
+            op,
+            request.disk.display(),
+            job_id
+        );
+        self.lock().insert(
+            job_id.clone(),
+            JobEntry {
+                info,
+                cancelled: false,
+                detail,
+            },
+        );
+        let sent = self
+            .queue
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .send(request);
+        if sent.is_err() {
+            self.lock().remove(&job_id);
+            return Err(BynarError::from("No job runners are left to run the job"));
+        }
+        Ok(job_id)
+    }
+
+    /// Look up a job.  Falls back to the database for jobs from before
+    /// disk-manager last started
+    pub fn get(&self, job_id: &str) -> BynarResult<Option<Job>> {
+        if let Some(entry) = self.lock().get(job_id) {
+            return Ok(Some(to_proto(&entry.info)));
+        }
+        match self.db {
+            Some(ref db) => Ok(in_progress::get_job(&db.pool, job_id)?
+                .as_ref()
+                .map(to_proto)),
+            None => Ok(None),
+        }
+    }
+
+    /// Every job this host knows about, oldest first
+    pub fn list(&self) -> BynarResult<Vec<Job>> {
+        let mut infos: HashMap<String, JobInfo> = HashMap::new();
+        if let Some(ref db) = self.db {
+            for info in in_progress::get_jobs(&db.pool, db.mapping.storage_detail_id)? {
+                infos.insert(info.job_id.clone(), info);
+            }
+        }
+        // Memory is never behind the database
+        for (id, entry) in self.lock().iter() {
+            infos.insert(id.clone(), entry.info.clone());
+        }
+        let mut infos: Vec<JobInfo> = infos.into_iter().map(|(_, info)| info).collect();
+        infos.sort_by_key(|info| info.start_time);
+        Ok(infos.iter().map(to_proto).collect())
+    }
+
+    /// Cancel a job.  A pending job never starts.  A running one stops at its
+    /// next step.  Anything it already did is left in place
+    pub fn cancel(&self, job_id: &str) -> BynarResult<()> {
+        let mut jobs = self.lock();
+        let entry = jobs
+            .get_mut(job_id)
+            .ok_or_else(|| BynarError::new(format!("No active job {}", job_id)))?;
+        if is_finished(entry.info.status) {
+            return Err(BynarError::new(format!("Job {} already finished", job_id)));
+        }
+        info!("Cancelling job {}", job_id);
+        entry.cancelled = true;
+        if entry.info.status == OperationStatus::Pending {
+            entry.info.status = OperationStatus::Cancelled;
+            entry.info.done_time = Some(Utc::now());
+            self.save(entry);
+        }
+        Ok(())
+    }
+
+    /// Called when a runner picks a job up.  Returns false if it was
+    /// cancelled while it waited
+    fn start(&self, job_id: &str) -> bool {
+        match self.lock().get(job_id) {
+            Some(entry) => !entry.cancelled,
+            None => false,
+        }
+    }
+
+    fn step(&self, job_id: &str, name: &str) -> BynarResult<()> {
+        let mut jobs = self.lock();
+        let entry = jobs
+            .get_mut(job_id)
+            .ok_or_else(|| BynarError::new(format!("No active job {}", job_id)))?;
+        if entry.cancelled {
+            return Err(BynarError::new(format!("Job {} was cancelled", job_id)));
+        }
+        debug!("Job {} starting step {}", job_id, name);
+        entry.info.status = OperationStatus::InProgress;
+        entry.info.step = Some(name.to_string());
+        self.save(entry);
+        Ok(())
+    }
+
+    fn finish(&self, job_id: &str, result: &BynarResult<()>) {
+        let mut jobs = self.lock();
+        if let Some(entry) = jobs.get_mut(job_id) {
+            match result {
+                Ok(_) => {
+                    info!("Job {} finished", job_id);
+                    entry.info.status = OperationStatus::Complete;
+                }
+                Err(e) if entry.cancelled => {
+                    info!("Job {} cancelled: {}", job_id, e);
+                    entry.info.status = OperationStatus::Cancelled;
+                    entry.info.error_msg = Some(format!(
+                        "Cancelled before {}.  Earlier steps weren't undone",
+                        entry.info.step.as_ref().map_or("starting", |s| s.as_str())
+                    ));
+                }
+                Err(e) => {
+                    error!("Job {} failed: {}", job_id, e);
+                    entry.info.status = OperationStatus::Failed;
+                    entry.info.error_msg = Some(e.to_string());
+                }
+            };
+            entry.info.done_time = Some(Utc::now());
+            self.save(entry);
+        }
+        // Keep memory bounded
+        let mut finished: Vec<(String, _)> = jobs
+            .iter()
+            .filter(|(_, entry)| is_finished(entry.info.status))
+            .map(|(id, entry)| (id.clone(), entry.info.start_time))
+            .collect();
+        if finished.len() > MAX_FINISHED_JOBS {
+            finished.sort_by_key(|(_, start)| *start);
+            for (id, _) in finished.iter().take(finished.len() - MAX_FINISHED_JOBS) {
+                jobs.remove(id);
+            }
+        }
+    }
+
+    // Write a job's current state to the database
+    fn save(&self, entry: &mut JobEntry) {
+        entry.info.snapshot_time = Utc::now();
+        if let (Some(db), Some(detail)) = (self.db.as_ref(), entry.detail.as_mut()) {
+            detail.set_operation_status(entry.info.status);
+            detail.set_snapshot_time(entry.info.snapshot_time);
+            if let Some(ref step) = entry.info.step {
+                detail.set_step(step.clone());
+            }
+            if let Some(ref msg) = entry.info.error_msg {
+                detail.set_error_msg(msg.clone());
+            }
+            if let Some(done) = entry.info.done_time {
+                detail.set_done_time(done);
+            }
+            if let Err(e) = in_progress::add_or_update_operation_detail(&db.pool, detail) {
+                warn!(
+                    "Unable to save job {} to the database: {}",
+                    entry.info.job_id, e
+                );
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, JobEntry>> {
+        // Nothing panics while holding this so a poisoned map is still sane
+        self.jobs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Feeds a running job's steps back into the registry
+struct JobProgress<'a> {
+    jobs: &'a Jobs,
+    job_id: &'a str,
+}
+
+impl<'a> Progress for JobProgress<'a> {
+    fn step(&self, name: &str) -> BynarResult<()> {
+        self.jobs.step(self.job_id, name)
+    }
+}
+
+/// Run queued jobs until disk-manager exits.  Each runner thread keeps its
+/// own backend
+pub fn run_jobs(jobs: &Jobs, queue: &Mutex<Receiver<JobRequest>>, backend: &mut CachedBackend) {
+    loop {
+        let request = match queue.lock().unwrap_or_else(|e| e.into_inner()).recv() {
+            Ok(request) => request,
+            // The registry is gone so we're shutting down
+            Err(_) => return,
+        };
+        if !jobs.start(&request.job_id) {
+            debug!("Job {} was cancelled before it started", request.job_id);
+            continue;
+        }
+        let progress = JobProgress {
+            jobs,
+            job_id: &request.job_id,
+        };
+        let result = backend
+            .get()
+            .and_then(|backend| run_job(backend, jobs.db.as_ref(), &request, &progress));
+        jobs.finish(&request.job_id, &result);
+    }
+}
+
+fn run_job(
+    backend: &dyn Backend,
+    db: Option<&JobDb>,
+    request: &JobRequest,
+    progress: &dyn Progress,
+) -> BynarResult<()> {
+    match request.op {
+        Op::Add if request.resume => backend.resume_add(&request.disk, progress),
+        Op::Add => match (db, replaced_placement(db, request)) {
+            (Some(db), Some(placement)) => {
+                info!(
+                    "Adding {} in place of id {}",
+                    request.disk.display(),
+                    placement.id
+                );
+                backend.replace_disk(&request.disk, &placement, request.simulate, progress)?;
+                if request.simulate {
+                    return Ok(());
+                }
+                if let Err(e) = in_progress::remove_replaced_osd(
+                    &db.pool,
+                    db.mapping.storage_detail_id,
+                    &request.disk,
+                ) {
+                    warn!("Unable to clear the replacement record: {}", e);
+                }
+                Ok(())
+            }
+            _ => backend.add_disk_in_replica_set(
+                &request.disk,
+                request.osd_id,
+                &request.replica_set,
+                request.simulate,
+                progress,
+            ),
+        },
+        Op::Remove => {
+            progress.step("safe_to_remove")?;
+            let answer = backend.check_safe_to_remove(&request.disk, request.simulate)?;
+            if !answer.safe {
+                let failed: Vec<String> = answer
+                    .checks
+                    .iter()
+                    .filter(|c| !c.passed)
+                    .map(|c| format!("{}: {}", c.name, c.detail))
+                    .collect();
+                return Err(BynarError::new(format!(
+                    "Not safe to remove disk. {}",
+                    failed.join(". ")
+                )));
+            }
+            // Has to be read before the disk is gone
+            let placement = backend.placement(&request.disk).map_err(|e| {
+                BynarError::new(format!(
+                    "Unable to find where {} sits in the cluster: {}",
+                    request.disk.display(),
+                    e
+                ))
+            })?;
+            match (db, placement) {
+                (Some(db), Some(placement)) => {
+                    // Recorded first so a kept id is never left without a
+                    // record of it.  The record stays if the remove fails
+                    // part way since the id may already be destroyed
+                    if !request.simulate {
+                        db.save_placement(&request.disk, &placement)?;
+                    }
+                    backend.remove_disk(&request.disk, request.simulate, progress)
+                }
+                // Nothing would reuse what's kept
+                _ => backend.purge_disk(&request.disk, request.simulate, progress),
+            }
+        }
+        _ => Err(BynarError::new(format!(
+            "{:?} can't run as a job",
+            request.op
+        ))),
+    }
+}
+
+// What the disk being added replaces.  An add that asks for a different id
+// than the one recorded is left alone
+fn replaced_placement(db: Option<&JobDb>, request: &JobRequest) -> Option<Placement> {
+    let placement = match db?.placement(&request.disk) {
+        Ok(placement) => placement?,
+        Err(e) => {
+            warn!(
+                "Unable to look up what {} replaces: {}",
+                request.disk.display(),
+                e
+            );
+            return None;
+        }
+    };
+    match request.osd_id {
+        Some(id) if id != placement.id => None,
+        _ => Some(placement),
+    }
+}
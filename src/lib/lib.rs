@@ -3,11 +3,18 @@
 use serde_derive::*;
 use std::fs::read_to_string;
 use std::path::Path;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Duration;
 
 use crate::error::{BynarError, BynarResult};
-use api::service::{Disk, Op, OpBoolResult, Operation, ResultType,OpJiraTicketsResult,JiraInfo};
+use api::service::{
+    Disk, ErrorCode, JiraInfo, Job, JobState, Op, OpBoolResult, OpJiraTicketsResult, OpJobResult,
+    OpJobsResult, OpPartitionResult, OpResult, Operation, Partition, ResultType,
+};
 use hashicorp_vault::client::VaultClient;
-use log::{debug, error,trace};
+use lazy_static::lazy_static;
+use log::{debug, error, info, trace};
 use protobuf::parse_from_bytes;
 use protobuf::Message as ProtobufMsg;
 use serde::de::DeserializeOwned;
@@ -15,6 +22,22 @@ use zmq::{Message, Socket};
 
 pub mod error;
 pub mod host_information;
+pub mod migrations;
+pub mod nvme;
+pub mod systemd;
+
+lazy_static! {
+    // LVM segfaults if more than one thread uses it at the same time
+    static ref LVM_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Every use of liblvm in a process has to hold this.  It isn't reentrant
+/// so don't call anything that takes it while holding it
+pub fn lock_lvm() -> MutexGuard<'static, ()> {
+    // Nothing is guarded so a thread that panicked while holding the lock
+    // doesn't leave anything broken behind
+    LVM_LOCK.lock().unwrap_or_else(PoisonError::into_inner)
+}
 
 pub fn load_config<T>(config_dir: &Path, name: &str) -> BynarResult<T>
 where
@@ -30,6 +53,11 @@ where
 }
 
 pub fn connect(host: &str, port: &str, server_publickey: &str) -> BynarResult<Socket> {
+    connect_endpoint(&format!("tcp://{}:{}", host, port), server_publickey)
+}
+
+/// Connect to a disk-manager at any zmq endpoint.  ie: ipc:///run/bynar/dm.sock
+pub fn connect_endpoint(endpoint: &str, server_publickey: &str) -> BynarResult<Socket> {
     debug!("Starting zmq sender with version({:?})", zmq::version());
     let context = zmq::Context::new();
     let requester = context.socket(zmq::REQ)?;
@@ -38,27 +66,32 @@ pub fn connect(host: &str, port: &str, server_publickey: &str) -> BynarResult<So
     requester.set_curve_serverkey(server_publickey)?;
     requester.set_curve_publickey(&client_keypair.public_key)?;
     requester.set_curve_secretkey(&client_keypair.secret_key)?;
-    debug!("Connecting to tcp://{}:{}", host, port);
-    assert!(requester
-        .connect(&format!("tcp://{}:{}", host, port))
-        .is_ok());
+    debug!("Connecting to {}", endpoint);
+    requester.connect(endpoint)?;
     debug!("Client mechanism: {:?}", requester.get_mechanism());
 
     Ok(requester)
 }
 
+// An ERR reply as an error that says why it failed
+fn reply_error(code: ErrorCode, msg: &str) -> BynarError {
+    BynarError::new(format!("{:?}: {}", code, msg))
+}
+
 pub fn get_vault_token(endpoint: &str, token: &str, hostname: &str) -> BynarResult<String> {
     let client = VaultClient::new(endpoint, token)?;
     let res = client.get_secret(&format!("/{}", hostname))?;
     Ok(res)
 }
 
+/// Ask disk-manager to add a disk.  Returns the id of the background job
+/// doing the work.  Use wait_for_job to wait for it to finish
 pub fn add_disk_request(
     s: &mut Socket,
     path: &Path,
     id: Option<u64>,
     simulate: bool,
-) -> BynarResult<()> {
+) -> BynarResult<String> {
     let mut o = Operation::new();
     debug!("Creating add disk operation request");
     o.set_Op_type(Op::Add);
@@ -79,14 +112,14 @@ pub fn add_disk_request(
     let op_result = parse_from_bytes::<api::service::OpResult>(&add_response)?;
     match op_result.get_result() {
         ResultType::OK => {
-            debug!("Add disk successful");
-            Ok(())
+            debug!("Add disk queued as job {}", op_result.get_job_id());
+            Ok(op_result.get_job_id().to_string())
         }
         ResultType::ERR => {
             if op_result.has_error_msg() {
                 let msg = op_result.get_error_msg();
                 error!("Add disk failed: {}", msg);
-                Err(BynarError::from(op_result.get_error_msg()))
+                Err(reply_error(op_result.get_error_code(), msg))
             } else {
                 error!("Add disk failed but error_msg not set");
                 Err(BynarError::from("Add disk failed but error_msg not set"))
@@ -133,6 +166,12 @@ pub fn list_disks_request(s: &mut Socket) -> BynarResult<Vec<Disk>> {
     let disks_response = s.recv_bytes(0)?;
     debug!("Decoding msg len: {}", disks_response.len());
     let disk_list = parse_from_bytes::<api::service::Disks>(&disks_response)?;
+    if disk_list.get_result() == ResultType::ERR {
+        return Err(reply_error(
+            disk_list.get_error_code(),
+            disk_list.get_error_msg(),
+        ));
+    }
 
     let mut d: Vec<Disk> = Vec::new();
     for disk in disk_list.get_disk() {
@@ -142,7 +181,40 @@ pub fn list_disks_request(s: &mut Socket) -> BynarResult<Vec<Disk>> {
     Ok(d)
 }
 
-pub fn safe_to_remove_request(s: &mut Socket, path: &Path) -> BynarResult<bool> {
+/// Ask disk-manager to create a GPT partition from start to end.  Both are
+/// inclusive LBAs and start has to be 1MiB aligned.  Returns the partition
+/// created or that would be created if simulate is set
+pub fn add_partition_request(
+    s: &mut Socket,
+    path: &Path,
+    start: u64,
+    end: u64,
+    name: Option<&str>,
+    simulate: bool,
+) -> BynarResult<Partition> {
+    let mut o = Operation::new();
+    debug!("Creating add partition operation request");
+    o.set_Op_type(Op::AddPartition);
+    o.set_disk(format!("{}", path.display()));
+    o.set_partition_start(start);
+    o.set_partition_end(end);
+    if let Some(name) = name {
+        o.set_partition_name(name.to_string());
+    }
+    o.set_simulate(simulate);
+    let op_result = parse_from_bytes::<OpPartitionResult>(&request(s, &o)?)?;
+    match op_result.get_result() {
+        ResultType::OK => Ok(op_result.get_partition().clone()),
+        ResultType::ERR => Err(reply_error(
+            op_result.get_error_code(),
+            op_result.get_error_msg(),
+        )),
+    }
+}
+
+/// Ask disk-manager if it's safe to remove a disk.  Also returns what
+/// removing it would take down.  ie: osd.3 and osd.4
+pub fn safe_to_remove_request(s: &mut Socket, path: &Path) -> BynarResult<(bool, Vec<String>)> {
     let mut o = Operation::new();
     debug!("Creating safe to remove operation request");
     o.set_Op_type(Op::SafeToRemove);
@@ -157,17 +229,32 @@ pub fn safe_to_remove_request(s: &mut Socket, path: &Path) -> BynarResult<bool>
     debug!("Decoding msg len: {}", safe_response.len());
     let op_result = parse_from_bytes::<OpBoolResult>(&safe_response)?;
     match op_result.get_result() {
-        ResultType::OK => Ok(op_result.get_value()),
-        ResultType::ERR => Err(BynarError::from(op_result.get_error_msg())),
+        ResultType::OK => {
+            for check in op_result.get_checks().iter().filter(|c| !c.get_passed()) {
+                info!(
+                    "{} failed {}: {}",
+                    path.display(),
+                    check.get_name(),
+                    check.get_detail()
+                );
+            }
+            Ok((op_result.get_value(), op_result.get_affected().to_vec()))
+        }
+        ResultType::ERR => Err(reply_error(
+            op_result.get_error_code(),
+            op_result.get_error_msg(),
+        )),
     }
 }
 
+/// Ask disk-manager to remove a disk.  Returns the id of the background job
+/// doing the work.  Use wait_for_job to wait for it to finish
 pub fn remove_disk_request(
     s: &mut Socket,
     path: &Path,
     id: Option<u64>,
     simulate: bool,
-) -> BynarResult<()> {
+) -> BynarResult<String> {
     let mut o = Operation::new();
     debug!("Creating remove operation request");
     o.set_Op_type(Op::Remove);
@@ -188,14 +275,14 @@ pub fn remove_disk_request(
     let op_result = parse_from_bytes::<api::service::OpResult>(&remove_response)?;
     match op_result.get_result() {
         ResultType::OK => {
-            debug!("Add disk successful");
-            Ok(())
+            debug!("Remove disk queued as job {}", op_result.get_job_id());
+            Ok(op_result.get_job_id().to_string())
         }
         ResultType::ERR => {
             if op_result.has_error_msg() {
                 let msg = op_result.get_error_msg();
                 error!("Remove disk failed: {}", msg);
-                Err(BynarError::from(op_result.get_error_msg()))
+                Err(reply_error(op_result.get_error_code(), msg))
             } else {
                 error!("Remove disk failed but error_msg not set");
                 Err(BynarError::from("Remove disk failed but error_msg not set"))
@@ -204,6 +291,103 @@ pub fn remove_disk_request(
     }
 }
 
+// Send an operation and return the raw reply
+fn request(s: &mut Socket, o: &Operation) -> BynarResult<Vec<u8>> {
+    let encoded = o.write_to_bytes()?;
+    let msg = Message::from_slice(&encoded)?;
+    debug!("Sending message");
+    s.send_msg(msg, 0)?;
+
+    debug!("Waiting for response");
+    let response = s.recv_bytes(0)?;
+    debug!("Decoding msg len: {}", response.len());
+    Ok(response)
+}
+
+pub fn get_job_status_request(s: &mut Socket, job_id: &str) -> BynarResult<Job> {
+    let mut o = Operation::new();
+    debug!("Creating get job status operation request");
+    o.set_Op_type(Op::GetJobStatus);
+    o.set_job_id(job_id.to_string());
+    let op_result = parse_from_bytes::<OpJobResult>(&request(s, &o)?)?;
+    match op_result.get_result() {
+        ResultType::OK => Ok(op_result.get_job().clone()),
+        ResultType::ERR => Err(reply_error(
+            op_result.get_error_code(),
+            op_result.get_error_msg(),
+        )),
+    }
+}
+
+pub fn list_jobs_request(s: &mut Socket) -> BynarResult<Vec<Job>> {
+    let mut o = Operation::new();
+    debug!("Creating list jobs operation request");
+    o.set_Op_type(Op::ListJobs);
+    let op_result = parse_from_bytes::<OpJobsResult>(&request(s, &o)?)?;
+    match op_result.get_result() {
+        ResultType::OK => Ok(op_result.get_jobs().to_vec()),
+        ResultType::ERR => Err(reply_error(
+            op_result.get_error_code(),
+            op_result.get_error_msg(),
+        )),
+    }
+}
+
+pub fn cancel_job_request(s: &mut Socket, job_id: &str) -> BynarResult<()> {
+    let mut o = Operation::new();
+    debug!("Creating cancel job operation request");
+    o.set_Op_type(Op::CancelJob);
+    o.set_job_id(job_id.to_string());
+    let op_result = parse_from_bytes::<OpResult>(&request(s, &o)?)?;
+    match op_result.get_result() {
+        ResultType::OK => Ok(()),
+        ResultType::ERR => Err(reply_error(
+            op_result.get_error_code(),
+            op_result.get_error_msg(),
+        )),
+    }
+}
+
+/// Poll a job until it's done.  Returns an error if it failed or was
+/// cancelled
+pub fn wait_for_job(s: &mut Socket, job_id: &str, interval: Duration) -> BynarResult<Job> {
+    wait_for_job_with(s, job_id, interval, |_| {})
+}
+
+/// Same as wait_for_job but calls on_poll with every status it gets back
+pub fn wait_for_job_with<F>(
+    s: &mut Socket,
+    job_id: &str,
+    interval: Duration,
+    mut on_poll: F,
+) -> BynarResult<Job>
+where
+    F: FnMut(&Job),
+{
+    let mut last_step = String::new();
+    loop {
+        let job = get_job_status_request(s, job_id)?;
+        on_poll(&job);
+        if job.get_step() != last_step {
+            debug!("Job {} is at step {}", job_id, job.get_step());
+            last_step = job.get_step().to_string();
+        }
+        match job.get_state() {
+            JobState::PENDING | JobState::RUNNING => thread::sleep(interval),
+            JobState::COMPLETE => return Ok(job),
+            JobState::FAILED | JobState::CANCELLED => {
+                return Err(BynarError::new(format!(
+                    "Job {} {:?} at step {}: {}",
+                    job_id,
+                    job.get_state(),
+                    job.get_step(),
+                    job.get_error_msg()
+                )));
+            }
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct ConfigSettings {
     pub manager_host: String,
@@ -221,15 +405,224 @@ pub struct ConfigSettings {
     pub slack_botname: Option<String>,
     pub vault_endpoint: Option<String>,
     pub vault_token: Option<String>,
-    pub jira_user: String,
-    pub jira_password: String,
-    pub jira_host: String,
-    pub jira_issue_type: String,
-    pub jira_priority: String,
-    pub jira_project_id: String,
-    pub jira_ticket_assignee: String,
+    /// The ticketing system to file repair tickets with
+    pub ticketing: Option<TicketingConfig>,
+    // The jira_* fields predate the ticketing section.  They're still
+    // honored if no ticketing section is given.
+    pub jira_user: Option<String>,
+    pub jira_password: Option<String>,
+    pub jira_host: Option<String>,
+    pub jira_issue_type: Option<String>,
+    pub jira_priority: Option<String>,
+    pub jira_project_id: Option<String>,
+    pub jira_ticket_assignee: Option<String>,
     pub proxy: Option<String>,
     pub database: DBConfig,
+    /// How often in seconds the daemon checks the disks for failures
+    #[serde(default = "default_check_interval")]
+    pub disk_check_interval: u64,
+    /// How often in seconds the daemon checks the hardware for failures
+    #[serde(default = "default_check_interval")]
+    pub hardware_check_interval: u64,
+    /// How often in seconds the daemon checks for resolved repair tickets
+    #[serde(default = "default_repair_check_interval")]
+    pub repair_check_interval: u64,
+    /// How many disks are checked at once.  Defaults to the number of cpus
+    pub disk_check_threads: Option<usize>,
+    /// When an SSD or NVMe drive is worn out enough to replace
+    #[serde(default)]
+    pub wear_leveling: WearLevelingConfig,
+    /// When an NVMe drive's health log fails it
+    #[serde(default)]
+    pub nvme_health: NvmeHealthConfig,
+    /// Ask Ceph about the osds on each disk as part of the disk checks.
+    /// Turned off if missing
+    pub ceph_health: Option<CephHealthConfig>,
+}
+
+impl ConfigSettings {
+    /// The ticketing configuration.  Falls back to the legacy jira_* fields
+    /// if there's no ticketing section
+    pub fn ticketing(&self) -> BynarResult<TicketingConfig> {
+        if let Some(ref t) = self.ticketing {
+            return Ok(t.clone());
+        }
+        let field = |value: &Option<String>, name: &str| -> BynarResult<String> {
+            value.clone().ok_or_else(|| {
+                BynarError::new(format!(
+                    "No ticketing section in config and {} is missing",
+                    name
+                ))
+            })
+        };
+        Ok(TicketingConfig::Jira(JiraConfig {
+            user: field(&self.jira_user, "jira_user")?,
+            password: field(&self.jira_password, "jira_password")?,
+            host: field(&self.jira_host, "jira_host")?,
+            issue_type: field(&self.jira_issue_type, "jira_issue_type")?,
+            priority: field(&self.jira_priority, "jira_priority")?,
+            project_id: field(&self.jira_project_id, "jira_project_id")?,
+            ticket_assignee: field(&self.jira_ticket_assignee, "jira_ticket_assignee")?,
+            // Tickets were always filed against the Ceph component
+            component: Some("Ceph".into()),
+            close_transition: None,
+        }))
+    }
+}
+
+/// Which ticketing system to use.  Selected with the "backend" field
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum TicketingConfig {
+    Jira(JiraConfig),
+    Phabricator(PhabricatorConfig),
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct JiraConfig {
+    pub user: String,
+    pub password: String,
+    pub host: String,
+    pub issue_type: String,
+    pub priority: String,
+    pub project_id: String,
+    pub ticket_assignee: String,
+    /// Optional component to file tickets under
+    pub component: Option<String>,
+    /// Name of the workflow transition used to close tickets.
+    /// Defaults to the first transition into a done status
+    pub close_transition: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PhabricatorConfig {
+    /// Base url of the Phabricator install.  ie: https://phabricator.example.com
+    pub host: String,
+    /// Conduit API token.  These start with api-
+    pub api_token: String,
+    /// PHIDs of the projects to tag new tasks with
+    #[serde(default)]
+    pub project_phids: Vec<String>,
+    /// Optional PHID of the user to assign new tasks to
+    pub owner_phid: Option<String>,
+    /// Optional priority keyword for new tasks. ie: high
+    pub priority: Option<String>,
+    /// Task statuses that mean the disk was replaced.  Defaults to resolved.
+    /// Bynar closes tasks with the first one
+    #[serde(default = "default_resolved_statuses")]
+    pub resolved_statuses: Vec<String>,
+}
+
+fn default_resolved_statuses() -> Vec<String> {
+    vec!["resolved".to_string()]
+}
+
+/// What Ceph has to say about the osds on a disk can fail it even when
+/// SMART passes.  ie: an osd that keeps crashing or getting marked down
+#[derive(Clone, Debug, Deserialize)]
+pub struct CephHealthConfig {
+    /// Ceph user to connect as.  Defaults to admin
+    #[serde(default = "default_ceph_user")]
+    pub user_id: String,
+    #[serde(default = "default_ceph_config_file")]
+    pub config_file: String,
+    /// How far back in seconds to look for crashes and flaps
+    #[serde(default = "default_ceph_health_window")]
+    pub window: u64,
+    /// Crashes within the window that fail a disk
+    #[serde(default = "default_max_crashes")]
+    pub max_crashes: usize,
+    /// Times an osd can be marked down within the window before it's
+    /// considered flapping
+    #[serde(default = "default_max_flaps")]
+    pub max_flaps: usize,
+    /// Seconds an osd has to stay down while it's still in before its disk
+    /// is failed.  Restarts and network blips mark osds down too
+    #[serde(default = "default_min_down_time")]
+    pub min_down_time: u64,
+}
+
+/// Crossing either limit marks an SSD or NVMe drive for replacement
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct WearLevelingConfig {
+    /// Percent of the rated endurance used.  NVMe percentage_used or 100
+    /// minus the ATA Media_Wearout_Indicator or Wear_Leveling_Count
+    #[serde(default = "default_max_percent_used")]
+    pub max_percent_used: u8,
+    /// Lowest percent of spare blocks left.  NVMe available_spare
+    #[serde(default = "default_min_available_spare")]
+    pub min_available_spare: u8,
+}
+
+impl Default for WearLevelingConfig {
+    fn default() -> Self {
+        WearLevelingConfig {
+            max_percent_used: default_max_percent_used(),
+            min_available_spare: default_min_available_spare(),
+        }
+    }
+}
+
+/// The NVMe health log covers the whole controller so every namespace on it
+/// fails together
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct NvmeHealthConfig {
+    /// Media errors a drive can have before it's failed.  The count never
+    /// goes down over the life of the drive
+    #[serde(default = "default_max_media_errors")]
+    pub max_media_errors: u64,
+}
+
+impl Default for NvmeHealthConfig {
+    fn default() -> Self {
+        NvmeHealthConfig {
+            max_media_errors: default_max_media_errors(),
+        }
+    }
+}
+
+fn default_max_media_errors() -> u64 {
+    10
+}
+
+fn default_max_percent_used() -> u8 {
+    90
+}
+
+fn default_min_available_spare() -> u8 {
+    10
+}
+
+fn default_ceph_user() -> String {
+    "admin".to_string()
+}
+
+fn default_ceph_config_file() -> String {
+    "/etc/ceph/ceph.conf".to_string()
+}
+
+fn default_ceph_health_window() -> u64 {
+    86400
+}
+
+fn default_max_crashes() -> usize {
+    3
+}
+
+fn default_max_flaps() -> usize {
+    3
+}
+
+fn default_min_down_time() -> u64 {
+    300
+}
+
+fn default_check_interval() -> u64 {
+    3600
+}
+
+fn default_repair_check_interval() -> u64 {
+    600
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -270,7 +663,7 @@ pub fn get_jira_tickets(s: &mut Socket) -> BynarResult<()>{
             if op_jira_result.has_error_msg() {
                 let msg = op_jira_result.get_error_msg();
                 error!("get jira tickets failed : {}", msg);
-                Err(BynarError::from(op_jira_result.get_error_msg()))
+                Err(reply_error(op_jira_result.get_error_code(), msg))
             } else {
                 error!("Get jira tickets failed but error_msg not set");
                 Err(BynarError::from("Get jira tickets failed but error_msg not set"))
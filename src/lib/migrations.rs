@@ -0,0 +1,152 @@
+//! Versioned schema migrations for the bynar database.
+//!
+//! Each migration is applied in its own transaction and bumps
+//! schema_mgmt.revision.  New schema changes are added as a new file in
+//! src/dbschema/migrations and appended to MIGRATIONS.  Never edit a
+//! migration that has shipped.
+use crate::error::{BynarError, BynarResult};
+use log::{debug, info};
+use postgres::transaction::Transaction;
+use postgres::GenericConnection;
+
+#[derive(Debug)]
+pub struct Migration {
+    pub revision: i32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// Every migration this binary knows about in revision order
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        revision: 1,
+        name: "initial",
+        sql: include_str!("../dbschema/migrations/0001_initial.sql"),
+    },
+    Migration {
+        revision: 2,
+        name: "serial_number",
+        sql: include_str!("../dbschema/migrations/0002_serial_number.sql"),
+    },
+    Migration {
+        revision: 3,
+        name: "rename_waiting_for_replacement",
+        sql: include_str!("../dbschema/migrations/0003_rename_waiting_for_replacement.sql"),
+    },
+    Migration {
+        revision: 4,
+        name: "hardware",
+        sql: include_str!("../dbschema/migrations/0004_hardware.sql"),
+    },
+    Migration {
+        revision: 5,
+        name: "jobs",
+        sql: include_str!("../dbschema/migrations/0005_jobs.sql"),
+    },
+    Migration {
+        revision: 6,
+        name: "replaced_osds",
+        sql: include_str!("../dbschema/migrations/0006_replaced_osds.sql"),
+    },
+    Migration {
+        revision: 7,
+        name: "state_transitions",
+        sql: include_str!("../dbschema/migrations/0007_state_transitions.sql"),
+    },
+    Migration {
+        revision: 8,
+        name: "replaced_osd_db",
+        sql: include_str!("../dbschema/migrations/0008_replaced_osd_db.sql"),
+    },
+];
+
+// Any fixed number works as long as every bynar agrees on it.  Stops two
+// hosts from migrating the same database at the same time
+const MIGRATION_LOCK: i64 = 0x6279_6e61_72;
+
+/// The newest revision this binary can migrate to
+pub fn latest_revision() -> i32 {
+    MIGRATIONS.last().map(|m| m.revision).unwrap_or(0)
+}
+
+/// The revision recorded in schema_mgmt.  0 for an empty database
+pub fn current_revision<C: GenericConnection>(conn: &C) -> BynarResult<i32> {
+    let exists = conn.query("SELECT to_regclass('public.schema_mgmt') IS NOT NULL", &[])?;
+    let exists: bool = exists.get(0).get(0);
+    if !exists {
+        return Ok(0);
+    }
+    let rows = conn.query(
+        "SELECT revision FROM schema_mgmt ORDER BY revision DESC LIMIT 1",
+        &[],
+    )?;
+    Ok(rows.iter().next().map(|r| r.get(0)).unwrap_or(0))
+}
+
+// Errors out if the database has been migrated by a newer bynar
+fn check_not_newer(revision: i32) -> BynarResult<()> {
+    if revision > latest_revision() {
+        return Err(BynarError::new(format!(
+            "Database schema revision {} is newer than the {} this binary knows about. \
+             Upgrade bynar before running it against this database",
+            revision,
+            latest_revision()
+        )));
+    }
+    Ok(())
+}
+
+/// The migrations that haven't been applied to this database yet
+pub fn pending_migrations<C: GenericConnection>(conn: &C) -> BynarResult<Vec<&'static Migration>> {
+    let revision = current_revision(conn)?;
+    check_not_newer(revision)?;
+    Ok(MIGRATIONS
+        .iter()
+        .filter(|m| m.revision > revision)
+        .collect())
+}
+
+// Lock out other migrators and make sure schema_mgmt exists
+fn locked_revision(transaction: &Transaction<'_>) -> BynarResult<i32> {
+    transaction.execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK])?;
+    transaction
+        .batch_execute("CREATE TABLE IF NOT EXISTS schema_mgmt (revision INTEGER PRIMARY KEY)")?;
+    let rows = transaction.query("SELECT revision FROM schema_mgmt", &[])?;
+    match rows.iter().next() {
+        Some(row) => Ok(row.get(0)),
+        None => {
+            transaction.execute("INSERT INTO schema_mgmt(revision) VALUES (0)", &[])?;
+            Ok(0)
+        }
+    }
+}
+
+/// Apply every pending migration and return the revisions applied.
+/// Fails without changing anything if the database is newer than this binary
+pub fn run_migrations<C: GenericConnection>(conn: &C) -> BynarResult<Vec<i32>> {
+    // Fail early before taking any locks
+    pending_migrations(conn)?;
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS {
+        let transaction = conn.transaction()?;
+        let revision = locked_revision(&transaction)?;
+        check_not_newer(revision)?;
+        if migration.revision <= revision {
+            // Already applied.  Possibly by another host while we waited on the lock
+            continue;
+        }
+        info!(
+            "Applying database migration {} {}",
+            migration.revision, migration.name
+        );
+        debug!("{}", migration.sql);
+        transaction.batch_execute(migration.sql)?;
+        transaction.execute(
+            "UPDATE schema_mgmt SET revision = $1",
+            &[&migration.revision],
+        )?;
+        transaction.commit()?;
+        applied.push(migration.revision);
+    }
+    Ok(applied)
+}
@@ -0,0 +1,378 @@
+//! NVMe drives don't speak ATA so libatasmart can't read their health.
+//! These ask the drive directly with admin commands sent through the
+//! kernel's passthrough ioctl.  See linux/nvme_ioctl.h
+//!
+use crate::error::{BynarError, BynarResult};
+use log::debug;
+use nix::{
+    convert_ioctl_res, ioc, ioctl_none, ioctl_readwrite, request_code_none, request_code_readwrite,
+};
+use std::fmt;
+use std::fs::{read_dir, File};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+const ADMIN_GET_LOG_PAGE: u8 = 0x02;
+const ADMIN_IDENTIFY: u8 = 0x06;
+const LOG_SMART_HEALTH: u32 = 0x02;
+const SMART_LOG_LEN: usize = 512;
+const IDENTIFY_NAMESPACE: u32 = 0x00;
+const IDENTIFY_CONTROLLER: u32 = 0x01;
+const IDENTIFY_LEN: usize = 4096;
+// Asks for the log covering every namespace on the controller
+const NSID_ALL: u32 = 0xffff_ffff;
+// The critical_warning bit for the temperature crossing a threshold
+const TEMPERATURE_WARNING: u8 = 1 << 1;
+
+/// An admin command.  struct nvme_admin_cmd in linux/nvme_ioctl.h
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct AdminCmd {
+    opcode: u8,
+    flags: u8,
+    rsvd1: u16,
+    nsid: u32,
+    cdw2: u32,
+    cdw3: u32,
+    metadata: u64,
+    addr: u64,
+    metadata_len: u32,
+    data_len: u32,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+    cdw14: u32,
+    cdw15: u32,
+    timeout_ms: u32,
+    result: u32,
+}
+
+ioctl_none! {
+    /// Linux NVME_IOCTL_ID ioctl.  Returns the namespace id of a block device
+    nvme_ioctl_id, b'N', 0x40
+}
+
+ioctl_readwrite! {
+    /// Linux NVME_IOCTL_ADMIN_CMD ioctl to send an admin command
+    nvme_admin_cmd, b'N', 0x41, AdminCmd
+}
+
+/// The SMART / health information log page
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SmartLog {
+    /// Bit field of warnings.  0 if the drive is healthy
+    pub critical_warning: u8,
+    /// Composite temperature in Kelvin
+    pub temperature: u16,
+    /// Percent of the spare blocks left
+    pub available_spare: u8,
+    /// The drive warns when available_spare drops below this
+    pub available_spare_threshold: u8,
+    /// Percent of the rated endurance used.  Can go past 100
+    pub percentage_used: u8,
+    /// Unrecovered data integrity errors
+    pub media_errors: u128,
+    pub num_err_log_entries: u128,
+}
+
+impl SmartLog {
+    pub fn celsius(&self) -> i32 {
+        i32::from(self.temperature) - 273
+    }
+
+    /// Whether the temperature is outside the drive's thresholds.  A warm
+    /// rack can do this so it doesn't fail the drive
+    pub fn temperature_warning(&self) -> bool {
+        self.critical_warning & TEMPERATURE_WARNING != 0
+    }
+
+    /// Why the drive is unhealthy.  Empty if it's fine.  More than
+    /// max_media_errors media errors fail it
+    pub fn problems(&self, max_media_errors: u64) -> Vec<String> {
+        let warnings = [
+            "available spare is below its threshold",
+            "temperature is outside its thresholds",
+            "reliability is degraded by media or internal errors",
+            "media is read only",
+            "volatile memory backup failed",
+            "persistent memory region is read only",
+        ];
+        let mut problems: Vec<String> = warnings
+            .iter()
+            .enumerate()
+            .filter(|(bit, _)| self.critical_warning & !TEMPERATURE_WARNING & (1 << bit) != 0)
+            .map(|(_, warning)| warning.to_string())
+            .collect();
+        if self.media_errors > u128::from(max_media_errors) {
+            problems.push(format!(
+                "{} media errors.  The limit is {}",
+                self.media_errors, max_media_errors
+            ));
+        }
+        problems
+    }
+}
+
+/// Identifies the controller a namespace is on
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ControllerIdentity {
+    pub vendor_id: u16,
+    pub model: String,
+    pub serial_number: String,
+    pub firmware: String,
+}
+
+impl fmt::Display for ControllerIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} serial: {} firmware: {}",
+            self.model, self.serial_number, self.firmware
+        )
+    }
+}
+
+/// Identifies a namespace.  Drives that don't report a globally unique
+/// id leave them None
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NamespaceIdentity {
+    pub nsid: u32,
+    pub nguid: Option<String>,
+    pub eui64: Option<String>,
+}
+
+impl fmt::Display for NamespaceIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.nsid)?;
+        if let Some(ref eui64) = self.eui64 {
+            write!(f, " eui64: {}", eui64)?;
+        }
+        if let Some(ref nguid) = self.nguid {
+            write!(f, " nguid: {}", nguid)?;
+        }
+        Ok(())
+    }
+}
+
+/// The NVMe namespaces the kernel found.  ie: /dev/nvme0n1
+pub fn namespaces() -> BynarResult<Vec<PathBuf>> {
+    let mut namespaces = Vec::new();
+    for entry in read_dir("/sys/block")? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if is_namespace(&name) {
+            namespaces.push(Path::new("/dev").join(name));
+        }
+    }
+    namespaces.sort();
+    Ok(namespaces)
+}
+
+// nvme<controller>n<namespace>.  Multipath devices named
+// nvme<subsystem>c<controller>n<namespace> are paths to a namespace and
+// partitions end in p<num>
+fn is_namespace(name: &str) -> bool {
+    if !name.starts_with("nvme") {
+        return false;
+    }
+    let mut parts = name[4..].splitn(2, 'n');
+    match (parts.next(), parts.next()) {
+        (Some(controller), Some(namespace)) => {
+            let numeric = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+            numeric(controller) && numeric(namespace)
+        }
+        _ => false,
+    }
+}
+
+/// Read the SMART / health log of the controller a namespace is on
+pub fn smart_log(device: &Path) -> BynarResult<SmartLog> {
+    let mut page = vec![0; SMART_LOG_LEN];
+    // The number of dwords to read minus 1 goes in the upper half
+    let dwords = (SMART_LOG_LEN / 4 - 1) as u32;
+    admin(
+        device,
+        AdminCmd {
+            opcode: ADMIN_GET_LOG_PAGE,
+            nsid: NSID_ALL,
+            cdw10: dwords << 16 | LOG_SMART_HEALTH,
+            ..Default::default()
+        },
+        &mut page,
+    )?;
+    Ok(parse_smart_log(&page))
+}
+
+pub fn identify_controller(device: &Path) -> BynarResult<ControllerIdentity> {
+    let mut data = vec![0; IDENTIFY_LEN];
+    admin(
+        device,
+        AdminCmd {
+            opcode: ADMIN_IDENTIFY,
+            cdw10: IDENTIFY_CONTROLLER,
+            ..Default::default()
+        },
+        &mut data,
+    )?;
+    Ok(parse_controller(&data))
+}
+
+pub fn identify_namespace(device: &Path) -> BynarResult<NamespaceIdentity> {
+    let file = File::open(device)?;
+    let nsid = unsafe { nvme_ioctl_id(file.as_raw_fd()) }? as u32;
+    let mut data = vec![0; IDENTIFY_LEN];
+    admin(
+        device,
+        AdminCmd {
+            opcode: ADMIN_IDENTIFY,
+            nsid,
+            cdw10: IDENTIFY_NAMESPACE,
+            ..Default::default()
+        },
+        &mut data,
+    )?;
+    Ok(parse_namespace(nsid, &data))
+}
+
+// Send an admin command that reads into data
+fn admin(device: &Path, mut cmd: AdminCmd, data: &mut [u8]) -> BynarResult<()> {
+    debug!(
+        "Sending NVMe admin command {:#04x} to {}",
+        cmd.opcode,
+        device.display()
+    );
+    let file = File::open(device)?;
+    cmd.addr = data.as_mut_ptr() as u64;
+    cmd.data_len = data.len() as u32;
+    // A positive return is the NVMe status of a command the drive rejected
+    let status = unsafe { nvme_admin_cmd(file.as_raw_fd(), &mut cmd) }?;
+    if status != 0 {
+        return Err(BynarError::new(format!(
+            "NVMe admin command {:#04x} on {} failed with status {:#x}",
+            cmd.opcode,
+            device.display(),
+            status
+        )));
+    }
+    Ok(())
+}
+
+// NVMe numbers are little endian
+fn le(bytes: &[u8]) -> u128 {
+    bytes.iter().rev().fold(0, |n, b| n << 8 | u128::from(*b))
+}
+
+// Identify strings are ascii padded with spaces
+fn ascii(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes)
+        .trim_matches(|c| c == ' ' || c == '\0')
+        .to_string()
+}
+
+// None for the all zero ids drives use when they don't have one
+fn hex_id(bytes: &[u8]) -> Option<String> {
+    if bytes.iter().all(|b| *b == 0) {
+        return None;
+    }
+    Some(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn parse_smart_log(page: &[u8]) -> SmartLog {
+    SmartLog {
+        critical_warning: page[0],
+        temperature: le(&page[1..3]) as u16,
+        available_spare: page[3],
+        available_spare_threshold: page[4],
+        percentage_used: page[5],
+        media_errors: le(&page[160..176]),
+        num_err_log_entries: le(&page[176..192]),
+    }
+}
+
+fn parse_controller(data: &[u8]) -> ControllerIdentity {
+    ControllerIdentity {
+        vendor_id: le(&data[0..2]) as u16,
+        serial_number: ascii(&data[4..24]),
+        model: ascii(&data[24..64]),
+        firmware: ascii(&data[64..72]),
+    }
+}
+
+fn parse_namespace(nsid: u32, data: &[u8]) -> NamespaceIdentity {
+    NamespaceIdentity {
+        nsid,
+        nguid: hex_id(&data[104..120]),
+        eui64: hex_id(&data[120..128]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_is_namespace() {
+        assert!(super::is_namespace("nvme0n1"));
+        assert!(super::is_namespace("nvme12n3"));
+        assert!(!super::is_namespace("nvme0n1p1"));
+        assert!(!super::is_namespace("nvme0c1n1"));
+        assert!(!super::is_namespace("nvme0"));
+        assert!(!super::is_namespace("sda"));
+    }
+
+    #[test]
+    fn test_parse_smart_log() {
+        let mut page = vec![0; super::SMART_LOG_LEN];
+        page[0] = 0b110;
+        // 350 Kelvin
+        page[1] = 0x5e;
+        page[2] = 0x01;
+        page[3] = 100;
+        page[4] = 10;
+        page[5] = 3;
+        page[160] = 0x02;
+        page[161] = 0x01;
+        let log = super::parse_smart_log(&page);
+        assert_eq!(log.temperature, 350);
+        assert_eq!(log.celsius(), 77);
+        assert_eq!(log.percentage_used, 3);
+        assert_eq!(log.media_errors, 258);
+        assert!(log.temperature_warning());
+        assert_eq!(
+            log.problems(10),
+            vec![
+                "reliability is degraded by media or internal errors",
+                "258 media errors.  The limit is 10",
+            ]
+        );
+        assert_eq!(
+            log.problems(1000),
+            vec!["reliability is degraded by media or internal errors"]
+        );
+        // Running hot alone doesn't fail a drive
+        page[0] = 0b10;
+        page[160] = 0x01;
+        page[161] = 0x00;
+        assert!(super::parse_smart_log(&page).problems(10).is_empty());
+        assert!(super::parse_smart_log(&[0; super::SMART_LOG_LEN])
+            .problems(0)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_parse_identify() {
+        let mut data = vec![0; super::IDENTIFY_LEN];
+        data[4..12].copy_from_slice(b"S3EVNX0K");
+        data[12..24].copy_from_slice(&[b' '; 12]);
+        data[24..64].copy_from_slice(format!("{:40}", "Samsung SSD 970 EVO 500GB").as_bytes());
+        data[64..72].copy_from_slice(b"2B2QEXE7");
+        let controller = super::parse_controller(&data);
+        assert_eq!(controller.serial_number, "S3EVNX0K");
+        assert_eq!(controller.model, "Samsung SSD 970 EVO 500GB");
+        assert_eq!(controller.firmware, "2B2QEXE7");
+
+        data[120..128].copy_from_slice(&[0, 0x25, 0x38, 0x5b, 0x71, 0xb0, 0x17, 0x42]);
+        let namespace = super::parse_namespace(1, &data);
+        assert_eq!(namespace.nguid, None);
+        assert_eq!(namespace.eui64, Some("0025385b71b01742".to_string()));
+        assert_eq!(namespace.to_string(), "1 eui64: 0025385b71b01742");
+    }
+}
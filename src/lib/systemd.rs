@@ -0,0 +1,105 @@
+//! Minimal sd_notify support so the daemons can report readiness and
+//! watchdog liveness to systemd without linking libsystemd.
+//!
+use crate::error::BynarResult;
+use log::{debug, trace};
+use nix::sys::socket::{
+    sendto, socket, AddressFamily, MsgFlags, SockAddr, SockFlag, SockType, UnixAddr,
+};
+use nix::unistd::close;
+use std::env;
+use std::process::id;
+use std::time::Duration;
+
+/// Send a state string such as "READY=1" or "WATCHDOG=1" to systemd.
+/// This is a no-op if the service wasn't started with a NOTIFY_SOCKET
+pub fn notify(state: &str) -> BynarResult<()> {
+    let socket_path = match env::var("NOTIFY_SOCKET") {
+        Ok(p) => p,
+        Err(_) => {
+            trace!("NOTIFY_SOCKET not set.  Skipping notify {}", state);
+            return Ok(());
+        }
+    };
+    // Sockets starting with @ live in the abstract namespace
+    let addr = if socket_path.starts_with('@') {
+        UnixAddr::new_abstract(socket_path[1..].as_bytes())?
+    } else {
+        UnixAddr::new(socket_path.as_str())?
+    };
+    debug!("Notifying systemd: {}", state);
+    let fd = socket(
+        AddressFamily::Unix,
+        SockType::Datagram,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    let result = sendto(
+        fd,
+        state.as_bytes(),
+        &SockAddr::Unix(addr),
+        MsgFlags::empty(),
+    );
+    close(fd)?;
+    result?;
+    Ok(())
+}
+
+/// How often the watchdog needs to be notified if systemd has a watchdog
+/// enabled for this process.  This is half of WatchdogSec as recommended
+/// by sd_watchdog_enabled(3)
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(id()) {
+            return None;
+        }
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    if usec == 0 {
+        return None;
+    }
+    Some(Duration::from_micros(usec / 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{notify, watchdog_interval};
+    use std::env;
+    use std::os::unix::net::UnixDatagram;
+    use std::process::id;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    // The environment is shared by every test thread so everything that
+    // touches the systemd variables lives in this one test
+    #[test]
+    fn test_notify_and_watchdog() {
+        env::remove_var("NOTIFY_SOCKET");
+        assert!(notify("READY=1").is_ok());
+
+        let dir = TempDir::new("bynar-notify").unwrap();
+        let path = dir.path().join("notify");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        env::set_var("NOTIFY_SOCKET", &path);
+        notify("READY=1").unwrap();
+        let mut buf = [0; 64];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        env::remove_var("NOTIFY_SOCKET");
+
+        env::remove_var("WATCHDOG_PID");
+        env::remove_var("WATCHDOG_USEC");
+        assert_eq!(watchdog_interval(), None);
+        env::set_var("WATCHDOG_USEC", "0");
+        assert_eq!(watchdog_interval(), None);
+        env::set_var("WATCHDOG_USEC", "30000000");
+        assert_eq!(watchdog_interval(), Some(Duration::from_secs(15)));
+        env::set_var("WATCHDOG_PID", id().to_string());
+        assert_eq!(watchdog_interval(), Some(Duration::from_secs(15)));
+        // The watchdog belongs to another process
+        env::set_var("WATCHDOG_PID", (id() + 1).to_string());
+        assert_eq!(watchdog_interval(), None);
+        env::remove_var("WATCHDOG_PID");
+        env::remove_var("WATCHDOG_USEC");
+    }
+}
@@ -6,18 +6,19 @@
 /// 4. Put disk back into cluster
 use serde_derive::*;
 
-mod create_support_ticket;
 mod in_progress;
+mod support_tickets;
 mod test_disk;
 mod test_hardware;
 #[macro_use]
 mod util;
 
-use crate::create_support_ticket::{create_support_ticket, ticket_resolved};
 use crate::in_progress::*;
+use crate::support_tickets::{load_ticket_backend, TicketBackend};
 use crate::test_disk::State;
+use block_utils::MediaType;
 use clap::{crate_authors, crate_version, App, Arg};
-use helpers::{error::*, host_information::Host, ConfigSettings};
+use helpers::{error::*, host_information::Host, nvme, ConfigSettings, DBConfig};
 use log::{debug, error, info, warn};
 use r2d2::Pool;
 use r2d2_postgres::PostgresConnectionManager as ConnectionManager;
@@ -25,6 +26,14 @@ use simplelog::{CombinedLogger, Config, SharedLogger, TermLogger, WriteLogger};
 use slack_hook::{PayloadBuilder, Slack};
 use std::fs::{create_dir, read_to_string, File};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use zmq::Socket;
+
+// How often to check on a disk-manager add or remove job
+const JOB_POLL_INTERVAL: Duration = Duration::from_secs(10);
 
 /*#[derive(Clone, Debug, Deserialize)]
 pub struct ConfigSettings {
@@ -116,8 +125,38 @@ fn get_public_key(config: &ConfigSettings, host_info: &Host) -> BynarResult<Stri
     }
 }
 
+// The controller and namespace of an NVMe drive so the technician can find it
+fn nvme_identity(dev_path: &Path) -> String {
+    let mut identity = String::new();
+    match nvme::identify_controller(dev_path) {
+        Ok(controller) => identity.push_str(&format!("\nNVMe controller: {}", controller)),
+        Err(e) => error!(
+            "Unable to identify {} controller: {}",
+            dev_path.display(),
+            e
+        ),
+    }
+    match nvme::identify_namespace(dev_path) {
+        Ok(namespace) => identity.push_str(&format!("\nNVMe namespace: {}", namespace)),
+        Err(e) => error!("Unable to identify {} namespace: {}", dev_path.display(), e),
+    }
+    // Heat doesn't fail a drive but it may explain why it did
+    match nvme::smart_log(dev_path) {
+        Ok(log) => {
+            identity.push_str(&format!("\nNVMe temperature: {}C", log.celsius()));
+            if log.temperature_warning() {
+                identity.push_str(" (outside its thresholds)");
+            }
+            identity.push_str(&format!("\nNVMe media errors: {}", log.media_errors));
+        }
+        Err(e) => error!("Unable to read {} health log: {}", dev_path.display(), e),
+    }
+    identity
+}
+
 fn check_for_failed_disks(
     config: &ConfigSettings,
+    tickets: &dyn TicketBackend,
     host_info: &Host,
     pool: &Pool<ConnectionManager>,
     host_mapping: &HostDetailsMapping,
@@ -125,8 +164,8 @@ fn check_for_failed_disks(
 ) -> BynarResult<()> {
     let public_key = get_public_key(config, &host_info)?;
     //Host information to use in ticket creation
-    let mut description = format!("A disk on {} failed. Please replace.", host_info.hostname);
-    description.push_str(&format!(
+    let mut host_description = format!("A disk on {} failed. Please replace.", host_info.hostname);
+    host_description.push_str(&format!(
         "\nHostname: {}\nServer type: {}\nServer Serial: {}\nMachine Architecture: {}\nKernel: {}",
         host_info.hostname,
         host_info.server_type,
@@ -136,7 +175,8 @@ fn check_for_failed_disks(
     ));
 
     info!("Checking all drives");
-    for result in test_disk::check_all_disks(&host_info, pool, host_mapping)? {
+    let disks = test_disk::check_all_disks(&host_info, pool, host_mapping, config)?;
+    for result in disks {
         match result {
             Ok(state_machine) => {
                 info!(
@@ -148,21 +188,33 @@ fn check_for_failed_disks(
                 dev_path.push(&dev_name);
 
                 if state_machine.block_device.state == State::WaitingForReplacement {
+                    // Each ticket only describes its own disk
+                    let mut description = host_description.clone();
                     description.push_str(&format!("\nDisk path: {}", dev_path.display()));
                     if let Some(serial) = state_machine.block_device.device.serial_number {
                         description.push_str(&format!("\nDisk serial: {}", serial));
                     }
-                    description.push_str(&format!(
-                        "\nSCSI host: {}, channel: {} id: {} lun: {}",
-                        state_machine.block_device.scsi_info.host,
-                        state_machine.block_device.scsi_info.channel,
-                        state_machine.block_device.scsi_info.id,
-                        state_machine.block_device.scsi_info.lun
-                    ));
-                    description.push_str(&format!(
-                        "\nDisk vendor: {:?}",
-                        state_machine.block_device.scsi_info.vendor
-                    ));
+                    if state_machine.block_device.device.media_type == MediaType::NVME {
+                        // NVMe drives aren't behind a scsi host
+                        description.push_str(&nvme_identity(&dev_path));
+                    } else {
+                        description.push_str(&format!(
+                            "\nSCSI host: {}, channel: {} id: {} lun: {}",
+                            state_machine.block_device.scsi_info.host,
+                            state_machine.block_device.scsi_info.channel,
+                            state_machine.block_device.scsi_info.id,
+                            state_machine.block_device.scsi_info.lun
+                        ));
+                        description.push_str(&format!(
+                            "\nDisk vendor: {:?}",
+                            state_machine.block_device.scsi_info.vendor
+                        ));
+                    }
+                    // Shows the technician why the disk was condemned
+                    description.push_str("\nState transitions:");
+                    for record in &state_machine.block_device.trace {
+                        description.push_str(&format!("\n  {}", record));
+                    }
                     info!("Connecting to database to check if disk is in progress");
                     let in_progress = in_progress::is_hardware_waiting_repair(
                         pool,
@@ -182,10 +234,15 @@ fn check_for_failed_disks(
                                 &config.manager_port.to_string(),
                                 &public_key,
                             )?;
-                            match (
-                                helpers::safe_to_remove_request(&mut socket, &dev_path),
-                                config.slack_webhook.is_some(),
-                            ) {
+                            let safe = helpers::safe_to_remove_request(&mut socket, &dev_path);
+                            // ie: every osd on a device split into several
+                            if let Ok((_, ref affected)) = safe {
+                                if !affected.is_empty() {
+                                    description
+                                        .push_str(&format!("\nAffected: {}", affected.join(", ")));
+                                }
+                            }
+                            match (safe.map(|(safe, _)| safe), config.slack_webhook.is_some()) {
                                 (Ok(true), true) => {
                                     debug!("safe to remove: true");
                                     //Ok to remove the disk
@@ -203,7 +260,9 @@ fn check_for_failed_disks(
                                         &dev_path,
                                         None,
                                         false,
-                                    ) {
+                                    )
+                                    .and_then(|job_id| wait_for_job(&mut socket, &job_id))
+                                    {
                                         Ok(_) => {
                                             debug!("Disk removal successful");
                                         }
@@ -240,8 +299,7 @@ fn check_for_failed_disks(
                                 (..) => {}
                             };
                             debug!("Creating support ticket");
-                            let ticket_id =
-                                create_support_ticket(config, "Bynar: Dead disk", &description)?;
+                            let ticket_id = tickets.create("Bynar: Dead disk", &description)?;
                             debug!("Recording ticket id {} in database", ticket_id);
                             let op_id = match state_machine.block_device.operation_id {
                                 None => {
@@ -281,7 +339,7 @@ fn check_for_failed_disks(
 
 fn evaluate(
     results: Vec<BynarResult<()>>,
-    config: &ConfigSettings,
+    tickets: &dyn TicketBackend,
     pool: &Pool<ConnectionManager>,
     host_mapping: &HostDetailsMapping,
 ) -> BynarResult<()> {
@@ -306,11 +364,8 @@ fn evaluate(
                         debug!("Creating support ticket");
                         let mut op_info = OperationInfo::new(host_mapping.entry_id, 0);
                         add_or_update_operation(pool, &mut op_info)?;
-                        let ticket_id = create_support_ticket(
-                            config,
-                            "Bynar: Hardware Failure",
-                            &format!("{}", e),
-                        )?;
+                        let ticket_id =
+                            tickets.create("Bynar: Hardware Failure", &format!("{}", e))?;
                         let op_id = match op_info.operation_id {
                             None => {
                                 error!("Operation not recorded for {}", "",);
@@ -338,6 +393,7 @@ fn evaluate(
 
 fn check_for_failed_hardware(
     config: &ConfigSettings,
+    tickets: &dyn TicketBackend,
     host_info: &Host,
     pool: &Pool<ConnectionManager>,
     host_mapping: &HostDetailsMapping,
@@ -356,19 +412,19 @@ fn check_for_failed_hardware(
     let results = test_hardware::check_hardware(&config)?;
     if !simulate {
         // Check if evaluate found any errors and log anything other then hardware errors
-        if let Err(e) = evaluate(results.disk_drives, config, pool, host_mapping) {
+        if let Err(e) = evaluate(results.disk_drives, tickets, pool, host_mapping) {
             error!("Disk drive evaluation error: {:?}", e);
         }
-        if let Err(e) = evaluate(results.manager, config, pool, host_mapping) {
+        if let Err(e) = evaluate(results.manager, tickets, pool, host_mapping) {
             error!("Hardware manager evaluation error: {:?}", e);
         }
-        if let Err(e) = evaluate(results.power, config, pool, host_mapping) {
+        if let Err(e) = evaluate(results.power, tickets, pool, host_mapping) {
             error!("Power supply evaluation error: {:?}", e);
         }
-        if let Err(e) = evaluate(results.storage_enclosures, config, pool, host_mapping) {
+        if let Err(e) = evaluate(results.storage_enclosures, tickets, pool, host_mapping) {
             error!("Storage enclosures evaluation error: {:?}", e);
         }
-        if let Err(e) = evaluate(results.thermals, config, pool, host_mapping) {
+        if let Err(e) = evaluate(results.thermals, tickets, pool, host_mapping) {
             error!("Thermal evaluation error: {:?}", e);
         }
     }
@@ -378,6 +434,7 @@ fn check_for_failed_hardware(
 
 fn add_repaired_disks(
     config: &ConfigSettings,
+    tickets: &dyn TicketBackend,
     host_info: &Host,
     pool: &Pool<ConnectionManager>,
     storage_detail_id: u32,
@@ -386,11 +443,11 @@ fn add_repaired_disks(
     let public_key = get_public_key(&config, &host_info)?;
 
     info!("Getting outstanding repair tickets");
-    let tickets = in_progress::get_outstanding_repair_tickets(&pool, storage_detail_id)?;
-    debug!("outstanding tickets: {:?}", tickets);
+    let outstanding = in_progress::get_outstanding_repair_tickets(&pool, storage_detail_id)?;
+    debug!("outstanding tickets: {:?}", outstanding);
     info!("Checking for resolved repair tickets");
-    for ticket in tickets {
-        match ticket_resolved(config, &ticket.ticket_id.to_string()) {
+    for ticket in outstanding {
+        match tickets.ticket_resolved(&ticket.ticket_id.to_string()) {
             Ok(true) => {
                 //CALL RPC
                 debug!("Connecting to disk-manager");
@@ -400,12 +457,11 @@ fn add_repaired_disks(
                     &public_key,
                 )?;
 
-                match helpers::add_disk_request(
-                    &mut socket,
-                    &Path::new(&ticket.device_path),
-                    None,
-                    simulate,
-                ) {
+                let dev_path = Path::new(&ticket.device_path);
+                let osd_id = replaced_osd_id(pool, storage_detail_id, dev_path);
+                match helpers::add_disk_request(&mut socket, dev_path, osd_id, simulate)
+                    .and_then(|job_id| wait_for_job(&mut socket, &job_id))
+                {
                     Ok(_) => {
                         debug!("Disk added successfully. Updating database record");
                         match in_progress::resolve_ticket_in_db(pool, &ticket.ticket_id) {
@@ -414,9 +470,28 @@ fn add_repaired_disks(
                                 error!("Failed to resolve ticket {}.  {:?}", ticket.ticket_id, e)
                             }
                         };
+                        if !simulate {
+                            let comment =
+                                format!("Bynar added {} back to the cluster", dev_path.display());
+                            if let Err(e) = tickets
+                                .add_comment(&ticket.ticket_id, &comment)
+                                .and_then(|_| tickets.close_ticket(&ticket.ticket_id))
+                            {
+                                warn!("Unable to close ticket {}: {:?}", ticket.ticket_id, e);
+                            }
+                        }
                     }
                     Err(e) => {
                         error!("Failed to add disk: {:?}", e);
+                        // The technician marked it resolved and needs to
+                        // know the disk isn't back in yet
+                        if !simulate {
+                            let comment =
+                                format!("Bynar couldn't add {} back: {}", dev_path.display(), e);
+                            if let Err(e) = tickets.add_comment(&ticket.ticket_id, &comment) {
+                                warn!("Unable to comment on ticket {}: {:?}", ticket.ticket_id, e);
+                            }
+                        }
                     }
                 };
             }
@@ -432,6 +507,265 @@ fn add_repaired_disks(
     Ok(())
 }
 
+// The id of the osd a disk replaces.  Reusing it keeps the crush map and
+// placement group mapping stable
+fn replaced_osd_id(
+    pool: &Pool<ConnectionManager>,
+    storage_detail_id: u32,
+    dev_path: &Path,
+) -> Option<u64> {
+    match in_progress::get_replaced_osd(pool, storage_detail_id, dev_path) {
+        Ok(replaced) => replaced.map(|r| r.osd_id),
+        Err(e) => {
+            error!(
+                "Unable to look up the osd {} replaces: {:?}",
+                dev_path.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// The independent pieces of work bynar does on every run
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Phase {
+    FailedDisks,
+    FailedHardware,
+    RepairedDisks,
+}
+
+const PHASES: [Phase; 3] = [
+    Phase::FailedDisks,
+    Phase::FailedHardware,
+    Phase::RepairedDisks,
+];
+
+impl Phase {
+    // How long the daemon waits between runs of this phase
+    fn interval(self, config: &ConfigSettings) -> Duration {
+        let secs = match self {
+            Phase::FailedDisks => config.disk_check_interval,
+            Phase::FailedHardware => config.hardware_check_interval,
+            Phase::RepairedDisks => config.repair_check_interval,
+        };
+        Duration::from_secs(secs)
+    }
+}
+
+// When each phase runs next and when systemd's watchdog was last fed
+struct Schedule {
+    next_run: [Instant; 3],
+    watchdog: Option<Duration>,
+    last_watchdog: Instant,
+}
+
+impl Schedule {
+    // Every phase runs on the first pass
+    fn new(watchdog: Option<Duration>, now: Instant) -> Schedule {
+        Schedule {
+            next_run: [now; 3],
+            watchdog,
+            last_watchdog: now,
+        }
+    }
+
+    fn is_due(&self, phase: Phase, now: Instant) -> bool {
+        now >= self.next_run[phase as usize]
+    }
+
+    fn ran(&mut self, phase: Phase, now: Instant, interval: Duration) {
+        self.next_run[phase as usize] = now + interval;
+    }
+
+    // True when the watchdog needs feeding.  The caller is expected to
+    // notify systemd
+    fn feed_watchdog(&mut self, now: Instant) -> bool {
+        match self.watchdog {
+            Some(interval) if now.duration_since(self.last_watchdog) >= interval => {
+                self.last_watchdog = now;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+fn run_phase(
+    phase: Phase,
+    config: &ConfigSettings,
+    tickets: &dyn TicketBackend,
+    host_info: &Host,
+    pool: &Pool<ConnectionManager>,
+    host_details_mapping: &HostDetailsMapping,
+    simulate: bool,
+) {
+    match phase {
+        Phase::FailedDisks => {
+            match check_for_failed_disks(
+                config,
+                tickets,
+                host_info,
+                pool,
+                host_details_mapping,
+                simulate,
+            ) {
+                Err(e) => {
+                    error!("Check for failed disks failed with error: {}", e);
+                }
+                _ => {
+                    info!("Check for failed disks completed");
+                }
+            };
+        }
+        Phase::FailedHardware => {
+            match check_for_failed_hardware(
+                config,
+                tickets,
+                host_info,
+                pool,
+                host_details_mapping,
+                simulate,
+            ) {
+                Err(e) => {
+                    error!("Check for failed hardware failed with error: {}", e);
+                }
+                _ => {
+                    info!("Check for failed hardware completed");
+                }
+            };
+        }
+        Phase::RepairedDisks => {
+            match add_repaired_disks(
+                config,
+                tickets,
+                host_info,
+                pool,
+                host_details_mapping.storage_detail_id,
+                simulate,
+            ) {
+                Err(e) => {
+                    error!("Add repaired disks failed with error: {}", e);
+                }
+                _ => {
+                    info!("Add repaired disks completed");
+                }
+            };
+        }
+    }
+}
+
+// Reload bynar.json, the ticket backend and reconnect to the database.
+// On any failure the previous config, backend and pool are kept
+fn reload_config(
+    config_dir: &Path,
+    config: &mut ConfigSettings,
+    tickets: &mut Box<dyn TicketBackend>,
+    pool: &mut Pool<ConnectionManager>,
+) -> BynarResult<()> {
+    let new_config: ConfigSettings = helpers::load_config(config_dir, "bynar.json")?;
+    if !same_database(&config.database, &new_config.database) {
+        return Err(BynarError::from(
+            "The database can't be changed by a reload.  Restart bynar to switch databases",
+        ));
+    }
+    let new_tickets = load_ticket_backend(&new_config)?;
+    let new_pool = create_db_connection_pool(&new_config.database)?;
+    *config = new_config;
+    *tickets = new_tickets;
+    *pool = new_pool;
+    Ok(())
+}
+
+// The process and host ids bynar registered with only exist in the database
+// it started with.  New credentials for it are fine
+fn same_database(old: &DBConfig, new: &DBConfig) -> bool {
+    old.endpoint == new.endpoint && old.port == new.port && old.dbname == new.dbname
+}
+
+// Drains and ramps can keep a job running for hours.  Every answer from
+// disk-manager feeds systemd's watchdog so bynar isn't killed part way
+fn wait_for_job(socket: &mut Socket, job_id: &str) -> BynarResult<()> {
+    helpers::wait_for_job_with(socket, job_id, JOB_POLL_INTERVAL, |_| {
+        notify_systemd("WATCHDOG=1")
+    })?;
+    Ok(())
+}
+
+fn notify_systemd(state: &str) {
+    if let Err(e) = helpers::systemd::notify(state) {
+        warn!("Unable to notify systemd of {}: {}", state, e);
+    }
+}
+
+// Run the phases on their intervals until SIGTERM or SIGINT
+fn run_daemon(
+    config_dir: &Path,
+    mut config: ConfigSettings,
+    mut tickets: Box<dyn TicketBackend>,
+    host_info: &Host,
+    mut pool: Pool<ConnectionManager>,
+    host_details_mapping: &HostDetailsMapping,
+    simulate: bool,
+) {
+    let reload = Arc::new(AtomicBool::new(false));
+    let terminate = Arc::new(AtomicBool::new(false));
+    for (signal, flag) in &[
+        (signal_hook::SIGHUP, &reload),
+        (signal_hook::SIGTERM, &terminate),
+        (signal_hook::SIGINT, &terminate),
+    ] {
+        if let Err(e) = signal_hook::flag::register(*signal, Arc::clone(flag)) {
+            error!("Unable to register handler for signal {}: {}", signal, e);
+            return;
+        }
+    }
+    let watchdog = helpers::systemd::watchdog_interval();
+    debug!("systemd watchdog interval: {:?}", watchdog);
+    let mut schedule = Schedule::new(watchdog, Instant::now());
+
+    info!("Running as a daemon");
+    notify_systemd("READY=1");
+    while !terminate.load(Ordering::SeqCst) {
+        if reload.swap(false, Ordering::SeqCst) {
+            info!("Reloading {}", config_dir.join("bynar.json").display());
+            notify_systemd("RELOADING=1");
+            if let Err(e) = reload_config(config_dir, &mut config, &mut tickets, &mut pool) {
+                error!("Failed to reload config.  Keeping the old one: {}", e);
+            }
+            notify_systemd("READY=1");
+        }
+        for phase in PHASES.iter() {
+            if terminate.load(Ordering::SeqCst) {
+                break;
+            }
+            if schedule.is_due(*phase, Instant::now()) {
+                run_phase(
+                    *phase,
+                    &config,
+                    tickets.as_ref(),
+                    host_info,
+                    &pool,
+                    host_details_mapping,
+                    simulate,
+                );
+                schedule.ran(*phase, Instant::now(), phase.interval(&config));
+            }
+        }
+        // The watchdog is only fed between phases and while waiting on
+        // disk-manager jobs so a hung check gets bynar restarted
+        if schedule.feed_watchdog(Instant::now()) {
+            notify_systemd("WATCHDOG=1");
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+    info!("Shutting down");
+    notify_systemd("STOPPING=1");
+    if let Err(e) = deregister_from_process_manager(&pool, host_details_mapping.entry_id) {
+        error!("Failed to deregister from process manager: {}", e);
+    }
+}
+
 // 1. Gather a list of all the disks
 // 2. Check every disk
 // 3. Decide if a disk needs to be replaced
@@ -457,6 +791,12 @@ fn main() {
                 .long("simulate")
                 .required(false),
         )
+        .arg(
+            Arg::with_name("daemon")
+                .help("Keep running and check on the intervals set in bynar.json")
+                .long("daemon")
+                .required(false),
+        )
         .arg(
             Arg::with_name("v")
                 .short("v")
@@ -498,6 +838,7 @@ fn main() {
         }
     }
     let simulate = matches.is_present("simulate");
+    let daemon = matches.is_present("daemon");
     let h_info = Host::new();
     if h_info.is_err() {
         error!("Failed to gather host information");
@@ -517,6 +858,13 @@ fn main() {
         return;
     }
     let config: ConfigSettings = config.expect("Failed to load config");
+    let tickets = match load_ticket_backend(&config) {
+        Err(e) => {
+            error!("Failed to load ticketing backend {}", e);
+            return;
+        }
+        Ok(t) => t,
+    };
 
     let db_pool = match create_db_connection_pool(&config.database) {
         Err(e) => {
@@ -526,6 +874,11 @@ fn main() {
         Ok(p) => p,
     };
 
+    if let Err(e) = migrate_database(&db_pool) {
+        error!("Failed to migrate database {}", e);
+        return;
+    }
+
     // Successfully opened a a database pool. Update information about host
     let host_details_mapping: HostDetailsMapping = match update_storage_info(&host_info, &db_pool) {
         Err(e) => {
@@ -539,46 +892,103 @@ fn main() {
         }
     };
 
-    match check_for_failed_disks(
-        &config,
-        &host_info,
-        &db_pool,
-        &host_details_mapping,
-        simulate,
-    ) {
-        Err(e) => {
-            error!("Check for failed disks failed with error: {}", e);
-        }
-        _ => {
-            info!("Check for failed disks completed");
-        }
-    };
-    match check_for_failed_hardware(
-        &config,
-        &host_info,
-        &db_pool,
-        &host_details_mapping,
-        simulate,
-    ) {
-        Err(e) => {
-            error!("Check for failed hardware failed with error: {}", e);
+    if daemon {
+        run_daemon(
+            config_dir,
+            config,
+            tickets,
+            &host_info,
+            db_pool,
+            &host_details_mapping,
+            simulate,
+        );
+    } else {
+        for phase in &PHASES {
+            run_phase(
+                *phase,
+                &config,
+                tickets.as_ref(),
+                &host_info,
+                &db_pool,
+                &host_details_mapping,
+                simulate,
+            );
         }
-        _ => {
-            info!("Check for failed hardware completed");
+        if let Err(e) = deregister_from_process_manager(&db_pool, host_details_mapping.entry_id) {
+            error!("Failed to deregister from process manager: {}", e);
         }
-    };
-    match add_repaired_disks(
-        &config,
-        &host_info,
-        &db_pool,
-        host_details_mapping.storage_detail_id,
-        simulate,
-    ) {
-        Err(e) => {
-            error!("Add repaired disks failed with error: {}", e);
-        }
-        _ => {
-            info!("Add repaired disks completed");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{same_database, Phase, Schedule, PHASES};
+    use helpers::{ConfigSettings, DBConfig};
+    use std::path::Path;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_phase_interval() {
+        // The shipped config leaves the intervals at their defaults
+        let config: ConfigSettings = helpers::load_config(
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("config"),
+            "bynar.json",
+        )
+        .unwrap();
+        assert_eq!(
+            Phase::FailedDisks.interval(&config),
+            Duration::from_secs(3600)
+        );
+        assert_eq!(
+            Phase::FailedHardware.interval(&config),
+            Duration::from_secs(3600)
+        );
+        assert_eq!(
+            Phase::RepairedDisks.interval(&config),
+            Duration::from_secs(600)
+        );
+    }
+
+    #[test]
+    fn test_same_database() {
+        let db = |endpoint: &str, password: Option<&str>| DBConfig {
+            username: "postgres".into(),
+            password: password.map(String::from),
+            port: 5432,
+            endpoint: endpoint.into(),
+            dbname: "bynar".into(),
+        };
+        assert!(same_database(&db("db1", None), &db("db1", Some("secret"))));
+        assert!(!same_database(&db("db1", None), &db("db2", None)));
+    }
+
+    #[test]
+    fn test_schedule_phases() {
+        let start = Instant::now();
+        let mut schedule = Schedule::new(None, start);
+        for phase in PHASES.iter() {
+            assert!(schedule.is_due(*phase, start));
         }
-    };
+
+        schedule.ran(Phase::FailedDisks, start, Duration::from_secs(60));
+        assert!(!schedule.is_due(Phase::FailedDisks, start + Duration::from_secs(59)));
+        assert!(schedule.is_due(Phase::FailedDisks, start + Duration::from_secs(60)));
+        // The other phases keep their own times
+        assert!(schedule.is_due(Phase::FailedHardware, start));
+        assert!(schedule.is_due(Phase::RepairedDisks, start));
+    }
+
+    #[test]
+    fn test_schedule_watchdog() {
+        let start = Instant::now();
+        let mut schedule = Schedule::new(None, start);
+        assert!(!schedule.feed_watchdog(start + Duration::from_secs(3600)));
+
+        let mut schedule = Schedule::new(Some(Duration::from_secs(10)), start);
+        assert!(!schedule.feed_watchdog(start + Duration::from_secs(9)));
+        assert!(schedule.feed_watchdog(start + Duration::from_secs(10)));
+        // Fed at 10s so the next one is due at 20s
+        assert!(!schedule.feed_watchdog(start + Duration::from_secs(19)));
+        assert!(schedule.feed_watchdog(start + Duration::from_secs(20)));
+    }
 }
@@ -0,0 +1,208 @@
+use crate::support_tickets::TicketBackend;
+
+use goji::issues::*;
+use goji::{Credentials, Jira};
+use helpers::{error::*, JiraConfig};
+use log::debug;
+use serde_json::{json, value::Value};
+
+/// JIRA ticketing
+pub struct JiraBackend {
+    config: JiraConfig,
+    jira: Jira,
+    // Used for the REST calls goji doesn't cover
+    client: reqwest::Client,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::JiraBackend;
+    use crate::support_tickets::mock::serve as mock_jira;
+    use crate::support_tickets::TicketBackend;
+    use helpers::JiraConfig;
+
+    const TRANSITIONS: &str = r#"{"transitions":[
+        {"id":"11","name":"Start Progress","to":{"statusCategory":{"key":"indeterminate"}}},
+        {"id":"31","name":"Done","to":{"statusCategory":{"key":"done"}}}]}"#;
+
+    fn backend(host: String, close_transition: Option<&str>) -> JiraBackend {
+        let config = JiraConfig {
+            user: "bynar".into(),
+            password: "secret".into(),
+            host,
+            issue_type: "3".into(),
+            priority: "4".into(),
+            project_id: "DC".into(),
+            ticket_assignee: "dc-ops".into(),
+            component: None,
+            close_transition: close_transition.map(|t| t.to_string()),
+        };
+        JiraBackend::new(config, None).unwrap()
+    }
+
+    #[test]
+    fn test_comment_and_close() {
+        let (url, handle) = mock_jira(vec!["{}", TRANSITIONS, "{}"]);
+        let jira = backend(url, None);
+        jira.add_comment("DC-7", "Disk was replaced").unwrap();
+        jira.close_ticket("DC-7").unwrap();
+        let requests = handle.join().unwrap();
+        assert!(requests[0].starts_with("POST /rest/api/2/issue/DC-7/comment "));
+        assert!(requests[0].contains(r#"{"body":"Disk was replaced"}"#));
+        assert!(requests[1].starts_with("GET /rest/api/2/issue/DC-7/transitions "));
+        // Without a configured transition the first one into done is used
+        assert!(requests[2].starts_with("POST /rest/api/2/issue/DC-7/transitions "));
+        assert!(requests[2].contains(r#"{"transition":{"id":"31"}}"#));
+    }
+
+    #[test]
+    fn test_close_transition() {
+        let (url, handle) = mock_jira(vec![TRANSITIONS, "{}", TRANSITIONS]);
+        jira_close(&url, Some("Start Progress")).unwrap();
+        assert!(jira_close(&url, Some("Resolve")).is_err());
+        let requests = handle.join().unwrap();
+        assert!(requests[1].contains(r#"{"transition":{"id":"11"}}"#));
+        // Nothing is posted when the transition doesn't exist
+        assert_eq!(requests.len(), 3);
+    }
+
+    fn jira_close(url: &str, close_transition: Option<&str>) -> helpers::error::BynarResult<()> {
+        backend(url.to_string(), close_transition).close_ticket("DC-8")
+    }
+}
+
+impl JiraBackend {
+    pub fn new(config: JiraConfig, proxy: Option<&String>) -> BynarResult<JiraBackend> {
+        let client = match proxy {
+            Some(url) => reqwest::Client::builder()
+                .proxy(reqwest::Proxy::all(url)?)
+                .build()?,
+            None => reqwest::Client::new(),
+        };
+        let jira = Jira::from_client(
+            config.host.to_string(),
+            Credentials::Basic(config.user.clone(), config.password.clone()),
+            client.clone(),
+        )?;
+        Ok(JiraBackend {
+            config,
+            jira,
+            client,
+        })
+    }
+
+    fn issue_url(&self, issue_id: &str, path: &str) -> String {
+        format!(
+            "{}/rest/api/2/issue/{}/{}",
+            self.config.host.trim_end_matches('/'),
+            issue_id,
+            path
+        )
+    }
+
+    fn get(&self, url: &str) -> BynarResult<Value> {
+        debug!("GET {}", url);
+        let value = self
+            .client
+            .get(url)
+            .basic_auth(&self.config.user, Some(&self.config.password))
+            .send()?
+            .error_for_status()?
+            .json()?;
+        Ok(value)
+    }
+
+    fn post(&self, url: &str, body: &Value) -> BynarResult<()> {
+        debug!("POST {}: {}", url, body);
+        self.client
+            .post(url)
+            .basic_auth(&self.config.user, Some(&self.config.password))
+            .json(body)
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+impl TicketBackend for JiraBackend {
+    fn create(&self, title: &str, description: &str) -> BynarResult<String> {
+        let issue_description = CreateIssue {
+            fields: Fields {
+                assignee: Assignee {
+                    name: self.config.ticket_assignee.clone(),
+                },
+                components: self
+                    .config
+                    .component
+                    .iter()
+                    .map(|c| Component { name: c.clone() })
+                    .collect(),
+                description: description.into(),
+                issuetype: IssueType {
+                    id: self.config.issue_type.clone(),
+                },
+                priority: Priority {
+                    id: self.config.priority.clone(),
+                },
+                project: Project {
+                    key: self.config.project_id.clone(),
+                },
+                summary: title.into(),
+            },
+        };
+        let issue = Issues::new(&self.jira);
+
+        debug!(
+            "Creating JIRA ticket with information: {:?}",
+            issue_description
+        );
+        let results = issue.create(issue_description)?;
+        Ok(results.id)
+    }
+
+    fn ticket_resolved(&self, ticket_id: &str) -> BynarResult<bool> {
+        let issue = Issues::new(&self.jira);
+        debug!("Fetching issue: {} for resolution information", ticket_id);
+        let results = issue.get(ticket_id)?;
+        match results.fields.get("resolutiondate") {
+            Some(Value::Null) => Ok(false),
+            Some(Value::String(_)) => Ok(true),
+            Some(_) => Ok(false),
+            //resolutiondate doesn't exist
+            None => Ok(false),
+        }
+    }
+
+    fn add_comment(&self, ticket_id: &str, comment: &str) -> BynarResult<()> {
+        debug!("Adding comment to issue: {}", ticket_id);
+        self.post(
+            &self.issue_url(ticket_id, "comment"),
+            &json!({ "body": comment }),
+        )
+    }
+
+    fn close_ticket(&self, ticket_id: &str) -> BynarResult<()> {
+        // Closing is a workflow transition and the ids differ per project.
+        // Look up the one to use
+        let url = self.issue_url(ticket_id, "transitions");
+        let transitions = self.get(&url)?;
+        let empty = vec![];
+        let transitions = transitions["transitions"].as_array().unwrap_or(&empty);
+        let transition = transitions
+            .iter()
+            .find(|t| match self.config.close_transition {
+                Some(ref name) => t["name"].as_str() == Some(name.as_str()),
+                None => t["to"]["statusCategory"]["key"].as_str() == Some("done"),
+            });
+        match transition.and_then(|t| t["id"].as_str()) {
+            Some(id) => {
+                debug!("Closing issue {} with transition {}", ticket_id, id);
+                self.post(&url, &json!({ "transition": { "id": id } }))
+            }
+            None => Err(BynarError::new(format!(
+                "No transition found to close issue {}",
+                ticket_id
+            ))),
+        }
+    }
+}
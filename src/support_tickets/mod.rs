@@ -0,0 +1,88 @@
+pub mod jira;
+pub mod phabricator;
+
+use self::jira::JiraBackend;
+use self::phabricator::PhabricatorBackend;
+use helpers::{error::*, ConfigSettings, TicketingConfig};
+
+/// Different ticketing systems have different ways of filing and tracking
+/// repair tickets.  This will be consolidated here in trait impl's.
+/// disk-manager's workers share one backend so it has to be thread safe.
+pub trait TicketBackend: Send + Sync {
+    /// Create a new support ticket and return the ticket ID associated with it
+    fn create(&self, title: &str, description: &str) -> BynarResult<String>;
+
+    /// Check to see if a support ticket is marked as resolved
+    fn ticket_resolved(&self, ticket_id: &str) -> BynarResult<bool>;
+
+    /// Add a comment to an existing ticket
+    fn add_comment(&self, ticket_id: &str, comment: &str) -> BynarResult<()>;
+
+    /// Close a ticket.  Used once a replaced disk is back in the cluster
+    fn close_ticket(&self, ticket_id: &str) -> BynarResult<()>;
+}
+
+/// Given the bynar config, return the configured TicketBackend.
+pub fn load_ticket_backend(config: &ConfigSettings) -> BynarResult<Box<dyn TicketBackend>> {
+    let backend: Box<dyn TicketBackend> = match config.ticketing()? {
+        TicketingConfig::Jira(jira) => Box::new(JiraBackend::new(jira, config.proxy.as_ref())?),
+        TicketingConfig::Phabricator(phab) => {
+            Box::new(PhabricatorBackend::new(phab, config.proxy.as_ref())?)
+        }
+    };
+
+    Ok(backend)
+}
+
+#[cfg(test)]
+pub(crate) mod mock {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Serve one canned json response per connection and hand back the
+    // request line and body of every request received
+    pub fn serve(responses: Vec<&'static str>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header == "\r\n" {
+                        break;
+                    }
+                    let lower = header.to_lowercase();
+                    if lower.starts_with("content-length:") {
+                        content_length = lower["content-length:".len()..].trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                requests.push(format!(
+                    "{}\n{}",
+                    request_line.trim(),
+                    String::from_utf8(body).unwrap()
+                ));
+                let mut stream = reader.into_inner();
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    response.len(),
+                    response
+                )
+                .unwrap();
+            }
+            requests
+        });
+        (url, handle)
+    }
+}
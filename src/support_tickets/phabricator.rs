@@ -0,0 +1,234 @@
+use crate::support_tickets::TicketBackend;
+
+use helpers::{error::*, PhabricatorConfig};
+use log::debug;
+use serde_json::value::Value;
+
+/// Phabricator Maniphest ticketing through the Conduit API
+pub struct PhabricatorBackend {
+    config: PhabricatorConfig,
+    client: reqwest::Client,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PhabricatorBackend;
+    use crate::support_tickets::mock::serve as mock_conduit;
+    use crate::support_tickets::TicketBackend;
+    use helpers::PhabricatorConfig;
+
+    fn backend(host: String) -> PhabricatorBackend {
+        backend_with_statuses(host, vec!["resolved".into()])
+    }
+
+    fn backend_with_statuses(host: String, resolved_statuses: Vec<String>) -> PhabricatorBackend {
+        let config = PhabricatorConfig {
+            host,
+            api_token: "api-test".into(),
+            project_phids: vec!["PHID-PROJ-dc".into()],
+            owner_phid: None,
+            priority: Some("high".into()),
+            resolved_statuses,
+        };
+        PhabricatorBackend::new(config, None).unwrap()
+    }
+
+    #[test]
+    fn test_create_task() {
+        let (url, handle) = mock_conduit(vec![
+            r#"{"result":{"object":{"id":42,"phid":"PHID-TASK-abc"},"transactions":[]},"error_code":null,"error_info":null}"#,
+        ]);
+        let id = backend(url)
+            .create(
+                "Bynar: Dead disk",
+                "A disk on host1 failed. Please replace.",
+            )
+            .unwrap();
+        assert_eq!(id, "42");
+        let requests = handle.join().unwrap();
+        assert!(requests[0].starts_with("POST /api/maniphest.edit "));
+        assert!(requests[0].contains("api.token=api-test"));
+        assert!(requests[0].contains("=Bynar%3A+Dead+disk"));
+        assert!(requests[0].contains("=A+disk+on+host1+failed.+Please+replace."));
+        assert!(requests[0].contains("=PHID-PROJ-dc"));
+        assert!(requests[0].contains("=high"));
+    }
+
+    #[test]
+    fn test_ticket_resolved() {
+        let (url, handle) = mock_conduit(vec![
+            r#"{"result":{"data":[{"id":42,"fields":{"status":{"value":"resolved","name":"Resolved"}}}]},"error_code":null,"error_info":null}"#,
+            r#"{"result":{"data":[{"id":43,"fields":{"status":{"value":"open","name":"Open"}}}]},"error_code":null,"error_info":null}"#,
+        ]);
+        let phab = backend(url);
+        assert!(phab.ticket_resolved("42").unwrap());
+        assert!(!phab.ticket_resolved("43").unwrap());
+        let requests = handle.join().unwrap();
+        assert!(requests[0].starts_with("POST /api/maniphest.search "));
+        assert!(requests[0].contains("constraints%5Bids%5D%5B0%5D=42"));
+    }
+
+    #[test]
+    fn test_comment_and_errors() {
+        let (url, handle) = mock_conduit(vec![
+            r#"{"result":{"object":{"id":42,"phid":"PHID-TASK-abc"},"transactions":[]},"error_code":null,"error_info":null}"#,
+            r#"{"result":null,"error_code":"ERR-CONDUIT-CORE","error_info":"No such task"}"#,
+        ]);
+        let phab = backend(url);
+        phab.add_comment("42", "Disk was replaced").unwrap();
+        let err = phab.close_ticket("43").unwrap_err();
+        assert!(err.to_string().contains("No such task"));
+        let requests = handle.join().unwrap();
+        assert!(requests[0].contains("=comment"));
+        assert!(requests[0].contains("objectIdentifier=42"));
+        assert!(requests[1].contains("=resolved"));
+    }
+
+    #[test]
+    fn test_close_status() {
+        let task = r#"{"result":{"object":{"id":42,"phid":"PHID-TASK-abc"},"transactions":[]},"error_code":null,"error_info":null}"#;
+        let (url, handle) = mock_conduit(vec![task, task]);
+        backend_with_statuses(url.clone(), vec!["fixed".into(), "resolved".into()])
+            .close_ticket("42")
+            .unwrap();
+        // Nothing configured falls back on resolved
+        backend_with_statuses(url, vec![])
+            .close_ticket("42")
+            .unwrap();
+        let requests = handle.join().unwrap();
+        assert!(requests[0].contains("=fixed"));
+        assert!(!requests[0].contains("=resolved"));
+        assert!(requests[1].contains("=resolved"));
+    }
+}
+
+impl PhabricatorBackend {
+    pub fn new(
+        config: PhabricatorConfig,
+        proxy: Option<&String>,
+    ) -> BynarResult<PhabricatorBackend> {
+        let client = match proxy {
+            Some(url) => reqwest::Client::builder()
+                .proxy(reqwest::Proxy::all(url)?)
+                .build()?,
+            None => reqwest::Client::new(),
+        };
+        Ok(PhabricatorBackend { config, client })
+    }
+
+    // Call a Conduit method and return the result field.  Conduit always
+    // answers 200 and reports failures in error_code/error_info
+    fn call(&self, method: &str, mut params: Vec<(String, String)>) -> BynarResult<Value> {
+        let url = format!("{}/api/{}", self.config.host.trim_end_matches('/'), method);
+        debug!("Calling conduit {} with {:?}", url, params);
+        params.push(("api.token".into(), self.config.api_token.clone()));
+        let response: Value = self
+            .client
+            .post(&url)
+            .form(&params)
+            .send()?
+            .error_for_status()?
+            .json()?;
+        if !response["error_code"].is_null() {
+            return Err(BynarError::new(format!(
+                "{} failed with {}: {}",
+                method,
+                response["error_code"],
+                response["error_info"].as_str().unwrap_or("")
+            )));
+        }
+        Ok(response["result"].clone())
+    }
+
+    // Apply a list of (type, value) transactions to a task.  A task is
+    // created if object_id is None
+    fn edit(
+        &self,
+        object_id: Option<&str>,
+        transactions: Vec<(&str, Vec<String>)>,
+    ) -> BynarResult<Value> {
+        let mut params = Vec::new();
+        if let Some(id) = object_id {
+            params.push(("objectIdentifier".to_string(), id.to_string()));
+        }
+        for (i, (t_type, values)) in transactions.into_iter().enumerate() {
+            params.push((format!("transactions[{}][type]", i), t_type.to_string()));
+            match t_type {
+                // These take a list of PHIDs
+                "projects.add" | "subscribers.add" => {
+                    for (j, v) in values.into_iter().enumerate() {
+                        params.push((format!("transactions[{}][value][{}]", i, j), v));
+                    }
+                }
+                _ => {
+                    params.push((format!("transactions[{}][value]", i), values.concat()));
+                }
+            }
+        }
+        self.call("maniphest.edit", params)
+    }
+}
+
+impl TicketBackend for PhabricatorBackend {
+    fn create(&self, title: &str, description: &str) -> BynarResult<String> {
+        let mut transactions = vec![
+            ("title", vec![title.to_string()]),
+            ("description", vec![description.to_string()]),
+        ];
+        if !self.config.project_phids.is_empty() {
+            transactions.push(("projects.add", self.config.project_phids.clone()));
+        }
+        if let Some(ref owner) = self.config.owner_phid {
+            transactions.push(("owner", vec![owner.clone()]));
+        }
+        if let Some(ref priority) = self.config.priority {
+            transactions.push(("priority", vec![priority.clone()]));
+        }
+        debug!("Creating Maniphest task: {}", title);
+        let result = self.edit(None, transactions)?;
+        match result["object"]["id"].as_u64() {
+            Some(id) => Ok(id.to_string()),
+            None => Err(BynarError::new(format!(
+                "maniphest.edit didn't return a task id: {}",
+                result
+            ))),
+        }
+    }
+
+    fn ticket_resolved(&self, ticket_id: &str) -> BynarResult<bool> {
+        debug!("Fetching task: {} for resolution information", ticket_id);
+        // Accept T123 as well as 123
+        let id = ticket_id.trim_start_matches('T');
+        let result = self.call(
+            "maniphest.search",
+            vec![("constraints[ids][0]".to_string(), id.to_string())],
+        )?;
+        match result["data"][0]["fields"]["status"]["value"].as_str() {
+            Some(status) => Ok(self.config.resolved_statuses.iter().any(|s| s == status)),
+            None => Err(BynarError::new(format!("Task {} not found", ticket_id))),
+        }
+    }
+
+    fn add_comment(&self, ticket_id: &str, comment: &str) -> BynarResult<()> {
+        debug!("Adding comment to task: {}", ticket_id);
+        self.edit(
+            Some(ticket_id),
+            vec![("comment", vec![comment.to_string()])],
+        )?;
+        Ok(())
+    }
+
+    fn close_ticket(&self, ticket_id: &str) -> BynarResult<()> {
+        // The first status is the one to close with.  An install can rename
+        // or replace resolved
+        let status = self
+            .config
+            .resolved_statuses
+            .first()
+            .cloned()
+            .unwrap_or_else(|| "resolved".to_string());
+        debug!("Closing task: {} as {}", ticket_id, status);
+        self.edit(Some(ticket_id), vec![("status", vec![status])])?;
+        Ok(())
+    }
+}
@@ -11,16 +11,21 @@
 use mocktopus::*;
 
 use crate::in_progress::{
-    add_disk_detail, add_or_update_operation, get_devices_from_db, get_state,
-    is_hardware_waiting_repair, save_state, HostDetailsMapping, OperationInfo,
+    add_disk_detail, add_or_update_operation, get_devices_from_db, get_operation_id, get_state,
+    is_hardware_waiting_repair, save_state, save_trace, HostDetailsMapping, OperationInfo,
 };
 use blkid::BlkId;
 use block_utils::{
     format_block_device, get_device_info, mount_device, unmount_device, Device, DeviceState,
     Filesystem, FilesystemType, MediaType, ScsiDeviceType, ScsiInfo, Vendor,
 };
+use ceph::ceph::{connect_to_ceph, Rados};
+use chrono::{DateTime, NaiveDateTime, Utc};
 use gpt::{disk, header::read_header, partition::read_partitions, partition::Partition};
-use helpers::{error::*, host_information::Host};
+use helpers::{
+    error::*, host_information::Host, lock_lvm, nvme, CephHealthConfig, ConfigSettings,
+    NvmeHealthConfig, WearLevelingConfig,
+};
 use log::{debug, error, trace, warn};
 use lvm::*;
 #[cfg(test)]
@@ -29,7 +34,12 @@ use petgraph::graphmap::GraphMap;
 use petgraph::Directed;
 use r2d2::Pool;
 use r2d2_postgres::PostgresConnectionManager as ConnectionManager;
-use std::collections::{BTreeMap, HashSet};
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use serde::de::DeserializeOwned;
+use serde_derive::*;
+use serde_json::json;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fmt;
 use std::fs::OpenOptions;
@@ -37,6 +47,7 @@ use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::{self, Command};
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 use tempdir::TempDir;
 use uuid::Uuid;
 
@@ -44,6 +55,11 @@ use uuid::Uuid;
 type TransitionFn =
     fn(State, &mut BlockDevice, &Option<(ScsiInfo, Option<ScsiInfo>)>, bool) -> State;
 
+// How many times a run can enter the same state.  A disk that isn't mounted
+// is Scanned once, again after Mount and a third time after a reformat or
+// replacement sends it back to Unscanned
+const MAX_STATE_VISITS: usize = 3;
+
 #[derive(Clone, Debug)]
 pub struct BlockDevice {
     pub device: Device,
@@ -56,6 +72,41 @@ pub struct BlockDevice {
     pub state: State,
     pub storage_detail_id: u32,
     pub operation_id: Option<u32>,
+    // What Ceph found wrong with the osds on this disk.  None means Ceph
+    // wasn't asked
+    pub ceph_problems: Option<Vec<String>>,
+    // Every transition tried during the last state machine run in order
+    pub trace: Vec<TransitionRecord>,
+    // When an SSD or NVMe drive counts as worn out
+    pub wear_leveling: WearLevelingConfig,
+    // When an NVMe drive's health log fails it
+    pub nvme_health: NvmeHealthConfig,
+}
+
+/// A transition the state machine tried and what came of it
+#[derive(Clone, Debug)]
+pub struct TransitionRecord {
+    pub from: State,
+    pub to: State,
+    /// Name of the transition.  ie: Scan, Eval
+    pub label: String,
+    /// The state the transition returned.  Fail if it didn't apply
+    pub result: State,
+    pub duration: Duration,
+}
+
+impl fmt::Display for TransitionRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {} ({}): {} in {}ms",
+            self.from,
+            self.to,
+            self.label,
+            self.result,
+            self.duration.as_millis()
+        )
+    }
 }
 
 impl BlockDevice {
@@ -65,7 +116,7 @@ impl BlockDevice {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use std::collections::BTreeMap;
     use std::fs::File;
     use std::io::Write;
@@ -77,6 +128,7 @@ mod tests {
     use lazy_static::lazy_static;
     use log::debug;
     use mocktopus::mocking::*;
+    use serde_json::json;
     use simplelog::{Config, TermLogger};
     use tempdir::TempDir;
     use uuid::Uuid;
@@ -128,6 +180,33 @@ mod tests {
         PathBuf::from(free_device)
     }
 
+    // An Unscanned device that isn't behind a raid controller.  Tests set
+    // whatever else they need on it
+    pub(crate) fn test_device(name: &str, media_type: super::MediaType) -> super::BlockDevice {
+        super::BlockDevice {
+            device: super::Device {
+                id: None,
+                name: name.into(),
+                media_type,
+                capacity: 26214400,
+                fs_type: super::FilesystemType::Xfs,
+                serial_number: None,
+            },
+            dev_path: Path::new("/dev").join(name),
+            device_database_id: None,
+            mount_point: None,
+            partitions: BTreeMap::new(),
+            scsi_info: super::ScsiInfo::default(),
+            state: super::State::Unscanned,
+            storage_detail_id: 1,
+            operation_id: None,
+            ceph_problems: None,
+            trace: Vec::new(),
+            wear_leveling: super::WearLevelingConfig::default(),
+            nvme_health: super::NvmeHealthConfig::default(),
+        }
+    }
+
     fn cleanup_loop_device(p: &Path) {
         // Cleanup
         Command::new("umount")
@@ -157,24 +236,13 @@ mod tests {
 
         let drive_id = Uuid::parse_str(&drive_uuid).unwrap();
 
-        let d = super::BlockDevice {
-            device: super::Device {
-                id: Some(drive_id),
-                name: dev.file_name().unwrap().to_str().unwrap().to_string(),
-                media_type: super::MediaType::Rotational,
-                capacity: 26214400,
-                fs_type: super::FilesystemType::Xfs,
-                serial_number: Some("123456".into()),
-            },
-            dev_path: PathBuf::from(""),
-            device_database_id: None,
-            mount_point: None,
-            partitions: BTreeMap::new(),
-            scsi_info: super::ScsiInfo::default(),
-            state: super::State::Unscanned,
-            storage_detail_id: 1,
-            operation_id: None,
-        };
+        let mut d = test_device(
+            dev.file_name().unwrap().to_str().unwrap(),
+            super::MediaType::Rotational,
+        );
+        d.device.id = Some(drive_id);
+        d.device.serial_number = Some("123456".into());
+        d.dev_path = PathBuf::from("");
         let mut s = super::StateMachine::new(d, None, true);
         s.setup_state_machine();
         s.print_graph();
@@ -212,24 +280,13 @@ mod tests {
             .unwrap();
 
         let drive_id = Uuid::parse_str(&drive_uuid).unwrap();
-        let d = super::BlockDevice {
-            device: super::Device {
-                id: Some(drive_id),
-                name: dev.file_name().unwrap().to_str().unwrap().to_string(),
-                media_type: super::MediaType::Rotational,
-                capacity: 26214400,
-                fs_type: super::FilesystemType::Xfs,
-                serial_number: Some("123456".into()),
-            },
-            dev_path: PathBuf::from(""),
-            device_database_id: None,
-            mount_point: None,
-            partitions: BTreeMap::new(),
-            scsi_info: super::ScsiInfo::default(),
-            state: super::State::Unscanned,
-            storage_detail_id: 1,
-            operation_id: None,
-        };
+        let mut d = test_device(
+            dev.file_name().unwrap().to_str().unwrap(),
+            super::MediaType::Rotational,
+        );
+        d.device.id = Some(drive_id);
+        d.device.serial_number = Some("123456".into());
+        d.dev_path = PathBuf::from("");
         let mut s = super::StateMachine::new(d, None, true);
         s.setup_state_machine();
         s.print_graph();
@@ -266,24 +323,13 @@ mod tests {
 
         let drive_id = Uuid::parse_str(&drive_uuid).unwrap();
 
-        let d = super::BlockDevice {
-            device: super::Device {
-                id: Some(drive_id),
-                name: dev.file_name().unwrap().to_str().unwrap().to_string(),
-                media_type: super::MediaType::Rotational,
-                capacity: 26214400,
-                fs_type: super::FilesystemType::Xfs,
-                serial_number: Some("123456".into()),
-            },
-            dev_path: PathBuf::from(""),
-            device_database_id: None,
-            mount_point: None,
-            partitions: BTreeMap::new(),
-            scsi_info: super::ScsiInfo::default(),
-            state: super::State::Unscanned,
-            storage_detail_id: 1,
-            operation_id: None,
-        };
+        let mut d = test_device(
+            dev.file_name().unwrap().to_str().unwrap(),
+            super::MediaType::Rotational,
+        );
+        d.device.id = Some(drive_id);
+        d.device.serial_number = Some("123456".into());
+        d.dev_path = PathBuf::from("");
         let mut s = super::StateMachine::new(d, None, false);
         s.setup_state_machine();
         s.print_graph();
@@ -311,24 +357,14 @@ mod tests {
 
         // Set the previous state to something other than Unscanned
 
-        let d = super::BlockDevice {
-            device: super::Device {
-                id: Some(drive_id),
-                name: dev.file_name().unwrap().to_str().unwrap().to_string(),
-                media_type: super::MediaType::Rotational,
-                capacity: 26214400,
-                fs_type: super::FilesystemType::Xfs,
-                serial_number: Some("123456".into()),
-            },
-            dev_path: PathBuf::from(""),
-            device_database_id: None,
-            mount_point: None,
-            partitions: BTreeMap::new(),
-            scsi_info: super::ScsiInfo::default(),
-            state: super::State::Replaced,
-            storage_detail_id: 1,
-            operation_id: None,
-        };
+        let mut d = test_device(
+            dev.file_name().unwrap().to_str().unwrap(),
+            super::MediaType::Rotational,
+        );
+        d.device.id = Some(drive_id);
+        d.device.serial_number = Some("123456".into());
+        d.dev_path = PathBuf::from("");
+        d.state = super::State::Replaced;
         // restore state?
         let mut s = super::StateMachine::new(d, None, true);
         s.setup_state_machine();
@@ -337,6 +373,303 @@ mod tests {
         println!("final state: {}", s.block_device.state);
         assert_eq!(s.block_device.state, super::State::Good);
     }
+
+    #[test]
+    fn test_state_machine_cycle() {
+        use super::Transition;
+
+        let d = test_device("sdz", super::MediaType::Rotational);
+        // Goes around 3 states forever unless run() stops it
+        let mut s = super::StateMachine::new(d, None, true);
+        s.add_transition(
+            super::State::Unscanned,
+            super::State::Scanned,
+            super::NoOp::transition,
+            "NoOp",
+        );
+        s.add_transition(
+            super::State::Scanned,
+            super::State::Reformatted,
+            super::NoOp::transition,
+            "NoOp",
+        );
+        s.add_transition(
+            super::State::Reformatted,
+            super::State::Unscanned,
+            super::NoOp::transition,
+            "NoOp",
+        );
+        s.run();
+        assert_eq!(s.block_device.state, super::State::Unscanned);
+        assert_eq!(s.block_device.trace.len(), 3 * super::MAX_STATE_VISITS);
+        assert_eq!(
+            s.block_device.trace[0].to_string().split(':').next(),
+            Some("unscanned -> scanned (NoOp)")
+        );
+    }
+
+    #[test]
+    fn test_state_machine_worn_out() {
+        super::run_smart_checks.mock_safe(|_| MockResult::Return(Ok(true)));
+        super::get_wear.mock_safe(|_| {
+            MockResult::Return(Ok(super::Wear {
+                percent_used: Some(97),
+                available_spare: Some(100),
+            }))
+        });
+
+        let d = test_device("sdz", super::MediaType::SolidState);
+        let mut s = super::StateMachine::new(d, None, true);
+        s.setup_state_machine();
+        s.run();
+        assert_eq!(s.block_device.state, super::State::WaitingForReplacement);
+    }
+
+    #[test]
+    fn test_state_machine_nvme() {
+        // libatasmart would pass it.  The NVMe health log doesn't
+        super::run_smart_checks.mock_safe(|_| MockResult::Return(Ok(true)));
+        super::run_nvme_checks.mock_safe(|_, _| MockResult::Return(Ok(false)));
+
+        let d = test_device("nvme0n1", super::MediaType::NVME);
+        let mut s = super::StateMachine::new(d, None, true);
+        s.setup_state_machine();
+        s.run();
+        assert_eq!(s.block_device.state, super::State::WaitingForReplacement);
+        assert_eq!(s.block_device.trace.len(), 1);
+    }
+
+    #[test]
+    fn test_state_machine_nvme_retry() {
+        use helpers::error::*;
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        // The health log can't be read the first time but is fine after
+        let calls = Rc::new(Cell::new(0));
+        let counter = Rc::clone(&calls);
+        super::run_nvme_checks.mock_safe(move |_, _| {
+            counter.set(counter.get() + 1);
+            if counter.get() == 1 {
+                MockResult::Return(Err(BynarError::from("ioctl failed")))
+            } else {
+                MockResult::Return(Ok(true))
+            }
+        });
+
+        let d = test_device("nvme0n1", super::MediaType::NVME);
+        let mut s = super::StateMachine::new(d, None, true);
+        s.setup_state_machine();
+        s.run();
+        // A disk that passed isn't ticketed
+        assert_ne!(s.block_device.state, super::State::WaitingForReplacement);
+        assert_eq!(calls.get(), 2);
+        assert!(s
+            .block_device
+            .trace
+            .iter()
+            .all(|r| r.result != super::State::WaitingForReplacement));
+    }
+
+    #[test]
+    fn test_wear() {
+        let limits = super::WearLevelingConfig::default();
+        let ata = json!({
+            "ata_smart_attributes": {
+                "table": [
+                    {"id": 9, "name": "Power_On_Hours", "value": 99},
+                    {"id": 177, "name": "Wear_Leveling_Count", "value": 40},
+                    {"id": 233, "name": "Media_Wearout_Indicator", "value": 8}
+                ]
+            }
+        });
+        let wear = super::parse_wear(&ata);
+        assert_eq!(
+            wear,
+            super::Wear {
+                percent_used: Some(92),
+                available_spare: None,
+            }
+        );
+        assert!(wear.worn_out(&limits).is_some());
+
+        let nvme = json!({
+            "nvme_smart_health_information_log": {
+                "percentage_used": 12,
+                "available_spare": 9
+            }
+        });
+        let wear = super::parse_wear(&nvme);
+        assert_eq!(
+            wear,
+            super::Wear {
+                percent_used: Some(12),
+                available_spare: Some(9),
+            }
+        );
+        assert_eq!(
+            wear.worn_out(&limits),
+            Some("9% of its spare blocks left.  The limit is 10%".to_string())
+        );
+
+        let healthy = json!({
+            "nvme_smart_health_information_log": {
+                "percentage_used": 3,
+                "available_spare": 100
+            }
+        });
+        assert_eq!(super::parse_wear(&healthy).worn_out(&limits), None);
+        // Drives that don't report wear pass
+        assert_eq!(super::parse_wear(&json!({})), super::Wear::default());
+        assert_eq!(super::Wear::default().worn_out(&limits), None);
+    }
+
+    #[test]
+    fn test_state_graph() {
+        use super::{State, TransitionRecord};
+        use std::time::Duration;
+
+        let record = |from, to, label: &str, result| TransitionRecord {
+            from,
+            to,
+            label: label.into(),
+            result,
+            duration: Duration::from_millis(5),
+        };
+        let trace = vec![
+            record(State::Unscanned, State::Scanned, "Scan", State::Scanned),
+            record(
+                State::Scanned,
+                State::WaitingForReplacement,
+                "CheckCeph",
+                State::Fail,
+            ),
+            record(State::Scanned, State::Good, "Eval", State::Good),
+        ];
+        let dot = super::state_graph(Some(State::Good), &trace);
+        assert!(dot.starts_with("digraph state_machine {"));
+        assert!(dot.contains("\tUnscanned -> Scanned[label=\"Scan\", color=green, penwidth=2];"));
+        assert!(dot.contains(
+            "\tScanned -> WaitingForReplacement[label=\"CheckCeph\", color=red, style=dashed];"
+        ));
+        assert!(dot.contains("\tWornOut -> WaitingForReplacement[label=\"MarkForReplacement\"];"));
+        assert!(dot.contains("\tGood[label=\"good\", style=filled, fillcolor=yellow];"));
+        assert!(dot.contains("\tScanned[label=\"scanned\"];"));
+    }
+
+    #[test]
+    fn test_ceph_problems() {
+        use chrono::{TimeZone, Utc};
+        use std::collections::{HashMap, HashSet};
+
+        let config: helpers::CephHealthConfig = serde_json::from_str("{}").unwrap();
+        let now = Utc.ymd(2019, 10, 16).and_hms(12, 0, 0);
+        let device = |dev: &str, devid: &str, osd: &str| super::CephDevice {
+            devid: devid.into(),
+            location: vec![super::CephDeviceLocation { dev: dev.into() }],
+            daemons: vec![osd.into()],
+        };
+        let log = |stamp: &str, message: &str| super::ClusterLogEntry {
+            stamp: stamp.into(),
+            message: message.into(),
+        };
+        let crash = |entity_name: &str, timestamp: &str| super::CrashReport {
+            entity_name: entity_name.into(),
+            timestamp: timestamp.into(),
+        };
+        let osd = |osd: u64, up: u8| super::OsdState {
+            osd,
+            up,
+            is_in: 1,
+            down_at: 0,
+        };
+        let mut smart_failed = HashSet::new();
+        smart_failed.insert("ATA_ST4000_4".to_string());
+        let mut checks = HashMap::new();
+        checks.insert(
+            "SLOW_OPS".to_string(),
+            super::HealthCheck {
+                summary: super::HealthMessage {
+                    message: "3 slow ops, oldest one blocked for 35 sec, daemons [osd.2,osd.12] have slow ops.".into(),
+                },
+                detail: vec![],
+            },
+        );
+        let mut down_since = HashMap::new();
+        // Just went down.  It might be restarting
+        down_since.insert(5, Utc.ymd(2019, 10, 16).and_hms(11, 58, 0));
+        down_since.insert(6, Utc.ymd(2019, 10, 16).and_hms(10, 0, 0));
+        let health = super::CephHealth {
+            osds: vec![
+                osd(0, 1),
+                osd(1, 0),
+                osd(2, 1),
+                osd(3, 1),
+                osd(4, 1),
+                osd(5, 0),
+                osd(6, 0),
+            ],
+            down_since,
+            devices: vec![
+                device("sdb", "ATA_ST4000_0", "osd.0"),
+                device("sdc", "ATA_ST4000_1", "osd.1"),
+                device("sdd", "ATA_ST4000_2", "osd.2"),
+                device("sde", "ATA_ST4000_3", "osd.3"),
+                device("sdf", "ATA_ST4000_4", "osd.4"),
+            ],
+            smart_failed,
+            crashes: vec![
+                crash("osd.3", "2019-10-16 08:00:00.000000Z"),
+                crash("osd.3", "2019-10-16T09:00:00.000000Z"),
+                crash("osd.3", "2019-10-16 10:00:00.000000Z"),
+                // Too long ago to count
+                crash("osd.0", "2019-10-10 10:00:00.000000Z"),
+                crash("osd.0", "2019-10-11 10:00:00.000000Z"),
+                crash("osd.0", "2019-10-12 10:00:00.000000Z"),
+            ],
+            cluster_log: vec![
+                log("2019-10-16 11:00:00.000000", "osd.0 failed (root=default,host=ceph01) (2 reporters from different host after 20.000 >= grace 20.000)"),
+                log("2019-10-16 11:10:00.000000", "osd.0 boot"),
+                log("2019-10-16 11:20:00.000000", "osd.0 marked itself down"),
+                log("2019-10-16 11:30:00.000000", "osd.0 boot"),
+                log("2019-10-16T11:40:00.000000+0000", "osd.0 failed (root=default,host=ceph01) (2 reporters from different host after 20.000 >= grace 20.000)"),
+                log("2019-10-16 11:50:00.000000", "osd.2 failed (root=default,host=ceph01) (2 reporters from different host after 20.000 >= grace 20.000)"),
+                log("2019-10-16 11:51:00.000000", "osd.2 marked itself down"),
+            ],
+            checks,
+        };
+
+        let sdb = health.problems("sdb", &[], &config, now);
+        assert_eq!(sdb.len(), 1);
+        assert!(sdb[0].starts_with("osd.0 was marked down 3 times"));
+        assert_eq!(
+            health.problems("sdc", &[], &config, now),
+            vec!["osd.1 is down"]
+        );
+        // 2 mark downs isn't flapping but the slow ops count
+        let sdd = health.problems("sdd", &[], &config, now);
+        assert_eq!(sdd.len(), 1);
+        assert!(sdd[0].starts_with("SLOW_OPS: "));
+        let sde = health.problems("sde", &[], &config, now);
+        assert_eq!(sde.len(), 1);
+        assert!(sde[0].starts_with("osd.3 crashed 3 times"));
+        assert_eq!(
+            health.problems("sdf", &[], &config, now),
+            vec!["Ceph's latest health metrics for ATA_ST4000_4 say SMART failed"]
+        );
+        // Ceph doesn't know this disk
+        assert!(health.problems("sdg", &[], &config, now).is_empty());
+        // Older clusters don't list devices.  The osds found on the disk
+        // are checked instead
+        let sdh = health.problems("sdh", &[3], &config, now);
+        assert_eq!(sdh.len(), 1);
+        assert!(sdh[0].starts_with("osd.3 crashed 3 times"));
+        assert!(health.problems("sdh", &[5], &config, now).is_empty());
+        assert_eq!(
+            health.problems("sdh", &[6], &config, now),
+            vec!["osd.6 has been down since 2019-10-16 10:00:00 UTC"]
+        );
+    }
 }
 
 trait Transition {
@@ -374,6 +707,29 @@ impl Transition for AttemptRepair {
     }
 }
 
+// Fail the disk if Ceph has trouble with its osds that SMART can't see.
+// ie: osds that are down, flapping, crashing or reporting slow ops
+impl Transition for CheckCeph {
+    fn transition(
+        to_state: State,
+        device: &mut BlockDevice,
+        _scsi_info: &Option<(ScsiInfo, Option<ScsiInfo>)>,
+        _simulate: bool,
+    ) -> State {
+        debug!("thread {} running CheckCeph transition", process::id());
+        match device.ceph_problems {
+            Some(ref problems) if !problems.is_empty() => {
+                for problem in problems {
+                    error!("{}: {}", device.dev_path.display(), problem);
+                }
+                to_state
+            }
+            // Ceph wasn't asked or has nothing against this disk
+            _ => State::Fail,
+        }
+    }
+}
+
 impl Transition for CheckForCorruption {
     fn transition(
         to_state: State,
@@ -419,10 +775,12 @@ impl Transition for CheckReadOnly {
     }
 }
 
+// Flash wears out with writes.  Replace SSD and NVMe drives before they
+// run out of endurance or spare blocks instead of waiting for them to fail
 impl Transition for CheckWearLeveling {
     fn transition(
         to_state: State,
-        _device: &mut BlockDevice,
+        device: &mut BlockDevice,
         _scsi_info: &Option<(ScsiInfo, Option<ScsiInfo>)>,
         _simulate: bool,
     ) -> State {
@@ -430,9 +788,37 @@ impl Transition for CheckWearLeveling {
             "thread {} running CheckWearLeveling transition",
             process::id()
         );
-
-        //TODO: How can we check wear leveling?
-        to_state
+        let wear = match device.device.media_type {
+            MediaType::SolidState => get_wear(&device.dev_path),
+            MediaType::NVME => get_nvme_wear(&device.dev_path),
+            // Only flash wears out like this
+            _ => return State::Fail,
+        };
+        match wear {
+            Ok(wear) => match wear.worn_out(&device.wear_leveling) {
+                Some(reason) => {
+                    error!("{} is worn out: {}", device.dev_path.display(), reason);
+                    to_state
+                }
+                None => {
+                    debug!(
+                        "thread {} {} wear: {:?}",
+                        process::id(),
+                        device.dev_path.display(),
+                        wear
+                    );
+                    State::Fail
+                }
+            },
+            Err(e) => {
+                error!(
+                    "Checking wear on {} failed: {}",
+                    device.dev_path.display(),
+                    e
+                );
+                State::Fail
+            }
+        }
     }
 }
 
@@ -684,7 +1070,22 @@ impl Transition for Scan {
         debug!("thread {} running Scan transition", process::id());
         let raid_backed = is_raid_backed(&scsi_info);
         match (raid_backed.0, raid_backed.1) {
-            (false, _) => match run_smart_checks(&Path::new(&device.dev_path)) {
+            // libatasmart only speaks ATA.  NVMe drives have their own
+            // health log
+            (false, _) if device.device.media_type == MediaType::NVME => {
+                match run_nvme_checks(&device.dev_path, device.nvme_health.max_media_errors) {
+                    Ok(true) => to_state,
+                    Ok(false) => {
+                        error!("{} failed its health check", device.dev_path.display());
+                        State::WaitingForReplacement
+                    }
+                    Err(e) => {
+                        error!("NVMe health check failed: {:?}", e);
+                        State::Fail
+                    }
+                }
+            }
+            (false, _) => match run_smart_checks(&device.dev_path) {
                 Ok(_) => to_state,
                 Err(e) => {
                     error!("Smart test failed: {:?}", e);
@@ -726,8 +1127,8 @@ pub struct StateMachine {
     // A record of the transitions so they can be written as a dot graph
     // for later visual debugging
     dot_graph: Vec<(State, State, String)>,
-    // Mapping of valid From -> To transitions
-    graph: GraphMap<State, TransitionFn, Directed>,
+    // Mapping of valid From -> To transitions and their labels
+    graph: GraphMap<State, (TransitionFn, String), Directed>,
     pub block_device: BlockDevice,
     // optional info of this device and optional scsi host information
     // used to determine whether this device is behind a raid controller
@@ -766,7 +1167,11 @@ impl StateMachine {
     ) {
         self.dot_graph
             .push((from_state, to_state, transition_label.to_string()));
-        self.graph.add_edge(from_state, to_state, callback);
+        self.graph.add_edge(
+            from_state,
+            to_state,
+            (callback, transition_label.to_string()),
+        );
     }
 
     // Run all transitions until we can't go any further and return
@@ -778,9 +1183,14 @@ impl StateMachine {
             self.block_device.dev_path.display(),
             self.block_device.state
         );
+        self.block_device.trace.clear();
+        // Times each state was entered.  Catches cycles that pass through
+        // several states before coming back around
+        let mut visits: HashMap<State, usize> = HashMap::new();
+        visits.insert(self.block_device.state, 1);
         'outer: loop {
             // Gather all the possible edges from this current State
-            let edges: Vec<(State, State, &TransitionFn)> =
+            let edges: Vec<(State, State, &(TransitionFn, String))> =
                 self.graph.edges(self.block_device.state).collect();
             // Some states have multiple paths they could go down.
             // If the state transition returns State::Fail try the next path
@@ -792,7 +1202,16 @@ impl StateMachine {
                     &e.0,
                     &e.1
                 );
-                let state = e.2(e.1, &mut self.block_device, &self.scsi_info, self.simulate);
+                let (callback, label) = e.2;
+                let start = Instant::now();
+                let state = callback(e.1, &mut self.block_device, &self.scsi_info, self.simulate);
+                self.block_device.trace.push(TransitionRecord {
+                    from: e.0,
+                    to: e.1,
+                    label: label.clone(),
+                    result: state,
+                    duration: start.elapsed(),
+                });
                 match state {
                     State::Fail => {
                         debug!(
@@ -826,8 +1245,6 @@ impl StateMachine {
                 }
             }
             // At this point we should've advanced further.  If not then we're stuck in an infinite loop
-            // Note this won't detect more complicated infinite loops where it changes through 2 states
-            // before ending back around again.
             if self.block_device.state == beginning_state {
                 // We're stuck in an infinite loop we can't advance further from
                 debug!(
@@ -838,27 +1255,60 @@ impl StateMachine {
                 );
                 break 'outer;
             }
+            // Longer loops come back around to a state that was already visited
+            let count = visits.entry(self.block_device.state).or_insert(0);
+            *count += 1;
+            if *count > MAX_STATE_VISITS {
+                error!(
+                    "thread {} {} Breaking loop: entered state {} {} times",
+                    process::id(),
+                    self.block_device.dev_path.display(),
+                    self.block_device.state,
+                    count
+                );
+                break 'outer;
+            }
         }
     }
 
     #[allow(dead_code)]
     fn print_graph(&self) {
-        // FIXME: Too simple.  Doesn't label the transitions
-        // Walk the graph and create a Dot
-        let mut states = HashSet::new();
-        println!("digraph state_machine{{");
+        println!(
+            "{}",
+            self.dot(Some(self.block_device.state), &self.block_device.trace)
+        );
+    }
+
+    // Walk the graph and create a Dot.  If a state is given it's filled in.
+    // Transitions in the trace are drawn green if they were taken and red
+    // if they were tried and didn't apply
+    fn dot(&self, state: Option<State>, trace: &[TransitionRecord]) -> String {
+        let mut states = BTreeSet::new();
+        let mut dot = String::from("digraph state_machine {\n");
         for n in &self.dot_graph {
             states.insert(n.0);
             states.insert(n.1);
-            println!("\t{:?} -> {:?}[label=\"{}\"];", n.0, n.1, n.2);
+            // The last try wins if a transition was tried more than once
+            let style = match trace.iter().rev().find(|r| r.from == n.0 && r.to == n.1) {
+                Some(r) if r.result == r.to => ", color=green, penwidth=2",
+                Some(_) => ", color=red, style=dashed",
+                None => "",
+            };
+            dot.push_str(&format!(
+                "\t{:?} -> {:?}[label=\"{}\"{}];\n",
+                n.0, n.1, n.2, style
+            ));
         }
         for n in states {
-            println!("\t{:?}[label=\"{:?}\"];", n, n);
+            let style = if Some(n) == state {
+                ", style=filled, fillcolor=yellow"
+            } else {
+                ""
+            };
+            dot.push_str(&format!("\t{:?}[label=\"{}\"{}];\n", n, n, style));
         }
-        //for edge in self.graph.all_edges() {
-        //println!("\t{:?} -> {:?}[label=\"\"];", edge.0, edge.1);
-        //}
-        println!("}}");
+        dot.push_str("}\n");
+        dot
     }
 
     // Add all the transition states here
@@ -867,6 +1317,8 @@ impl StateMachine {
         // If Unscanned has 2 edges it will run the first added one first
         // and then the second one.  To deal with this the
         // states are ordered from most to least ideal outcome.
+        // Scan goes straight to WaitingForReplacement when a health check
+        // fails so it doesn't have an edge of its own
         self.add_transition(State::Unscanned, State::Scanned, Scan::transition, "Scan");
         self.add_transition(State::Unscanned, State::Fail, Scan::transition, "Scan");
         self.add_transition(
@@ -888,15 +1340,23 @@ impl StateMachine {
             "CheckForCorruption",
         );
 
-        self.add_transition(State::Scanned, State::Good, Eval::transition, "Eval");
-        self.add_transition(State::Scanned, State::NotMounted, Eval::transition, "Eval");
-        self.add_transition(State::Scanned, State::WriteFailed, Eval::transition, "Eval");
+        // CheckCeph and CheckWearLeveling fail over to Eval unless the disk
+        // is in trouble.  They have to run first because Eval can end at Good
+        self.add_transition(
+            State::Scanned,
+            State::WaitingForReplacement,
+            CheckCeph::transition,
+            "CheckCeph",
+        );
         self.add_transition(
             State::Scanned,
             State::WornOut,
             CheckWearLeveling::transition,
             "CheckWearLeveling",
         );
+        self.add_transition(State::Scanned, State::Good, Eval::transition, "Eval");
+        self.add_transition(State::Scanned, State::NotMounted, Eval::transition, "Eval");
+        self.add_transition(State::Scanned, State::WriteFailed, Eval::transition, "Eval");
 
         self.add_transition(State::Mounted, State::Scanned, NoOp::transition, "NoOp");
         self.add_transition(
@@ -1032,6 +1492,8 @@ impl FromStr for State {
             "unscanned" => Ok(State::Unscanned),
             "waiting_for_replacement" => Ok(State::WaitingForReplacement),
             "worn_out" => Ok(State::WornOut),
+            "not_mounted" => Ok(State::NotMounted),
+            "write_failed" => Ok(State::WriteFailed),
             _ => Err(BynarError::new(format!("Unknown state: {}", s))),
         }
     }
@@ -1065,6 +1527,9 @@ impl fmt::Display for State {
 #[derive(Debug)]
 struct AttemptRepair;
 
+#[derive(Debug)]
+struct CheckCeph;
+
 #[derive(Debug)]
 struct CheckForCorruption;
 
@@ -1126,9 +1591,16 @@ fn filter_disks(devices: &[PathBuf], storage_detail_id: u32) -> BynarResult<Vec<
                 debug!("device mount: {}", mount.display());
                 mount_point = Some(mount);
             }
+            let mut device = d;
+            if device.media_type == MediaType::NVME && device.serial_number.is_none() {
+                match nvme::identify_controller(&dev_path) {
+                    Ok(controller) => device.serial_number = Some(controller.serial_number),
+                    Err(e) => warn!("Unable to identify {}: {}", dev_path.display(), e),
+                }
+            }
 
             BlockDevice {
-                device: d.clone(),
+                device,
                 dev_path,
                 // None means disk is not in the database
                 device_database_id: None,
@@ -1138,6 +1610,10 @@ fn filter_disks(devices: &[PathBuf], storage_detail_id: u32) -> BynarResult<Vec<
                 state: State::Unscanned,
                 storage_detail_id,
                 operation_id: None,
+                ceph_problems: None,
+                trace: Vec::new(),
+                wear_leveling: WearLevelingConfig::default(),
+                nvme_health: NvmeHealthConfig::default(),
             }
         })
         .collect();
@@ -1155,11 +1631,7 @@ fn filter_disks(devices: &[PathBuf], storage_detail_id: u32) -> BynarResult<Vec<
         // Get rid of root disk
         .filter(|b| {
             for p in b.partitions.iter().enumerate() {
-                let partition_path = Path::new("/dev").join(format!(
-                    "{name}{num}",
-                    name = b.device.name,
-                    num = p.0 + 1
-                ));
+                let partition_path = partition_path(&b.device.name, p.0 as u32 + 1);
                 debug!("partition_path: {}", partition_path.display());
                 if let Ok(Some(mount)) = block_utils::get_mountpoint(&partition_path) {
                     debug!("partition mount: {}", mount.display());
@@ -1176,6 +1648,16 @@ fn filter_disks(devices: &[PathBuf], storage_detail_id: u32) -> BynarResult<Vec<
     Ok(filtered_devices)
 }
 
+// Names ending in a number put a p before the partition number.
+// ie: nvme0n1p1
+fn partition_path(dev_name: &str, num: u32) -> PathBuf {
+    let separator = match dev_name.chars().last() {
+        Some(c) if c.is_ascii_digit() => "p",
+        _ => "",
+    };
+    Path::new("/dev").join(format!("{}{}{}", dev_name, separator, num))
+}
+
 // Add in any disks that the database knew about that linux can no longer find
 fn add_previous_devices(
     devices: &mut Vec<BlockDevice>,
@@ -1231,6 +1713,10 @@ fn add_previous_devices(
                     state: State::WaitingForReplacement,
                     storage_detail_id: host_mapping.storage_detail_id,
                     operation_id: None,
+                    ceph_problems: None,
+                    trace: Vec::new(),
+                    wear_leveling: WearLevelingConfig::default(),
+                    nvme_health: NvmeHealthConfig::default(),
                 };
                 save_state(pool, &b, State::WaitingForReplacement)?;
                 devices.push(b);
@@ -1241,6 +1727,38 @@ fn add_previous_devices(
     Ok(())
 }
 
+/// The disk state machine as a DOT digraph with its transitions labeled.
+/// Pass a device's saved state and trace to highlight where it is and the
+/// path its last run took
+pub fn state_graph(state: Option<State>, trace: &[TransitionRecord]) -> String {
+    // The transitions don't depend on the device.  Any will do
+    let block_device = BlockDevice {
+        device: Device {
+            id: None,
+            name: String::new(),
+            media_type: MediaType::Unknown,
+            capacity: 0,
+            fs_type: FilesystemType::Unknown,
+            serial_number: None,
+        },
+        dev_path: PathBuf::new(),
+        device_database_id: None,
+        mount_point: None,
+        partitions: BTreeMap::new(),
+        scsi_info: ScsiInfo::default(),
+        state: State::Unscanned,
+        storage_detail_id: 0,
+        operation_id: None,
+        ceph_problems: None,
+        trace: Vec::new(),
+        wear_leveling: WearLevelingConfig::default(),
+        nvme_health: NvmeHealthConfig::default(),
+    };
+    let mut s = StateMachine::new(block_device, None, true);
+    s.setup_state_machine();
+    s.dot(state, trace)
+}
+
 /// Retrives a list of disks, and sets up a state machine on each of them.
 /// Retrives previous state and runs through the state machine and preserves
 /// the final state in the database before returning a vector of StateMachine
@@ -1248,6 +1766,7 @@ pub fn check_all_disks(
     host_info: &Host,
     pool: &Pool<ConnectionManager>,
     host_mapping: &HostDetailsMapping,
+    config: &ConfigSettings,
 ) -> BynarResult<Vec<BynarResult<StateMachine>>> {
     // Udev will only show the disks that are currently attached to the tree
     // It will fail to show disks that have died and disconnected but are still
@@ -1266,6 +1785,11 @@ pub fn check_all_disks(
     mtab_devices.retain(|mtab_device| !devices.iter().any(|device| mtab_device == device));
     devices.extend_from_slice(&mtab_devices);
 
+    // block-utils doesn't list NVMe namespaces
+    let mut nvme_devices = nvme::namespaces()?;
+    nvme_devices.retain(|nvme_device| !devices.contains(nvme_device));
+    devices.extend_from_slice(&nvme_devices);
+
     // Gather info on all devices and skip Loopback devices
     let mut device_info = filter_disks(&devices, host_mapping.storage_detail_id)?;
 
@@ -1280,46 +1804,450 @@ pub fn check_all_disks(
             Some(i) => i,
         };
         let mut op_info = OperationInfo::new(host_mapping.entry_id, device_db_id);
+        // The daemon checks every disk on each pass under the same entry_id
+        if let Some(op_id) = get_operation_id(pool, host_mapping.entry_id, device_db_id)? {
+            op_info.set_operation_id(op_id);
+        }
         add_or_update_operation(pool, &mut op_info)?;
 
         // store the operation_id in BlockDevice struct
         dev.operation_id = op_info.operation_id;
     }
-    //TODO: Add nvme devices to block-utils
 
-    // Create 1 state machine per Device
-    // TODO: This could be evaulated in parallel but LVM usage is preventing this
+    // Ceph is asked once about all the disks.  If it can't be reached the
+    // disks are still checked without it
+    let ceph_health = config.ceph_health.as_ref();
+    let ceph = match ceph_health.map(|c| (c, CephHealth::gather(c, &host_info.hostname))) {
+        Some((ceph_config, Ok(health))) => Some((ceph_config, health)),
+        Some((_, Err(e))) => {
+            error!("Unable to get Ceph's view of the osds: {}", e);
+            None
+        }
+        None => None,
+    };
+    let now = Utc::now();
+
+    // Rayon picks one thread per cpu if disk_check_threads isn't set
+    let thread_pool = ThreadPoolBuilder::new()
+        .num_threads(config.disk_check_threads.unwrap_or(0))
+        .build()
+        .map_err(|e| BynarError::new(format!("Unable to start disk check threads: {}", e)))?;
+
+    // Create 1 state machine per Device and run them in parallel.
     // There's a bug in LVM that segfaults if more than 1 is started at the same
-    // time.
-    let mut disk_states: Vec<BynarResult<StateMachine>> = Vec::new();
-    for device in device_info {
-        let scsi_info = scsi_info
-            .iter()
-            .find(|r| {
-                if let Some(ref dev_name) = r.0.block_device {
-                    if let Some(file_name) = dev_name.file_name() {
-                        if file_name == OsStr::new(&device.device.name) {
-                            return true;
+    // time so the LVM calls take turns behind lock_lvm().
+    let disk_states: Vec<BynarResult<StateMachine>> = thread_pool.install(|| {
+        device_info
+            .into_par_iter()
+            .map(|mut device| -> BynarResult<StateMachine> {
+                device.ceph_problems = ceph.as_ref().map(|(ceph_config, health)| {
+                    let local_osds = osd_ids_on_disk(&device).unwrap_or_else(|e| {
+                        debug!("No osds found on {}: {}", device.dev_path.display(), e);
+                        vec![]
+                    });
+                    health.problems(&device.device.name, &local_osds, ceph_config, now)
+                });
+                device.wear_leveling = config.wear_leveling;
+                device.nvme_health = config.nvme_health;
+                let scsi_info = scsi_info
+                    .iter()
+                    .find(|r| {
+                        if let Some(ref dev_name) = r.0.block_device {
+                            if let Some(file_name) = dev_name.file_name() {
+                                if file_name == OsStr::new(&device.device.name) {
+                                    return true;
+                                }
+                            } else {
+                                return false;
+                            }
                         }
-                    } else {
-                        return false;
+                        false
+                    })
+                    .and_then(|r| Some(r.clone()));
+                debug!("thread {} scsi_info: {:?}", process::id(), scsi_info);
+                debug!("thread {} device: {:?}", process::id(), device);
+                let mut s = StateMachine::new(device, scsi_info, false);
+                s.setup_state_machine();
+                s.block_device.state = get_state(pool, &s.block_device)?;
+                s.run();
+                // Save the state to database after state machine finishes its run
+                save_state(pool, &s.block_device, s.block_device.state)?;
+                save_trace(pool, &s.block_device)?;
+                Ok(s)
+            })
+            .collect()
+    });
+
+    Ok(disk_states)
+}
+
+// Health checks that can point at a failing disk under an osd
+const DISK_HEALTH_CHECKS: &[&str] = &[
+    "SLOW_OPS",
+    "BLUESTORE_SPURIOUS_READ_ERRORS",
+    "OSD_TOO_MANY_REPAIRS",
+];
+
+// How far back in the cluster log to look for osds being marked down
+const CLUSTER_LOG_ENTRIES: u64 = 1000;
+
+// The part of osd dump that says whether an osd is up and in
+#[derive(Debug, Deserialize)]
+struct OsdDump {
+    osds: Vec<OsdState>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OsdState {
+    osd: u64,
+    up: u8,
+    #[serde(rename = "in")]
+    is_in: u8,
+    // The osd map epoch it last went down in
+    #[serde(default)]
+    down_at: u64,
+}
+
+// When an osd map epoch was made
+#[derive(Debug, Deserialize)]
+struct OsdMapStamp {
+    modified: String,
+}
+
+// A disk as the mgr sees it.  daemons are the osds using it.  ie: osd.3
+#[derive(Debug, Deserialize)]
+struct CephDevice {
+    devid: String,
+    #[serde(default)]
+    location: Vec<CephDeviceLocation>,
+    #[serde(default)]
+    daemons: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CephDeviceLocation {
+    // Kernel name.  ie: sdb
+    dev: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CrashReport {
+    entity_name: String,
+    timestamp: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClusterLogEntry {
+    stamp: String,
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct HealthDetail {
+    #[serde(default)]
+    checks: HashMap<String, HealthCheck>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HealthCheck {
+    summary: HealthMessage,
+    #[serde(default)]
+    detail: Vec<HealthMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HealthMessage {
+    message: String,
+}
+
+// Ceph's view of the osds on this host
+#[derive(Debug, Default)]
+struct CephHealth {
+    osds: Vec<OsdState>,
+    // When the osds that are down and in went down
+    down_since: HashMap<u64, DateTime<Utc>>,
+    devices: Vec<CephDevice>,
+    // devids whose latest health metrics say SMART failed
+    smart_failed: HashSet<String>,
+    crashes: Vec<CrashReport>,
+    cluster_log: Vec<ClusterLogEntry>,
+    checks: HashMap<String, HealthCheck>,
+}
+
+impl CephHealth {
+    fn gather(config: &CephHealthConfig, hostname: &str) -> BynarResult<CephHealth> {
+        let cluster = connect_to_ceph(&config.user_id, &config.config_file)?;
+        let dump: OsdDump = mon_json(&cluster, &json!({"prefix": "osd dump", "format": "json"}))?;
+        let health: HealthDetail = mon_json(
+            &cluster,
+            &json!({"prefix": "health", "detail": "detail", "format": "json"}),
+        )?;
+        let cluster_log: Vec<ClusterLogEntry> = mon_json(
+            &cluster,
+            &json!({
+                "prefix": "log last",
+                "num": CLUSTER_LOG_ENTRIES,
+                "channel": "cluster",
+                "format": "json"
+            }),
+        )?;
+
+        // The map an osd went down in says when.  Old maps get trimmed so
+        // an osd without one has been down a long time
+        let mut down_since = HashMap::new();
+        for osd in dump
+            .osds
+            .iter()
+            .filter(|o| o.up == 0 && o.is_in == 1 && o.down_at > 0)
+        {
+            let cmd = json!({"prefix": "osd dump", "epoch": osd.down_at, "format": "json"});
+            match mon_json::<OsdMapStamp>(&cluster, &cmd) {
+                Ok(map) => {
+                    if let Some(stamp) = parse_ceph_time(&map.modified) {
+                        down_since.insert(osd.osd, stamp);
                     }
                 }
-                false
-            })
-            .and_then(|r| Some(r.clone()));
-        debug!("thread {} scsi_info: {:?}", process::id(), scsi_info);
-        debug!("thread {} device: {:?}", process::id(), device);
-        let mut s = StateMachine::new(device, scsi_info, false);
-        s.setup_state_machine();
-        s.block_device.state = get_state(pool, &s.block_device)?;
-        s.run();
-        // Save the state to database after state machine finishes its run
-        save_state(pool, &s.block_device, s.block_device.state)?;
-        disk_states.push(Ok(s));
+                Err(e) => debug!("No osd map for epoch {}: {}", osd.down_at, e),
+            }
+        }
+
+        // The device and crash commands came with Nautilus.  Older clusters
+        // find the osds on a disk from its LVM tags instead and skip the
+        // SMART and crash checks
+        let host = hostname.split('.').next().unwrap_or(hostname);
+        let devices: Vec<CephDevice> = mgr_json(
+            &cluster,
+            &json!({"prefix": "device ls-by-host", "host": host, "format": "json"}),
+        )
+        .unwrap_or_else(|e| {
+            warn!("Unable to list Ceph's devices on {}: {}", host, e);
+            vec![]
+        });
+        let mut smart_failed = HashSet::new();
+        for device in &devices {
+            let cmd = json!({
+                "prefix": "device get-health-metrics",
+                "devid": device.devid,
+                "format": "json"
+            });
+            match mgr_json::<BTreeMap<String, serde_json::Value>>(&cluster, &cmd) {
+                Ok(ref metrics) if latest_smart_failed(metrics) => {
+                    smart_failed.insert(device.devid.clone());
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Unable to get health metrics of {}: {}", device.devid, e),
+            }
+        }
+        let crashes: Vec<CrashReport> =
+            mgr_json(&cluster, &json!({"prefix": "crash ls", "format": "json"})).unwrap_or_else(
+                |e| {
+                    warn!("Unable to list Ceph crash reports: {}", e);
+                    vec![]
+                },
+            );
+
+        Ok(CephHealth {
+            osds: dump.osds,
+            down_since,
+            devices,
+            smart_failed,
+            crashes,
+            cluster_log,
+            checks: health.checks,
+        })
     }
 
-    Ok(disk_states)
+    // What's wrong with the osds on a disk.  dev_name is the kernel name.
+    // ie: sdb.  local_osds are the osds found on the disk itself.  Empty if
+    // Ceph has no complaints or doesn't know the disk
+    fn problems(
+        &self,
+        dev_name: &str,
+        local_osds: &[u64],
+        config: &CephHealthConfig,
+        now: DateTime<Utc>,
+    ) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut osd_ids: BTreeSet<u64> = local_osds.iter().cloned().collect();
+        for device in self
+            .devices
+            .iter()
+            .filter(|d| d.location.iter().any(|l| l.dev == dev_name))
+        {
+            if self.smart_failed.contains(&device.devid) {
+                problems.push(format!(
+                    "Ceph's latest health metrics for {} say SMART failed",
+                    device.devid
+                ));
+            }
+            osd_ids.extend(device.daemons.iter().filter_map(|d| osd_id(d)));
+        }
+        for osd_id in osd_ids {
+            problems.extend(self.osd_problems(osd_id, config, now));
+        }
+        problems
+    }
+
+    fn osd_problems(
+        &self,
+        osd_id: u64,
+        config: &CephHealthConfig,
+        now: DateTime<Utc>,
+    ) -> Vec<String> {
+        let name = format!("osd.{}", osd_id);
+        let since = now - chrono::Duration::seconds(config.window as i64);
+        let recent = |stamp: &str| parse_ceph_time(stamp).map_or(false, |t| t >= since);
+        let mut problems = Vec::new();
+
+        // Down and out is how an osd is left while it's being removed
+        if let Some(osd) = self.osds.iter().find(|o| o.osd == osd_id) {
+            if osd.up == 0 && osd.is_in == 1 {
+                let min_down = chrono::Duration::seconds(config.min_down_time as i64);
+                match self.down_since.get(&osd_id) {
+                    Some(down) if now - *down < min_down => {
+                        debug!(
+                            "{} went down at {}.  Waiting to see if it stays down",
+                            name, down
+                        );
+                    }
+                    Some(down) => problems.push(format!("{} has been down since {}", name, down)),
+                    None => problems.push(format!("{} is down", name)),
+                }
+            }
+        }
+        let flaps = self
+            .cluster_log
+            .iter()
+            .filter(|e| is_mark_down(&e.message, &name) && recent(&e.stamp))
+            .count();
+        if flaps >= config.max_flaps {
+            problems.push(format!(
+                "{} was marked down {} times since {}",
+                name, flaps, since
+            ));
+        }
+        let crashes = self
+            .crashes
+            .iter()
+            .filter(|c| c.entity_name == name && recent(&c.timestamp))
+            .count();
+        if crashes >= config.max_crashes {
+            problems.push(format!(
+                "{} crashed {} times since {}",
+                name, crashes, since
+            ));
+        }
+        for check_name in DISK_HEALTH_CHECKS {
+            if let Some(check) = self.checks.get(*check_name) {
+                if mentions_daemon(&check.summary.message, &name)
+                    || check
+                        .detail
+                        .iter()
+                        .any(|d| mentions_daemon(&d.message, &name))
+                {
+                    problems.push(format!("{}: {}", check_name, check.summary.message));
+                }
+            }
+        }
+        problems
+    }
+}
+
+// The osds on a disk from their LVM tags or a mounted filestore osd's
+// whoami.  Ceph's own device list only exists from Nautilus on
+#[cfg_attr(test, mockable)]
+fn osd_ids_on_disk(device: &BlockDevice) -> BynarResult<Vec<u64>> {
+    let mut ids = vec![];
+    {
+        let _lvm_lock = lock_lvm();
+        let lvm = Lvm::new(None)?;
+        lvm.scan()?;
+        if let Some(vg_name) = lvm.vg_name_from_device(&device.dev_path.to_string_lossy())? {
+            let vg = lvm.vg_open(&vg_name, &OpenMode::Read)?;
+            for lv in &vg.list_lvs()? {
+                for tag in lv.get_tags()? {
+                    let mut parts = tag.splitn(2, '=');
+                    if parts.next() == Some("ceph.osd_id") {
+                        ids.extend(parts.next().and_then(|id| u64::from_str(id).ok()));
+                    }
+                }
+            }
+        }
+    }
+    if !ids.is_empty() {
+        return Ok(ids);
+    }
+    let mut mounts: Vec<PathBuf> = device.mount_point.iter().cloned().collect();
+    for num in device.partitions.keys() {
+        let partition = partition_path(&device.device.name, *num);
+        if let Ok(Some(mount)) = block_utils::get_mountpoint(&partition) {
+            mounts.push(mount);
+        }
+    }
+    for mount in mounts {
+        if let Ok(whoami) = std::fs::read_to_string(mount.join("whoami")) {
+            ids.extend(u64::from_str(whoami.trim()).ok());
+        }
+    }
+    Ok(ids)
+}
+
+fn mon_json<T: DeserializeOwned>(cluster: &Rados, cmd: &serde_json::Value) -> BynarResult<T> {
+    let (out, _) = cluster.ceph_mon_command_without_data(cmd)?;
+    Ok(serde_json::from_slice(&out)?)
+}
+
+fn mgr_json<T: DeserializeOwned>(cluster: &Rados, cmd: &serde_json::Value) -> BynarResult<T> {
+    let (out, _) = cluster.ceph_mgr_command_without_data(cmd)?;
+    Ok(serde_json::from_slice(&out)?)
+}
+
+// Health metrics are smartctl's json output keyed by when they were
+// scraped.  ie: 20191016-120000
+fn latest_smart_failed(metrics: &BTreeMap<String, serde_json::Value>) -> bool {
+    metrics
+        .values()
+        .next_back()
+        .and_then(|m| m.pointer("/smart_status/passed"))
+        .and_then(|passed| passed.as_bool())
+        == Some(false)
+}
+
+// ie: osd.3 -> 3
+fn osd_id(daemon: &str) -> Option<u64> {
+    if daemon.starts_with("osd.") {
+        u64::from_str(&daemon[4..]).ok()
+    } else {
+        None
+    }
+}
+
+// Cluster log messages for an osd being marked down.  ie: osd.3 failed
+// (2 reporters from different host ...) or osd.3 marked itself down
+fn is_mark_down(message: &str, daemon: &str) -> bool {
+    let prefix = format!("{} ", daemon);
+    if !message.starts_with(&prefix) {
+        return false;
+    }
+    let rest = &message[prefix.len()..];
+    rest.starts_with("failed") || rest.starts_with("marked itself down")
+}
+
+// Whether a health message names a daemon.  ie: osd.1 in
+// 3 slow ops, oldest one blocked for 35 sec, daemons [osd.0,osd.1] have slow ops.
+fn mentions_daemon(message: &str, daemon: &str) -> bool {
+    message
+        .split(|c: char| !(c.is_alphanumeric() || c == '.'))
+        .any(|word| word.trim_end_matches('.') == daemon)
+}
+
+// Ceph prints times as 2019-10-16 12:34:56.123456 or
+// 2019-10-16T12:34:56.123456+0000 depending on the version.  Read as UTC
+fn parse_ceph_time(stamp: &str) -> Option<DateTime<Utc>> {
+    let stamp = stamp.get(..19)?.replace('T', " ");
+    NaiveDateTime::parse_from_str(&stamp, "%Y-%m-%d %H:%M:%S")
+        .ok()
+        .map(|t| DateTime::<Utc>::from_utc(t, Utc))
 }
 
 #[cfg_attr(test, mockable)]
@@ -1380,6 +2308,7 @@ fn check_lvm(device: &Path) -> BynarResult<Fsck> {
     // lv display should show whether lvm can even access the device
     // do a write test against the device
     debug!("thread {} Checking lvm for corruption", process::id());
+    let _lvm_lock = lock_lvm();
     let lvm = Lvm::new(None)?;
     lvm.scan()?;
     // This might fail if the lvm on the disk is corrupt
@@ -1503,6 +2432,119 @@ fn run_smart_checks(device: &Path) -> BynarResult<bool> {
     Ok(status)
 }
 
+// Read the health log of an NVMe drive.  A critical warning other than the
+// temperature or too many media errors fails it
+#[cfg_attr(test, mockable)]
+fn run_nvme_checks(device: &Path, max_media_errors: u64) -> BynarResult<bool> {
+    let log = nvme::smart_log(device)?;
+    debug!(
+        "thread {} {} temperature: {}C",
+        process::id(),
+        device.display(),
+        log.celsius()
+    );
+    if log.temperature_warning() {
+        warn!(
+            "{} temperature {}C is outside its thresholds",
+            device.display(),
+            log.celsius()
+        );
+    }
+    let problems = log.problems(max_media_errors);
+    for problem in &problems {
+        error!("{}: {}", device.display(), problem);
+    }
+    Ok(problems.is_empty())
+}
+
+// How worn out a flash drive is.  None if the drive doesn't say
+#[derive(Debug, Default, PartialEq)]
+struct Wear {
+    // Percent of the rated endurance used.  Can go past 100
+    percent_used: Option<u8>,
+    // Percent of the spare blocks left
+    available_spare: Option<u8>,
+}
+
+impl Wear {
+    // Why the drive needs replacing.  None if it's within the limits
+    fn worn_out(&self, limits: &WearLevelingConfig) -> Option<String> {
+        match (self.percent_used, self.available_spare) {
+            (Some(used), _) if used >= limits.max_percent_used => Some(format!(
+                "{}% of its endurance used.  The limit is {}%",
+                used, limits.max_percent_used
+            )),
+            (_, Some(spare)) if spare <= limits.min_available_spare => Some(format!(
+                "{}% of its spare blocks left.  The limit is {}%",
+                spare, limits.min_available_spare
+            )),
+            _ => None,
+        }
+    }
+}
+
+// Pull the wear out of smartctl's json output.  ATA drives report it as
+// a normalized attribute counting down from 100.  NVMe drives have it in
+// their SMART/health log
+fn parse_wear(smart: &serde_json::Value) -> Wear {
+    let as_percent = |v: &serde_json::Value| v.as_u64().map(|v| v.min(255) as u8);
+    let mut wear = Wear {
+        percent_used: smart
+            .pointer("/nvme_smart_health_information_log/percentage_used")
+            .and_then(as_percent),
+        available_spare: smart
+            .pointer("/nvme_smart_health_information_log/available_spare")
+            .and_then(as_percent),
+    };
+    if let Some(table) = smart
+        .pointer("/ata_smart_attributes/table")
+        .and_then(|t| t.as_array())
+    {
+        for attribute in table {
+            match attribute["name"].as_str() {
+                Some("Media_Wearout_Indicator") | Some("Wear_Leveling_Count") => {}
+                _ => continue,
+            }
+            if let Some(value) = attribute["value"].as_u64() {
+                let used = 100u64.saturating_sub(value) as u8;
+                // Drives with both attributes are as worn as the worse one
+                wear.percent_used = Some(wear.percent_used.map_or(used, |u| u.max(used)));
+            }
+        }
+    }
+    wear
+}
+
+// Needs smartmontools 7 or newer for json output
+#[cfg_attr(test, mockable)]
+fn get_wear(device: &Path) -> BynarResult<Wear> {
+    let output = Command::new("smartctl")
+        .args(&["--json", "--all"])
+        .arg(device)
+        .output()?;
+    // Bits 0 and 1 of the exit status mean smartctl couldn't read the
+    // drive.  The rest describe what it found
+    if output.status.code().map_or(true, |code| code & 0b11 != 0) {
+        return Err(BynarError::new(format!(
+            "smartctl failed on {}: {}",
+            device.display(),
+            String::from_utf8_lossy(&output.stdout)
+        )));
+    }
+    let smart: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    Ok(parse_wear(&smart))
+}
+
+// NVMe drives hand over their wear without smartctl
+#[cfg_attr(test, mockable)]
+fn get_nvme_wear(device: &Path) -> BynarResult<Wear> {
+    let log = nvme::smart_log(device)?;
+    Ok(Wear {
+        percent_used: Some(log.percentage_used),
+        available_spare: Some(log.available_spare),
+    })
+}
+
 #[cfg_attr(test, mockable)]
 fn format_device(device: &Device) -> BynarResult<()> {
     let tmp = format!("/dev/{}", device.name);
@@ -1571,24 +2613,27 @@ fn is_device_mounted(dev_path: &Path) -> bool {
 // a few tests and makes a best guess if the disk is
 // blank
 fn is_disk_blank(dev: &Path) -> BynarResult<bool> {
-    debug!("thread {} Initializing lvm", process::id());
-    let lvm = Lvm::new(None)?;
-    lvm.scan()?;
-    // This might fail if the lvm on the disk is corrupt
-    if let Ok(vol_names) = lvm.get_volume_group_names() {
-        debug!("thread {} lvm volume names: {:?}", process::id(), vol_names);
-        for v in vol_names {
-            let vg = lvm.vg_open(&v, &OpenMode::Read)?;
-            let physical_vols = vg.list_pvs()?;
-            trace!(
-                "thread {} lvm physical volumes: {:?}",
-                process::id(),
-                physical_vols
-            );
-            for p in physical_vols {
-                trace!("thread {} physical volume: {}", process::id(), p.get_name());
-                if dev == Path::new(&p.get_name()) {
-                    return Ok(false);
+    {
+        debug!("thread {} Initializing lvm", process::id());
+        let _lvm_lock = lock_lvm();
+        let lvm = Lvm::new(None)?;
+        lvm.scan()?;
+        // This might fail if the lvm on the disk is corrupt
+        if let Ok(vol_names) = lvm.get_volume_group_names() {
+            debug!("thread {} lvm volume names: {:?}", process::id(), vol_names);
+            for v in vol_names {
+                let vg = lvm.vg_open(&v, &OpenMode::Read)?;
+                let physical_vols = vg.list_pvs()?;
+                trace!(
+                    "thread {} lvm physical volumes: {:?}",
+                    process::id(),
+                    physical_vols
+                );
+                for p in physical_vols {
+                    trace!("thread {} physical volume: {}", process::id(), p.get_name());
+                    if dev == Path::new(&p.get_name()) {
+                        return Ok(false);
+                    }
                 }
             }
         }